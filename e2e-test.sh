@@ -56,7 +56,7 @@ test_endpoint() {
 }
 
 echo "=== Basic Connectivity Tests ==="
-test_endpoint "GET /" "GET" "/" 200 "" "Hello from Rust Workers"
+test_endpoint "GET /" "GET" "/" 200 "" '"routes"'
 
 echo -e "\n=== Counter Tests ==="
 # Get initial counter value
@@ -0,0 +1,314 @@
+//! Typed client for the Worker's HTTP API
+//!
+//! Each method sends one request and deserializes the response into the
+//! structs below, so a change to a response's shape fails here instead of
+//! slipping past a substring match. Non-2xx responses become
+//! `ClientError::Status`, carrying the status code and body.
+//!
+//! Requests that fail to connect or time out can be retried with exponential
+//! backoff; see `with_retries`. Error statuses are never retried.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CounterResponse {
+    pub count: i64,
+    pub last_updated: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionData {
+    pub user_id: String,
+    pub data: serde_json::Value,
+    pub created_at: u64,
+    pub updated_at: u64,
+    #[serde(default)]
+    pub version: u64,
+}
+
+/// Response to a session `PUT`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionUpdate {
+    pub status: String,
+    pub user_id: String,
+    pub timestamp: u64,
+    pub version: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileUploadResponse {
+    pub key: String,
+    pub size: u64,
+    pub content_type: Option<String>,
+    pub uploaded_at: u64,
+    pub sha256: String,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    /// Whether the content was already stored, so no new storage was used
+    #[serde(default)]
+    pub deduplicated: bool,
+}
+
+/// Outcome of an upload: the stored file, and whether the key was new
+#[derive(Debug)]
+pub struct Uploaded {
+    /// `true` for `201 Created`, `false` when an existing file was replaced
+    pub created: bool,
+    pub file: FileUploadResponse,
+}
+
+/// A file's mapping, as returned by stat, copy, move and restore
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileMapping {
+    pub filename: String,
+    pub sha256: String,
+    pub size: u64,
+    pub content_type: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeletePrefixResponse {
+    pub prefix: String,
+    pub deleted: usize,
+    pub keys: Vec<String>,
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExistsStatus {
+    pub exists: bool,
+    pub sha256: Option<String>,
+}
+
+/// A request that failed, either in transport or with an error status
+#[derive(Debug)]
+pub enum ClientError {
+    Http(reqwest::Error),
+    Status { status: u16, body: String },
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Http(e) => write!(f, "request failed: {}", e),
+            ClientError::Status { status, body } => write!(f, "status {}: {}", status, body),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(err: reqwest::Error) -> Self {
+        ClientError::Http(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, ClientError>;
+
+/// Client for one deployment of the Worker
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    retries: u32,
+    backoff: Duration,
+    /// Retries made since the last `take_retry_count`
+    retry_count: AtomicU32,
+}
+
+impl Client {
+    pub fn new(base_url: &str) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            retries: 0,
+            backoff: Duration::ZERO,
+            retry_count: AtomicU32::new(0),
+        }
+    }
+
+    /// Give up on any request that takes longer than `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Result<Self> {
+        self.http = reqwest::Client::builder().timeout(timeout).build()?;
+        Ok(self)
+    }
+
+    /// Retry requests that fail to connect or time out, up to `retries` times
+    ///
+    /// The wait before each retry starts at `backoff` and doubles every time.
+    pub fn with_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    /// Number of retries made since the last call, resetting the count
+    pub fn take_retry_count(&self) -> u32 {
+        self.retry_count.swap(0, Ordering::Relaxed)
+    }
+
+    /// Send a request, retrying connection failures and timeouts
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        let mut delay = self.backoff;
+        for _ in 0..self.retries {
+            // Streaming bodies can't be cloned, so those only get one attempt
+            let attempt = match request.try_clone() {
+                Some(attempt) => attempt,
+                None => break,
+            };
+            match attempt.send().await {
+                Err(e) if e.is_connect() || e.is_timeout() => {
+                    self.retry_count.fetch_add(1, Ordering::Relaxed);
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                result => return Ok(result?),
+            }
+        }
+        Ok(request.send().await?)
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    /// Send a request without interpreting the response
+    ///
+    /// Returns the status and body whatever the status is, for checks on
+    /// error responses.
+    pub async fn send_raw(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<String>,
+    ) -> Result<(u16, String)> {
+        let mut request = self.http.request(method, self.url(path));
+        if let Some(body) = body {
+            request = request
+                .header("Content-Type", "application/json")
+                .body(body);
+        }
+        let response = self.send(request).await?;
+        let status = response.status().as_u16();
+        Ok((status, response.text().await?))
+    }
+
+    /// Fail non-2xx responses with their status and body
+    async fn check(response: reqwest::Response) -> Result<reqwest::Response> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        Err(ClientError::Status {
+            status: status.as_u16(),
+            body: response.text().await.unwrap_or_default(),
+        })
+    }
+
+    async fn get_json<T: for<'de> Deserialize<'de>>(&self, path: &str) -> Result<T> {
+        let response = self.send(self.http.get(self.url(path))).await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    async fn delete_text(&self, path: &str) -> Result<String> {
+        let response = self.send(self.http.delete(self.url(path))).await?;
+        Ok(Self::check(response).await?.text().await?)
+    }
+
+    pub async fn get_counter(&self) -> Result<CounterResponse> {
+        self.get_json("/counter").await
+    }
+
+    pub async fn increment(&self) -> Result<CounterResponse> {
+        let request = self.http.post(self.url("/counter/increment"));
+        let response = self.send(request).await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    pub async fn reset_counter(&self) -> Result<String> {
+        self.delete_text("/counter").await
+    }
+
+    pub async fn put_session(
+        &self,
+        session_id: &str,
+        user_id: &str,
+        data: serde_json::Value,
+    ) -> Result<SessionUpdate> {
+        let request = self
+            .http
+            .put(self.url(&format!("/session/{}", session_id)))
+            .json(&serde_json::json!({ "user_id": user_id, "data": data }));
+        let response = self.send(request).await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    pub async fn get_session(&self, session_id: &str) -> Result<SessionData> {
+        self.get_json(&format!("/session/{}", session_id)).await
+    }
+
+    pub async fn delete_session(&self, session_id: &str) -> Result<String> {
+        self.delete_text(&format!("/session/{}", session_id)).await
+    }
+
+    pub async fn upload_file(&self, key: &str, body: impl Into<reqwest::Body>) -> Result<Uploaded> {
+        let request = self
+            .http
+            .put(self.url(&format!("/files/{}", key)))
+            .body(body);
+        let response = self.send(request).await?;
+        let response = Self::check(response).await?;
+        let created = response.status() == reqwest::StatusCode::CREATED;
+        Ok(Uploaded {
+            created,
+            file: response.json().await?,
+        })
+    }
+
+    pub async fn download_file(&self, key: &str) -> Result<Vec<u8>> {
+        let request = self.http.get(self.url(&format!("/files/{}", key)));
+        let response = self.send(request).await?;
+        Ok(Self::check(response).await?.bytes().await?.to_vec())
+    }
+
+    /// Get a file's mapping without downloading it
+    pub async fn stat_file(&self, key: &str) -> Result<FileMapping> {
+        self.get_json(&format!("/files/{}?stat", key)).await
+    }
+
+    pub async fn copy_file(&self, source: &str, destination: &str) -> Result<FileMapping> {
+        let request = self
+            .http
+            .post(self.url(&format!("/files/{}/copy", source)))
+            .json(&serde_json::json!({ "destination": destination }));
+        let response = self.send(request).await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    pub async fn delete_file(&self, key: &str) -> Result<String> {
+        self.delete_text(&format!("/files/{}", key)).await
+    }
+
+    pub async fn list_files(&self) -> Result<Vec<String>> {
+        self.get_json("/files/").await
+    }
+
+    /// Delete every file under `prefix`, which should end in `/`
+    pub async fn delete_prefix(&self, prefix: &str) -> Result<DeletePrefixResponse> {
+        let request = self.http.delete(self.url(&format!("/files/{}", prefix)));
+        let response = self.send(request).await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+
+    pub async fn check_exists(&self, keys: &[&str]) -> Result<HashMap<String, ExistsStatus>> {
+        let request = self.http.post(self.url("/files/exists")).json(keys);
+        let response = self.send(request).await?;
+        Ok(Self::check(response).await?.json().await?)
+    }
+}
@@ -1,8 +1,15 @@
+mod client;
+
+use client::Client;
 use colored::*;
-use serde::{Deserialize, Serialize};
 use std::env;
-use std::time::Instant;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
 
+/// A request checked only by its status and a substring of its body
 #[derive(Debug)]
 struct TestCase {
     name: String,
@@ -19,189 +26,166 @@ struct TestResult {
     passed: bool,
     error: Option<String>,
     duration_ms: u128,
+    /// Requests sent, counting retries after connection failures
+    attempts: u32,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct CounterResponse {
-    count: i64,
-    last_updated: i64,
+/// Runner settings, from CLI flags or else environment variables
+#[derive(Debug)]
+struct Config {
+    base_url: String,
+    /// Per-request timeout (`--timeout-secs`, `E2E_TIMEOUT_SECS`); none by default
+    timeout: Option<Duration>,
+    /// Retries after a connection failure or timeout (`--retries`, `E2E_RETRIES`)
+    retries: u32,
+    /// Wait before the first retry, doubling after each (`--retry-backoff-ms`,
+    /// `E2E_RETRY_BACKOFF_MS`)
+    backoff: Duration,
+    /// Test groups run at once (`--parallel`, `E2E_PARALLEL`); 1, the default, runs them in turn
+    parallel: usize,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct SessionData {
-    user_id: String,
-    data: serde_json::Value,
+impl Config {
+    fn from_args(
+        mut args: impl Iterator<Item = String>,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, String> {
+        let mut base_url = None;
+        let mut timeout = var("E2E_TIMEOUT_SECS");
+        let mut retries = var("E2E_RETRIES");
+        let mut backoff = var("E2E_RETRY_BACKOFF_MS");
+        let mut parallel = var("E2E_PARALLEL");
+
+        while let Some(arg) = args.next() {
+            let setting = match arg.as_str() {
+                "--timeout-secs" => &mut timeout,
+                "--retries" => &mut retries,
+                "--retry-backoff-ms" => &mut backoff,
+                "--parallel" => &mut parallel,
+                flag if flag.starts_with("--") => return Err(format!("unknown flag {}", flag)),
+                _ => {
+                    base_url = Some(arg);
+                    continue;
+                }
+            };
+            *setting = Some(
+                args.next()
+                    .ok_or_else(|| format!("{} needs a value", arg))?,
+            );
+        }
+
+        let number = |name: &str, value: Option<String>| -> Result<Option<u64>, String> {
+            value
+                .map(|v| v.parse().map_err(|_| format!("invalid {}: {}", name, v)))
+                .transpose()
+        };
+        Ok(Config {
+            base_url: base_url.unwrap_or_else(|| "http://localhost:8787".to_string()),
+            timeout: number("timeout", timeout)?.map(Duration::from_secs),
+            retries: number("retries", retries)?.unwrap_or(0) as u32,
+            backoff: Duration::from_millis(number("retry backoff", backoff)?.unwrap_or(500)),
+            parallel: number("parallel", parallel)?.unwrap_or(1).max(1) as usize,
+        })
+    }
+
+    /// A client for the Worker with the configured timeout and retries
+    fn client(&self) -> Result<Client, client::ClientError> {
+        let client = Client::new(&self.base_url).with_retries(self.retries, self.backoff);
+        match self.timeout {
+            Some(timeout) => client.with_timeout(timeout),
+            None => Ok(client),
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct FileUploadResponse {
-    key: String,
-    size: u64,
-    content_type: Option<String>,
-    uploaded_at: i64,
-    sha256: String,
+/// Names shared by the test groups, unique to this run
+struct Context {
+    timestamp: i64,
+    session_id: String,
+    file_key: String,
+    copy_key: String,
+    file_content: String,
+    bulk_prefix: String,
+}
+
+/// The results of one group of tests, run in order
+type GroupFuture = Pin<Box<dyn Future<Output = Vec<TestResult>> + Send>>;
+
+type CheckResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+/// Fail a check with `message` unless `condition` holds
+fn ensure(condition: bool, message: impl Into<String>) -> CheckResult {
+    if condition {
+        Ok(())
+    } else {
+        Err(message.into().into())
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let base_url = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "http://localhost:8787".to_string());
+    let config = Config::from_args(env::args().skip(1), |name| env::var(name).ok())?;
 
-    println!("🧪 Running E2E tests against: {}", base_url.cyan());
+    println!("🧪 Running E2E tests against: {}", config.base_url.cyan());
+    if config.retries > 0 {
+        println!(
+            "Retrying connection failures up to {} times",
+            config.retries
+        );
+    }
+    if config.parallel > 1 {
+        println!("Running up to {} test groups at once", config.parallel);
+    }
     println!();
 
     let timestamp = chrono::Utc::now().timestamp();
-    let client = reqwest::Client::new();
-
-    let test_cases = vec![
-        // Basic connectivity
-        TestCase {
-            name: "GET / - Basic connectivity".to_string(),
-            method: reqwest::Method::GET,
-            path: "/".to_string(),
-            body: None,
-            expected_status: 200,
-            expected_content: Some("Hello from Rust Workers".to_string()),
-        },
-        // Counter tests
-        TestCase {
-            name: "GET /counter - Initial state".to_string(),
-            method: reqwest::Method::GET,
-            path: "/counter".to_string(),
-            body: None,
-            expected_status: 200,
-            expected_content: Some("count".to_string()),
-        },
-        TestCase {
-            name: "POST /counter/increment".to_string(),
-            method: reqwest::Method::POST,
-            path: "/counter/increment".to_string(),
-            body: None,
-            expected_status: 200,
-            expected_content: Some("count".to_string()),
-        },
-        TestCase {
-            name: "DELETE /counter - Reset".to_string(),
-            method: reqwest::Method::DELETE,
-            path: "/counter".to_string(),
-            body: None,
-            expected_status: 200,
-            expected_content: Some("Counter reset".to_string()),
-        },
-        // Session tests
-        TestCase {
-            name: format!("PUT /session/test-{} - Create session", timestamp),
-            method: reqwest::Method::PUT,
-            path: format!("/session/test-{}", timestamp),
-            body: Some(r#"{"user_id":"test-user","data":{"theme":"dark"}}"#.to_string()),
-            expected_status: 200,
-            expected_content: Some("status".to_string()), // Response contains status field
-        },
-        TestCase {
-            name: format!("GET /session/test-{} - Read session", timestamp),
-            method: reqwest::Method::GET,
-            path: format!("/session/test-{}", timestamp),
-            body: None,
-            expected_status: 200,
-            expected_content: Some("test-user".to_string()),
-        },
-        TestCase {
-            name: format!("DELETE /session/test-{} - Delete session", timestamp),
-            method: reqwest::Method::DELETE,
-            path: format!("/session/test-{}", timestamp),
-            body: None,
-            expected_status: 200,
-            expected_content: Some("Session cleared".to_string()),
-        },
-        // R2 storage tests
-        TestCase {
-            name: format!("PUT /files/test-{}.txt - Upload file", timestamp),
-            method: reqwest::Method::PUT,
-            path: format!("/files/test-{}.txt", timestamp),
-            body: Some(format!("Hello from E2E test at {}", timestamp)),
-            expected_status: 200,
-            expected_content: Some("sha256".to_string()),
-        },
-        TestCase {
-            name: format!("GET /files/test-{}.txt - Download file", timestamp),
-            method: reqwest::Method::GET,
-            path: format!("/files/test-{}.txt", timestamp),
-            body: None,
-            expected_status: 200,
-            expected_content: Some(format!("Hello from E2E test at {}", timestamp)),
-        },
-        TestCase {
-            name: "GET /files/ - List files (empty path)".to_string(),
-            method: reqwest::Method::GET,
-            path: "/files/".to_string(),
-            body: None,
-            expected_status: 400, // Empty path after sanitization
-            expected_content: None,
-        },
-        TestCase {
-            name: format!("DELETE /files/test-{}.txt - Delete file", timestamp),
-            method: reqwest::Method::DELETE,
-            path: format!("/files/test-{}.txt", timestamp),
-            body: None,
-            expected_status: 200,
-            expected_content: Some("File deleted".to_string()),
-        },
-        // Security tests - path traversal
-        // Note: These return 404 because the router doesn't match the path pattern
-        TestCase {
-            name: "Security: GET /files/../etc/passwd".to_string(),
-            method: reqwest::Method::GET,
-            path: "/files/../etc/passwd".to_string(),
-            body: None,
-            expected_status: 404, // Router rejects before reaching file handler
-            expected_content: None,
-        },
-        TestCase {
-            name: "Security: PUT /files/../../etc/passwd".to_string(),
-            method: reqwest::Method::PUT,
-            path: "/files/../../etc/passwd".to_string(),
-            body: Some("malicious content".to_string()),
-            expected_status: 404, // Router rejects before reaching file handler
-            expected_content: None,
-        },
-        TestCase {
-            name: "Security: GET /session/../../../etc/passwd".to_string(),
-            method: reqwest::Method::GET,
-            path: "/session/../../../etc/passwd".to_string(),
-            body: None,
-            expected_status: 404, // Router rejects before reaching session handler
-            expected_content: None,
-        },
-    ];
+    let ctx = Arc::new(Context {
+        timestamp,
+        session_id: format!("test-{}", timestamp),
+        file_key: format!("test-{}.txt", timestamp),
+        copy_key: format!("test-{}-copy.txt", timestamp),
+        file_content: format!("Hello from E2E test at {}", timestamp),
+        bulk_prefix: format!("bulk-{}/", timestamp),
+    });
 
-    let mut results = Vec::new();
+    // Groups touch separate keys, so they can run in any order; the tests
+    // within a group depend on each other and always run in sequence
+    let groups: Vec<fn(Client, Arc<Context>) -> GroupFuture> = vec![
+        basic_tests,
+        counter_tests,
+        session_tests,
+        file_tests,
+        prefix_tests,
+        rejection_tests,
+    ];
 
     println!("{}", "=== Running Tests ===".bold());
-    for test_case in test_cases {
-        let result = run_test(&client, &base_url, test_case).await?;
-
-        let status_icon = if result.passed {
-            "✓".green()
-        } else {
-            "✗".red()
-        };
-        let status_text = if result.passed {
-            "PASSED".green()
-        } else {
-            "FAILED".red()
-        };
-
-        println!(
-            "{} {} - {} ({}ms)",
-            status_icon, result.name, status_text, result.duration_ms
-        );
-
-        if let Some(error) = &result.error {
-            println!("  {}: {}", "Error".red(), error);
+    let mut results = Vec::new();
+    if config.parallel <= 1 {
+        for group in groups {
+            for result in group(config.client()?, ctx.clone()).await {
+                record(&mut results, result);
+            }
+        }
+    } else {
+        let permits = Arc::new(Semaphore::new(config.parallel));
+        let mut handles = Vec::new();
+        for group in groups {
+            // Each group gets its own client so retry counts stay per test
+            let client = config.client()?;
+            let ctx = ctx.clone();
+            let permits = permits.clone();
+            handles.push(tokio::spawn(async move {
+                let _permit = permits.acquire_owned().await;
+                group(client, ctx).await
+            }));
+        }
+        // Report in group order, however the groups finished
+        for handle in handles {
+            for result in handle.await? {
+                record(&mut results, result);
+            }
         }
-
-        results.push(result);
     }
 
     println!();
@@ -212,6 +196,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("Tests passed: {}", passed.to_string().green());
     println!("Tests failed: {}", failed.to_string().red());
+    let retried = results.iter().filter(|r| r.attempts > 1).count();
+    if retried > 0 {
+        println!("Tests retried: {}", retried.to_string().yellow());
+    }
 
     if failed > 0 {
         println!();
@@ -231,25 +219,507 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-async fn run_test(
-    client: &reqwest::Client,
-    base_url: &str,
-    test_case: TestCase,
-) -> Result<TestResult, Box<dyn std::error::Error>> {
-    let url = format!("{}{}", base_url, test_case.path);
-    let start = Instant::now();
+/// Connectivity of the Worker's root route
+fn basic_tests(client: Client, _ctx: Arc<Context>) -> GroupFuture {
+    Box::pin(async move {
+        let mut results = Vec::new();
+        results.push(
+            run_test(
+                &client,
+                TestCase {
+                    name: "GET / - Basic connectivity".to_string(),
+                    method: reqwest::Method::GET,
+                    path: "/".to_string(),
+                    body: None,
+                    expected_status: 200,
+                    expected_content: Some("\"routes\"".to_string()),
+                },
+            )
+            .await,
+        );
+        results
+    })
+}
+
+/// Counter reads, increments and resets
+fn counter_tests(client: Client, _ctx: Arc<Context>) -> GroupFuture {
+    Box::pin(async move {
+        let mut results = Vec::new();
+        results.push(
+            run_check(&client, "GET /counter - Initial state", async {
+                client.get_counter().await?;
+                Ok(())
+            })
+            .await,
+        );
+        results.push(
+            run_check(&client, "POST /counter/increment", async {
+                let before = client.get_counter().await?.count;
+                let after = client.increment().await?.count;
+                ensure(
+                    after == before + 1,
+                    format!("count went from {} to {}", before, after),
+                )
+            })
+            .await,
+        );
+        results.push(
+            run_check(&client, "DELETE /counter - Reset", async {
+                let message = client.reset_counter().await?;
+                ensure(
+                    message == "Counter reset",
+                    format!("unexpected reply '{}'", message),
+                )?;
+                let count = client.get_counter().await?.count;
+                ensure(count == 0, format!("count is {} after reset", count))
+            })
+            .await,
+        );
+        results
+    })
+}
+
+/// Create, read and delete one session
+fn session_tests(client: Client, ctx: Arc<Context>) -> GroupFuture {
+    Box::pin(async move {
+        let mut results = Vec::new();
+        results.push(
+            run_check(
+                &client,
+                &format!("PUT /session/{} - Create session", ctx.session_id),
+                async {
+                    let update = client
+                        .put_session(
+                            &ctx.session_id,
+                            "test-user",
+                            serde_json::json!({"theme": "dark"}),
+                        )
+                        .await?;
+                    ensure(
+                        update.status == "updated",
+                        format!("status is '{}'", update.status),
+                    )
+                },
+            )
+            .await,
+        );
+        results.push(
+            run_check(
+                &client,
+                &format!("GET /session/{} - Read session", ctx.session_id),
+                async {
+                    let session = client.get_session(&ctx.session_id).await?;
+                    ensure(
+                        session.user_id == "test-user",
+                        format!("user_id is '{}'", session.user_id),
+                    )?;
+                    ensure(
+                        session.data["theme"] == "dark",
+                        format!("data is {}", session.data),
+                    )
+                },
+            )
+            .await,
+        );
+        results.push(
+            run_check(
+                &client,
+                &format!("DELETE /session/{} - Delete session", ctx.session_id),
+                async {
+                    let message = client.delete_session(&ctx.session_id).await?;
+                    ensure(
+                        message == "Session cleared",
+                        format!("unexpected reply '{}'", message),
+                    )
+                },
+            )
+            .await,
+        );
+        results
+    })
+}
+
+/// Upload, copy, list and delete one file
+fn file_tests(client: Client, ctx: Arc<Context>) -> GroupFuture {
+    Box::pin(async move {
+        let mut results = Vec::new();
+        results.push(
+            run_check(
+                &client,
+                &format!("PUT /files/{} - Upload file", ctx.file_key),
+                async {
+                    let uploaded = client
+                        .upload_file(&ctx.file_key, ctx.file_content.clone())
+                        .await?;
+                    ensure(uploaded.created, "expected 201 Created for a new file")?;
+                    ensure(
+                        uploaded.file.size == ctx.file_content.len() as u64,
+                        format!("size is {}", uploaded.file.size),
+                    )
+                },
+            )
+            .await,
+        );
+        results.push(
+            run_check(
+                &client,
+                &format!("PUT /files/{} - Overwrite file", ctx.file_key),
+                async {
+                    let uploaded = client
+                        .upload_file(&ctx.file_key, ctx.file_content.clone())
+                        .await?;
+                    ensure(!uploaded.created, "expected 200 OK when overwriting")?;
+                    ensure(
+                        uploaded.file.deduplicated,
+                        "expected identical content to be deduplicated",
+                    )
+                },
+            )
+            .await,
+        );
+        results.push(
+            run_check(
+                &client,
+                &format!("GET /files/{}?stat - Get file metadata", ctx.file_key),
+                async {
+                    let mapping = client.stat_file(&ctx.file_key).await?;
+                    ensure(
+                        mapping.size == ctx.file_content.len() as u64,
+                        format!("size is {}", mapping.size),
+                    )
+                },
+            )
+            .await,
+        );
+        results.push(
+            run_check(
+                &client,
+                &format!("GET /files/{} - Download file", ctx.file_key),
+                async {
+                    let body = client.download_file(&ctx.file_key).await?;
+                    ensure(
+                        body == ctx.file_content.as_bytes(),
+                        "downloaded content differs",
+                    )
+                },
+            )
+            .await,
+        );
+        results.push(
+            run_check(
+                &client,
+                &format!("POST /files/{}/copy - Copy file", ctx.file_key),
+                async {
+                    let copied = client.copy_file(&ctx.file_key, &ctx.copy_key).await?;
+                    ensure(
+                        copied.filename == ctx.copy_key,
+                        format!("copied to '{}'", copied.filename),
+                    )
+                },
+            )
+            .await,
+        );
+        results.push(
+            run_test(
+                &client,
+                TestCase {
+                    name: format!("POST /files/{}/copy - Destination exists", ctx.file_key),
+                    method: reqwest::Method::POST,
+                    path: format!("/files/{}/copy", ctx.file_key),
+                    body: Some(format!(r#"{{"destination":"{}"}}"#, ctx.copy_key)),
+                    expected_status: 409,
+                    expected_content: None,
+                },
+            )
+            .await,
+        );
+        results.push(
+            run_check(
+                &client,
+                &format!("GET /files/{} - Download copy", ctx.copy_key),
+                async {
+                    let body = client.download_file(&ctx.copy_key).await?;
+                    ensure(
+                        body == ctx.file_content.as_bytes(),
+                        "copied content differs",
+                    )
+                },
+            )
+            .await,
+        );
+        results.push(
+            run_check(
+                &client,
+                &format!("DELETE /files/{} - Delete copy", ctx.copy_key),
+                async {
+                    let message = client.delete_file(&ctx.copy_key).await?;
+                    ensure(
+                        message == "File deleted",
+                        format!("unexpected reply '{}'", message),
+                    )
+                },
+            )
+            .await,
+        );
+        results.push(
+            run_check(&client, "GET /files/ - List files", async {
+                let files = client.list_files().await?;
+                ensure(
+                    files.contains(&ctx.file_key),
+                    format!("{} not listed", ctx.file_key),
+                )
+            })
+            .await,
+        );
+        results.push(
+            run_check(
+                &client,
+                &format!("DELETE /files/{} - Delete file", ctx.file_key),
+                async {
+                    let message = client.delete_file(&ctx.file_key).await?;
+                    ensure(
+                        message == "File deleted",
+                        format!("unexpected reply '{}'", message),
+                    )
+                },
+            )
+            .await,
+        );
+        results
+    })
+}
 
-    let mut request = client.request(test_case.method, &url);
+/// Upload a file and delete its prefix
+fn prefix_tests(client: Client, ctx: Arc<Context>) -> GroupFuture {
+    Box::pin(async move {
+        let mut results = Vec::new();
+        results.push(
+            run_check(
+                &client,
+                &format!("PUT /files/{}a.txt - Upload file", ctx.bulk_prefix),
+                async {
+                    client
+                        .upload_file(&format!("{}a.txt", ctx.bulk_prefix), "bulk delete test")
+                        .await?;
+                    Ok(())
+                },
+            )
+            .await,
+        );
+        results.push(
+            run_check(
+                &client,
+                &format!("DELETE /files/{} - Delete by prefix", ctx.bulk_prefix),
+                async {
+                    let deleted = client.delete_prefix(&ctx.bulk_prefix).await?;
+                    ensure(
+                        deleted.deleted == 1,
+                        format!("deleted {} files", deleted.deleted),
+                    )
+                },
+            )
+            .await,
+        );
+        results
+    })
+}
+
+/// Requests that should be rejected
+fn rejection_tests(client: Client, ctx: Arc<Context>) -> GroupFuture {
+    Box::pin(async move {
+        let mut results = Vec::new();
+        results.push(
+            run_test(
+                &client,
+                TestCase {
+                    name: "DELETE /files/ - Requires confirmation".to_string(),
+                    method: reqwest::Method::DELETE,
+                    path: "/files/".to_string(),
+                    body: None,
+                    expected_status: 400,
+                    expected_content: Some("confirm".to_string()),
+                },
+            )
+            .await,
+        );
+        results.push(
+            run_check(
+                &client,
+                "POST /files/exists - Batch existence check",
+                async {
+                    let missing = format!("missing-{}.txt", ctx.timestamp);
+                    let results = client.check_exists(&[&missing]).await?;
+                    ensure(
+                        results.get(&missing).is_some_and(|status| !status.exists),
+                        format!("{} reported as existing", missing),
+                    )
+                },
+            )
+            .await,
+        );
 
-    if let Some(body) = &test_case.body {
-        request = request
-            .header("Content-Type", "application/json")
-            .body(body.clone());
+        let test_cases = vec![
+            TestCase {
+                name: "GET /blobs/not-a-hash - Invalid hash".to_string(),
+                method: reqwest::Method::GET,
+                path: "/blobs/not-a-hash".to_string(),
+                body: None,
+                expected_status: 400,
+                expected_content: Some("Invalid SHA256".to_string()),
+            },
+            // Admin endpoints; the Worker under test has no ADMIN_TOKEN set
+            TestCase {
+                name: "POST /files/verify - Requires the admin token".to_string(),
+                method: reqwest::Method::POST,
+                path: "/files/verify".to_string(),
+                body: None,
+                expected_status: 403,
+                expected_content: Some("Admin endpoints are disabled".to_string()),
+            },
+            TestCase {
+                name: "PUT /files/stats - Reserved filename".to_string(),
+                method: reqwest::Method::PUT,
+                path: "/files/stats".to_string(),
+                body: Some("shadowed".to_string()),
+                expected_status: 400,
+                expected_content: Some("reserved".to_string()),
+            },
+            TestCase {
+                name: "GET /files/{path}?content_type=text/html - Script-capable override"
+                    .to_string(),
+                method: reqwest::Method::GET,
+                path: "/files/any.txt?content_type=text/html".to_string(),
+                body: None,
+                expected_status: 400,
+                expected_content: Some("runs scripts".to_string()),
+            },
+            TestCase {
+                name: "PUT /session/{id} - Malformed JSON".to_string(),
+                method: reqwest::Method::PUT,
+                path: format!("/session/malformed-{}", ctx.timestamp),
+                body: Some(r#"{"user_id": "test_user", "data": "#.to_string()),
+                expected_status: 400,
+                expected_content: Some("Invalid JSON".to_string()),
+            },
+            TestCase {
+                name: "PUT /session/{id} - Create without user_id".to_string(),
+                method: reqwest::Method::PUT,
+                path: format!("/session/no-user-{}", ctx.timestamp),
+                body: Some(r#"{"data": {"theme": "dark"}}"#.to_string()),
+                expected_status: 400,
+                expected_content: Some("user_id is required".to_string()),
+            },
+            TestCase {
+                name: "DELETE /session/{id}/a/b - Nested key".to_string(),
+                method: reqwest::Method::DELETE,
+                path: format!("/session/nested-{}/a/b", ctx.timestamp),
+                body: None,
+                expected_status: 400,
+                expected_content: Some("at most one key".to_string()),
+            },
+            // Security tests - path traversal
+            // Note: These return 404 because the router doesn't match the path pattern
+            TestCase {
+                name: "Security: GET /files/../etc/passwd".to_string(),
+                method: reqwest::Method::GET,
+                path: "/files/../etc/passwd".to_string(),
+                body: None,
+                expected_status: 404, // Router rejects before reaching file handler
+                expected_content: None,
+            },
+            TestCase {
+                name: "Security: PUT /files/../../etc/passwd".to_string(),
+                method: reqwest::Method::PUT,
+                path: "/files/../../etc/passwd".to_string(),
+                body: Some("malicious content".to_string()),
+                expected_status: 404, // Router rejects before reaching file handler
+                expected_content: None,
+            },
+            TestCase {
+                name: "Security: GET /session/../../../etc/passwd".to_string(),
+                method: reqwest::Method::GET,
+                path: "/session/../../../etc/passwd".to_string(),
+                body: None,
+                expected_status: 404, // Router rejects before reaching session handler
+                expected_content: None,
+            },
+        ];
+        for test_case in test_cases {
+            results.push(run_test(&client, test_case).await);
+        }
+        results
+    })
+}
+
+/// Print a test's outcome and add it to `results`
+fn record(results: &mut Vec<TestResult>, result: TestResult) {
+    let status_icon = if result.passed {
+        "✓".green()
+    } else {
+        "✗".red()
+    };
+    let status_text = if result.passed {
+        "PASSED".green()
+    } else {
+        "FAILED".red()
+    };
+
+    let attempts = if result.attempts > 1 {
+        format!(", {} attempts", result.attempts)
+    } else {
+        String::new()
+    };
+    println!(
+        "{} {} - {} ({}ms{})",
+        status_icon, result.name, status_text, result.duration_ms, attempts
+    );
+
+    if let Some(error) = &result.error {
+        println!("  {}: {}", "Error".red(), error);
     }
 
-    let response = request.send().await?;
-    let status = response.status().as_u16();
-    let body = response.text().await?;
+    results.push(result);
+}
+
+/// Run a check made of typed client calls, timing it
+async fn run_check(
+    client: &Client,
+    name: &str,
+    check: impl Future<Output = CheckResult>,
+) -> TestResult {
+    client.take_retry_count();
+    let start = Instant::now();
+    let outcome = check.await;
+
+    TestResult {
+        name: name.to_string(),
+        passed: outcome.is_ok(),
+        error: outcome.err().map(|e| e.to_string()),
+        duration_ms: start.elapsed().as_millis(),
+        attempts: 1 + client.take_retry_count(),
+    }
+}
+
+/// Send a raw request and compare its status and body with the expectations
+async fn run_test(client: &Client, test_case: TestCase) -> TestResult {
+    client.take_retry_count();
+    let start = Instant::now();
+
+    let (status, body) = match client
+        .send_raw(test_case.method, &test_case.path, test_case.body)
+        .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            return TestResult {
+                name: test_case.name,
+                passed: false,
+                error: Some(e.to_string()),
+                duration_ms: start.elapsed().as_millis(),
+                attempts: 1 + client.take_retry_count(),
+            }
+        }
+    };
 
     let duration_ms = start.elapsed().as_millis();
 
@@ -279,10 +749,11 @@ async fn run_test(
         None
     };
 
-    Ok(TestResult {
+    TestResult {
         name: test_case.name,
         passed,
         error,
         duration_ms,
-    })
+        attempts: 1 + client.take_retry_count(),
+    }
 }
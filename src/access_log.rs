@@ -0,0 +1,343 @@
+//! Audit log of mutating file operations
+//!
+//! Off unless `ACCESS_LOG_ENABLED=true`. Uploads, deletes, copies and moves
+//! each send an entry to a single `AccessLogObject`, which buffers them in its
+//! storage, one key per entry, and flushes them from an alarm every
+//! `ACCESS_LOG_FLUSH_INTERVAL_MS`.
+//! R2 can't append, so each flush writes its entries as new objects in
+//! `LOG_BUCKET`, one per day they fall on: `logs/YYYY-MM-DD/{flush_ts}.jsonl`.
+//!
+//! Logging is best effort: failing to record an entry never fails the
+//! operation being logged.
+
+use crate::http_date::format_rfc3339;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use wasm_bindgen::JsValue;
+use worker::*;
+
+/// How often buffered entries are written out when `ACCESS_LOG_FLUSH_INTERVAL_MS` isn't set
+const DEFAULT_FLUSH_INTERVAL_MS: u64 = 60_000;
+/// Prefix of the storage keys of entries waiting to be flushed
+const PENDING_PREFIX: &str = "pending:";
+/// Storage key entries were buffered under, all in one list, before `PENDING_PREFIX`
+const LEGACY_PENDING_KEY: &str = "pending";
+/// Most entries one flush writes; any more are left for the next
+const MAX_FLUSH_ENTRIES: usize = 1000;
+/// Most keys one `delete_multiple` call may remove
+const MAX_DELETE_KEYS: usize = 128;
+/// Storage key of the timestamp naming an unfinished flush's objects
+const FLUSH_TS_KEY: &str = "flush_ts";
+
+/// One logged operation, written as a line of JSON
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AccessLogEntry {
+    /// When the operation happened, in Unix epoch milliseconds
+    pub timestamp: u64,
+    /// HTTP method of the request
+    pub method: String,
+    /// What was done: `upload`, `delete`, `copy` or `move`
+    pub operation: String,
+    pub key: String,
+    /// For copies and moves, the key the file came from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<usize>,
+    /// The request's `CF-Ray` ID, to correlate with other logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Storage key of a buffered entry, in the order entries were appended
+///
+/// `seq` tells apart entries appended in the same millisecond. Both numbers
+/// are zero-padded so keys sort by time.
+pub fn pending_key(timestamp_ms: u64, seq: u64) -> String {
+    format!("{}{:013}:{:010}", PENDING_PREFIX, timestamp_ms, seq)
+}
+
+/// R2 key of the object a flush at `flush_ts` writes entries from the day of `timestamp_ms` to
+pub fn log_object_key(timestamp_ms: u64, flush_ts: u64) -> String {
+    format!(
+        "logs/{}/{}.jsonl",
+        &format_rfc3339(timestamp_ms)[..10],
+        flush_ts
+    )
+}
+
+/// Group entries into JSON lines by the log object a flush at `flush_ts` puts them in
+pub fn group_log_lines(
+    entries: &[AccessLogEntry],
+    flush_ts: u64,
+) -> Result<BTreeMap<String, String>> {
+    let mut objects: BTreeMap<String, String> = BTreeMap::new();
+    for entry in entries {
+        let lines = objects
+            .entry(log_object_key(entry.timestamp, flush_ts))
+            .or_default();
+        lines.push_str(&serde_json::to_string(entry)?);
+        lines.push('\n');
+    }
+    Ok(objects)
+}
+
+/// Whether access logging is turned on, from `ACCESS_LOG_ENABLED`
+pub fn access_log_enabled(env: &Env) -> bool {
+    env.var("ACCESS_LOG_ENABLED")
+        .map(|v| v.to_string() == "true")
+        .unwrap_or(false)
+}
+
+/// Durable Object buffering access log entries and flushing them to R2
+#[durable_object]
+pub struct AccessLogObject {
+    state: State,
+    env: Env,
+    /// Appends so far, for `pending_key`
+    seq: Cell<u64>,
+}
+
+impl AccessLogObject {
+    fn flush_interval_ms(&self) -> u64 {
+        self.env
+            .var("ACCESS_LOG_FLUSH_INTERVAL_MS")
+            .ok()
+            .and_then(|v| v.to_string().parse().ok())
+            .filter(|&ms: &u64| ms > 0)
+            .unwrap_or(DEFAULT_FLUSH_INTERVAL_MS)
+    }
+
+    /// Write the pending entries to new log objects, one per day
+    async fn flush(&self) -> Result<()> {
+        let storage = self.state.storage();
+        // Entries from before `PENDING_PREFIX` go out first
+        let mut pending = storage
+            .get::<Vec<AccessLogEntry>>(LEGACY_PENDING_KEY)
+            .await
+            .unwrap_or_default();
+        let listed = storage
+            .list_with_options(
+                ListOptions::new()
+                    .prefix(PENDING_PREFIX)
+                    .limit(MAX_FLUSH_ENTRIES),
+            )
+            .await?;
+        let mut keys = Vec::new();
+        for entry in listed.entries() {
+            let entry: js_sys::Array = entry.map_err(Error::from)?.into();
+            keys.push(entry.get(0).as_string().unwrap_or_default());
+            match serde_wasm_bindgen::from_value::<AccessLogEntry>(entry.get(1)) {
+                Ok(e) => pending.push(e),
+                Err(e) => console_log!("Dropping unreadable access log entry: {:?}", e),
+            }
+        }
+        if pending.is_empty() && keys.is_empty() {
+            return Ok(());
+        }
+
+        // A retried flush keeps its timestamp, so it overwrites the objects an
+        // earlier attempt got written rather than duplicating their entries
+        let flush_ts = match storage.get::<u64>(FLUSH_TS_KEY).await {
+            Ok(ts) => ts,
+            Err(_) => {
+                let ts = js_sys::Date::now() as u64;
+                storage.put(FLUSH_TS_KEY, ts).await?;
+                ts
+            }
+        };
+
+        let bucket = self.env.bucket("LOG_BUCKET")?;
+        for (key, lines) in group_log_lines(&pending, flush_ts)? {
+            bucket.put(&key, lines.into_bytes()).execute().await?;
+        }
+
+        // Only drop entries once they're safely in R2; a failed flush retries them.
+        // Entries appended while the objects were being written have keys of
+        // their own, so they wait for the next flush.
+        for chunk in keys.chunks(MAX_DELETE_KEYS) {
+            storage.delete_multiple(chunk.to_vec()).await?;
+        }
+        storage.delete(LEGACY_PENDING_KEY).await?;
+        storage.delete(FLUSH_TS_KEY).await?;
+
+        // A backlog bigger than one flush carries on right away
+        if keys.len() >= MAX_FLUSH_ENTRIES {
+            storage.set_alarm(Duration::from_millis(0)).await?;
+        }
+        Ok(())
+    }
+}
+
+impl DurableObject for AccessLogObject {
+    fn new(state: State, env: Env) -> Self {
+        Self {
+            state,
+            env,
+            seq: Cell::new(0),
+        }
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        match (req.method(), req.path().as_str()) {
+            (Method::Post, "/append") => {
+                let entry: AccessLogEntry = match req.json().await {
+                    Ok(e) => e,
+                    Err(_) => return Response::error("Invalid log entry", 400),
+                };
+
+                // Each entry is its own small write, however many are waiting
+                let storage = self.state.storage();
+                let seq = self.seq.get();
+                self.seq.set(seq + 1);
+                storage
+                    .put(&pending_key(js_sys::Date::now() as u64, seq), &entry)
+                    .await?;
+
+                if storage.get_alarm().await?.is_none() {
+                    storage
+                        .set_alarm(Duration::from_millis(self.flush_interval_ms()))
+                        .await?;
+                }
+                Response::ok("Logged")
+            }
+            _ => Response::error("Not found", 404),
+        }
+    }
+
+    async fn alarm(&self) -> Result<Response> {
+        if let Err(e) = self.flush().await {
+            console_log!("Failed to flush access log: {:?}", e);
+            // Try again later rather than losing the entries
+            self.state
+                .storage()
+                .set_alarm(Duration::from_millis(self.flush_interval_ms()))
+                .await?;
+        }
+        Response::ok("Flushed")
+    }
+}
+
+/// Send an entry to the `AccessLogObject`, if logging is on
+///
+/// Errors are logged and swallowed, so logging can never fail the operation.
+pub async fn record_access(env: &Env, entry: &AccessLogEntry) {
+    if !access_log_enabled(env) {
+        return;
+    }
+    if let Err(e) = send_entry(env, entry).await {
+        console_log!("Failed to record access log entry: {:?}", e);
+    }
+}
+
+async fn send_entry(env: &Env, entry: &AccessLogEntry) -> Result<()> {
+    let headers = Headers::new();
+    headers.set("content-type", "application/json")?;
+    let request = Request::new_with_init(
+        "https://fake-host/append",
+        RequestInit::new()
+            .with_method(Method::Post)
+            .with_body(Some(JsValue::from_str(&serde_json::to_string(entry)?)))
+            .with_headers(headers),
+    )?;
+
+    let namespace = env.durable_object("ACCESS_LOG_OBJECT")?;
+    let id = namespace.id_from_name("global")?; // Single global log buffer
+    id.get_stub()?.fetch_with_request(request).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(timestamp: u64, key: &str) -> AccessLogEntry {
+        AccessLogEntry {
+            timestamp,
+            method: "PUT".to_string(),
+            operation: "upload".to_string(),
+            key: key.to_string(),
+            source: None,
+            sha256: Some("abc".to_string()),
+            size: Some(5),
+            request_id: None,
+        }
+    }
+
+    #[test]
+    fn test_log_object_key() {
+        let flush_ts = 1_709_251_260_000;
+        assert_eq!(
+            log_object_key(0, flush_ts),
+            "logs/1970-01-01/1709251260000.jsonl"
+        );
+        assert_eq!(
+            log_object_key(1_709_251_199_999, flush_ts),
+            "logs/2024-02-29/1709251260000.jsonl"
+        );
+        assert_eq!(
+            log_object_key(1_709_251_200_000, flush_ts),
+            "logs/2024-03-01/1709251260000.jsonl"
+        );
+
+        // Each flush gets objects of its own
+        assert_ne!(log_object_key(0, flush_ts), log_object_key(0, flush_ts + 1));
+    }
+
+    #[test]
+    fn test_pending_keys_sort_by_append_order() {
+        let keys = [
+            pending_key(999, 7),
+            pending_key(1_000, 0),
+            pending_key(1_000, 1),
+            pending_key(1_709_251_200_000, 2),
+        ];
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(sorted, keys);
+        assert!(keys.iter().all(|k| k.starts_with(PENDING_PREFIX)));
+        assert_ne!(keys[0], LEGACY_PENDING_KEY);
+    }
+
+    #[test]
+    fn test_group_log_lines() {
+        let entries = [
+            entry(1_709_251_199_000, "a.txt"),
+            entry(1_709_251_200_000, "b.txt"),
+            entry(1_709_251_199_500, "c.txt"),
+        ];
+        let objects = group_log_lines(&entries, 1_709_251_260_000).unwrap();
+
+        assert_eq!(objects.len(), 2);
+        let first_day: Vec<&str> = objects["logs/2024-02-29/1709251260000.jsonl"]
+            .lines()
+            .collect();
+        assert_eq!(first_day.len(), 2);
+        assert!(first_day[0].contains("\"key\":\"a.txt\""));
+        assert!(first_day[1].contains("\"key\":\"c.txt\""));
+        assert!(objects["logs/2024-03-01/1709251260000.jsonl"].ends_with('\n'));
+    }
+
+    #[test]
+    fn test_entry_serialization() {
+        let mut e = entry(1000, "a.txt");
+        e.sha256 = None;
+        e.size = None;
+        e.request_id = Some("8a1b2c3d4e5f-SJC".to_string());
+
+        assert_eq!(
+            serde_json::to_value(&e).unwrap(),
+            serde_json::json!({
+                "timestamp": 1000,
+                "method": "PUT",
+                "operation": "upload",
+                "key": "a.txt",
+                "request_id": "8a1b2c3d4e5f-SJC"
+            })
+        );
+    }
+}
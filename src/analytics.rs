@@ -0,0 +1,81 @@
+//! Per-request data points in Workers Analytics Engine
+//!
+//! With an `ANALYTICS` Analytics Engine binding, every request writes one
+//! data point, which can be queried with SQL without going through the
+//! `MetricsObject`. Without the binding nothing is written. Writes are
+//! fire-and-forget, and a failed one is dropped, so analytics can never change
+//! a response.
+//!
+//! Each data point has this schema; keep it stable, since saved queries refer
+//! to fields by position:
+//!
+//! | Field     | Value                                                   |
+//! |-----------|---------------------------------------------------------|
+//! | `index1`  | Route label, as in `/metrics` (`files`, `counter`, ...) |
+//! | `blob1`   | Route label                                             |
+//! | `blob2`   | Request method, e.g. `GET`                              |
+//! | `blob3`   | Response status code, e.g. `200`                        |
+//! | `double1` | Time to produce the response, in milliseconds           |
+//! | `double2` | Response size from `Content-Length`, or 0 if unknown    |
+
+use worker::{AnalyticsEngineDataPointBuilder, Env};
+
+/// Name of the Analytics Engine dataset binding
+pub const ANALYTICS_BINDING: &str = "ANALYTICS";
+
+/// One handled request, as written to Analytics Engine
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnalyticsEvent {
+    pub route: &'static str,
+    pub method: String,
+    pub status: u16,
+    pub duration_ms: f64,
+    /// Response body size, 0 when it isn't known up front, as for streams
+    pub bytes: u64,
+}
+
+impl AnalyticsEvent {
+    /// `blob1` to `blob3`
+    pub fn blobs(&self) -> [String; 3] {
+        [
+            self.route.to_string(),
+            self.method.clone(),
+            self.status.to_string(),
+        ]
+    }
+
+    /// `double1` and `double2`
+    pub fn doubles(&self) -> [f64; 2] {
+        [self.duration_ms, self.bytes as f64]
+    }
+}
+
+/// Write `event` to the `ANALYTICS` dataset, if it's bound
+pub fn record(env: &Env, event: &AnalyticsEvent) {
+    let Ok(dataset) = env.analytics_engine(ANALYTICS_BINDING) else {
+        return;
+    };
+    let _ = AnalyticsEngineDataPointBuilder::new()
+        .indexes([event.route])
+        .blobs(event.blobs())
+        .doubles(event.doubles())
+        .write_to(&dataset);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_schema() {
+        let event = AnalyticsEvent {
+            route: "files",
+            method: "PUT".to_string(),
+            status: 201,
+            duration_ms: 12.5,
+            bytes: 2048,
+        };
+        assert_eq!(event.blobs(), ["files", "PUT", "201"]);
+        assert_eq!(event.doubles(), [12.5, 2048.0]);
+    }
+}
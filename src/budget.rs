@@ -0,0 +1,73 @@
+//! Time budgets for long-running admin operations
+//!
+//! Integrity scans and reindexes walk every blob, and on a big bucket that can
+//! outlast the Worker's time limit. Rather than being killed part way, they
+//! check a `TimeBudget` between steps and stop cleanly once it's spent,
+//! reporting how to resume. The budget comes from `OPERATION_BUDGET_MS` and
+//! defaults to `DEFAULT_OPERATION_BUDGET_MS`, comfortably inside the limit.
+
+/// Milliseconds an operation may run when `OPERATION_BUDGET_MS` isn't set
+pub const DEFAULT_OPERATION_BUDGET_MS: u64 = 20_000;
+
+/// An operation's time budget from `OPERATION_BUDGET_MS`
+///
+/// Missing, unparsable or zero values fall back to the default.
+pub fn operation_budget_ms(value: Option<&str>) -> u64 {
+    value
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .filter(|&ms| ms > 0)
+        .unwrap_or(DEFAULT_OPERATION_BUDGET_MS)
+}
+
+/// A deadline, checked against a clock that returns milliseconds
+///
+/// The clock is passed in so the budget can be tested without a runtime.
+pub struct TimeBudget<'a> {
+    deadline: u64,
+    now: &'a dyn Fn() -> u64,
+}
+
+impl<'a> TimeBudget<'a> {
+    /// A budget of `budget_ms` starting now
+    pub fn new(budget_ms: u64, now: &'a dyn Fn() -> u64) -> Self {
+        Self {
+            deadline: now().saturating_add(budget_ms),
+            now,
+        }
+    }
+
+    /// Whether the deadline has passed, so the operation should stop
+    pub fn exhausted(&self) -> bool {
+        (self.now)() >= self.deadline
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_operation_budget_ms() {
+        assert_eq!(operation_budget_ms(None), DEFAULT_OPERATION_BUDGET_MS);
+        assert_eq!(operation_budget_ms(Some("5000")), 5000);
+        assert_eq!(operation_budget_ms(Some("0")), DEFAULT_OPERATION_BUDGET_MS);
+        assert_eq!(
+            operation_budget_ms(Some("soon")),
+            DEFAULT_OPERATION_BUDGET_MS
+        );
+    }
+
+    #[test]
+    fn test_time_budget() {
+        let clock = Cell::new(1_000);
+        let now = || clock.get();
+        let budget = TimeBudget::new(500, &now);
+
+        assert!(!budget.exhausted());
+        clock.set(1_499);
+        assert!(!budget.exhausted());
+        clock.set(1_500);
+        assert!(budget.exhausted());
+    }
+}
@@ -0,0 +1,128 @@
+//! Client IP address extraction
+//!
+//! On Cloudflare the client's address is in `CF-Connecting-IP`, but that header
+//! is missing under `wrangler dev` or behind another proxy. `client_ip` checks
+//! an ordered list of headers and takes the first one present. The list comes
+//! from `CLIENT_IP_HEADERS`, a comma-separated list of header names, and
+//! defaults to `DEFAULT_CLIENT_IP_HEADERS`.
+//!
+//! Only list headers set by a proxy you trust: anything else can be forged by
+//! the client.
+
+use worker::{Env, Request};
+
+/// Headers checked for the client IP when `CLIENT_IP_HEADERS` isn't set, in order
+pub const DEFAULT_CLIENT_IP_HEADERS: [&str; 3] =
+    ["CF-Connecting-IP", "X-Forwarded-For", "X-Real-IP"];
+
+/// Header names from a `CLIENT_IP_HEADERS` value, in the order to check them
+///
+/// Missing or empty values fall back to the defaults.
+pub fn client_ip_headers(value: Option<&str>) -> Vec<String> {
+    let headers: Vec<String> = value
+        .unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect();
+    if headers.is_empty() {
+        DEFAULT_CLIENT_IP_HEADERS
+            .iter()
+            .map(|h| h.to_string())
+            .collect()
+    } else {
+        headers
+    }
+}
+
+/// The client IP carried by one header's value
+///
+/// `X-Forwarded-For` lists every hop, so only its leftmost entry, the
+/// original client, is used.
+pub fn ip_from_header(name: &str, value: &str) -> Option<String> {
+    let ip = if name.eq_ignore_ascii_case("X-Forwarded-For") {
+        value.split(',').next().unwrap_or("")
+    } else {
+        value
+    };
+    let ip = ip.trim();
+    (!ip.is_empty()).then(|| ip.to_string())
+}
+
+/// The first client IP found in `headers`, looking each one up with `get`
+pub fn first_client_ip(headers: &[String], get: impl Fn(&str) -> Option<String>) -> Option<String> {
+    headers
+        .iter()
+        .find_map(|name| get(name).and_then(|value| ip_from_header(name, &value)))
+}
+
+/// The client's IP address, from the first configured header present
+pub fn client_ip(req: &Request, env: &Env) -> Option<String> {
+    let configured = env.var("CLIENT_IP_HEADERS").ok().map(|v| v.to_string());
+    let headers = client_ip_headers(configured.as_deref());
+    first_client_ip(&headers, |name| req.headers().get(name).ok().flatten())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_client_ip_headers() {
+        assert_eq!(client_ip_headers(None), DEFAULT_CLIENT_IP_HEADERS);
+        assert_eq!(client_ip_headers(Some(" , ")), DEFAULT_CLIENT_IP_HEADERS);
+        assert_eq!(
+            client_ip_headers(Some("X-Real-IP, True-Client-IP")),
+            vec!["X-Real-IP", "True-Client-IP"]
+        );
+    }
+
+    #[test]
+    fn test_x_forwarded_for_takes_leftmost() {
+        assert_eq!(
+            ip_from_header("X-Forwarded-For", " 203.0.113.7 , 10.0.0.1, 10.0.0.2"),
+            Some("203.0.113.7".to_string())
+        );
+        assert_eq!(
+            ip_from_header("x-forwarded-for", "2001:db8::1"),
+            Some("2001:db8::1".to_string())
+        );
+        assert_eq!(ip_from_header("X-Forwarded-For", " , 10.0.0.1"), None);
+        assert_eq!(ip_from_header("X-Forwarded-For", ""), None);
+    }
+
+    #[test]
+    fn test_other_headers_are_used_whole() {
+        assert_eq!(
+            ip_from_header("X-Real-IP", " 198.51.100.2 "),
+            Some("198.51.100.2".to_string())
+        );
+        assert_eq!(ip_from_header("CF-Connecting-IP", "  "), None);
+    }
+
+    #[test]
+    fn test_first_client_ip_follows_header_order() {
+        let request: HashMap<&str, &str> = HashMap::from([
+            ("X-Forwarded-For", "203.0.113.7, 10.0.0.1"),
+            ("X-Real-IP", "198.51.100.2"),
+        ]);
+        let get = |name: &str| request.get(name).map(|v| v.to_string());
+
+        let headers = client_ip_headers(None);
+        assert_eq!(
+            first_client_ip(&headers, get),
+            Some("203.0.113.7".to_string())
+        );
+
+        let headers = client_ip_headers(Some("X-Real-IP,X-Forwarded-For"));
+        assert_eq!(
+            first_client_ip(&headers, get),
+            Some("198.51.100.2".to_string())
+        );
+
+        let headers = client_ip_headers(Some("CF-Connecting-IP"));
+        assert_eq!(first_client_ip(&headers, get), None);
+    }
+}
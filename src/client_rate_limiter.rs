@@ -0,0 +1,176 @@
+//! Per-client request rate limiting
+//!
+//! Off unless `CLIENT_RATE_LIMIT_ENABLED=true`. When on, every request except
+//! those to `EXEMPT_PATHS` is counted against the client's IP address, taken
+//! from `CF-Connecting-IP` (see `client_ip`), and rejected with a 429 once the client exceeds
+//! `CLIENT_RATE_LIMIT` requests in `CLIENT_RATE_LIMIT_WINDOW_MS`.
+//!
+//! Counting reuses `RateLimiter` from the R2 write limiter, held in
+//! `ClientRateLimiterObject` instances sharded by a hash of the IP the same way.
+//! History is in memory only, so an evicted instance forgets recent requests.
+
+use crate::error::method_not_allowed;
+use crate::r2_rate_limiter::{rate_limited_response, retry_after_secs, shard_for_key, RateLimiter};
+use std::cell::RefCell;
+use worker::*;
+
+/// Requests allowed per window when `CLIENT_RATE_LIMIT` isn't set
+pub const DEFAULT_CLIENT_RATE_LIMIT: usize = 100;
+/// Window length when `CLIENT_RATE_LIMIT_WINDOW_MS` isn't set
+pub const DEFAULT_CLIENT_RATE_LIMIT_WINDOW_MS: u64 = 60_000;
+/// Number of limiter instances client IPs are spread across
+const CLIENT_RATE_LIMITER_SHARDS: u32 = 16;
+/// Paths that are never rate limited, so monitoring keeps working under load
+pub const EXEMPT_PATHS: [&str; 2] = ["/health", "/metrics"];
+
+/// Whether requests to `path` skip the rate limiter
+pub fn is_exempt(path: &str) -> bool {
+    EXEMPT_PATHS.contains(&path)
+}
+
+/// Requests allowed per window, and the window length in milliseconds
+///
+/// Missing or invalid values fall back to the defaults.
+pub fn client_rate_limit_config(limit: Option<&str>, window_ms: Option<&str>) -> (usize, u64) {
+    let limit = limit
+        .and_then(|v| v.parse().ok())
+        .filter(|&n: &usize| n > 0)
+        .unwrap_or(DEFAULT_CLIENT_RATE_LIMIT);
+    let window_ms = window_ms
+        .and_then(|v| v.parse().ok())
+        .filter(|&ms: &u64| ms > 0)
+        .unwrap_or(DEFAULT_CLIENT_RATE_LIMIT_WINDOW_MS);
+    (limit, window_ms)
+}
+
+/// Whether per-client rate limiting is turned on, from `CLIENT_RATE_LIMIT_ENABLED`
+pub fn client_rate_limit_enabled(env: &Env) -> bool {
+    env.var("CLIENT_RATE_LIMIT_ENABLED")
+        .map(|v| v.to_string() == "true")
+        .unwrap_or(false)
+}
+
+/// Durable Object counting requests per client IP
+#[durable_object]
+pub struct ClientRateLimiterObject {
+    _state: State,
+    _env: Env,
+    limiter: RefCell<RateLimiter>,
+}
+
+impl DurableObject for ClientRateLimiterObject {
+    fn new(state: State, env: Env) -> Self {
+        let var = |name: &str| env.var(name).ok().map(|v| v.to_string());
+        let (limit, window_ms) = client_rate_limit_config(
+            var("CLIENT_RATE_LIMIT").as_deref(),
+            var("CLIENT_RATE_LIMIT_WINDOW_MS").as_deref(),
+        );
+
+        Self {
+            _state: state,
+            _env: env,
+            limiter: RefCell::new(RateLimiter::new(limit, window_ms)),
+        }
+    }
+
+    async fn fetch(&self, req: Request) -> Result<Response> {
+        let path = req.path();
+
+        match (req.method(), path.strip_prefix("/check/")) {
+            (Method::Post, Some(ip)) if !ip.is_empty() => {
+                let now = js_sys::Date::now() as u64;
+                let result = {
+                    let mut limiter = self.limiter.borrow_mut();
+                    limiter.cleanup(now);
+                    limiter.check_rate_limit(ip, now)
+                };
+
+                match result {
+                    Ok(()) => Response::ok("OK"),
+                    Err(retry_after_ms) => rate_limited_response(retry_after_secs(retry_after_ms)),
+                }
+            }
+            (Method::Post, _) => Response::error("Client IP required", 400),
+            _ => method_not_allowed(&[Method::Post]),
+        }
+    }
+}
+
+/// Check whether another request from `ip` is allowed right now
+///
+/// Returns `Some(retry_after_secs)` when the request should be rejected. If the
+/// limiter Durable Object isn't bound, requests are let through.
+pub async fn check_client_rate_limit(env: &Env, ip: &str) -> Result<Option<u64>> {
+    let namespace = match env.durable_object("CLIENT_RATE_LIMITER_OBJECT") {
+        Ok(ns) => ns,
+        Err(_) => return Ok(None),
+    };
+
+    let shard = shard_for_key(ip, CLIENT_RATE_LIMITER_SHARDS);
+    let id = namespace.id_from_name(&format!("client-rate-limiter-shard-{}", shard))?;
+    let request = Request::new_with_init(
+        &format!("https://fake-host/check/{}", ip),
+        RequestInit::new().with_method(Method::Post),
+    )?;
+    let response = id.get_stub()?.fetch_with_request(request).await?;
+
+    if response.status_code() == 429 {
+        let retry_after = response
+            .headers()
+            .get("Retry-After")?
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1);
+        return Ok(Some(retry_after));
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_exempt() {
+        assert!(is_exempt("/health"));
+        assert!(is_exempt("/metrics"));
+
+        assert!(!is_exempt("/"));
+        assert!(!is_exempt("/files/metrics"));
+        assert!(!is_exempt("/metrics/extra"));
+    }
+
+    #[test]
+    fn test_client_rate_limit_config() {
+        assert_eq!(
+            client_rate_limit_config(None, None),
+            (
+                DEFAULT_CLIENT_RATE_LIMIT,
+                DEFAULT_CLIENT_RATE_LIMIT_WINDOW_MS
+            )
+        );
+        assert_eq!(
+            client_rate_limit_config(Some("10"), Some("1000")),
+            (10, 1000)
+        );
+        // Zero or garbage falls back to the defaults
+        assert_eq!(
+            client_rate_limit_config(Some("0"), Some("soon")),
+            (
+                DEFAULT_CLIENT_RATE_LIMIT,
+                DEFAULT_CLIENT_RATE_LIMIT_WINDOW_MS
+            )
+        );
+    }
+
+    #[test]
+    fn test_limits_each_client_separately() {
+        let (limit, window_ms) = client_rate_limit_config(Some("2"), Some("1000"));
+        let mut limiter = RateLimiter::new(limit, window_ms);
+
+        assert!(limiter.check_rate_limit("203.0.113.1", 0).is_ok());
+        assert!(limiter.check_rate_limit("203.0.113.1", 10).is_ok());
+        assert_eq!(limiter.check_rate_limit("203.0.113.1", 20), Err(980));
+        assert!(limiter.check_rate_limit("2001:db8::1", 20).is_ok());
+    }
+}
@@ -0,0 +1,134 @@
+//! `Content-Digest` algorithm negotiation (RFC 9530)
+//!
+//! Downloads carry `Content-Digest: sha-256=:...:`, taken from the hash the
+//! file is stored under. A client can ask for CRC32C instead, as S3 tools
+//! prefer, with `Want-Content-Digest: crc32c=10, sha-256=1`. CRC32C isn't
+//! stored, so it's computed from the body, which is only possible when the
+//! body is in memory; streamed downloads keep the SHA256 digest.
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+
+/// A digest algorithm a `Content-Digest` header can use
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DigestAlgorithm {
+    Sha256,
+    Crc32c,
+}
+
+impl DigestAlgorithm {
+    /// The algorithm's key in `Content-Digest` and `Want-Content-Digest`
+    pub fn name(self) -> &'static str {
+        match self {
+            DigestAlgorithm::Sha256 => "sha-256",
+            DigestAlgorithm::Crc32c => "crc32c",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "sha-256" => Some(DigestAlgorithm::Sha256),
+            "crc32c" => Some(DigestAlgorithm::Crc32c),
+            _ => None,
+        }
+    }
+}
+
+/// The supported algorithm a `Want-Content-Digest` header prefers
+///
+/// Entries are `algorithm=weight`, with weights from 0 to 10 and 0 meaning
+/// "not this one"; an entry without a weight counts as 1. The highest weight
+/// wins, with ties going to SHA256 since it costs nothing. Anything else,
+/// including no header or only unsupported algorithms, gives SHA256.
+pub fn preferred_digest(want: Option<&str>) -> DigestAlgorithm {
+    let mut best: Option<(DigestAlgorithm, u8)> = None;
+    for entry in want.unwrap_or("").split(',') {
+        let (name, weight) = match entry.split_once('=') {
+            Some((name, weight)) => match weight.trim().parse::<u8>() {
+                Ok(w) if w <= 10 => (name, w),
+                _ => continue,
+            },
+            None => (entry, 1),
+        };
+        let Some(algorithm) = DigestAlgorithm::from_name(name.trim()) else {
+            continue;
+        };
+        if weight == 0 {
+            continue;
+        }
+        let better = match best {
+            None => true,
+            Some((_, w)) => weight > w || (weight == w && algorithm == DigestAlgorithm::Sha256),
+        };
+        if better {
+            best = Some((algorithm, weight));
+        }
+    }
+    best.map_or(DigestAlgorithm::Sha256, |(algorithm, _)| algorithm)
+}
+
+/// CRC32C (Castagnoli) checksum of `data`
+pub fn crc32c(data: &[u8]) -> u32 {
+    const POLY: u32 = 0x82F6_3B78;
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// A `Content-Digest` value for `algorithm` and raw digest bytes
+pub fn digest_header(algorithm: DigestAlgorithm, digest: &[u8]) -> String {
+    format!("{}=:{}:", algorithm.name(), STANDARD.encode(digest))
+}
+
+/// `Content-Digest` with the CRC32C of `data`, in network byte order
+pub fn crc32c_digest_header(data: &[u8]) -> String {
+    digest_header(DigestAlgorithm::Crc32c, &crc32c(data).to_be_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preferred_digest() {
+        use DigestAlgorithm::*;
+        assert_eq!(preferred_digest(None), Sha256);
+        assert_eq!(preferred_digest(Some("crc32c=10")), Crc32c);
+        assert_eq!(preferred_digest(Some("CRC32C")), Crc32c);
+        assert_eq!(preferred_digest(Some("sha-256=1, crc32c=5")), Crc32c);
+        assert_eq!(preferred_digest(Some("crc32c=5, sha-256=9")), Sha256);
+        // Ties go to the stored hash
+        assert_eq!(preferred_digest(Some("crc32c=3, sha-256=3")), Sha256);
+        // Unsupported or refused algorithms fall back to SHA256
+        assert_eq!(preferred_digest(Some("md5=10, sha-512=9")), Sha256);
+        assert_eq!(preferred_digest(Some("crc32c=0")), Sha256);
+        assert_eq!(preferred_digest(Some("crc32c=11")), Sha256);
+        assert_eq!(preferred_digest(Some("")), Sha256);
+    }
+
+    #[test]
+    fn test_crc32c() {
+        // The standard check value, then examples from RFC 3720, B.4
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+        assert_eq!(crc32c(&[0u8; 32]), 0x8A91_36AA);
+        assert_eq!(crc32c(&[0xFFu8; 32]), 0x62A8_AB43);
+    }
+
+    #[test]
+    fn test_crc32c_digest_header() {
+        assert_eq!(crc32c_digest_header(b"123456789"), "crc32c=:4waSgw==:");
+        assert_eq!(
+            digest_header(DigestAlgorithm::Sha256, &[0xAB; 4]),
+            "sha-256=:q6urqw==:"
+        );
+    }
+}
@@ -1,13 +1,207 @@
+use crate::do_storage::StorageWrites;
+use crate::error::{method_not_allowed, storage_error_response};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use worker::*;
 
-#[derive(Serialize, Deserialize)]
+/// Storage key holding the whole `CounterData`
+pub const COUNTER_KEY: &str = "counter";
+/// Keys the count and timestamp were stored under before `COUNTER_KEY`
+pub const LEGACY_COUNT_KEY: &str = "count";
+pub const LEGACY_LAST_UPDATED_KEY: &str = "last_updated";
+/// Storage key holding the recent values, oldest first
+pub const HISTORY_KEY: &str = "history";
+
+/// Most counter IDs one `POST /counters/batch` may read
+pub const MAX_BATCH_COUNTERS: usize = 100;
+
+/// How many past values the history keeps
+pub const HISTORY_LEN: usize = 100;
+/// How far back `rate_per_minute` looks, in milliseconds
+pub const RATE_WINDOW_MS: u64 = 5 * 60_000;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct CounterData {
     pub count: i32,
     pub last_updated: u64,
 }
 
+/// A new value and the history including it, written to storage in one go
+///
+/// Each field becomes its own storage key, so they're named after
+/// `COUNTER_KEY` and `HISTORY_KEY`.
+#[derive(Serialize, Debug)]
+pub struct CounterWrite<'a> {
+    pub counter: &'a CounterData,
+    pub history: &'a [CounterData],
+}
+
+/// The counter's value from whichever storage layout holds it
+///
+/// Counters written before `COUNTER_KEY` existed keep their value in two
+/// separate keys; the combined key wins once it's been written. A counter
+/// that was never written is 0.
+pub fn resolve_counter(
+    combined: Option<CounterData>,
+    legacy_count: Option<i32>,
+    legacy_last_updated: Option<u64>,
+) -> CounterData {
+    combined.unwrap_or(CounterData {
+        count: legacy_count.unwrap_or_default(),
+        last_updated: legacy_last_updated.unwrap_or_default(),
+    })
+}
+
+/// Response to `GET ?stats=true`: the counter and how fast it's moving
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct CounterStats {
+    pub count: i32,
+    pub last_updated: u64,
+    /// Change per minute over the last `RATE_WINDOW_MS`, or `None` without enough history
+    pub rate_per_minute: Option<f64>,
+}
+
+/// Add a value to the history, dropping the oldest past `HISTORY_LEN`
+pub fn push_history(history: &mut Vec<CounterData>, data: CounterData) {
+    history.push(data);
+    if history.len() > HISTORY_LEN {
+        history.drain(..history.len() - HISTORY_LEN);
+    }
+}
+
+/// How fast the counter changed over the `RATE_WINDOW_MS` before `now_ms`
+///
+/// The rate runs from the value the counter had when the window opened,
+/// which is the last change before it, to the current value. A counter that
+/// only started changing inside the window is measured from its first value
+/// instead, and one with a single value has no rate yet. Quiet counters
+/// have a rate of 0.
+pub fn rate_per_minute(history: &[CounterData], now_ms: u64) -> Option<f64> {
+    let current = history.last()?;
+    let window_start = now_ms.saturating_sub(RATE_WINDOW_MS);
+    let (baseline, since) = match history
+        .iter()
+        .rev()
+        .find(|entry| entry.last_updated <= window_start)
+    {
+        Some(before) => (before.count, window_start),
+        None if history.len() >= 2 => (history[0].count, history[0].last_updated),
+        None => return None,
+    };
+    let elapsed_ms = now_ms.checked_sub(since).filter(|&ms| ms > 0)?;
+    Some((current.count as f64 - baseline as f64) * 60_000.0 / elapsed_ms as f64)
+}
+
+/// Response to `POST /counters/batch`
+///
+/// Each ID asked for appears in exactly one of the maps.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct CounterBatch {
+    pub counters: BTreeMap<String, CounterData>,
+    /// Why each counter that couldn't be read failed
+    pub errors: BTreeMap<String, String>,
+}
+
+/// The counter IDs in a batch read body, without duplicates
+///
+/// The body must be a JSON array of non-empty strings, at most
+/// `MAX_BATCH_COUNTERS` of them once duplicates are dropped.
+pub fn parse_batch_ids(body: &serde_json::Value) -> std::result::Result<Vec<String>, String> {
+    let ids = body
+        .as_array()
+        .ok_or("Expected a JSON array of counter IDs")?;
+    let mut unique: Vec<String> = Vec::new();
+    for id in ids {
+        let id = match id.as_str() {
+            Some(id) if !id.is_empty() => id,
+            _ => return Err("Counter IDs must be non-empty strings".to_string()),
+        };
+        if !unique.iter().any(|seen| seen == id) {
+            unique.push(id.to_string());
+        }
+    }
+    if unique.len() > MAX_BATCH_COUNTERS {
+        return Err(format!(
+            "Too many counters: at most {} per request",
+            MAX_BATCH_COUNTERS
+        ));
+    }
+    Ok(unique)
+}
+
+/// Body of a compare-and-swap request
+#[derive(Serialize, Deserialize)]
+pub struct CasRequest {
+    pub expected: i32,
+    pub new: i32,
+}
+
+/// Read the `value` field of a set request, which must fit in an i32
+pub fn parse_counter_value(body: &serde_json::Value) -> std::result::Result<i32, &'static str> {
+    let value = body
+        .get("value")
+        .ok_or("Missing value")?
+        .as_i64()
+        .ok_or("Value must be an integer")?;
+    i32::try_from(value).map_err(|_| "Value out of range for i32")
+}
+
+/// Media type for JSON Merge Patch documents (RFC 7396)
+pub const MERGE_PATCH_CONTENT_TYPE: &str = "application/merge-patch+json";
+
+/// Check a `Content-Type` header names JSON Merge Patch, ignoring any parameters
+pub fn is_merge_patch_content_type(content_type: &str) -> bool {
+    content_type
+        .split(';')
+        .next()
+        .is_some_and(|media| media.trim().eq_ignore_ascii_case(MERGE_PATCH_CONTENT_TYPE))
+}
+
+/// The count after merging `patch` into the counter, or `None` if it's unchanged
+///
+/// Only `count` can be patched; setting it to `null` removes it, resetting the
+/// counter to 0. `last_updated` is set by the server and can't be written.
+pub fn apply_counter_patch(
+    patch: &serde_json::Value,
+) -> std::result::Result<Option<i32>, &'static str> {
+    let fields = patch.as_object().ok_or("Patch must be a JSON object")?;
+    let mut count = None;
+    for (field, value) in fields {
+        match field.as_str() {
+            "count" if value.is_null() => count = Some(0),
+            "count" => {
+                let value = value.as_i64().ok_or("count must be an integer")?;
+                count = Some(i32::try_from(value).map_err(|_| "count out of range for i32")?);
+            }
+            "last_updated" => return Err("last_updated is set by the server"),
+            _ => return Err("Only count can be patched"),
+        }
+    }
+    Ok(count)
+}
+
+/// Check an `Upgrade` header asks for a WebSocket
+pub fn is_websocket_upgrade(upgrade: Option<&str>) -> bool {
+    upgrade.is_some_and(|value| {
+        value
+            .split(',')
+            .any(|protocol| protocol.trim().eq_ignore_ascii_case("websocket"))
+    })
+}
+
+/// Whether a request asks for `?stats=true`
+fn has_stats_flag(req: &Request) -> Result<bool> {
+    Ok(req
+        .url()?
+        .query_pairs()
+        .any(|(name, value)| name == "stats" && value == "true"))
+}
+
 /// A simple counter Durable Object that maintains state across requests
+///
+/// Clients connected over WebSocket are sent the new `CounterData` whenever
+/// the counter changes. Sockets use the hibernation API, so the runtime keeps
+/// track of them and the object can be evicted while they're idle.
 #[durable_object]
 pub struct CounterObject {
     state: State,
@@ -19,58 +213,214 @@ impl DurableObject for CounterObject {
         Self { state, _env: env }
     }
 
-    async fn fetch(&self, _req: Request) -> Result<Response> {
-        let storage = self.state.storage();
-        let path = _req.path();
+    async fn fetch(&self, req: Request) -> Result<Response> {
+        // Storage failures become a 503 rather than an opaque 500
+        self.route(req).await.or_else(storage_error_response)
+    }
 
-        match _req.method() {
-            Method::Get => {
-                let count = storage.get::<i32>("count").await.unwrap_or_default();
-                let last_updated = storage.get::<u64>("last_updated").await.unwrap_or_default();
+    async fn websocket_message(
+        &self,
+        _ws: WebSocket,
+        _message: WebSocketIncomingMessage,
+    ) -> Result<()> {
+        // Updates only flow to clients; anything they send is ignored
+        Ok(())
+    }
 
-                let data = CounterData {
-                    count,
-                    last_updated,
-                };
-                Response::from_json(&data)
-            }
-            Method::Post => {
-                if path.ends_with("/increment") {
-                    let mut count = storage.get::<i32>("count").await.unwrap_or_default();
-                    count += 1;
+    async fn websocket_close(
+        &self,
+        ws: WebSocket,
+        _code: usize,
+        _reason: String,
+        _was_clean: bool,
+    ) -> Result<()> {
+        // Complete the closing handshake; the socket may already be gone
+        let _ = ws.close(Some(1000), Some("Closing"));
+        Ok(())
+    }
 
-                    let now = js_sys::Date::now() as u64;
-                    storage.put("count", count).await?;
-                    storage.put("last_updated", now).await?;
+    async fn websocket_error(&self, ws: WebSocket, _error: Error) -> Result<()> {
+        let _ = ws.close(Some(1011), Some("WebSocket error"));
+        Ok(())
+    }
+}
 
-                    let data = CounterData {
-                        count,
-                        last_updated: now,
-                    };
+impl CounterObject {
+    async fn route(&self, mut req: Request) -> Result<Response> {
+        let path = req.path();
+
+        if path.ends_with("/ws") {
+            return self.accept_websocket(&req).await;
+        }
+
+        match req.method() {
+            Method::Get if has_stats_flag(&req)? => Response::from_json(&self.stats().await),
+            Method::Get => Response::from_json(&self.load().await),
+            Method::Post => {
+                if path.ends_with("/increment") {
+                    let count = self.load().await.count + 1;
+                    let data = self.save(count).await?;
                     Response::from_json(&data)
                 } else if path.ends_with("/decrement") {
-                    let mut count = storage.get::<i32>("count").await.unwrap_or_default();
-                    count -= 1;
+                    let count = self.load().await.count - 1;
+                    let data = self.save(count).await?;
+                    Response::from_json(&data)
+                } else if path.ends_with("/cas") {
+                    let cas = match req.json::<CasRequest>().await {
+                        Ok(c) => c,
+                        Err(_) => return Response::error("Invalid CAS request", 400),
+                    };
 
-                    let now = js_sys::Date::now() as u64;
-                    storage.put("count", count).await?;
-                    storage.put("last_updated", now).await?;
+                    // A counter that was never written counts as 0. Nothing else runs
+                    // between this read and the write below, so the swap is atomic.
+                    let current = self.load().await;
+                    if current.count != cas.expected {
+                        return Ok(Response::from_json(&current)?.with_status(409));
+                    }
 
-                    let data = CounterData {
-                        count,
-                        last_updated: now,
-                    };
+                    let data = self.save(cas.new).await?;
                     Response::from_json(&data)
                 } else {
                     Response::error("Invalid path", 404)
                 }
             }
+            Method::Put => {
+                // Set the counter to an exact value
+                let body = match req.json::<serde_json::Value>().await {
+                    Ok(b) => b,
+                    Err(_) => return Response::error("Invalid JSON", 400),
+                };
+                let count = match parse_counter_value(&body) {
+                    Ok(v) => v,
+                    Err(e) => return Response::error(e, 400),
+                };
+
+                let data = self.save(count).await?;
+                Response::from_json(&data)
+            }
+            Method::Patch => {
+                let content_type = req.headers().get("Content-Type")?.unwrap_or_default();
+                if !is_merge_patch_content_type(&content_type) {
+                    return Response::error(
+                        format!("Content-Type must be {}", MERGE_PATCH_CONTENT_TYPE),
+                        415,
+                    );
+                }
+                let patch = match req.json::<serde_json::Value>().await {
+                    Ok(p) => p,
+                    Err(_) => return Response::error("Invalid JSON", 400),
+                };
+                match apply_counter_patch(&patch) {
+                    // An empty patch changes nothing, so nothing is written
+                    Ok(None) => Response::from_json(&self.load().await),
+                    Ok(Some(count)) => Response::from_json(&self.save(count).await?),
+                    Err(e) => Response::error(e, 400),
+                }
+            }
             Method::Delete => {
-                storage.delete("count").await?;
-                storage.delete("last_updated").await?;
+                let storage = self.state.storage();
+                let mut writes = StorageWrites::new(&storage);
+                writes.delete(COUNTER_KEY).await?;
+                writes.delete(LEGACY_COUNT_KEY).await?;
+                writes.delete(LEGACY_LAST_UPDATED_KEY).await?;
+                writes.delete(HISTORY_KEY).await?;
+                self.broadcast(&CounterData {
+                    count: 0,
+                    last_updated: js_sys::Date::now() as u64,
+                });
                 Response::ok("Counter reset")
             }
-            _ => Response::error("Method not allowed", 405),
+            _ => method_not_allowed(&[
+                Method::Get,
+                Method::Post,
+                Method::Put,
+                Method::Patch,
+                Method::Delete,
+            ]),
+        }
+    }
+
+    /// The counter's current value, from either storage layout
+    async fn load(&self) -> CounterData {
+        // Reads are served from the object's in-memory cache, so checking
+        // both layouts costs little
+        let storage = self.state.storage();
+        resolve_counter(
+            storage.get::<CounterData>(COUNTER_KEY).await.ok(),
+            storage.get::<i32>(LEGACY_COUNT_KEY).await.ok(),
+            storage.get::<u64>(LEGACY_LAST_UPDATED_KEY).await.ok(),
+        )
+    }
+
+    /// Recent values, oldest first; counters from before the history have none
+    async fn history(&self) -> Vec<CounterData> {
+        let storage = self.state.storage();
+        storage
+            .get::<Vec<CounterData>>(HISTORY_KEY)
+            .await
+            .unwrap_or_default()
+    }
+
+    /// The counter with its rate of change as of now
+    async fn stats(&self) -> CounterStats {
+        let data = self.load().await;
+        let history = self.history().await;
+        CounterStats {
+            count: data.count,
+            last_updated: data.last_updated,
+            rate_per_minute: rate_per_minute(&history, js_sys::Date::now() as u64),
+        }
+    }
+
+    /// Store a new count, stamped now, and tell connected clients
+    ///
+    /// Count and timestamp go under one key, written together with the
+    /// history in a single `put_multiple`, so none of them can disagree.
+    async fn save(&self, count: i32) -> Result<CounterData> {
+        let data = CounterData {
+            count,
+            last_updated: js_sys::Date::now() as u64,
+        };
+        let mut history = self.history().await;
+        push_history(&mut history, data.clone());
+
+        let storage = self.state.storage();
+        StorageWrites::new(&storage)
+            .put_multiple(
+                &[COUNTER_KEY, HISTORY_KEY],
+                CounterWrite {
+                    counter: &data,
+                    history: &history,
+                },
+            )
+            .await?;
+        self.broadcast(&data);
+        Ok(data)
+    }
+
+    /// Accept a WebSocket that will receive the counter's value on every change
+    async fn accept_websocket(&self, req: &Request) -> Result<Response> {
+        let upgrade = req.headers().get("Upgrade")?;
+        if !is_websocket_upgrade(upgrade.as_deref()) {
+            return Response::error("Expected a WebSocket upgrade", 426);
+        }
+
+        let pair = WebSocketPair::new()?;
+        self.state.accept_web_socket(&pair.server);
+
+        // Start the client off with the current value
+        pair.server.send(&self.load().await)?;
+
+        Response::from_websocket(pair.client)
+    }
+
+    /// Send the counter's new value to every connected WebSocket
+    fn broadcast(&self, data: &CounterData) {
+        for ws in self.state.get_websockets() {
+            // A socket that can't be written to is dead, so close it
+            if ws.send(data).is_err() {
+                let _ = ws.close(Some(1011), Some("Send failed"));
+            }
         }
     }
 }
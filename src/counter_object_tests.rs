@@ -1,6 +1,12 @@
 #[cfg(test)]
 mod counter_object_tests {
-    use crate::counter_object::CounterData;
+    use crate::counter_object::{
+        apply_counter_patch, is_merge_patch_content_type, is_websocket_upgrade, parse_batch_ids,
+        parse_counter_value, push_history, rate_per_minute, resolve_counter, CasRequest,
+        CounterBatch, CounterData, CounterStats, CounterWrite, COUNTER_KEY, HISTORY_KEY,
+        HISTORY_LEN, MAX_BATCH_COUNTERS, RATE_WINDOW_MS,
+    };
+    use serde_json::json;
 
     #[test]
     fn test_counter_data_structure() {
@@ -103,4 +109,232 @@ mod counter_object_tests {
         assert_eq!(json["count"], 1);
         assert_eq!(json["last_updated"], 1234567890);
     }
+
+    #[test]
+    fn test_cas_request_parsing() {
+        let cas: CasRequest = serde_json::from_str(r#"{"expected": 5, "new": 6}"#).unwrap();
+        assert_eq!(cas.expected, 5);
+        assert_eq!(cas.new, 6);
+
+        assert!(serde_json::from_str::<CasRequest>(r#"{"expected": 5}"#).is_err());
+        assert!(serde_json::from_str::<CasRequest>(r#"{"expected": "5", "new": 6}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_counter_value() {
+        assert_eq!(parse_counter_value(&json!({"value": 42})), Ok(42));
+        assert_eq!(parse_counter_value(&json!({"value": -7})), Ok(-7));
+        assert_eq!(
+            parse_counter_value(&json!({"value": i32::MAX})),
+            Ok(i32::MAX)
+        );
+
+        assert!(parse_counter_value(&json!({})).is_err());
+        assert!(parse_counter_value(&json!({"value": "42"})).is_err());
+        assert!(parse_counter_value(&json!({"value": 1.5})).is_err());
+        assert!(parse_counter_value(&json!({"value": i32::MAX as i64 + 1})).is_err());
+        assert!(parse_counter_value(&json!({"value": i32::MIN as i64 - 1})).is_err());
+        assert!(parse_counter_value(&json!({"value": u64::MAX})).is_err());
+    }
+
+    #[test]
+    fn test_is_websocket_upgrade() {
+        assert!(is_websocket_upgrade(Some("websocket")));
+        assert!(is_websocket_upgrade(Some("WebSocket")));
+        assert!(is_websocket_upgrade(Some("h2c, websocket")));
+
+        assert!(!is_websocket_upgrade(None));
+        assert!(!is_websocket_upgrade(Some("h2c")));
+        assert!(!is_websocket_upgrade(Some("")));
+    }
+
+    #[test]
+    fn test_counter_write_keys() {
+        let data = CounterData {
+            count: 3,
+            last_updated: 1000,
+        };
+        let history = [data.clone()];
+        let value = serde_json::to_value(CounterWrite {
+            counter: &data,
+            history: &history,
+        })
+        .unwrap();
+
+        let keys: Vec<&String> = value.as_object().unwrap().keys().collect();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(
+            value[COUNTER_KEY],
+            json!({"count": 3, "last_updated": 1000})
+        );
+        assert_eq!(
+            value[HISTORY_KEY],
+            json!([{"count": 3, "last_updated": 1000}])
+        );
+    }
+
+    #[test]
+    fn test_resolve_counter_layouts() {
+        let combined = CounterData {
+            count: 7,
+            last_updated: 2000,
+        };
+
+        // The combined key wins over leftover legacy keys
+        assert_eq!(
+            resolve_counter(Some(combined), Some(3), Some(1000)),
+            CounterData {
+                count: 7,
+                last_updated: 2000
+            }
+        );
+        // Counters from before the combined key keep their value
+        assert_eq!(
+            resolve_counter(None, Some(3), Some(1000)),
+            CounterData {
+                count: 3,
+                last_updated: 1000
+            }
+        );
+        assert_eq!(
+            resolve_counter(None, None, None),
+            CounterData {
+                count: 0,
+                last_updated: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_is_merge_patch_content_type() {
+        assert!(is_merge_patch_content_type("application/merge-patch+json"));
+        assert!(is_merge_patch_content_type(
+            "Application/Merge-Patch+JSON; charset=utf-8"
+        ));
+        assert!(!is_merge_patch_content_type("application/json"));
+        assert!(!is_merge_patch_content_type(""));
+    }
+
+    #[test]
+    fn test_apply_counter_patch() {
+        assert_eq!(apply_counter_patch(&json!({"count": 42})), Ok(Some(42)));
+        assert_eq!(apply_counter_patch(&json!({"count": null})), Ok(Some(0)));
+        assert_eq!(apply_counter_patch(&json!({})), Ok(None));
+
+        assert!(apply_counter_patch(&json!([{"op": "replace"}])).is_err());
+        assert!(apply_counter_patch(&json!({"count": "42"})).is_err());
+        assert!(apply_counter_patch(&json!({"count": i32::MAX as i64 + 1})).is_err());
+        assert!(apply_counter_patch(&json!({"last_updated": 0})).is_err());
+        assert!(apply_counter_patch(&json!({"count": 1, "color": "red"})).is_err());
+    }
+
+    fn at(count: i32, last_updated: u64) -> CounterData {
+        CounterData {
+            count,
+            last_updated,
+        }
+    }
+
+    #[test]
+    fn test_push_history_keeps_the_latest() {
+        let mut history = Vec::new();
+        for i in 0..HISTORY_LEN as i32 + 5 {
+            push_history(&mut history, at(i, i as u64));
+        }
+        assert_eq!(history.len(), HISTORY_LEN);
+        assert_eq!(history[0].count, 5);
+        assert_eq!(history.last().unwrap().count, HISTORY_LEN as i32 + 4);
+    }
+
+    #[test]
+    fn test_rate_per_minute() {
+        let now = 10 * 60_000;
+        // 20 at the window's start (from a change before it), 30 now: 10 over 5 minutes
+        let history = [
+            at(0, 0),
+            at(20, 60_000),
+            at(25, 7 * 60_000),
+            at(30, 9 * 60_000),
+        ];
+        assert_eq!(rate_per_minute(&history, now), Some(2.0));
+
+        // A counter that started inside the window is measured from its first value
+        let history = [at(0, 8 * 60_000), at(10, 9 * 60_000)];
+        assert_eq!(rate_per_minute(&history, now), Some(5.0));
+
+        // Falling counters have a negative rate
+        let history = [at(50, 0), at(40, 9 * 60_000)];
+        assert_eq!(rate_per_minute(&history, now), Some(-2.0));
+    }
+
+    #[test]
+    fn test_rate_per_minute_without_enough_history() {
+        let now = 10 * 60_000;
+        assert_eq!(rate_per_minute(&[], now), None);
+        assert_eq!(rate_per_minute(&[at(3, 9 * 60_000)], now), None);
+        // Two values written at this very moment span no time
+        assert_eq!(rate_per_minute(&[at(1, now), at(2, now)], now), None);
+        // A counter that hasn't changed in the window isn't moving
+        assert_eq!(rate_per_minute(&[at(7, 0)], now), Some(0.0));
+        assert_eq!(rate_per_minute(&[at(7, 0)], RATE_WINDOW_MS / 2), Some(0.0));
+    }
+
+    #[test]
+    fn test_counter_stats_serialization() {
+        let stats = CounterStats {
+            count: 3,
+            last_updated: 1000,
+            rate_per_minute: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&stats).unwrap(),
+            json!({ "count": 3, "last_updated": 1000, "rate_per_minute": null })
+        );
+    }
+
+    #[test]
+    fn test_parse_batch_ids() {
+        assert_eq!(
+            parse_batch_ids(&json!(["visits", "likes", "visits"])).unwrap(),
+            vec!["visits".to_string(), "likes".to_string()]
+        );
+        assert!(parse_batch_ids(&json!([])).unwrap().is_empty());
+        assert!(parse_batch_ids(&json!({ "ids": ["a"] })).is_err());
+        assert!(parse_batch_ids(&json!(["a", 1])).is_err());
+        assert!(parse_batch_ids(&json!(["a", ""])).is_err());
+    }
+
+    #[test]
+    fn test_parse_batch_ids_caps_the_batch() {
+        let ids: Vec<String> = (0..MAX_BATCH_COUNTERS).map(|i| i.to_string()).collect();
+        assert_eq!(
+            parse_batch_ids(&json!(ids)).unwrap().len(),
+            MAX_BATCH_COUNTERS
+        );
+
+        let mut too_many = ids.clone();
+        too_many.push("one more".to_string());
+        assert!(parse_batch_ids(&json!(too_many)).is_err());
+        // Duplicates don't count against the cap
+        let mut repeated = ids;
+        repeated.push("0".to_string());
+        assert!(parse_batch_ids(&json!(repeated)).is_ok());
+    }
+
+    #[test]
+    fn test_counter_batch_serialization() {
+        let mut batch = CounterBatch::default();
+        batch.counters.insert("visits".to_string(), at(7, 1000));
+        batch.errors.insert(
+            "likes".to_string(),
+            "Counter read failed with status 503".to_string(),
+        );
+        assert_eq!(
+            serde_json::to_value(&batch).unwrap(),
+            json!({
+                "counters": { "visits": { "count": 7, "last_updated": 1000 } },
+                "errors": { "likes": "Counter read failed with status 503" }
+            })
+        );
+    }
 }
@@ -0,0 +1,67 @@
+//! Durable Object storage writes that report how far they got
+//!
+//! A DO's `storage.put(...).await?` failure would otherwise surface as an
+//! opaque 500. Writes made through `StorageWrites` instead fail with
+//! `StorageError::WriteFailed`, a 503 with `Retry-After`, naming the key that
+//! failed and the keys already written before it. Separate writes aren't a
+//! transaction, so an update can be left half applied; the error says exactly
+//! which half. Keys that must change together go in one `put_multiple`.
+
+use crate::error::StorageError;
+use serde::Serialize;
+use worker::{Result, Storage};
+
+/// A sequence of writes to one Durable Object's storage
+pub struct StorageWrites<'a> {
+    storage: &'a Storage,
+    written: Vec<String>,
+}
+
+impl<'a> StorageWrites<'a> {
+    pub fn new(storage: &'a Storage) -> Self {
+        Self {
+            storage,
+            written: Vec::new(),
+        }
+    }
+
+    pub async fn put<T: Serialize>(&mut self, key: &str, value: T) -> Result<()> {
+        let result = self.storage.put(key, value).await;
+        self.record(key, result)
+    }
+
+    /// Write several keys at once, all or nothing
+    ///
+    /// `values` serializes to an object with one field per key; `keys` names
+    /// them for the error if the write fails.
+    pub async fn put_multiple<T: Serialize>(&mut self, keys: &[&str], values: T) -> Result<()> {
+        let result = self.storage.put_multiple(values).await;
+        match result {
+            Ok(()) => {
+                self.written.extend(keys.iter().map(|k| k.to_string()));
+                Ok(())
+            }
+            Err(e) => self.record(&keys.join(", "), Err(e)),
+        }
+    }
+
+    pub async fn delete(&mut self, key: &str) -> Result<()> {
+        let result = self.storage.delete(key).await.map(|_| ());
+        self.record(key, result)
+    }
+
+    fn record(&mut self, key: &str, result: Result<()>) -> Result<()> {
+        match result {
+            Ok(()) => {
+                self.written.push(key.to_string());
+                Ok(())
+            }
+            Err(e) => Err(StorageError::WriteFailed {
+                key: key.to_string(),
+                written: self.written.clone(),
+                reason: e.to_string(),
+            }
+            .into()),
+        }
+    }
+}
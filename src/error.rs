@@ -0,0 +1,375 @@
+//! Structured errors for storage operations
+//!
+//! `worker::Error` can't carry custom types, so a `StorageError` converts to an
+//! `Error::Json` holding its message and HTTP status code. HTTP handlers turn
+//! those back into responses with `storage_error_response`.
+//!
+//! A Durable Object namespace that isn't bound, as can happen mid-rollout, is
+//! reported the same way wherever it's found: a JSON 503 naming the service and
+//! binding, with a `Retry-After` hint. See `service_unavailable`.
+
+use crate::quota::{quota_exceeded_response, QuotaExceeded};
+use crate::r2_rate_limiter::{rate_limit_retry_after, rate_limited_response};
+use std::fmt;
+use worker::{Error, Headers, Method, Response, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageError {
+    /// A file, blob or staged chunk doesn't exist
+    NotFound(String),
+    /// A blob's content doesn't match the hash it's stored under
+    IntegrityMismatch {
+        key: String,
+        expected: String,
+        actual: String,
+    },
+    /// A stored blob couldn't be decoded, e.g. corrupt gzip data
+    Corrupt(String),
+    /// A Durable Object couldn't be reached or failed
+    MappingUnavailable(String),
+    /// The write was rate limited; retry after this many seconds
+    RateLimited { retry_after_secs: u64 },
+    /// The request was malformed
+    Invalid(String),
+    /// A Durable Object storage write failed, after `written` were stored
+    WriteFailed {
+        key: String,
+        written: Vec<String>,
+        reason: String,
+    },
+    /// A Durable Object namespace binding, e.g. `FILE_MAPPING_OBJECT`, is missing
+    Unbound(String),
+    /// A write would go past `MAX_FILES` or `MAX_TOTAL_BYTES`
+    QuotaExceeded(QuotaExceeded),
+}
+
+/// Marks an `Error::Json` message as naming an unbound binding
+const UNBOUND_PREFIX: &str = "unbound:";
+
+/// `Retry-After` seconds sent with 503 responses
+pub const UNAVAILABLE_RETRY_AFTER_SECS: u64 = 1;
+
+impl StorageError {
+    /// HTTP status code to report this error with
+    pub fn status_code(&self) -> u16 {
+        match self {
+            StorageError::NotFound(_) => 404,
+            // Our upstream (R2) returned bad data, so this is a gateway error
+            StorageError::IntegrityMismatch { .. } | StorageError::Corrupt(_) => 502,
+            StorageError::MappingUnavailable(_)
+            | StorageError::WriteFailed { .. }
+            | StorageError::Unbound(_) => 503,
+            StorageError::RateLimited { .. } => 429,
+            StorageError::Invalid(_) => 400,
+            StorageError::QuotaExceeded(_) => 507,
+        }
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::NotFound(what) => write!(f, "{} not found", what),
+            StorageError::IntegrityMismatch {
+                key,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Blob integrity check failed for {}. Expected: {}, Actual: {}",
+                key, expected, actual
+            ),
+            StorageError::Corrupt(reason) => write!(f, "Stored blob is corrupt: {}", reason),
+            StorageError::MappingUnavailable(reason) => {
+                write!(f, "Storage service unavailable: {}", reason)
+            }
+            StorageError::RateLimited { retry_after_secs } => {
+                write!(f, "Rate limited; retry after {} seconds", retry_after_secs)
+            }
+            StorageError::Invalid(reason) => write!(f, "Invalid request: {}", reason),
+            StorageError::Unbound(binding) => write!(f, "{} is not bound", binding),
+            StorageError::QuotaExceeded(QuotaExceeded::Files {
+                count, max_files, ..
+            }) => write!(f, "File quota exceeded: {} of {} files", count, max_files),
+            StorageError::QuotaExceeded(QuotaExceeded::Bytes {
+                used_bytes,
+                max_total_bytes,
+                ..
+            }) => write!(
+                f,
+                "Storage quota exceeded: {} of {} bytes",
+                used_bytes, max_total_bytes
+            ),
+            StorageError::WriteFailed {
+                key,
+                written,
+                reason,
+            } if written.is_empty() => {
+                write!(f, "Storage write of {} failed: {}", key, reason)
+            }
+            StorageError::WriteFailed {
+                key,
+                written,
+                reason,
+            } => write!(
+                f,
+                "Storage write of {} failed after {} were written; the update is incomplete: {}",
+                key,
+                written.join(", "),
+                reason
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<StorageError> for Error {
+    fn from(err: StorageError) -> Self {
+        match err {
+            // Only the delay is carried, which `rate_limit_retry_after` reads back
+            StorageError::RateLimited { retry_after_secs } => {
+                Error::Json((retry_after_secs.to_string(), 429))
+            }
+            // Likewise only the binding, which `storage_error_response` reads back
+            StorageError::Unbound(binding) => {
+                Error::Json((format!("{}{}", UNBOUND_PREFIX, binding), 503))
+            }
+            // The message is the JSON body, so the counts reach the client
+            StorageError::QuotaExceeded(quota) => {
+                Error::Json((serde_json::to_string(&quota).unwrap_or_default(), 507))
+            }
+            err => Error::Json((err.to_string(), err.status_code())),
+        }
+    }
+}
+
+/// Turn an error into the HTTP response it calls for
+///
+/// Errors built from a `StorageError` get their own status code and message,
+/// plus a `Retry-After` hint when they're a 503; anything else is passed on to
+/// become a 500.
+pub fn storage_error_response(err: Error) -> Result<Response> {
+    if let Some(retry_after) = rate_limit_retry_after(&err) {
+        return rate_limited_response(retry_after);
+    }
+    match err {
+        Error::Json((message, 503)) if message.starts_with(UNBOUND_PREFIX) => {
+            service_unavailable(&message[UNBOUND_PREFIX.len()..])
+        }
+        Error::Json((message, 507)) => match serde_json::from_str::<QuotaExceeded>(&message) {
+            Ok(quota) => quota_exceeded_response(&quota),
+            Err(_) => Response::error(message, 507),
+        },
+        Error::Json((message, 503)) => {
+            let headers = Headers::new();
+            headers.set("Retry-After", &UNAVAILABLE_RETRY_AFTER_SECS.to_string())?;
+            Ok(Response::error(message, 503)?.with_headers(headers))
+        }
+        Error::Json((message, status)) if (400..600).contains(&status) => {
+            Response::error(message, status)
+        }
+        err => Err(err),
+    }
+}
+
+/// Name of the service a Durable Object binding provides, for error responses
+pub fn binding_service(binding: &str) -> &str {
+    match binding {
+        "COUNTER_OBJECT" => "counter",
+        "SESSION_OBJECT" => "session",
+        "FILE_MAPPING_OBJECT" => "file-mapping",
+        "METRICS_OBJECT" => "metrics",
+        "UPLOAD_OBJECT" => "upload",
+        other => other,
+    }
+}
+
+/// JSON body of the 503 sent when `binding` isn't bound
+pub fn unavailable_body(binding: &str) -> serde_json::Value {
+    let service = binding_service(binding);
+    serde_json::json!({
+        "error": format!("{} service not available", service),
+        "service": service,
+        "binding": binding,
+        "retry_after": UNAVAILABLE_RETRY_AFTER_SECS
+    })
+}
+
+/// 503 for a Durable Object namespace that isn't bound, with `Retry-After`
+pub fn service_unavailable(binding: &str) -> Result<Response> {
+    let headers = Headers::new();
+    headers.set("Retry-After", &UNAVAILABLE_RETRY_AFTER_SECS.to_string())?;
+    Ok(Response::from_json(&unavailable_body(binding))?
+        .with_status(503)
+        .with_headers(headers))
+}
+
+/// `Allow` header value listing `allowed`
+pub fn allow_header(allowed: &[Method]) -> String {
+    allowed
+        .iter()
+        .map(|m| m.as_ref())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Reject a request's method with 405, saying which methods the path accepts
+pub fn method_not_allowed(allowed: &[Method]) -> Result<Response> {
+    let headers = Headers::new();
+    headers.set("Allow", &allow_header(allowed))?;
+    Ok(Response::error("Method not allowed", 405)?.with_headers(headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn integrity_error() -> StorageError {
+        StorageError::IntegrityMismatch {
+            key: "a.txt".to_string(),
+            expected: "abc".to_string(),
+            actual: "def".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_status_codes() {
+        assert_eq!(
+            StorageError::NotFound("File".to_string()).status_code(),
+            404
+        );
+        assert_eq!(integrity_error().status_code(), 502);
+        assert_eq!(StorageError::Corrupt("bad".to_string()).status_code(), 502);
+        assert_eq!(
+            StorageError::MappingUnavailable("down".to_string()).status_code(),
+            503
+        );
+        assert_eq!(
+            StorageError::RateLimited {
+                retry_after_secs: 1
+            }
+            .status_code(),
+            429
+        );
+        assert_eq!(StorageError::Invalid("bad".to_string()).status_code(), 400);
+        assert_eq!(write_failed(&[]).status_code(), 503);
+    }
+
+    fn write_failed(written: &[&str]) -> StorageError {
+        StorageError::WriteFailed {
+            key: "last_updated".to_string(),
+            written: written.iter().map(|k| k.to_string()).collect(),
+            reason: "storage reset".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_write_failed_reports_partial_writes() {
+        assert_eq!(
+            write_failed(&[]).to_string(),
+            "Storage write of last_updated failed: storage reset"
+        );
+        assert_eq!(
+            write_failed(&["count"]).to_string(),
+            "Storage write of last_updated failed after count were written; \
+             the update is incomplete: storage reset"
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            StorageError::NotFound("Blob abc".to_string()).to_string(),
+            "Blob abc not found"
+        );
+        assert_eq!(
+            integrity_error().to_string(),
+            "Blob integrity check failed for a.txt. Expected: abc, Actual: def"
+        );
+    }
+
+    #[test]
+    fn test_into_worker_error() {
+        match Error::from(integrity_error()) {
+            Error::Json((message, status)) => {
+                assert_eq!(status, 502);
+                assert!(message.contains("a.txt"));
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        // Rate limits keep the format `rate_limit_retry_after` understands
+        let err = Error::from(StorageError::RateLimited {
+            retry_after_secs: 7,
+        });
+        assert_eq!(rate_limit_retry_after(&err), Some(7));
+    }
+
+    #[test]
+    fn test_storage_error_response_passes_other_errors() {
+        let err = Error::RustError("boom".to_string());
+        match storage_error_response(err) {
+            Err(Error::RustError(message)) => assert_eq!(message, "boom"),
+            other => panic!("unexpected result: {:?}", other.map(|r| r.status_code())),
+        }
+    }
+
+    #[test]
+    fn test_allow_header() {
+        assert_eq!(allow_header(&[Method::Get]), "GET");
+        assert_eq!(
+            allow_header(&[Method::Get, Method::Head, Method::Delete]),
+            "GET, HEAD, DELETE"
+        );
+    }
+
+    #[test]
+    fn test_unavailable_body() {
+        assert_eq!(
+            unavailable_body("COUNTER_OBJECT"),
+            serde_json::json!({
+                "error": "counter service not available",
+                "service": "counter",
+                "binding": "COUNTER_OBJECT",
+                "retry_after": UNAVAILABLE_RETRY_AFTER_SECS
+            })
+        );
+        assert_eq!(binding_service("FILE_MAPPING_OBJECT"), "file-mapping");
+        assert_eq!(binding_service("NEW_OBJECT"), "NEW_OBJECT");
+    }
+
+    #[test]
+    fn test_unbound_error_keeps_its_binding() {
+        let err = StorageError::Unbound("SESSION_OBJECT".to_string());
+        assert_eq!(err.status_code(), 503);
+        assert_eq!(err.to_string(), "SESSION_OBJECT is not bound");
+        match Error::from(err) {
+            Error::Json((message, 503)) => {
+                assert_eq!(&message[UNBOUND_PREFIX.len()..], "SESSION_OBJECT")
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_quota_error_carries_its_counts() {
+        let err = StorageError::QuotaExceeded(QuotaExceeded::files(10, 10));
+        assert_eq!(err.status_code(), 507);
+        assert_eq!(err.to_string(), "File quota exceeded: 10 of 10 files");
+        match Error::from(err) {
+            Error::Json((message, 507)) => assert_eq!(
+                serde_json::from_str::<QuotaExceeded>(&message).unwrap(),
+                QuotaExceeded::files(10, 10)
+            ),
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        let err = StorageError::QuotaExceeded(QuotaExceeded::bytes(
+            crate::quota::BytesMetric::Logical,
+            5,
+            8,
+        ));
+        assert_eq!(err.to_string(), "Storage quota exceeded: 5 of 8 bytes");
+    }
+}
@@ -1,7 +1,16 @@
+use crate::error::method_not_allowed;
+use crate::quota::{
+    bytes_metric, bytes_quota_exceeded, file_quota_exceeded, max_files, max_total_bytes,
+    quota_exceeded_response, BytesMetric, QuotaExceeded,
+};
 use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
 use worker::*;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileMapping {
     pub filename: String,
     pub sha256: String,
@@ -9,6 +18,498 @@ pub struct FileMapping {
     pub content_type: Option<String>,
     pub created_at: u64,
     pub updated_at: u64,
+    /// User-supplied key/value pairs from `X-Meta-*` upload headers
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    /// When the file was soft-deleted; it can be restored until the retention window ends
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<u64>,
+    /// Whether the blob is stored gzip-compressed; `size` is still the original size
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub compressed: bool,
+    /// When the file expires, from an `X-Expires-In` upload header; it's gone after this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// Base64 content of a small file stored in the mapping instead of as a blob
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inline: Option<String>,
+    /// Pre-compressed variants by content coding, e.g. `br`, each its own blob
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub encodings: BTreeMap<String, EncodedVariant>,
+}
+
+/// A pre-compressed representation of a file, uploaded with `?encoding=`
+///
+/// `sha256` and `size` are of the compressed bytes, which is what the variant's
+/// blob holds.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EncodedVariant {
+    pub sha256: String,
+    pub size: usize,
+}
+
+/// A previous (or current) upload of a file, kept when versioning is enabled
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileVersion {
+    pub version: u32,
+    pub sha256: String,
+    pub size: usize,
+    pub content_type: Option<String>,
+    pub created_at: u64,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub compressed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inline: Option<String>,
+}
+
+impl FileVersion {
+    /// Build a mapping for `filename` pointing at this version's blob
+    pub fn to_mapping(&self, filename: &str) -> FileMapping {
+        FileMapping {
+            filename: filename.to_string(),
+            sha256: self.sha256.clone(),
+            size: self.size,
+            content_type: self.content_type.clone(),
+            created_at: self.created_at,
+            updated_at: self.created_at,
+            metadata: self.metadata.clone(),
+            deleted_at: None,
+            compressed: self.compressed,
+            expires_at: None,
+            inline: self.inline.clone(),
+            encodings: BTreeMap::new(),
+        }
+    }
+}
+
+/// Maximum number of versions retained per file; older versions are evicted
+pub const MAX_RETAINED_VERSIONS: usize = 10;
+
+/// Prefix of every mapping's storage key
+///
+/// Filenames may contain `:`, so without it a file could be named after one of
+/// the object's own keys, like `usage:totals`, and overwrite it.
+pub const MAPPING_KEY_PREFIX: &str = "file:";
+
+/// Storage key holding the mapping for a filename
+pub fn mapping_key(filename: &str) -> String {
+    format!("{}{}", MAPPING_KEY_PREFIX, filename)
+}
+
+/// Storage key holding the version list for a filename
+///
+/// Mappings live under `mapping_key`, so a file named `versions:foo` can't
+/// overwrite foo's history, and purging foo can't remove that file.
+pub fn versions_key(filename: &str) -> String {
+    format!("versions:{}", filename)
+}
+
+/// Whether a mapping is still stored under its bare filename
+///
+/// Mappings used to be keyed by filename alone. One moved to `mapping_key` no
+/// longer matches, so the move can safely run again.
+pub fn is_legacy_mapping_key(key: &str, mapping: &FileMapping) -> bool {
+    key == mapping.filename
+}
+
+/// Writes that move legacy mappings to their `mapping_key`: puts, then deletes
+///
+/// A legacy file can be named like another's new key (`a` moves to `file:a`,
+/// where a file named `file:a` may still be waiting to move). Puts go longest
+/// key first, so such a file is copied out before anything lands on its key,
+/// and keys that receive a moved mapping aren't deleted afterwards. Stopping
+/// partway leaves every file reachable, and running the moves again is safe.
+pub fn legacy_key_moves(
+    mut legacy: Vec<(String, FileMapping)>,
+) -> (Vec<(String, FileMapping)>, Vec<String>) {
+    legacy.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    let targets: Vec<String> = legacy
+        .iter()
+        .map(|(_, mapping)| mapping_key(&mapping.filename))
+        .collect();
+    let deletes = legacy
+        .iter()
+        .map(|(key, _)| key.clone())
+        .filter(|key| !targets.contains(key))
+        .collect();
+    let puts = targets
+        .into_iter()
+        .zip(legacy.into_iter().map(|(_, mapping)| mapping))
+        .collect();
+    (puts, deletes)
+}
+
+/// Append `mapping` as the newest version, evicting the oldest beyond `max_versions`
+pub fn record_version(versions: &mut Vec<FileVersion>, mapping: &FileMapping, max_versions: usize) {
+    let version = versions.last().map_or(1, |v| v.version + 1);
+    versions.push(FileVersion {
+        version,
+        sha256: mapping.sha256.clone(),
+        size: mapping.size,
+        content_type: mapping.content_type.clone(),
+        created_at: mapping.updated_at,
+        metadata: mapping.metadata.clone(),
+        compressed: mapping.compressed,
+        inline: mapping.inline.clone(),
+    });
+
+    if versions.len() > max_versions {
+        let excess = versions.len() - max_versions;
+        versions.drain(..excess);
+    }
+}
+
+/// How much storage content addressing saves across all mappings
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct StorageStats {
+    pub total_files: usize,
+    pub unique_blobs: usize,
+    /// Sum of every mapping's size, as if each file had its own copy
+    pub logical_bytes: u64,
+    /// Sum of the sizes of distinct blobs actually stored in R2
+    pub physical_bytes: u64,
+    /// `logical_bytes / physical_bytes`, or 1.0 when nothing is stored
+    pub dedup_ratio: f64,
+}
+
+/// Files per page when a sorted listing doesn't give `limit`
+pub const DEFAULT_LIST_LIMIT: usize = 100;
+/// Largest `limit` a sorted listing accepts
+pub const MAX_LIST_LIMIT: usize = 1000;
+
+/// Field a listing is sorted by
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListSort {
+    Name,
+    Size,
+    Updated,
+}
+
+/// A sorted, paginated listing request: `?sort=&order=&limit=&offset=`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListQuery {
+    pub sort: ListSort,
+    pub descending: bool,
+    pub limit: usize,
+    pub offset: usize,
+}
+
+impl ListQuery {
+    /// Read a listing request from query parameters
+    ///
+    /// Returns `Ok(None)` when none of the parameters are given, so plain
+    /// listings keep their original shape. Unknown values are an error.
+    pub fn from_query<K: AsRef<str>, V: AsRef<str>>(
+        pairs: impl IntoIterator<Item = (K, V)>,
+    ) -> std::result::Result<Option<Self>, String> {
+        let mut query = ListQuery {
+            sort: ListSort::Name,
+            descending: false,
+            limit: DEFAULT_LIST_LIMIT,
+            offset: 0,
+        };
+        let mut requested = false;
+
+        for (key, value) in pairs {
+            let value = value.as_ref();
+            match key.as_ref() {
+                "sort" => {
+                    query.sort = match value {
+                        "name" => ListSort::Name,
+                        "size" => ListSort::Size,
+                        "updated" => ListSort::Updated,
+                        _ => return Err("sort must be name, size or updated".to_string()),
+                    }
+                }
+                "order" => {
+                    query.descending = match value {
+                        "asc" => false,
+                        "desc" => true,
+                        _ => return Err("order must be asc or desc".to_string()),
+                    }
+                }
+                "limit" => {
+                    query.limit = value
+                        .parse()
+                        .ok()
+                        .filter(|n| (1..=MAX_LIST_LIMIT).contains(n))
+                        .ok_or_else(|| format!("limit must be between 1 and {}", MAX_LIST_LIMIT))?
+                }
+                "offset" => {
+                    query.offset = value
+                        .parse()
+                        .map_err(|_| "offset must be a non-negative integer".to_string())?
+                }
+                _ => continue,
+            }
+            requested = true;
+        }
+
+        Ok(requested.then_some(query))
+    }
+
+    /// The query parameters that request this listing
+    pub fn to_query(&self) -> Vec<(&'static str, String)> {
+        let sort = match self.sort {
+            ListSort::Name => "name",
+            ListSort::Size => "size",
+            ListSort::Updated => "updated",
+        };
+        vec![
+            ("sort", sort.to_string()),
+            (
+                "order",
+                if self.descending { "desc" } else { "asc" }.to_string(),
+            ),
+            ("limit", self.limit.to_string()),
+            ("offset", self.offset.to_string()),
+        ]
+    }
+}
+
+/// Response to a mapping `PUT` with `?return=previous`
+///
+/// The new mapping's fields come first, as without the parameter, so the body
+/// still reads as a `FileMapping`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReplacedMapping {
+    #[serde(flatten)]
+    pub mapping: FileMapping,
+    /// The mapping that was overwritten, or `None` if the file is new
+    pub previous: Option<FileMapping>,
+}
+
+/// The filenames of `mappings`, for a keys-only listing
+pub fn mapping_names(mappings: Vec<FileMapping>) -> Vec<String> {
+    mappings.into_iter().map(|m| m.filename).collect()
+}
+
+/// One page of a sorted listing
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MappingPage {
+    pub mappings: Vec<FileMapping>,
+    /// Number of files across all pages
+    pub total: usize,
+    /// Offset of the next page, or `None` on the last one
+    pub next_offset: Option<usize>,
+}
+
+/// Order two mappings by `sort`, breaking ties by filename
+///
+/// `descending` reverses the sort field only; ties always fall back to
+/// ascending filename order, so every listing has one fixed order.
+pub fn compare_mappings(
+    a: &FileMapping,
+    b: &FileMapping,
+    sort: ListSort,
+    descending: bool,
+) -> Ordering {
+    let primary = match sort {
+        ListSort::Name => a.filename.cmp(&b.filename),
+        ListSort::Size => a.size.cmp(&b.size),
+        ListSort::Updated => a.updated_at.cmp(&b.updated_at),
+    };
+    let primary = if descending {
+        primary.reverse()
+    } else {
+        primary
+    };
+    primary.then_with(|| a.filename.cmp(&b.filename))
+}
+
+/// Sort mappings as `query` asks and cut out the requested page
+pub fn paginate_mappings(mut mappings: Vec<FileMapping>, query: &ListQuery) -> MappingPage {
+    mappings.sort_by(|a, b| compare_mappings(a, b, query.sort, query.descending));
+
+    let total = mappings.len();
+    let start = query.offset.min(total);
+    let end = start.saturating_add(query.limit).min(total);
+    MappingPage {
+        mappings: mappings.drain(start..end).collect(),
+        total,
+        next_offset: (end < total).then_some(end),
+    }
+}
+
+/// Aggregate mappings into storage stats, grouping by SHA256
+///
+/// Uses the recorded `size` of each mapping, so no blob is read.
+pub fn compute_stats(mappings: &[FileMapping]) -> StorageStats {
+    let mut seen = HashSet::new();
+    let mut logical_bytes = 0;
+    let mut physical_bytes = 0;
+
+    for mapping in mappings {
+        logical_bytes += mapping.size as u64;
+        if seen.insert(mapping.sha256.as_str()) {
+            physical_bytes += mapping.size as u64;
+        }
+    }
+
+    let dedup_ratio = if physical_bytes == 0 {
+        1.0
+    } else {
+        logical_bytes as f64 / physical_bytes as f64
+    };
+
+    StorageStats {
+        total_files: mappings.len(),
+        unique_blobs: seen.len(),
+        logical_bytes,
+        physical_bytes,
+        dedup_ratio,
+    }
+}
+
+/// Running totals of the mappings that aren't soft-deleted
+///
+/// Kept up to date as mappings are written and removed, so the storage and
+/// file quotas can be checked without listing every mapping.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct StorageUsage {
+    /// Sum of every file's size
+    pub logical_bytes: u64,
+    /// Sum of each referenced blob's size, counted once
+    pub physical_bytes: u64,
+    /// Number of files, expired ones included until they're purged
+    pub files: u64,
+}
+
+impl StorageUsage {
+    /// The total `metric` counts
+    pub fn bytes(&self, metric: BytesMetric) -> u64 {
+        match metric {
+            BytesMetric::Logical => self.logical_bytes,
+            BytesMetric::Physical => self.physical_bytes,
+        }
+    }
+}
+
+/// How many counted mappings point at a blob, and the blob's size
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct BlobRefs {
+    pub refs: u64,
+    pub size: u64,
+}
+
+/// Apply replacing `previous` with `next` to the running totals
+///
+/// Either side may be `None`, for a file being created or removed. `refs`
+/// holds the reference counts of the blobs involved, with a missing entry
+/// meaning no references. Physical bytes only change when a blob gains its
+/// first reference or loses its last one. A file's precompressed variants are
+/// counted along with it, as blobs of their own.
+pub fn apply_mapping_change(
+    usage: &mut StorageUsage,
+    refs: &mut BTreeMap<String, BlobRefs>,
+    previous: Option<&FileMapping>,
+    next: Option<&FileMapping>,
+) {
+    if let Some(previous) = previous {
+        usage.files = usage.files.saturating_sub(1);
+        for (sha256, size) in counted_blobs(previous) {
+            usage.logical_bytes = usage.logical_bytes.saturating_sub(size);
+            let blob = refs.entry(sha256.to_string()).or_default();
+            if blob.refs > 0 {
+                blob.refs -= 1;
+                if blob.refs == 0 {
+                    usage.physical_bytes = usage.physical_bytes.saturating_sub(blob.size);
+                }
+            }
+        }
+    }
+    if let Some(next) = next {
+        usage.files += 1;
+        for (sha256, size) in counted_blobs(next) {
+            usage.logical_bytes += size;
+            let blob = refs.entry(sha256.to_string()).or_default();
+            if blob.refs == 0 {
+                blob.size = size;
+                usage.physical_bytes += blob.size;
+            }
+            blob.refs += 1;
+        }
+    }
+}
+
+/// The blobs a mapping uses, with their sizes: its content, then its variants
+pub fn counted_blobs(mapping: &FileMapping) -> impl Iterator<Item = (&str, u64)> {
+    std::iter::once((mapping.sha256.as_str(), mapping.size as u64)).chain(
+        mapping
+            .encodings
+            .values()
+            .map(|variant| (variant.sha256.as_str(), variant.size as u64)),
+    )
+}
+
+/// Tally the running totals from scratch, skipping soft-deleted mappings
+pub fn tally_usage(mappings: &[FileMapping]) -> (StorageUsage, BTreeMap<String, BlobRefs>) {
+    let mut usage = StorageUsage::default();
+    let mut refs = BTreeMap::new();
+    for mapping in mappings.iter().filter(|m| m.deleted_at.is_none()) {
+        apply_mapping_change(&mut usage, &mut refs, None, Some(mapping));
+    }
+    (usage, refs)
+}
+
+/// Whether a soft-deleted mapping has outlived the retention window
+pub fn is_purgeable(mapping: &FileMapping, retention_ms: u64, now: u64) -> bool {
+    mapping
+        .deleted_at
+        .is_some_and(|deleted_at| deleted_at.saturating_add(retention_ms) <= now)
+}
+
+/// When the next soft-deleted mapping becomes purgeable, if any are pending
+pub fn next_purge_at(mappings: &[FileMapping], retention_ms: u64) -> Option<u64> {
+    mappings
+        .iter()
+        .filter_map(|m| m.deleted_at)
+        .map(|deleted_at| deleted_at.saturating_add(retention_ms))
+        .min()
+}
+
+/// Whether a mapping's expiry time has passed
+pub fn is_expired(mapping: &FileMapping, now: u64) -> bool {
+    mapping
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= now)
+}
+
+/// Refresh a mapping's `updated_at` to `now` without changing its content
+///
+/// A new `expires_at` replaces the old expiry. Without one, a file that
+/// expires keeps its lifetime: the expiry moves forward by as much as
+/// `updated_at` does.
+pub fn touch_mapping(mapping: &mut FileMapping, now: u64, expires_at: Option<u64>) {
+    mapping.expires_at = expires_at.or_else(|| {
+        mapping
+            .expires_at
+            .map(|at| now.saturating_add(at.saturating_sub(mapping.updated_at)))
+    });
+    mapping.updated_at = now;
+}
+
+/// When the alarm should next fire: the earliest pending purge or expiry
+pub fn next_alarm_at(mappings: &[FileMapping], retention_ms: u64) -> Option<u64> {
+    let next_expiry = mappings.iter().filter_map(|m| m.expires_at).min();
+    match (next_purge_at(mappings, retention_ms), next_expiry) {
+        (Some(purge_at), Some(expires_at)) => Some(purge_at.min(expires_at)),
+        (purge_at, expires_at) => purge_at.or(expires_at),
+    }
+}
+
+/// Whether a conditional mapping write may go ahead
+///
+/// With no `expected` SHA256 the write is unconditional. Otherwise there must
+/// be a live mapping pointing at that SHA256; anything else means another
+/// writer got there first.
+pub fn expected_sha256_matches(expected: Option<&str>, existing: Option<&FileMapping>) -> bool {
+    match expected {
+        None => true,
+        Some(expected) => existing.is_some_and(|m| m.sha256.eq_ignore_ascii_case(expected)),
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -16,20 +517,317 @@ struct MappingRequest {
     sha256: String,
     size: usize,
     content_type: Option<String>,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+    #[serde(default)]
+    compressed: bool,
+    /// Only write if the current mapping points at this SHA256
+    #[serde(default)]
+    expected_sha256: Option<String>,
+    #[serde(default)]
+    expires_at: Option<u64>,
+    #[serde(default)]
+    inline: Option<String>,
+}
+
+/// Storage key of the running `StorageUsage`
+pub const USAGE_KEY: &str = "usage:totals:v2";
+/// Where the totals were kept before they counted files; they're tallied again
+const PREVIOUS_USAGE_KEY: &str = "usage:totals";
+
+/// Storage entries read per page while looking for mappings to move
+const MIGRATION_PAGE_SIZE: usize = 1000;
+/// Storage key set once mappings have moved under `MAPPING_KEY_PREFIX`
+const KEYS_MIGRATED_KEY: &str = "migrated:mapping-keys";
+
+/// Prefix of the storage keys holding each blob's `BlobRefs`
+const BLOB_REFS_PREFIX: &str = "blobrefs:";
+
+/// Storage key of a blob's `BlobRefs`
+pub fn blob_refs_key(sha256: &str) -> String {
+    format!("{}{}", BLOB_REFS_PREFIX, sha256)
+}
+
+/// Totals before and after a mapping change that hasn't been saved yet
+struct UsageChange {
+    before: StorageUsage,
+    after: StorageUsage,
+    refs: BTreeMap<String, BlobRefs>,
 }
 
 #[durable_object]
 pub struct FileMappingObject {
     state: State,
-    _env: Env,
+    env: Env,
+    /// Whether `migrate_mapping_keys` has run since this instance started
+    keys_migrated: Cell<bool>,
+}
+
+impl FileMappingObject {
+    /// Whether `VERSIONING_ENABLED` is set to `true` or `1`
+    fn versioning_enabled(&self) -> bool {
+        self.env
+            .var("VERSIONING_ENABLED")
+            .map(|v| matches!(v.to_string().as_str(), "true" | "1"))
+            .unwrap_or(false)
+    }
+
+    /// Most live files this object may hold, from `MAX_FILES`
+    fn max_files(&self) -> Option<usize> {
+        max_files(self.env_var("MAX_FILES").as_deref())
+    }
+
+    /// Most bytes this object may hold, from `MAX_TOTAL_BYTES`
+    fn max_total_bytes(&self) -> Option<u64> {
+        max_total_bytes(self.env_var("MAX_TOTAL_BYTES").as_deref())
+    }
+
+    /// Which bytes `MAX_TOTAL_BYTES` counts, from `QUOTA_BYTES_METRIC`
+    fn bytes_metric(&self) -> BytesMetric {
+        bytes_metric(self.env_var("QUOTA_BYTES_METRIC").as_deref())
+    }
+
+    fn env_var(&self, name: &str) -> Option<String> {
+        self.env.var(name).ok().map(|v| v.to_string())
+    }
+
+    /// Recovery window for deletes, from `SOFT_DELETE_RETENTION_SECS`
+    ///
+    /// `None` (the default) means deletes remove mappings immediately.
+    fn soft_delete_retention_ms(&self) -> Option<u64> {
+        self.env
+            .var("SOFT_DELETE_RETENTION_SECS")
+            .ok()
+            .and_then(|v| v.to_string().parse::<u64>().ok())
+            .filter(|&secs| secs > 0)
+            .map(|secs| secs * 1000)
+    }
+
+    /// Get a mapping unless it is missing, soft-deleted or expired
+    ///
+    /// Expired mappings stay in storage until the alarm purges them.
+    async fn get_live_mapping(&self, filename: &str) -> Option<FileMapping> {
+        let now = js_sys::Date::now() as u64;
+        self.state
+            .storage()
+            .get::<FileMapping>(&mapping_key(filename))
+            .await
+            .ok()
+            .filter(|m| m.deleted_at.is_none() && !is_expired(m, now))
+    }
+
+    /// Remove a mapping and its version history
+    async fn purge_mapping(&self, filename: &str) -> Result<()> {
+        let storage = self.state.storage();
+        // Soft-deleted mappings already stopped counting when they were deleted
+        if let Ok(mapping) = storage.get::<FileMapping>(&mapping_key(filename)).await {
+            if mapping.deleted_at.is_none() {
+                self.record_usage(Some(&mapping), None).await?;
+            }
+        }
+        storage.delete(&mapping_key(filename)).await?;
+        storage.delete(&versions_key(filename)).await?;
+        Ok(())
+    }
+
+    /// The running byte totals, tallied from the stored mappings the first time
+    async fn load_usage(&self) -> Result<StorageUsage> {
+        let storage = self.state.storage();
+        if let Ok(usage) = storage.get::<StorageUsage>(USAGE_KEY).await {
+            return Ok(usage);
+        }
+
+        let (usage, refs) = tally_usage(&self.list_all_mappings(None).await?);
+        for (sha256, blob) in &refs {
+            storage.put(&blob_refs_key(sha256), blob).await?;
+        }
+        storage.put(USAGE_KEY, &usage).await?;
+        storage.delete(PREVIOUS_USAGE_KEY).await?;
+        Ok(usage)
+    }
+
+    /// Work out the totals after replacing `previous` with `next`
+    ///
+    /// Nothing is written until the change is passed to `save_usage`.
+    async fn usage_change(
+        &self,
+        previous: Option<&FileMapping>,
+        next: Option<&FileMapping>,
+    ) -> Result<UsageChange> {
+        let storage = self.state.storage();
+        let before = self.load_usage().await?;
+        let mut refs = BTreeMap::new();
+        for mapping in previous.into_iter().chain(next) {
+            for (sha256, _) in counted_blobs(mapping) {
+                if !refs.contains_key(sha256) {
+                    let blob = storage
+                        .get::<BlobRefs>(&blob_refs_key(sha256))
+                        .await
+                        .unwrap_or_default();
+                    refs.insert(sha256.to_string(), blob);
+                }
+            }
+        }
+
+        let mut after = before;
+        apply_mapping_change(&mut after, &mut refs, previous, next);
+        Ok(UsageChange {
+            before,
+            after,
+            refs,
+        })
+    }
+
+    /// Store the totals and reference counts from `usage_change`
+    async fn save_usage(&self, change: UsageChange) -> Result<()> {
+        let storage = self.state.storage();
+        for (sha256, blob) in &change.refs {
+            if blob.refs == 0 {
+                storage.delete(&blob_refs_key(sha256)).await?;
+            } else {
+                storage.put(&blob_refs_key(sha256), blob).await?;
+            }
+        }
+        storage.put(USAGE_KEY, &change.after).await
+    }
+
+    /// Update the running totals for a mapping change
+    async fn record_usage(
+        &self,
+        previous: Option<&FileMapping>,
+        next: Option<&FileMapping>,
+    ) -> Result<()> {
+        let change = self.usage_change(previous, next).await?;
+        self.save_usage(change).await
+    }
+
+    /// Make sure the alarm fires by `expires_at`
+    ///
+    /// Only moves the alarm earlier, so a sooner purge or expiry isn't lost.
+    /// Soft deletes use this too: their purge time is always the latest yet,
+    /// so there's no need to look at the other mappings, and the alarm works
+    /// out what's next once it fires.
+    async fn schedule_expiry(&self, expires_at: u64) -> Result<()> {
+        let storage = self.state.storage();
+        let scheduled = storage.get_alarm().await?;
+        if scheduled.map_or(true, |at| at as u64 > expires_at) {
+            let now = js_sys::Date::now() as u64;
+            storage
+                .set_alarm(Duration::from_millis(expires_at.saturating_sub(now)))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Load every mapping that hasn't been soft-deleted or expired
+    async fn list_mappings(&self, prefix: Option<&str>) -> Result<Vec<FileMapping>> {
+        let now = js_sys::Date::now() as u64;
+        let mut mappings = self.list_all_mappings(prefix).await?;
+        mappings.retain(|m| m.deleted_at.is_none() && !is_expired(m, now));
+        Ok(mappings)
+    }
+
+    /// Load every stored mapping, optionally restricted to a filename prefix
+    async fn list_all_mappings(&self, prefix: Option<&str>) -> Result<Vec<FileMapping>> {
+        let key_prefix = mapping_key(prefix.unwrap_or(""));
+        let entries = self
+            .state
+            .storage()
+            .list_with_options(ListOptions::new().prefix(&key_prefix))
+            .await?;
+
+        let mut mappings: Vec<FileMapping> = Vec::new();
+        for value in entries.values() {
+            let value = value.map_err(Error::from)?;
+            mappings.push(serde_wasm_bindgen::from_value(value)?);
+        }
+        Ok(mappings)
+    }
+
+    /// Move mappings stored under their bare filename to `mapping_key`
+    ///
+    /// Runs once; `KEYS_MIGRATED_KEY` records that it has. If a file had been
+    /// named after one of the object's own keys, the totals and reference
+    /// counts it overwrote are dropped, so `load_usage` tallies them again from
+    /// the moved mappings.
+    async fn migrate_mapping_keys(&self) -> Result<()> {
+        if self.keys_migrated.get() {
+            return Ok(());
+        }
+        let storage = self.state.storage();
+        if storage.get::<bool>(KEYS_MIGRATED_KEY).await.is_ok() {
+            self.keys_migrated.set(true);
+            return Ok(());
+        }
+
+        // Find every legacy mapping before moving any, a page at a time
+        let mut legacy = Vec::new();
+        let mut start: Option<String> = None;
+        loop {
+            let mut options = ListOptions::new().limit(MIGRATION_PAGE_SIZE);
+            if let Some(start) = &start {
+                options = options.start(start);
+            }
+            let page = storage.list_with_options(options).await?;
+            let mut last = None;
+            for entry in page.entries() {
+                let entry: js_sys::Array = entry.map_err(Error::from)?.into();
+                let key = entry.get(0).as_string().unwrap_or_default();
+                last = Some(key.clone());
+                // `start` is inclusive, so each page after the first repeats a key
+                if start.as_deref() == Some(key.as_str()) {
+                    continue;
+                }
+                // Versions, totals and reference counts aren't mappings
+                if let Ok(m) = serde_wasm_bindgen::from_value::<FileMapping>(entry.get(1)) {
+                    if is_legacy_mapping_key(&key, &m) {
+                        legacy.push((key, m));
+                    }
+                }
+            }
+            if page.size() < MIGRATION_PAGE_SIZE as u32 || last.is_none() {
+                break;
+            }
+            start = last;
+        }
+
+        let moved = legacy.len();
+        let (puts, deletes) = legacy_key_moves(legacy);
+        for (key, mapping) in &puts {
+            storage.put(key, mapping).await?;
+        }
+        for key in &deletes {
+            storage.delete(key).await?;
+        }
+        if moved > 0 {
+            let refs = storage
+                .list_with_options(ListOptions::new().prefix(BLOB_REFS_PREFIX))
+                .await?;
+            for key in refs.keys() {
+                if let Some(key) = key.map_err(Error::from)?.as_string() {
+                    storage.delete(&key).await?;
+                }
+            }
+            storage.delete(USAGE_KEY).await?;
+        }
+
+        storage.put(KEYS_MIGRATED_KEY, true).await?;
+        self.keys_migrated.set(true);
+        Ok(())
+    }
 }
 
 impl DurableObject for FileMappingObject {
     fn new(state: State, env: Env) -> Self {
-        Self { state, _env: env }
+        Self {
+            state,
+            env,
+            keys_migrated: Cell::new(false),
+        }
     }
 
     async fn fetch(&self, mut req: Request) -> Result<Response> {
+        self.migrate_mapping_keys().await?;
         let url = req.url()?;
         let path = url.path();
 
@@ -41,19 +839,107 @@ impl DurableObject for FileMappingObject {
         match req.method() {
             Method::Get => {
                 // Get mapping for a filename
-                if filename.is_empty() {
-                    // For now, return empty list since storage.list() is not available
-                    // In production, you would need to maintain a separate index of all keys
-                    let mappings: Vec<FileMapping> = Vec::new();
-                    Response::from_json(&mappings)
+                if filename.is_empty() && url.query_pairs().any(|(k, _)| k == "stats") {
+                    // Aggregate over every mapping. This reads all of storage on
+                    // each call, so cost grows with the number of files.
+                    let mappings = self.list_mappings(None).await?;
+                    Response::from_json(&compute_stats(&mappings))
+                } else if filename.is_empty() && url.query_pairs().any(|(k, _)| k == "usage") {
+                    // The running totals, without listing anything
+                    Response::from_json(&self.load_usage().await?)
+                } else if filename.is_empty() {
+                    // List all mappings, optionally restricted to a filename prefix
+                    let prefix = url
+                        .query_pairs()
+                        .find(|(k, _)| k == "prefix")
+                        .map(|(_, v)| v.into_owned());
+
+                    // Sorted and paginated when `sort`, `order`, `limit` or `offset` is given
+                    let list_query = match ListQuery::from_query(url.query_pairs()) {
+                        Ok(q) => q,
+                        Err(e) => return Response::error(e, 400),
+                    };
+
+                    // `fields=key` sends only filenames, for callers that need no more
+                    let keys_only = match url.query_pairs().find(|(k, _)| k == "fields") {
+                        Some((_, fields)) if fields == "key" => true,
+                        Some(_) => return Response::error("Unsupported fields", 400),
+                        None => false,
+                    };
+
+                    let mappings = self.list_mappings(prefix.as_deref()).await?;
+                    match list_query {
+                        Some(query) => Response::from_json(&paginate_mappings(mappings, &query)),
+                        None if keys_only => Response::from_json(&mapping_names(mappings)),
+                        None => Response::from_json(&mappings),
+                    }
+                } else if url.query_pairs().any(|(k, _)| k == "versions") {
+                    // List retained versions, oldest first
+                    let storage = self.state.storage();
+                    let versions = storage
+                        .get::<Vec<FileVersion>>(&versions_key(filename))
+                        .await
+                        .unwrap_or_default();
+                    Response::from_json(&versions)
+                } else if let Some((_, version)) = url.query_pairs().find(|(k, _)| k == "version") {
+                    // Get the mapping as it was at a specific version
+                    let version: u32 = match version.parse() {
+                        Ok(v) => v,
+                        Err(_) => return Response::error("Invalid version", 400),
+                    };
+
+                    let storage = self.state.storage();
+                    let versions = storage
+                        .get::<Vec<FileVersion>>(&versions_key(filename))
+                        .await
+                        .unwrap_or_default();
+                    match versions.iter().find(|v| v.version == version) {
+                        Some(v) => Response::from_json(&v.to_mapping(filename)),
+                        None => Response::error("Version not found", 404),
+                    }
                 } else {
                     // Get specific mapping
-                    let storage = self.state.storage();
-                    match storage.get::<FileMapping>(filename).await {
-                        Ok(mapping) => Response::from_json(&mapping),
-                        Err(_) => Response::error("Mapping not found", 404),
+                    match self.get_live_mapping(filename).await {
+                        Some(mapping) => Response::from_json(&mapping),
+                        None => Response::error("Mapping not found", 404),
+                    }
+                }
+            }
+            Method::Put if url.query_pairs().any(|(k, _)| k == "encoding") => {
+                // Attach a pre-compressed variant to an existing file
+                let encoding = url
+                    .query_pairs()
+                    .find(|(k, _)| k == "encoding")
+                    .map(|(_, v)| v.into_owned())
+                    .unwrap_or_default();
+                if filename.is_empty() || encoding.is_empty() {
+                    return Response::error("Filename and encoding required", 400);
+                }
+                let variant: EncodedVariant = serde_json::from_str(&req.text().await?)
+                    .map_err(|e| Error::RustError(format!("Invalid JSON: {}", e)))?;
+
+                let previous = match self.get_live_mapping(filename).await {
+                    Some(m) => m,
+                    None => return Response::error("Mapping not found", 404),
+                };
+                let mut mapping = previous.clone();
+                mapping.encodings.insert(encoding, variant);
+
+                // Variants take up storage too, so they're held to the byte quota
+                let usage = self.usage_change(Some(&previous), Some(&mapping)).await?;
+                if let Some(limit) = self.max_total_bytes() {
+                    let metric = self.bytes_metric();
+                    let used = usage.before.bytes(metric);
+                    if bytes_quota_exceeded(used, usage.after.bytes(metric), Some(limit)) {
+                        return quota_exceeded_response(&QuotaExceeded::bytes(metric, used, limit));
                     }
                 }
+                self.state
+                    .storage()
+                    .put(&mapping_key(filename), &mapping)
+                    .await?;
+                self.save_usage(usage).await?;
+                Response::from_json(&mapping)
             }
             Method::Put => {
                 // Create or update mapping
@@ -67,10 +953,19 @@ impl DurableObject for FileMappingObject {
 
                 let now = js_sys::Date::now() as u64;
 
-                // Check if mapping already exists
+                // Check if mapping already exists; a soft-deleted one is replaced as new
                 let storage = self.state.storage();
-                let existing = storage.get::<FileMapping>(filename).await.ok();
+                let existing = self.get_live_mapping(filename).await;
 
+                if !expected_sha256_matches(request.expected_sha256.as_deref(), existing.as_ref()) {
+                    return Response::error("Mapping was changed by another writer", 409);
+                }
+                // Variants only still apply if the identity content is unchanged
+                let encodings = existing
+                    .as_ref()
+                    .filter(|m| m.sha256 == request.sha256)
+                    .map(|m| m.encodings.clone())
+                    .unwrap_or_default();
                 let mapping = FileMapping {
                     filename: filename.to_string(),
                     sha256: request.sha256,
@@ -78,35 +973,190 @@ impl DurableObject for FileMappingObject {
                     content_type: request.content_type,
                     created_at: existing.as_ref().map(|m| m.created_at).unwrap_or(now),
                     updated_at: now,
+                    metadata: request.metadata,
+                    deleted_at: None,
+                    compressed: request.compressed,
+                    expires_at: request.expires_at,
+                    inline: request.inline,
+                    encodings,
                 };
 
                 // Check if content has changed
-                let changed = existing
-                    .as_ref()
-                    .map_or(true, |m| m.sha256 != mapping.sha256);
+                let changed = existing.as_ref().map_or(true, |m| {
+                    m.sha256 != mapping.sha256 || m.metadata != mapping.metadata
+                });
+
+                // Expired mappings still count until the alarm purges them
+                let stored = storage
+                    .get::<FileMapping>(&mapping_key(filename))
+                    .await
+                    .ok()
+                    .filter(|m| m.deleted_at.is_none());
+                let usage = self.usage_change(stored.as_ref(), Some(&mapping)).await?;
+                // Only new files count against the file quota
+                if let Some(limit) = self.max_files() {
+                    let count = usage.before.files as usize;
+                    if usage.after.files > usage.before.files
+                        && file_quota_exceeded(count, Some(limit))
+                    {
+                        return quota_exceeded_response(&QuotaExceeded::files(count, limit));
+                    }
+                }
+                if let Some(limit) = self.max_total_bytes() {
+                    let metric = self.bytes_metric();
+                    let used = usage.before.bytes(metric);
+                    if bytes_quota_exceeded(used, usage.after.bytes(metric), Some(limit)) {
+                        return quota_exceeded_response(&QuotaExceeded::bytes(metric, used, limit));
+                    }
+                }
 
                 // Save mapping
-                storage.put(filename, &mapping).await?;
+                storage.put(&mapping_key(filename), &mapping).await?;
+                self.save_usage(usage).await?;
+                if let Some(expires_at) = mapping.expires_at {
+                    self.schedule_expiry(expires_at).await?;
+                }
+
+                // Keep the previous content reachable as an older version
+                if changed && self.versioning_enabled() {
+                    let key = versions_key(filename);
+                    let mut versions = storage
+                        .get::<Vec<FileVersion>>(&key)
+                        .await
+                        .unwrap_or_default();
+                    if versions.is_empty() {
+                        if let Some(previous) = &existing {
+                            // File predates versioning; keep what it held before
+                            record_version(&mut versions, previous, MAX_RETAINED_VERSIONS);
+                        }
+                    }
+                    record_version(&mut versions, &mapping, MAX_RETAINED_VERSIONS);
+                    storage.put(&key, &versions).await?;
+                }
 
-                let mut response = Response::from_json(&mapping)?;
-                if !changed {
-                    response = response.with_status(304); // Not Modified
+                // 201 tells the caller the file is new. Unchanged content can't use 304
+                // here since a 304 response isn't allowed to carry the mapping body.
+                let created = existing.is_none();
+                let return_previous = url
+                    .query_pairs()
+                    .any(|(k, v)| k == "return" && v == "previous");
+                let mut response = if return_previous {
+                    Response::from_json(&ReplacedMapping {
+                        mapping,
+                        previous: existing,
+                    })?
+                } else {
+                    Response::from_json(&mapping)?
+                };
+                if created {
+                    response = response.with_status(201); // Created
                 }
 
                 Ok(response)
             }
             Method::Delete => {
-                // Delete mapping
+                // Delete mapping, or with `?all` wipe every mapping, version and stat
+                if filename.is_empty() && url.query_pairs().any(|(k, _)| k == "all") {
+                    let deleted = self.list_mappings(None).await?.len();
+                    let storage = self.state.storage();
+                    storage.delete_alarm().await?;
+                    storage.delete_all().await?;
+                    return Response::from_json(&serde_json::json!({ "deleted": deleted }));
+                }
                 if filename.is_empty() {
                     return Response::error("Filename required", 400);
                 }
 
-                let storage = self.state.storage();
-                storage.delete(filename).await?;
+                // `?purge` skips the recovery window, e.g. for mappings whose blob is gone
+                let purge = url.query_pairs().any(|(k, _)| k == "purge");
+                match self.soft_delete_retention_ms() {
+                    Some(retention_ms) if !purge => {
+                        let mut mapping = match self.get_live_mapping(filename).await {
+                            Some(m) => m,
+                            None => return Response::ok("Mapping deleted"),
+                        };
+                        self.record_usage(Some(&mapping), None).await?;
+                        let now = js_sys::Date::now() as u64;
+                        mapping.deleted_at = Some(now);
+                        self.state
+                            .storage()
+                            .put(&mapping_key(filename), &mapping)
+                            .await?;
+                        self.schedule_expiry(now.saturating_add(retention_ms))
+                            .await?;
+                    }
+                    _ => self.purge_mapping(filename).await?,
+                }
 
                 Response::ok("Mapping deleted")
             }
-            _ => Response::error("Method not allowed", 405),
+            Method::Post if url.query_pairs().any(|(k, _)| k == "touch") => {
+                // Keep a file alive by refreshing its timestamps; only the mapping changes
+                let expires_at = match url.query_pairs().find(|(k, _)| k == "expires_at") {
+                    Some((_, v)) => match v.parse::<u64>() {
+                        Ok(at) => Some(at),
+                        Err(_) => return Response::error("Invalid expires_at", 400),
+                    },
+                    None => None,
+                };
+                let mut mapping = match self.get_live_mapping(filename).await {
+                    Some(m) => m,
+                    None => return Response::error("Mapping not found", 404),
+                };
+
+                touch_mapping(&mut mapping, js_sys::Date::now() as u64, expires_at);
+                self.state
+                    .storage()
+                    .put(&mapping_key(filename), &mapping)
+                    .await?;
+                if let Some(expires_at) = mapping.expires_at {
+                    self.schedule_expiry(expires_at).await?;
+                }
+                Response::from_json(&mapping)
+            }
+            Method::Post if url.query_pairs().any(|(k, _)| k == "restore") => {
+                // Undelete a soft-deleted mapping; the blob was never touched
+                let storage = self.state.storage();
+                let mut mapping = match storage.get::<FileMapping>(&mapping_key(filename)).await {
+                    Ok(m) => m,
+                    Err(_) => return Response::error("Mapping not found", 404),
+                };
+                if mapping.deleted_at.is_none() {
+                    return Response::error("File is not deleted", 409);
+                }
+
+                mapping.deleted_at = None;
+                storage.put(&mapping_key(filename), &mapping).await?;
+                self.record_usage(None, Some(&mapping)).await?;
+                Response::from_json(&mapping)
+            }
+            _ => method_not_allowed(&[Method::Get, Method::Put, Method::Delete, Method::Post]),
+        }
+    }
+
+    async fn alarm(&self) -> Result<Response> {
+        self.migrate_mapping_keys().await?;
+        // Retention may have been turned off since the delete; purge everything then
+        let retention_ms = self.soft_delete_retention_ms().unwrap_or(0);
+        let now = js_sys::Date::now() as u64;
+
+        let mappings = self.list_all_mappings(None).await?;
+        let mut remaining = Vec::new();
+        for mapping in mappings {
+            if is_purgeable(&mapping, retention_ms, now) || is_expired(&mapping, now) {
+                self.purge_mapping(&mapping.filename).await?;
+            } else if mapping.deleted_at.is_some() || mapping.expires_at.is_some() {
+                remaining.push(mapping);
+            }
         }
+
+        if let Some(purge_at) = next_alarm_at(&remaining, retention_ms) {
+            self.state
+                .storage()
+                .set_alarm(Duration::from_millis(purge_at.saturating_sub(now)))
+                .await?;
+        }
+
+        Response::ok("Purge complete")
     }
 }
@@ -0,0 +1,647 @@
+#[cfg(test)]
+mod file_mapping_object_tests {
+    use crate::file_mapping_object::*;
+    use crate::quota::{bytes_quota_exceeded, BytesMetric};
+    use std::collections::{BTreeMap, HashMap};
+
+    fn mapping(sha256: &str, updated_at: u64) -> FileMapping {
+        FileMapping {
+            filename: "report.pdf".to_string(),
+            sha256: sha256.to_string(),
+            size: 100,
+            content_type: Some("application/pdf".to_string()),
+            created_at: 1000,
+            updated_at,
+            metadata: HashMap::new(),
+            deleted_at: None,
+            compressed: false,
+            expires_at: None,
+            inline: None,
+            encodings: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_record_version_numbering() {
+        let mut versions = Vec::new();
+        record_version(&mut versions, &mapping("aaa", 1000), MAX_RETAINED_VERSIONS);
+        record_version(&mut versions, &mapping("bbb", 2000), MAX_RETAINED_VERSIONS);
+
+        assert_eq!(versions.len(), 2);
+        assert_eq!(versions[0].version, 1);
+        assert_eq!(versions[0].sha256, "aaa");
+        assert_eq!(versions[0].created_at, 1000);
+        assert_eq!(versions[1].version, 2);
+        assert_eq!(versions[1].sha256, "bbb");
+    }
+
+    #[test]
+    fn test_record_version_evicts_oldest() {
+        let mut versions = Vec::new();
+        for i in 0..5 {
+            record_version(&mut versions, &mapping(&format!("sha{}", i), i), 3);
+        }
+
+        // Only the newest three are kept, and numbering continues past evictions
+        let numbers: Vec<u32> = versions.iter().map(|v| v.version).collect();
+        assert_eq!(numbers, vec![3, 4, 5]);
+        assert_eq!(versions[0].sha256, "sha2");
+    }
+
+    #[test]
+    fn test_file_version_to_mapping() {
+        let mut versions = Vec::new();
+        record_version(&mut versions, &mapping("aaa", 5000), MAX_RETAINED_VERSIONS);
+
+        let restored = versions[0].to_mapping("report.pdf");
+        assert_eq!(restored.filename, "report.pdf");
+        assert_eq!(restored.sha256, "aaa");
+        assert_eq!(restored.size, 100);
+        assert_eq!(restored.content_type, Some("application/pdf".to_string()));
+        assert_eq!(restored.updated_at, 5000);
+    }
+
+    #[test]
+    fn test_file_version_keeps_inline_content() {
+        let mut inline = mapping("aaa", 5000);
+        inline.inline = Some("aGk=".to_string());
+        let mut versions = Vec::new();
+        record_version(&mut versions, &inline, MAX_RETAINED_VERSIONS);
+
+        let restored = versions[0].to_mapping("report.pdf");
+        assert_eq!(restored.inline.as_deref(), Some("aGk="));
+    }
+
+    #[test]
+    fn test_file_mapping_without_metadata_deserializes() {
+        // Mappings stored before user metadata existed have no metadata field
+        let json = r#"{"filename":"a.txt","sha256":"abc","size":1,"content_type":null,"created_at":1,"updated_at":2}"#;
+        let mapping: FileMapping = serde_json::from_str(json).unwrap();
+        assert!(mapping.metadata.is_empty());
+    }
+
+    #[test]
+    fn test_compute_stats() {
+        let mut a = mapping("aaa", 1000);
+        a.size = 300;
+        let mut copy = mapping("aaa", 1000);
+        copy.filename = "copy.pdf".to_string();
+        copy.size = 300;
+        let b = mapping("bbb", 1000);
+
+        let stats = compute_stats(&[a, copy, b]);
+        assert_eq!(stats.total_files, 3);
+        assert_eq!(stats.unique_blobs, 2);
+        assert_eq!(stats.logical_bytes, 700);
+        assert_eq!(stats.physical_bytes, 400);
+        assert_eq!(stats.dedup_ratio, 1.75);
+    }
+
+    #[test]
+    fn test_compute_stats_empty() {
+        let stats = compute_stats(&[]);
+        assert_eq!(stats.total_files, 0);
+        assert_eq!(stats.unique_blobs, 0);
+        assert_eq!(stats.logical_bytes, 0);
+        assert_eq!(stats.physical_bytes, 0);
+        assert_eq!(stats.dedup_ratio, 1.0);
+    }
+
+    #[test]
+    fn test_is_purgeable() {
+        let live = mapping("aaa", 1000);
+        assert!(!is_purgeable(&live, 0, u64::MAX));
+
+        let mut deleted = mapping("aaa", 1000);
+        deleted.deleted_at = Some(5000);
+        assert!(!is_purgeable(&deleted, 1000, 5999));
+        assert!(is_purgeable(&deleted, 1000, 6000));
+        // No retention purges immediately
+        assert!(is_purgeable(&deleted, 0, 5000));
+        assert!(!is_purgeable(&deleted, u64::MAX, u64::MAX - 1));
+    }
+
+    #[test]
+    fn test_next_purge_at() {
+        let live = mapping("aaa", 1000);
+        assert_eq!(next_purge_at(&[live], 1000), None);
+        assert_eq!(next_purge_at(&[], 1000), None);
+
+        let mut first = mapping("aaa", 1000);
+        first.deleted_at = Some(9000);
+        let mut second = mapping("bbb", 1000);
+        second.deleted_at = Some(4000);
+        assert_eq!(
+            next_purge_at(&[first, mapping("ccc", 1000), second], 1000),
+            Some(5000)
+        );
+    }
+
+    #[test]
+    fn test_is_expired() {
+        assert!(!is_expired(&mapping("aaa", 1000), u64::MAX));
+
+        let mut expiring = mapping("aaa", 1000);
+        expiring.expires_at = Some(5000);
+        assert!(!is_expired(&expiring, 4999));
+        assert!(is_expired(&expiring, 5000));
+    }
+
+    #[test]
+    fn test_next_alarm_at() {
+        assert_eq!(next_alarm_at(&[mapping("aaa", 1000)], 1000), None);
+
+        let mut deleted = mapping("aaa", 1000);
+        deleted.deleted_at = Some(4000);
+        let mut expiring = mapping("bbb", 1000);
+        expiring.expires_at = Some(7000);
+        assert_eq!(next_alarm_at(&[expiring], 1000), Some(7000));
+
+        let mut expiring = mapping("bbb", 1000);
+        expiring.expires_at = Some(3000);
+        // Whichever comes first, the purge at 5000 or the expiry at 3000
+        assert_eq!(next_alarm_at(&[deleted, expiring], 1000), Some(3000));
+    }
+
+    #[test]
+    fn test_deleted_at_serialization() {
+        // Live mappings don't carry the field, so stored JSON is unchanged
+        let json = serde_json::to_value(mapping("aaa", 1000)).unwrap();
+        assert!(json.get("deleted_at").is_none());
+
+        let mut deleted = mapping("aaa", 1000);
+        deleted.deleted_at = Some(5000);
+        let json = serde_json::to_string(&deleted).unwrap();
+        let parsed: FileMapping = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.deleted_at, Some(5000));
+    }
+
+    #[test]
+    fn test_expected_sha256_matches() {
+        let current = mapping("aaa", 1000);
+
+        // Absent: the write is unconditional
+        assert!(expected_sha256_matches(None, Some(&current)));
+        assert!(expected_sha256_matches(None, None));
+
+        // Match
+        assert!(expected_sha256_matches(Some("aaa"), Some(&current)));
+        assert!(expected_sha256_matches(Some("AAA"), Some(&current)));
+
+        // Mismatch, including when there's no mapping to match
+        assert!(!expected_sha256_matches(Some("bbb"), Some(&current)));
+        assert!(!expected_sha256_matches(Some("aaa"), None));
+    }
+
+    fn listed(filename: &str, size: usize, updated_at: u64) -> FileMapping {
+        FileMapping {
+            filename: filename.to_string(),
+            size,
+            ..mapping("abc", updated_at)
+        }
+    }
+
+    fn names(mappings: &[FileMapping]) -> Vec<&str> {
+        mappings.iter().map(|m| m.filename.as_str()).collect()
+    }
+
+    #[test]
+    fn test_compare_mappings() {
+        use std::cmp::Ordering;
+
+        let small = listed("b.txt", 10, 2000);
+        let large = listed("a.txt", 20, 1000);
+        assert_eq!(
+            compare_mappings(&small, &large, ListSort::Name, false),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare_mappings(&small, &large, ListSort::Size, false),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_mappings(&small, &large, ListSort::Size, true),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare_mappings(&small, &large, ListSort::Updated, false),
+            Ordering::Greater
+        );
+
+        // Ties break by ascending filename, even in descending order
+        let same_size = listed("c.txt", 10, 3000);
+        assert_eq!(
+            compare_mappings(&small, &same_size, ListSort::Size, false),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_mappings(&small, &same_size, ListSort::Size, true),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn test_paginate_mappings() {
+        let mappings = || {
+            vec![
+                listed("d.txt", 10, 4000),
+                listed("a.txt", 30, 1000),
+                listed("c.txt", 10, 3000),
+                listed("b.txt", 20, 2000),
+            ]
+        };
+        let query = |pairs: &[(&str, &str)]| {
+            ListQuery::from_query(pairs.iter().copied())
+                .unwrap()
+                .unwrap()
+        };
+
+        let page = paginate_mappings(mappings(), &query(&[("sort", "size"), ("limit", "3")]));
+        assert_eq!(names(&page.mappings), vec!["c.txt", "d.txt", "b.txt"]);
+        assert_eq!(page.total, 4);
+        assert_eq!(page.next_offset, Some(3));
+
+        let page = paginate_mappings(
+            mappings(),
+            &query(&[("sort", "updated"), ("order", "desc"), ("offset", "3")]),
+        );
+        assert_eq!(names(&page.mappings), vec!["a.txt"]);
+        assert_eq!(page.next_offset, None);
+
+        // Offsets past the end give an empty last page
+        let page = paginate_mappings(mappings(), &query(&[("offset", "10")]));
+        assert!(page.mappings.is_empty());
+        assert_eq!(page.total, 4);
+        assert_eq!(page.next_offset, None);
+    }
+
+    #[test]
+    fn test_list_query_from_query() {
+        assert_eq!(ListQuery::from_query([("prefix", "docs/")]), Ok(None));
+        assert_eq!(
+            ListQuery::from_query([("sort", "size")]),
+            Ok(Some(ListQuery {
+                sort: ListSort::Size,
+                descending: false,
+                limit: DEFAULT_LIST_LIMIT,
+                offset: 0,
+            }))
+        );
+        for bad in [
+            ("sort", "color"),
+            ("order", "up"),
+            ("limit", "0"),
+            ("limit", "1001"),
+            ("offset", "-1"),
+        ] {
+            assert!(ListQuery::from_query([bad]).is_err(), "accepted {:?}", bad);
+        }
+
+        // Parameters round-trip through the Durable Object request
+        let query =
+            ListQuery::from_query([("sort", "updated"), ("order", "desc"), ("offset", "5")])
+                .unwrap()
+                .unwrap();
+        let pairs = query.to_query();
+        assert_eq!(
+            ListQuery::from_query(pairs.iter().map(|(k, v)| (*k, v.as_str()))),
+            Ok(Some(query))
+        );
+    }
+
+    #[test]
+    fn test_replaced_mapping_serialization() {
+        let replaced = ReplacedMapping {
+            mapping: mapping("new", 2000),
+            previous: Some(mapping("old", 1500)),
+        };
+        let json = serde_json::to_value(&replaced).unwrap();
+        assert_eq!(json["sha256"], "new");
+        assert_eq!(json["previous"]["sha256"], "old");
+        assert_eq!(json["previous"]["size"], 100);
+
+        // Still readable as the new mapping
+        let read: FileMapping = serde_json::from_value(json).unwrap();
+        assert_eq!(read.sha256, "new");
+
+        let created = ReplacedMapping {
+            mapping: mapping("new", 2000),
+            previous: None,
+        };
+        let json = serde_json::to_value(&created).unwrap();
+        assert!(json["previous"].is_null());
+    }
+
+    #[test]
+    fn test_mapping_names() {
+        let mut a = mapping("aaa", 1000);
+        a.filename = "a.txt".to_string();
+        let mut b = mapping("bbb", 1000);
+        b.filename = "docs/b.txt".to_string();
+        assert_eq!(mapping_names(vec![a, b]), vec!["a.txt", "docs/b.txt"]);
+        assert!(mapping_names(vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_mapping_encodings_serialization() {
+        // Mappings stored before variants existed have none
+        let stored = serde_json::to_value(mapping("aaa", 1000)).unwrap();
+        assert!(stored.get("encodings").is_none());
+        let parsed: FileMapping = serde_json::from_value(stored).unwrap();
+        assert!(parsed.encodings.is_empty());
+
+        let mut with_br = mapping("aaa", 1000);
+        with_br.encodings.insert(
+            "br".to_string(),
+            EncodedVariant {
+                sha256: "bbb".to_string(),
+                size: 40,
+            },
+        );
+        let value = serde_json::to_value(&with_br).unwrap();
+        assert_eq!(
+            value["encodings"],
+            serde_json::json!({ "br": { "sha256": "bbb", "size": 40 } })
+        );
+        let parsed: FileMapping = serde_json::from_value(value).unwrap();
+        assert_eq!(parsed.encodings, with_br.encodings);
+    }
+
+    #[test]
+    fn test_touch_mapping() {
+        let mut file = mapping("aaaa", 2000);
+        touch_mapping(&mut file, 5000, None);
+        assert_eq!(file.updated_at, 5000);
+        assert_eq!(file.created_at, 1000);
+        assert_eq!(file.expires_at, None);
+
+        // An expiring file keeps its lifetime, counted from the touch
+        let mut file = mapping("aaaa", 2000);
+        file.expires_at = Some(3000);
+        touch_mapping(&mut file, 5000, None);
+        assert_eq!(file.expires_at, Some(6000));
+
+        // A given expiry replaces it
+        touch_mapping(&mut file, 7000, Some(60_000));
+        assert_eq!(file.updated_at, 7000);
+        assert_eq!(file.expires_at, Some(60_000));
+        assert_eq!(file.sha256, "aaaa");
+    }
+
+    fn sized(sha256: &str, size: usize) -> FileMapping {
+        let mut m = mapping(sha256, 1000);
+        m.size = size;
+        m
+    }
+
+    #[test]
+    fn test_apply_mapping_change_counts_shared_blobs_once() {
+        let mut usage = StorageUsage::default();
+        let mut refs = BTreeMap::new();
+
+        apply_mapping_change(&mut usage, &mut refs, None, Some(&sized("aaa", 100)));
+        assert_eq!(usage.logical_bytes, 100);
+        assert_eq!(usage.physical_bytes, 100);
+
+        // A second file with the same content only adds logical bytes
+        apply_mapping_change(&mut usage, &mut refs, None, Some(&sized("aaa", 100)));
+        assert_eq!(usage.logical_bytes, 200);
+        assert_eq!(usage.physical_bytes, 100);
+        assert_eq!(refs["aaa"], BlobRefs { refs: 2, size: 100 });
+
+        apply_mapping_change(&mut usage, &mut refs, None, Some(&sized("bbb", 50)));
+        assert_eq!(usage.bytes(BytesMetric::Logical), 250);
+        assert_eq!(usage.bytes(BytesMetric::Physical), 150);
+    }
+
+    #[test]
+    fn test_apply_mapping_change_frees_blob_with_last_reference() {
+        let mut usage = StorageUsage::default();
+        let mut refs = BTreeMap::new();
+        let a = sized("aaa", 100);
+        apply_mapping_change(&mut usage, &mut refs, None, Some(&a));
+        apply_mapping_change(&mut usage, &mut refs, None, Some(&a));
+
+        apply_mapping_change(&mut usage, &mut refs, Some(&a), None);
+        assert_eq!(usage.logical_bytes, 100);
+        assert_eq!(usage.physical_bytes, 100);
+
+        apply_mapping_change(&mut usage, &mut refs, Some(&a), None);
+        assert_eq!(usage, StorageUsage::default());
+        assert_eq!(refs["aaa"].refs, 0);
+
+        // Removing what was never counted doesn't underflow
+        apply_mapping_change(&mut usage, &mut refs, Some(&a), None);
+        assert_eq!(usage, StorageUsage::default());
+    }
+
+    #[test]
+    fn test_apply_mapping_change_overwrite() {
+        let mut usage = StorageUsage::default();
+        let mut refs = BTreeMap::new();
+        apply_mapping_change(&mut usage, &mut refs, None, Some(&sized("aaa", 100)));
+
+        // New content swaps one blob for another
+        apply_mapping_change(
+            &mut usage,
+            &mut refs,
+            Some(&sized("aaa", 100)),
+            Some(&sized("bbb", 30)),
+        );
+        assert_eq!(usage.logical_bytes, 30);
+        assert_eq!(usage.physical_bytes, 30);
+        assert_eq!(refs["aaa"].refs, 0);
+        assert_eq!(refs["bbb"].refs, 1);
+
+        // Rewriting the same content changes nothing
+        apply_mapping_change(
+            &mut usage,
+            &mut refs,
+            Some(&sized("bbb", 30)),
+            Some(&sized("bbb", 30)),
+        );
+        assert_eq!(usage.logical_bytes, 30);
+        assert_eq!(usage.physical_bytes, 30);
+        assert_eq!(refs["bbb"].refs, 1);
+        // Overwrites keep the file count; only creates and removals change it
+        assert_eq!(usage.files, 1);
+
+        apply_mapping_change(&mut usage, &mut refs, Some(&sized("bbb", 30)), None);
+        assert_eq!(usage.files, 0);
+        assert_eq!(usage.logical_bytes, 0);
+    }
+
+    #[test]
+    fn test_apply_mapping_change_counts_variants() {
+        let mut usage = StorageUsage::default();
+        let mut refs = BTreeMap::new();
+        let plain = sized("aaa", 100);
+        apply_mapping_change(&mut usage, &mut refs, None, Some(&plain));
+
+        let mut with_br = plain.clone();
+        with_br.encodings.insert(
+            "br".to_string(),
+            EncodedVariant {
+                sha256: "bbb".to_string(),
+                size: 40,
+            },
+        );
+        apply_mapping_change(&mut usage, &mut refs, Some(&plain), Some(&with_br));
+        assert_eq!(usage.files, 1);
+        assert_eq!(usage.logical_bytes, 140);
+        assert_eq!(usage.physical_bytes, 140);
+        assert_eq!(refs["bbb"].refs, 1);
+
+        // Removing the file releases its variants too
+        apply_mapping_change(&mut usage, &mut refs, Some(&with_br), None);
+        assert_eq!(usage, StorageUsage::default());
+        assert_eq!(refs["bbb"].refs, 0);
+    }
+
+    #[test]
+    fn test_tally_usage_matches_incremental_accounting() {
+        let mut deleted = sized("ccc", 70);
+        deleted.deleted_at = Some(5000);
+        let mappings = vec![
+            sized("aaa", 100),
+            sized("aaa", 100),
+            sized("bbb", 50),
+            deleted,
+        ];
+
+        let (usage, refs) = tally_usage(&mappings);
+        assert_eq!(
+            usage,
+            StorageUsage {
+                logical_bytes: 250,
+                physical_bytes: 150,
+                files: 3
+            }
+        );
+        assert!(!refs.contains_key("ccc"));
+
+        let mut incremental = StorageUsage::default();
+        let mut incremental_refs = BTreeMap::new();
+        for m in &mappings[..3] {
+            apply_mapping_change(&mut incremental, &mut incremental_refs, None, Some(m));
+        }
+        assert_eq!(incremental, usage);
+        assert_eq!(incremental_refs, refs);
+    }
+
+    #[test]
+    fn test_mapping_keys_stay_clear_of_internal_keys() {
+        let sha = "a".repeat(64);
+        assert_ne!(mapping_key("usage:totals"), USAGE_KEY);
+        assert_ne!(
+            mapping_key(&format!("blobrefs:{}", sha)),
+            blob_refs_key(&sha)
+        );
+        assert!(mapping_key("report.pdf").starts_with(MAPPING_KEY_PREFIX));
+    }
+
+    #[test]
+    fn test_upload_named_usage_totals_keeps_quota() {
+        // Storage as the object sees it: 900 of 1000 bytes already used
+        let mut storage: BTreeMap<String, serde_json::Value> = BTreeMap::new();
+        let mut usage = StorageUsage::default();
+        let mut refs = BTreeMap::new();
+        let existing = sized("aaa", 900);
+        apply_mapping_change(&mut usage, &mut refs, None, Some(&existing));
+        storage.insert(USAGE_KEY.to_string(), serde_json::to_value(usage).unwrap());
+
+        let mut upload = sized("bbb", 50);
+        upload.filename = "usage:totals".to_string();
+        storage.insert(
+            mapping_key(&upload.filename),
+            serde_json::to_value(&upload).unwrap(),
+        );
+        apply_mapping_change(&mut usage, &mut refs, None, Some(&upload));
+        storage.insert(USAGE_KEY.to_string(), serde_json::to_value(usage).unwrap());
+
+        // The totals survive the upload, so the next write is still refused
+        let stored: StorageUsage = serde_json::from_value(storage[USAGE_KEY].clone()).unwrap();
+        assert_eq!(stored.logical_bytes, 950);
+        let after = stored.logical_bytes + 100;
+        assert!(bytes_quota_exceeded(
+            stored.logical_bytes,
+            after,
+            Some(1000)
+        ));
+
+        // And the upload is still there to download
+        let mapping: FileMapping =
+            serde_json::from_value(storage[&mapping_key("usage:totals")].clone()).unwrap();
+        assert_eq!(mapping.sha256, "bbb");
+    }
+
+    #[test]
+    fn test_is_legacy_mapping_key() {
+        let mut m = sized("aaa", 10);
+        m.filename = "usage:totals".to_string();
+        assert!(is_legacy_mapping_key("usage:totals", &m));
+        assert!(!is_legacy_mapping_key(&mapping_key("usage:totals"), &m));
+
+        // A legacy file that happens to start with the prefix still moves
+        m.filename = "file:notes.txt".to_string();
+        assert!(is_legacy_mapping_key("file:notes.txt", &m));
+        assert!(!is_legacy_mapping_key("file:file:notes.txt", &m));
+    }
+
+    #[test]
+    fn test_legacy_key_moves_keep_colliding_files() {
+        // `a` moves onto `file:a`, which is itself a legacy file waiting to move.
+        // Storage is simulated as key -> filename of the mapping stored there.
+        let legacy_file = |name: &str| {
+            let mut m = sized("aaa", 10);
+            m.filename = name.to_string();
+            (name.to_string(), m)
+        };
+        let storage: BTreeMap<String, String> = ["a", "file:a"]
+            .iter()
+            .map(|name| (name.to_string(), name.to_string()))
+            .collect();
+        let (puts, deletes) = legacy_key_moves(vec![legacy_file("a"), legacy_file("file:a")]);
+
+        let reachable = |storage: &BTreeMap<String, String>, name: &str| {
+            storage.get(name).map(String::as_str) == Some(name)
+                || storage.get(&mapping_key(name)).map(String::as_str) == Some(name)
+        };
+
+        // Stopping after any put loses nothing: each file is under its old or new key
+        for done in 0..=puts.len() {
+            let mut partial = storage.clone();
+            for (key, mapping) in &puts[..done] {
+                partial.insert(key.clone(), mapping.filename.clone());
+            }
+            assert!(reachable(&partial, "a"), "a lost after {} puts", done);
+            assert!(
+                reachable(&partial, "file:a"),
+                "file:a lost after {} puts",
+                done
+            );
+        }
+
+        let mut storage = storage;
+        for (key, mapping) in &puts {
+            storage.insert(key.clone(), mapping.filename.clone());
+        }
+        for key in &deletes {
+            storage.remove(key);
+        }
+        assert_eq!(
+            storage,
+            BTreeMap::from([
+                (mapping_key("a"), "a".to_string()),
+                (mapping_key("file:a"), "file:a".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_versions_key_stays_clear_of_mapping_keys() {
+        // A file named `versions:foo` is its own file, not foo's history
+        assert_ne!(mapping_key("versions:foo"), versions_key("foo"));
+        assert_ne!(mapping_key("foo"), versions_key("foo"));
+    }
+}
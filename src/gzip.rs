@@ -0,0 +1,104 @@
+//! Gzip compression (RFC 1952) for storing text blobs compactly
+//!
+//! Workers offer `CompressionStream`, but it isn't available in native unit
+//! tests, so this uses `flate2` with its pure-Rust backend, which builds for
+//! wasm32 as well. Any gzip member can be read back, whatever block types it uses.
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{Read, Write};
+
+/// Whether `data` starts with the gzip magic bytes
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&[0x1f, 0x8b])
+}
+
+/// Compress `data` into a single gzip member
+pub fn gzip_compress(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    // Writing to a Vec can't fail
+    encoder
+        .write_all(data)
+        .and_then(|_| encoder.finish())
+        .expect("gzip into memory")
+}
+
+/// Decompress a gzip member, checking its CRC and length
+pub fn gzip_decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+    if !is_gzip(data) {
+        return Err("not a gzip stream".to_string());
+    }
+    let mut out = Vec::new();
+    GzDecoder::new(data)
+        .read_to_end(&mut out)
+        .map_err(|e| e.to_string())?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let text = "The quick brown fox jumps over the lazy dog. ".repeat(100);
+        let long_run = vec![b'a'; 100_000];
+        let binary: Vec<u8> = (0..5000u32).map(|i| (i * 7 % 251) as u8).collect();
+
+        for data in [
+            &b""[..],
+            b"a",
+            b"abcabcabc",
+            text.as_bytes(),
+            &long_run,
+            &binary,
+        ] {
+            let compressed = gzip_compress(data);
+            assert!(is_gzip(&compressed));
+            assert_eq!(gzip_decompress(&compressed).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_compresses_repetitive_text() {
+        let text = "{\"key\": \"value\", \"count\": 1}\n".repeat(200);
+        let compressed = gzip_compress(text.as_bytes());
+        assert!(compressed.len() < text.len() / 10);
+    }
+
+    #[test]
+    fn test_decompress_stored_and_dynamic_blocks() {
+        // Produced by zlib at level 0: one stored block
+        let stored = [
+            0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 4, 3, 1, 5, 0, 0xfa, 0xff, b'h', b'e', b'l', b'l', b'o',
+            0x86, 0xa6, 0x10, 0x36, 5, 0, 0, 0,
+        ];
+        assert_eq!(gzip_decompress(&stored).unwrap(), b"hello");
+
+        // Produced by zlib with Z_HUFFMAN_ONLY: one dynamic Huffman block
+        let dynamic = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x05, 0xc1, 0x01, 0x01,
+            0x00, 0x00, 0x00, 0x80, 0x90, 0xad, 0xf9, 0x3f, 0x22, 0xaa, 0xaa, 0x0a, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x06, 0x19, 0x0a, 0x52,
+            0x66, 0x6e, 0x00, 0x00, 0x00,
+        ];
+        let expected = [vec![b'a'; 10], vec![b'b'; 100]].concat();
+        assert_eq!(gzip_decompress(&dynamic).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_decompress_rejects_corruption() {
+        assert!(gzip_decompress(b"not gzip at all, definitely").is_err());
+
+        let mut compressed = gzip_compress(b"some text worth keeping");
+        let crc_pos = compressed.len() - 8;
+        compressed[crc_pos] ^= 0xFF;
+        assert!(gzip_decompress(&compressed)
+            .unwrap_err()
+            .contains("checksum"));
+
+        let compressed = gzip_compress(b"some text worth keeping");
+        assert!(gzip_decompress(&compressed[..compressed.len() - 4]).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+//! HTML directory listings for browsing the file store
+//!
+//! `GET /files/` (or any prefix) normally lists files as JSON. A client whose
+//! `Accept` header asks for `text/html`, as browsers do, gets a page of links
+//! instead, with each file's size and last update. Filenames come from
+//! whoever uploaded them, so they're escaped before going into the page.
+//!
+//! Stored filenames are the percent-encoded paths they were uploaded to, so
+//! they're decoded for display and their escapes are kept as is in links.
+
+use crate::file_mapping_object::FileMapping;
+use crate::http_date::format_rfc3339;
+
+/// Escape `text` for use in HTML text or a quoted attribute
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Percent-encode a file key for a URL path, keeping its `/` separators
+///
+/// Keys are stored as they appeared in the URL, so `%XX` escapes already in
+/// the key are kept rather than encoded again.
+pub fn encode_path(key: &str) -> String {
+    let bytes = key.as_bytes();
+    let mut encoded = String::with_capacity(key.len());
+    for (i, &b) in bytes.iter().enumerate() {
+        let escape = b == b'%' && is_escape(&bytes[i..]);
+        if escape || b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~' | b'/') {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+/// Decode the `%XX` escapes in a stored key, for showing it to people
+///
+/// Escapes that don't decode to valid UTF-8 come out as U+FFFD.
+pub fn decode_path(key: &str) -> String {
+    let bytes = key.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if is_escape(&bytes[i..]) {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("00");
+            decoded.push(u8::from_str_radix(hex, 16).unwrap_or(0));
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Whether `bytes` starts with a `%` followed by two hex digits
+fn is_escape(bytes: &[u8]) -> bool {
+    bytes.len() >= 3 && bytes[0] == b'%' && bytes[1..3].iter().all(u8::is_ascii_hexdigit)
+}
+
+/// An HTML page listing `mappings` under `prefix`
+///
+/// Links are `base` followed by each filename, e.g. `/files/docs/a.txt`.
+pub fn listing_html(base: &str, prefix: &str, mappings: &[FileMapping]) -> String {
+    let title = escape_html(&format!("Index of /{}", decode_path(prefix)));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{title}</title></head>\n\
+         <body>\n<h1>{title}</h1>\n<table>\n\
+         <tr><th>Name</th><th>Size</th><th>Updated</th></tr>\n"
+    );
+    for mapping in mappings {
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            escape_html(&format!("{}{}", base, encode_path(&mapping.filename))),
+            escape_html(&decode_path(&mapping.filename)),
+            mapping.size,
+            format_rfc3339(mapping.updated_at)
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, HashMap};
+
+    fn mapping(filename: &str, size: usize) -> FileMapping {
+        FileMapping {
+            filename: filename.to_string(),
+            sha256: "a".repeat(64),
+            size,
+            content_type: None,
+            created_at: 0,
+            updated_at: 0,
+            metadata: HashMap::new(),
+            deleted_at: None,
+            compressed: false,
+            expires_at: None,
+            inline: None,
+            encodings: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(escape_html("plain.txt"), "plain.txt");
+        assert_eq!(
+            escape_html("<script>alert('x')</script>&\""),
+            "&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt;&amp;&quot;"
+        );
+    }
+
+    #[test]
+    fn test_encode_path() {
+        assert_eq!(encode_path("docs/a-b_c.txt"), "docs/a-b_c.txt");
+        assert_eq!(encode_path("my file?#.txt"), "my%20file%3F%23.txt");
+        assert_eq!(encode_path("café"), "caf%C3%A9");
+
+        // Escapes from the upload URL are kept; a stray `%` is still encoded
+        assert_eq!(encode_path("b%20c.txt"), "b%20c.txt");
+        assert_eq!(encode_path("caf%C3%A9"), "caf%C3%A9");
+        assert_eq!(encode_path("100%.txt"), "100%25.txt");
+        assert_eq!(encode_path("%zz"), "%25zz");
+    }
+
+    #[test]
+    fn test_decode_path() {
+        assert_eq!(decode_path("docs/b%20c.txt"), "docs/b c.txt");
+        assert_eq!(decode_path("caf%C3%A9"), "café");
+        assert_eq!(decode_path("100%.txt"), "100%.txt");
+        assert_eq!(decode_path("%FF"), "\u{FFFD}");
+    }
+
+    #[test]
+    fn test_listing_html() {
+        let html = listing_html(
+            "/files/",
+            "docs/",
+            &[mapping("docs/a.txt", 12), mapping("docs/b%20c.txt", 3)],
+        );
+        assert!(html.contains("<title>Index of /docs/</title>"));
+        assert!(html.contains(
+            "<tr><td><a href=\"/files/docs/a.txt\">docs/a.txt</a></td><td>12</td>\
+             <td>1970-01-01T00:00:00.000Z</td></tr>"
+        ));
+        assert!(html.contains("<a href=\"/files/docs/b%20c.txt\">docs/b c.txt</a>"));
+        assert!(!html.contains("%2520"));
+    }
+
+    #[test]
+    fn test_listing_html_escapes_filenames() {
+        let html = listing_html(
+            "/files/",
+            "",
+            &[mapping("%3Cimg%20src=x%20onerror=alert(1)%3E%22", 1)],
+        );
+        assert!(!html.contains("<img"));
+        assert!(html.contains(">&lt;img src=x onerror=alert(1)&gt;&quot;</a>"));
+        assert!(html.contains("href=\"/files/%3Cimg%20src%3Dx%20onerror%3Dalert%281%29%3E%22\""));
+    }
+}
@@ -0,0 +1,291 @@
+//! HTTP date formatting and parsing (RFC 7231 IMF-fixdate), plus RFC 3339
+//! timestamps for JSON responses
+//!
+//! `js_sys::Date` can produce these strings, but it isn't available in native
+//! unit tests, so the conversion is done here with plain integer arithmetic.
+
+/// Media type clients can `Accept` to get ISO-8601 timestamps in JSON responses
+pub const ISO_TIMESTAMPS_MEDIA_TYPE: &str = "application/json+iso";
+/// JSON fields holding epoch-millisecond timestamps
+const TIMESTAMP_FIELDS: [&str; 6] = [
+    "uploaded_at",
+    "created_at",
+    "updated_at",
+    "deleted_at",
+    "last_updated",
+    "timestamp",
+];
+
+const DAY_NAMES: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTH_NAMES: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Format a Unix timestamp in milliseconds as an HTTP date
+///
+/// Example: `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn format_http_date(timestamp_ms: u64) -> String {
+    let secs = timestamp_ms / 1000;
+    let days = secs / 86_400;
+    let secs_of_day = secs % 86_400;
+
+    let (year, month, day) = civil_from_days(days as i64);
+    // 1970-01-01 was a Thursday, which is the first entry of DAY_NAMES
+    let weekday = DAY_NAMES[(days % 7) as usize];
+
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        weekday,
+        day,
+        MONTH_NAMES[(month - 1) as usize],
+        year,
+        secs_of_day / 3600,
+        (secs_of_day % 3600) / 60,
+        secs_of_day % 60
+    )
+}
+
+/// Format a Unix timestamp in milliseconds as an RFC 3339 UTC timestamp
+///
+/// Example: `1994-11-06T08:49:37.000Z`
+pub fn format_rfc3339(timestamp_ms: u64) -> String {
+    let secs = timestamp_ms / 1000;
+    let days = secs / 86_400;
+    let secs_of_day = secs % 86_400;
+    let (year, month, day) = civil_from_days(days as i64);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        (secs_of_day % 3600) / 60,
+        secs_of_day % 60,
+        timestamp_ms % 1000
+    )
+}
+
+/// Whether a client asked for ISO-8601 timestamps, via `?pretty` or `Accept`
+pub fn wants_iso_timestamps(pretty: bool, accept: Option<&str>) -> bool {
+    pretty
+        || accept.is_some_and(|accept| {
+            accept.split(',').any(|media| {
+                media
+                    .split(';')
+                    .next()
+                    .is_some_and(|m| m.trim().eq_ignore_ascii_case(ISO_TIMESTAMPS_MEDIA_TYPE))
+            })
+        })
+}
+
+/// Add an `{field}_iso` string next to every numeric timestamp field
+///
+/// Walks nested objects and arrays, so lists of mappings are covered too. The
+/// numeric fields are left as they are.
+pub fn add_iso_timestamps(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(object) => {
+            let additions: Vec<(String, String)> = TIMESTAMP_FIELDS
+                .iter()
+                .filter_map(|field| {
+                    let ms = object.get(*field)?.as_u64()?;
+                    Some((format!("{}_iso", field), format_rfc3339(ms)))
+                })
+                .collect();
+            for child in object.values_mut() {
+                add_iso_timestamps(child);
+            }
+            for (field, iso) in additions {
+                object.insert(field, serde_json::Value::String(iso));
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(add_iso_timestamps),
+        _ => {}
+    }
+}
+
+/// Whether `req` asked for ISO-8601 timestamps
+pub fn request_wants_iso_timestamps(req: &worker::Request) -> bool {
+    let pretty = req
+        .url()
+        .map(|url| url.query_pairs().any(|(k, _)| k == "pretty"))
+        .unwrap_or(false);
+    let accept = req.headers().get("Accept").ok().flatten();
+    wants_iso_timestamps(pretty, accept.as_deref())
+}
+
+/// JSON response for `value`, with `*_iso` timestamps added when `iso` is set
+pub fn timestamped_json<T: serde::Serialize>(
+    value: &T,
+    iso: bool,
+) -> worker::Result<worker::Response> {
+    if !iso {
+        return worker::Response::from_json(value);
+    }
+    let mut json = serde_json::to_value(value)?;
+    add_iso_timestamps(&mut json);
+    worker::Response::from_json(&json)
+}
+
+/// Parse an HTTP date into a Unix timestamp in milliseconds
+///
+/// Only the IMF-fixdate form is accepted. Returns `None` for malformed input
+/// or dates before the Unix epoch.
+pub fn parse_http_date(value: &str) -> Option<u64> {
+    // "Sun, 06 Nov 1994 08:49:37 GMT"
+    let (_, rest) = value.trim().split_once(", ")?;
+    let parts: Vec<&str> = rest.split(' ').collect();
+    if parts.len() != 5 || parts[4] != "GMT" {
+        return None;
+    }
+
+    let day: u32 = parts[0].parse().ok()?;
+    let month = MONTH_NAMES.iter().position(|m| *m == parts[1])? as u32 + 1;
+    let year: i64 = parts[2].parse().ok()?;
+
+    let time: Vec<&str> = parts[3].split(':').collect();
+    if time.len() != 3 {
+        return None;
+    }
+    let hour: u64 = time[0].parse().ok()?;
+    let minute: u64 = time[1].parse().ok()?;
+    let second: u64 = time[2].parse().ok()?;
+
+    if !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    if days < 0 {
+        return None;
+    }
+
+    let secs = days as u64 * 86_400 + hour * 3600 + minute * 60 + second;
+    Some(secs * 1000)
+}
+
+/// Convert days since the Unix epoch to a (year, month, day) civil date
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Howard Hinnant's algorithm: http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Convert a (year, month, day) civil date to days since the Unix epoch
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_http_date() {
+        assert_eq!(format_http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(
+            format_http_date(784_111_777_000),
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+        // Milliseconds are truncated
+        assert_eq!(
+            format_http_date(1_709_210_096_999),
+            "Thu, 29 Feb 2024 12:34:56 GMT"
+        );
+    }
+
+    #[test]
+    fn test_parse_http_date() {
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(784_111_777_000)
+        );
+        assert_eq!(
+            parse_http_date("Thu, 29 Feb 2024 12:34:56 GMT"),
+            Some(1_709_210_096_000)
+        );
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"), Some(0));
+    }
+
+    #[test]
+    fn test_parse_http_date_invalid() {
+        assert_eq!(parse_http_date(""), None);
+        assert_eq!(parse_http_date("not a date"), None);
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 25:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 PST"), None);
+        assert_eq!(parse_http_date("Wed, 31 Dec 1969 23:59:59 GMT"), None);
+    }
+
+    #[test]
+    fn test_format_rfc3339() {
+        assert_eq!(format_rfc3339(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(format_rfc3339(784_111_777_000), "1994-11-06T08:49:37.000Z");
+        assert_eq!(
+            format_rfc3339(1_709_210_096_999),
+            "2024-02-29T12:34:56.999Z"
+        );
+    }
+
+    #[test]
+    fn test_wants_iso_timestamps() {
+        assert!(wants_iso_timestamps(true, None));
+        assert!(wants_iso_timestamps(false, Some("application/json+iso")));
+        assert!(wants_iso_timestamps(
+            false,
+            Some("text/html, Application/JSON+ISO;q=0.9")
+        ));
+
+        assert!(!wants_iso_timestamps(false, None));
+        assert!(!wants_iso_timestamps(false, Some("application/json")));
+    }
+
+    #[test]
+    fn test_add_iso_timestamps() {
+        let mut value = serde_json::json!({
+            "count": 3,
+            "last_updated": 784_111_777_000u64,
+            "versions": [{"version": 1, "created_at": 0}],
+            "timestamp": "not a number"
+        });
+        add_iso_timestamps(&mut value);
+
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "count": 3,
+                "last_updated": 784_111_777_000u64,
+                "last_updated_iso": "1994-11-06T08:49:37.000Z",
+                "versions": [{
+                    "version": 1,
+                    "created_at": 0,
+                    "created_at_iso": "1970-01-01T00:00:00.000Z"
+                }],
+                "timestamp": "not a number"
+            })
+        );
+    }
+
+    #[test]
+    fn test_http_date_round_trip() {
+        for ts in [0, 951_782_400_000, 1_234_567_890_000, 4_102_444_800_000] {
+            assert_eq!(parse_http_date(&format_http_date(ts)), Some(ts));
+        }
+    }
+}
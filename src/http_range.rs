@@ -0,0 +1,189 @@
+//! HTTP `Range` request parsing (RFC 9110, section 14)
+//!
+//! Only single byte ranges are supported. A header asking for several ranges,
+//! or one that can't be parsed, is ignored and the full content is served,
+//! which the spec allows.
+
+/// An inclusive range of byte offsets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    /// Number of bytes in the range
+    pub fn length(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// `Content-Range` value for this range of a `size`-byte representation
+    pub fn content_range(&self, size: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, size)
+    }
+}
+
+/// How to answer a request, given its `Range` header
+#[derive(Debug, PartialEq)]
+pub enum RangeRequest {
+    /// No usable range: send everything with 200
+    Full,
+    /// Send part of the content with 206
+    Partial(ByteRange),
+    /// The range lies outside the content: send 416
+    Unsatisfiable,
+}
+
+/// `Content-Range` value for a 416 response
+pub fn unsatisfiable_content_range(size: u64) -> String {
+    format!("bytes */{}", size)
+}
+
+/// `Accept-Ranges` value for stored content, so clients know whether to seek
+///
+/// Compressed blobs are always sent whole, since offsets into the stored
+/// bytes aren't offsets into the content, so they advertise `none`.
+pub fn accept_ranges(compressed: bool) -> &'static str {
+    if compressed {
+        "none"
+    } else {
+        "bytes"
+    }
+}
+
+/// Interpret a `Range` header against content of `size` bytes
+///
+/// Handles `bytes=start-end`, `bytes=start-` and the suffix form `bytes=-n`.
+/// An end past the last byte is clamped to it.
+pub fn parse_range(header: Option<&str>, size: u64) -> RangeRequest {
+    let spec = match header.and_then(|h| h.trim().strip_prefix("bytes=")) {
+        Some(spec) => spec.trim(),
+        None => return RangeRequest::Full,
+    };
+    if spec.contains(',') {
+        return RangeRequest::Full;
+    }
+    let (first, last) = match spec.split_once('-') {
+        Some(parts) => parts,
+        None => return RangeRequest::Full,
+    };
+    let (first, last) = (first.trim(), last.trim());
+
+    if first.is_empty() {
+        // Suffix range: the last `n` bytes
+        let suffix: u64 = match parse_offset(last) {
+            Some(n) => n,
+            None => return RangeRequest::Full,
+        };
+        if suffix == 0 || size == 0 {
+            return RangeRequest::Unsatisfiable;
+        }
+        return RangeRequest::Partial(ByteRange {
+            start: size.saturating_sub(suffix),
+            end: size - 1,
+        });
+    }
+
+    let start = match parse_offset(first) {
+        Some(s) => s,
+        None => return RangeRequest::Full,
+    };
+    let end = if last.is_empty() {
+        None
+    } else {
+        match parse_offset(last) {
+            Some(e) if e >= start => Some(e),
+            _ => return RangeRequest::Full,
+        }
+    };
+
+    if start >= size {
+        return RangeRequest::Unsatisfiable;
+    }
+    RangeRequest::Partial(ByteRange {
+        start,
+        end: end.map_or(size - 1, |e| e.min(size - 1)),
+    })
+}
+
+fn parse_offset(value: &str) -> Option<u64> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    value.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(start: u64, end: u64) -> RangeRequest {
+        RangeRequest::Partial(ByteRange { start, end })
+    }
+
+    #[test]
+    fn test_parse_range_forms() {
+        assert_eq!(parse_range(Some("bytes=0-99"), 1000), partial(0, 99));
+        assert_eq!(parse_range(Some("bytes=500-"), 1000), partial(500, 999));
+        assert_eq!(parse_range(Some("bytes=-100"), 1000), partial(900, 999));
+        assert_eq!(parse_range(Some(" bytes= 10 - 20 "), 1000), partial(10, 20));
+    }
+
+    #[test]
+    fn test_parse_range_clamps() {
+        // End past the last byte is clamped
+        assert_eq!(parse_range(Some("bytes=900-5000"), 1000), partial(900, 999));
+        // Suffix longer than the content covers all of it
+        assert_eq!(parse_range(Some("bytes=-5000"), 1000), partial(0, 999));
+    }
+
+    #[test]
+    fn test_parse_range_unsatisfiable() {
+        assert_eq!(
+            parse_range(Some("bytes=1000-"), 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(
+            parse_range(Some("bytes=-0"), 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(
+            parse_range(Some("bytes=0-"), 0),
+            RangeRequest::Unsatisfiable
+        );
+    }
+
+    #[test]
+    fn test_parse_range_ignored() {
+        for header in [
+            None,
+            Some("items=0-10"),
+            Some("bytes=0-10,20-30"),
+            Some("bytes=abc"),
+            Some("bytes=20-10"),
+            Some("bytes=-"),
+            Some("bytes=+1-2"),
+        ] {
+            assert_eq!(
+                parse_range(header, 1000),
+                RangeRequest::Full,
+                "{:?}",
+                header
+            );
+        }
+    }
+
+    #[test]
+    fn test_content_range() {
+        let range = ByteRange { start: 0, end: 99 };
+        assert_eq!(range.length(), 100);
+        assert_eq!(range.content_range(1000), "bytes 0-99/1000");
+        assert_eq!(unsatisfiable_content_range(1000), "bytes */1000");
+    }
+
+    #[test]
+    fn test_accept_ranges() {
+        assert_eq!(accept_ranges(false), "bytes");
+        assert_eq!(accept_ranges(true), "none");
+    }
+}
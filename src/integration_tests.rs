@@ -63,7 +63,7 @@ mod integration_tests {
         assert_eq!(count, 5);
 
         // Session update ordering
-        let mut timestamps = vec![100, 200, 150, 300, 250];
+        let mut timestamps = [100, 200, 150, 300, 250];
         timestamps.sort();
         assert_eq!(timestamps.last(), Some(&300));
     }
@@ -74,7 +74,7 @@ mod integration_tests {
 
         // 1. Validate counter bounds
         let valid_count = 42;
-        assert!(valid_count >= i32::MIN && valid_count <= i32::MAX);
+        assert!((i32::MIN..=i32::MAX).contains(&valid_count));
 
         // 2. Validate timestamps
         let timestamp = 1234567890u64;
@@ -145,12 +145,13 @@ mod integration_tests {
     }
 
     #[test]
+    #[allow(clippy::unnecessary_literal_unwrap)]
     fn test_memory_safety() {
         // Test memory safety scenarios
 
         // 1. Vector bounds
-        let vec = vec![1, 2, 3];
-        assert_eq!(vec.get(0), Some(&1));
+        let vec = [1, 2, 3];
+        assert_eq!(vec.first(), Some(&1));
         assert_eq!(vec.get(10), None);
 
         // 2. String slicing
@@ -163,6 +164,6 @@ mod integration_tests {
 
         // 4. Result handling
         let result: Result<i32, &str> = Err("error");
-        assert_eq!(result.unwrap_or_else(|_| 0), 0);
+        assert_eq!(result.unwrap_or(0), 0);
     }
 }
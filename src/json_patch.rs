@@ -0,0 +1,426 @@
+//! JSON Patch (RFC 6902) over `serde_json::Value`
+//!
+//! Patches apply atomically: operations run against a copy of the document,
+//! and the copy is only returned once every operation has succeeded.
+
+use serde::Deserialize;
+use serde_json::Value;
+use std::fmt;
+
+/// A single JSON Patch operation
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOperation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+/// Why a patch couldn't be applied
+#[derive(Debug, PartialEq)]
+pub enum PatchError {
+    /// A `test` operation didn't match the document
+    TestFailed(String),
+    /// An operation was malformed or pointed somewhere it couldn't apply
+    Invalid(String),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchError::TestFailed(msg) | PatchError::Invalid(msg) => f.write_str(msg),
+        }
+    }
+}
+
+/// Apply `operations` in order, returning the patched document
+pub fn apply_patch(doc: &Value, operations: &[PatchOperation]) -> Result<Value, PatchError> {
+    let mut doc = doc.clone();
+    for (i, operation) in operations.iter().enumerate() {
+        apply_operation(&mut doc, operation).map_err(|e| match e {
+            PatchError::TestFailed(msg) => {
+                PatchError::TestFailed(format!("operation {}: {}", i, msg))
+            }
+            PatchError::Invalid(msg) => PatchError::Invalid(format!("operation {}: {}", i, msg)),
+        })?;
+    }
+    Ok(doc)
+}
+
+fn apply_operation(doc: &mut Value, operation: &PatchOperation) -> Result<(), PatchError> {
+    match operation {
+        PatchOperation::Add { path, value } => add(doc, &parse_pointer(path)?, value.clone()),
+        PatchOperation::Remove { path } => remove(doc, &parse_pointer(path)?).map(|_| ()),
+        PatchOperation::Replace { path, value } => {
+            let target = get_mut(doc, &parse_pointer(path)?)?;
+            *target = value.clone();
+            Ok(())
+        }
+        PatchOperation::Move { from, path } => {
+            let from_tokens = parse_pointer(from)?;
+            let path_tokens = parse_pointer(path)?;
+            if path_tokens.len() > from_tokens.len() && path_tokens.starts_with(&from_tokens) {
+                return Err(invalid(format!(
+                    "cannot move {} into its own child {}",
+                    from, path
+                )));
+            }
+            let value = remove(doc, &from_tokens)?;
+            add(doc, &path_tokens, value)
+        }
+        PatchOperation::Copy { from, path } => {
+            let value = get_mut(doc, &parse_pointer(from)?)?.clone();
+            add(doc, &parse_pointer(path)?, value)
+        }
+        PatchOperation::Test { path, value } => {
+            let actual = get_mut(doc, &parse_pointer(path)?)?;
+            if actual != value {
+                return Err(PatchError::TestFailed(format!(
+                    "test failed at {}",
+                    display_path(path)
+                )));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Split a JSON Pointer (RFC 6901) into unescaped reference tokens
+pub fn parse_pointer(pointer: &str) -> Result<Vec<String>, PatchError> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    let rest = pointer
+        .strip_prefix('/')
+        .ok_or_else(|| invalid(format!("invalid pointer '{}'", pointer)))?;
+
+    rest.split('/')
+        .map(|token| {
+            // `~1` must be decoded before `~0` so `~01` becomes `~1`, not `/`
+            let mut decoded = String::with_capacity(token.len());
+            let mut chars = token.chars();
+            while let Some(c) = chars.next() {
+                if c == '~' {
+                    match chars.next() {
+                        Some('0') => decoded.push('~'),
+                        Some('1') => decoded.push('/'),
+                        _ => return Err(invalid(format!("invalid escape in '{}'", pointer))),
+                    }
+                } else {
+                    decoded.push(c);
+                }
+            }
+            Ok(decoded)
+        })
+        .collect()
+}
+
+fn invalid(msg: String) -> PatchError {
+    PatchError::Invalid(msg)
+}
+
+fn display_path(path: &str) -> &str {
+    if path.is_empty() {
+        "the document root"
+    } else {
+        path
+    }
+}
+
+/// Parse an array index token, which must be a plain non-negative integer
+fn parse_index(token: &str, len: usize, allow_end: bool) -> Result<usize, PatchError> {
+    if token == "-" && allow_end {
+        return Ok(len);
+    }
+    let valid = !token.is_empty()
+        && token.bytes().all(|b| b.is_ascii_digit())
+        && (token == "0" || !token.starts_with('0'));
+    let index: usize = if valid {
+        token
+            .parse()
+            .map_err(|_| invalid(format!("invalid array index '{}'", token)))?
+    } else {
+        return Err(invalid(format!("invalid array index '{}'", token)));
+    };
+
+    let max = if allow_end {
+        len
+    } else {
+        len.saturating_sub(1)
+    };
+    if index > max || (!allow_end && len == 0) {
+        return Err(invalid(format!(
+            "array index {} out of bounds for length {}",
+            index, len
+        )));
+    }
+    Ok(index)
+}
+
+fn get_mut<'a>(doc: &'a mut Value, tokens: &[String]) -> Result<&'a mut Value, PatchError> {
+    let mut current = doc;
+    for token in tokens {
+        current = match current {
+            Value::Object(map) => map
+                .get_mut(token)
+                .ok_or_else(|| invalid(format!("path not found: '{}'", token)))?,
+            Value::Array(items) => {
+                let index = parse_index(token, items.len(), false)?;
+                &mut items[index]
+            }
+            _ => {
+                return Err(invalid(format!(
+                    "cannot index into a scalar with '{}'",
+                    token
+                )))
+            }
+        };
+    }
+    Ok(current)
+}
+
+/// Split tokens into the parent path and the final token
+fn split_last(tokens: &[String]) -> Option<(&[String], &str)> {
+    tokens
+        .split_last()
+        .map(|(last, parent)| (parent, last.as_str()))
+}
+
+fn add(doc: &mut Value, tokens: &[String], value: Value) -> Result<(), PatchError> {
+    let (parent, last) = match split_last(tokens) {
+        Some(split) => split,
+        None => {
+            // Adding at the root replaces the whole document
+            *doc = value;
+            return Ok(());
+        }
+    };
+
+    match get_mut(doc, parent)? {
+        Value::Object(map) => {
+            map.insert(last.to_string(), value);
+            Ok(())
+        }
+        Value::Array(items) => {
+            let index = parse_index(last, items.len(), true)?;
+            items.insert(index, value);
+            Ok(())
+        }
+        _ => Err(invalid(format!("cannot add '{}' to a scalar", last))),
+    }
+}
+
+fn remove(doc: &mut Value, tokens: &[String]) -> Result<Value, PatchError> {
+    let (parent, last) =
+        split_last(tokens).ok_or_else(|| invalid("cannot remove the document root".to_string()))?;
+
+    match get_mut(doc, parent)? {
+        Value::Object(map) => map
+            .remove(last)
+            .ok_or_else(|| invalid(format!("path not found: '{}'", last))),
+        Value::Array(items) => {
+            let index = parse_index(last, items.len(), false)?;
+            Ok(items.remove(index))
+        }
+        _ => Err(invalid(format!("cannot remove '{}' from a scalar", last))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn ops(value: Value) -> Vec<PatchOperation> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_parse_pointer() {
+        assert_eq!(parse_pointer("").unwrap(), Vec::<String>::new());
+        assert_eq!(parse_pointer("/a/b").unwrap(), vec!["a", "b"]);
+        assert_eq!(parse_pointer("/").unwrap(), vec![""]);
+        assert_eq!(parse_pointer("/a~1b/m~0n").unwrap(), vec!["a/b", "m~n"]);
+        // ~01 decodes to ~1, not /
+        assert_eq!(parse_pointer("/~01").unwrap(), vec!["~1"]);
+
+        assert!(parse_pointer("a/b").is_err());
+        assert!(parse_pointer("/a~2").is_err());
+        assert!(parse_pointer("/a~").is_err());
+    }
+
+    #[test]
+    fn test_parse_operations() {
+        let parsed = ops(json!([
+            {"op": "add", "path": "/a", "value": 1},
+            {"op": "move", "from": "/a", "path": "/b"}
+        ]));
+        assert_eq!(
+            parsed[1],
+            PatchOperation::Move {
+                from: "/a".to_string(),
+                path: "/b".to_string()
+            }
+        );
+
+        assert!(serde_json::from_value::<Vec<PatchOperation>>(
+            json!([{"op": "frobnicate", "path": "/a"}])
+        )
+        .is_err());
+        assert!(serde_json::from_value::<Vec<PatchOperation>>(
+            json!([{"op": "add", "path": "/a"}])
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_add() {
+        let doc = json!({"a": {"b": 1}, "list": [1, 3]});
+        let patched = apply_patch(
+            &doc,
+            &ops(json!([
+                {"op": "add", "path": "/a/c", "value": 2},
+                {"op": "add", "path": "/list/1", "value": 2},
+                {"op": "add", "path": "/list/-", "value": 4},
+                {"op": "add", "path": "/a/b", "value": "replaced"}
+            ])),
+        )
+        .unwrap();
+        assert_eq!(
+            patched,
+            json!({"a": {"b": "replaced", "c": 2}, "list": [1, 2, 3, 4]})
+        );
+
+        // Adding at the root replaces the document
+        let patched = apply_patch(&doc, &ops(json!([{"op": "add", "path": "", "value": [1]}])));
+        assert_eq!(patched.unwrap(), json!([1]));
+    }
+
+    #[test]
+    fn test_remove_and_replace() {
+        let doc = json!({"a": 1, "b": 2, "list": ["x", "y", "z"]});
+        let patched = apply_patch(
+            &doc,
+            &ops(json!([
+                {"op": "remove", "path": "/a"},
+                {"op": "remove", "path": "/list/0"},
+                {"op": "replace", "path": "/b", "value": {"nested": true}},
+                {"op": "replace", "path": "/list/1", "value": "Z"}
+            ])),
+        )
+        .unwrap();
+        assert_eq!(patched, json!({"b": {"nested": true}, "list": ["y", "Z"]}));
+
+        assert!(apply_patch(&doc, &ops(json!([{"op": "remove", "path": "/missing"}]))).is_err());
+        assert!(apply_patch(
+            &doc,
+            &ops(json!([{"op": "replace", "path": "/missing", "value": 1}]))
+        )
+        .is_err());
+        assert!(apply_patch(&doc, &ops(json!([{"op": "remove", "path": ""}]))).is_err());
+    }
+
+    #[test]
+    fn test_move_and_copy() {
+        let doc = json!({"a": {"b": 1}, "list": [1, 2]});
+        let patched = apply_patch(
+            &doc,
+            &ops(json!([
+                {"op": "copy", "from": "/a", "path": "/c"},
+                {"op": "move", "from": "/a/b", "path": "/d"},
+                {"op": "move", "from": "/list/0", "path": "/list/-"}
+            ])),
+        )
+        .unwrap();
+        assert_eq!(
+            patched,
+            json!({"a": {}, "c": {"b": 1}, "d": 1, "list": [2, 1]})
+        );
+
+        // A value can't be moved inside itself
+        let result = apply_patch(
+            &doc,
+            &ops(json!([{"op": "move", "from": "/a", "path": "/a/b/c"}])),
+        );
+        assert!(matches!(result, Err(PatchError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_test_operation_is_atomic() {
+        let doc = json!({"version": 1, "name": "a"});
+        let result = apply_patch(
+            &doc,
+            &ops(json!([
+                {"op": "replace", "path": "/name", "value": "b"},
+                {"op": "test", "path": "/version", "value": 2},
+                {"op": "replace", "path": "/version", "value": 3}
+            ])),
+        );
+        assert_eq!(
+            result,
+            Err(PatchError::TestFailed(
+                "operation 1: test failed at /version".to_string()
+            ))
+        );
+        // The original document is untouched
+        assert_eq!(doc, json!({"version": 1, "name": "a"}));
+
+        let patched = apply_patch(
+            &doc,
+            &ops(json!([
+                {"op": "test", "path": "/version", "value": 1},
+                {"op": "replace", "path": "/version", "value": 2}
+            ])),
+        );
+        assert_eq!(patched.unwrap(), json!({"version": 2, "name": "a"}));
+    }
+
+    #[test]
+    fn test_array_index_errors() {
+        let doc = json!({"list": [1, 2, 3], "empty": []});
+        let cases = [
+            json!([{"op": "add", "path": "/list/4", "value": 0}]),
+            json!([{"op": "remove", "path": "/list/3"}]),
+            json!([{"op": "remove", "path": "/list/-"}]),
+            json!([{"op": "replace", "path": "/empty/0", "value": 0}]),
+            json!([{"op": "add", "path": "/list/01", "value": 0}]),
+            json!([{"op": "add", "path": "/list/-1", "value": 0}]),
+            json!([{"op": "add", "path": "/list/x", "value": 0}]),
+            json!([{"op": "test", "path": "/list/99", "value": 0}]),
+        ];
+
+        for case in cases {
+            let result = apply_patch(&doc, &ops(case.clone()));
+            assert!(
+                matches!(result, Err(PatchError::Invalid(_))),
+                "expected error for {}",
+                case
+            );
+        }
+
+        // Appending at the exact length is allowed
+        let patched = apply_patch(
+            &doc,
+            &ops(json!([{"op": "add", "path": "/list/3", "value": 4}])),
+        );
+        assert_eq!(patched.unwrap()["list"], json!([1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn test_scalar_parent_errors() {
+        let doc = json!({"a": 1});
+        assert!(apply_patch(
+            &doc,
+            &ops(json!([{"op": "add", "path": "/a/b", "value": 2}]))
+        )
+        .is_err());
+        assert!(apply_patch(
+            &doc,
+            &ops(json!([{"op": "test", "path": "/a/b", "value": 2}]))
+        )
+        .is_err());
+    }
+}
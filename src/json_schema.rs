@@ -0,0 +1,227 @@
+//! Minimal JSON Schema validation for session data
+//!
+//! Only a small subset of the spec is supported: `type`, `required`, `enum`,
+//! `properties` and `items`. Unknown keywords are ignored, so a schema written
+//! for a fuller validator still checks the parts that are understood here.
+
+use serde_json::Value;
+
+/// Check that a schema only uses supported keywords with well-formed values
+pub fn check_schema(schema: &Value) -> Result<(), String> {
+    let schema = schema
+        .as_object()
+        .ok_or_else(|| "schema must be an object".to_string())?;
+
+    if let Some(ty) = schema.get("type") {
+        let valid = match ty {
+            Value::String(name) => is_known_type(name),
+            Value::Array(names) => names.iter().all(|n| n.as_str().is_some_and(is_known_type)),
+            _ => false,
+        };
+        if !valid {
+            return Err(format!("invalid type: {}", ty));
+        }
+    }
+
+    if let Some(required) = schema.get("required") {
+        let valid = required
+            .as_array()
+            .is_some_and(|names| names.iter().all(Value::is_string));
+        if !valid {
+            return Err("required must be an array of strings".to_string());
+        }
+    }
+
+    if let Some(allowed) = schema.get("enum") {
+        if !allowed.is_array() {
+            return Err("enum must be an array".to_string());
+        }
+    }
+
+    if let Some(properties) = schema.get("properties") {
+        let properties = properties
+            .as_object()
+            .ok_or_else(|| "properties must be an object".to_string())?;
+        for (name, subschema) in properties {
+            check_schema(subschema).map_err(|e| format!("properties.{}: {}", name, e))?;
+        }
+    }
+
+    if let Some(items) = schema.get("items") {
+        check_schema(items).map_err(|e| format!("items: {}", e))?;
+    }
+
+    Ok(())
+}
+
+/// Validate a value against a schema, returning every violation found
+///
+/// Each error is prefixed with the path to the offending value, e.g.
+/// `$.preferences.theme: expected string`.
+pub fn validate(schema: &Value, value: &Value) -> Vec<String> {
+    let mut errors = Vec::new();
+    validate_at(schema, value, "$", &mut errors);
+    errors
+}
+
+fn validate_at(schema: &Value, value: &Value, path: &str, errors: &mut Vec<String>) {
+    let schema = match schema.as_object() {
+        Some(s) => s,
+        None => return,
+    };
+
+    if let Some(ty) = schema.get("type") {
+        let types: Vec<&str> = match ty {
+            Value::String(name) => vec![name.as_str()],
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => vec![],
+        };
+        if !types.iter().any(|t| matches_type(t, value)) {
+            errors.push(format!("{}: expected {}", path, types.join(" or ")));
+            // Other keywords assume the type matched
+            return;
+        }
+    }
+
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+        if !allowed.contains(value) {
+            errors.push(format!("{}: value is not one of the allowed values", path));
+        }
+    }
+
+    if let Some(object) = value.as_object() {
+        if let Some(required) = schema.get("required").and_then(Value::as_array) {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(name) {
+                    errors.push(format!("{}: missing required property '{}'", path, name));
+                }
+            }
+        }
+
+        if let Some(properties) = schema.get("properties").and_then(Value::as_object) {
+            for (name, subschema) in properties {
+                if let Some(child) = object.get(name) {
+                    validate_at(subschema, child, &format!("{}.{}", path, name), errors);
+                }
+            }
+        }
+    }
+
+    if let (Some(items), Some(array)) = (schema.get("items"), value.as_array()) {
+        for (i, item) in array.iter().enumerate() {
+            validate_at(items, item, &format!("{}[{}]", path, i), errors);
+        }
+    }
+}
+
+fn is_known_type(name: &str) -> bool {
+    matches!(
+        name,
+        "null" | "boolean" | "integer" | "number" | "string" | "array" | "object"
+    )
+}
+
+fn matches_type(name: &str, value: &Value) -> bool {
+    match name {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "string" => value.is_string(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_check_schema() {
+        assert!(check_schema(&json!({})).is_ok());
+        assert!(check_schema(&json!({
+            "type": "object",
+            "required": ["theme"],
+            "properties": {
+                "theme": {"type": "string", "enum": ["light", "dark"]},
+                "tags": {"type": "array", "items": {"type": "string"}}
+            }
+        }))
+        .is_ok());
+        assert!(check_schema(&json!({"type": ["string", "null"]})).is_ok());
+
+        assert!(check_schema(&json!("object")).is_err());
+        assert!(check_schema(&json!({"type": "widget"})).is_err());
+        assert!(check_schema(&json!({"required": "theme"})).is_err());
+        assert!(check_schema(&json!({"enum": "dark"})).is_err());
+        assert_eq!(
+            check_schema(&json!({"properties": {"theme": {"type": 1}}})),
+            Err("properties.theme: invalid type: 1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_validate_types() {
+        assert!(validate(&json!({"type": "integer"}), &json!(5)).is_empty());
+        assert!(validate(&json!({"type": "number"}), &json!(5.5)).is_empty());
+        assert!(validate(&json!({"type": ["string", "null"]}), &json!(null)).is_empty());
+
+        assert_eq!(
+            validate(&json!({"type": "integer"}), &json!(5.5)),
+            vec!["$: expected integer"]
+        );
+        assert_eq!(
+            validate(&json!({"type": ["string", "null"]}), &json!(true)),
+            vec!["$: expected string or null"]
+        );
+    }
+
+    #[test]
+    fn test_validate_required_and_enum() {
+        let schema = json!({
+            "type": "object",
+            "required": ["theme", "language"],
+            "properties": {
+                "theme": {"enum": ["light", "dark"]}
+            }
+        });
+
+        assert!(validate(&schema, &json!({"theme": "dark", "language": "en"})).is_empty());
+        assert_eq!(
+            validate(&schema, &json!({"theme": "blue"})),
+            vec![
+                "$: missing required property 'language'",
+                "$.theme: value is not one of the allowed values"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_nested() {
+        let schema = json!({
+            "properties": {
+                "preferences": {
+                    "type": "object",
+                    "properties": {"theme": {"type": "string"}}
+                },
+                "tags": {"type": "array", "items": {"type": "string"}}
+            }
+        });
+
+        assert!(validate(&schema, &json!({})).is_empty());
+        assert_eq!(
+            validate(
+                &schema,
+                &json!({"preferences": {"theme": 1}, "tags": ["a", 2, "c", false]})
+            ),
+            vec![
+                "$.preferences.theme: expected string",
+                "$.tags[1]: expected string",
+                "$.tags[3]: expected string"
+            ]
+        );
+    }
+}
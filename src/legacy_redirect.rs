@@ -0,0 +1,113 @@
+//! Redirects from legacy path shapes
+//!
+//! When routes move, `LEGACY_REDIRECTS` keeps old links working by sending
+//! them to the new path, e.g. `LEGACY_REDIRECTS=/file/:/files/` redirects
+//! `/file/a.txt?x=1` to `/files/a.txt?x=1`. Entries are `old:new` prefix
+//! pairs separated by commas, and the first matching entry wins. `GET` and
+//! `HEAD` get `301 Moved Permanently`; other methods get `308 Permanent
+//! Redirect`, since clients turn a redirected `PUT` into a `GET` after a
+//! `301`.
+
+use worker::{Method, Request, Response, Result};
+
+/// Prefix pairs from `LEGACY_REDIRECTS`, as `(old, new)`
+///
+/// Both sides must be paths starting with `/`; malformed entries, and ones
+/// redirecting a prefix to itself, are skipped.
+pub fn parse_legacy_redirects(value: Option<&str>) -> Vec<(String, String)> {
+    value
+        .unwrap_or("")
+        .split(',')
+        .filter_map(|entry| {
+            let (from, to) = entry.split_once(':')?;
+            let (from, to) = (from.trim(), to.trim());
+            (from.starts_with('/') && to.starts_with('/') && from != to)
+                .then(|| (from.to_string(), to.to_string()))
+        })
+        .collect()
+}
+
+/// The new path for a legacy `path`, or `None` if it should be served as is
+///
+/// A prefix only matches whole segments, so `/file` covers `/file/a.txt`
+/// but not `/files/a.txt`. Paths already under the new prefix are never
+/// redirected, which also keeps `/api/` to `/api/v2/` from looping.
+pub fn rewrite_legacy_path(path: &str, redirects: &[(String, String)]) -> Option<String> {
+    redirects.iter().find_map(|(from, to)| {
+        if path.starts_with(to.as_str()) {
+            return None;
+        }
+        let rest = path.strip_prefix(from.as_str())?;
+        let whole_segment = from.ends_with('/') || rest.is_empty() || rest.starts_with('/');
+        whole_segment.then(|| format!("{}{}", to, rest))
+    })
+}
+
+/// A permanent redirect of `req` to `path`, keeping its query
+pub fn legacy_redirect_response(req: &Request, path: &str) -> Result<Response> {
+    let mut url = req.url()?;
+    url.set_path(path);
+    let status = match req.method() {
+        Method::Get | Method::Head => 301,
+        _ => 308,
+    };
+    Response::redirect_with_status(url, status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redirects(value: &str) -> Vec<(String, String)> {
+        parse_legacy_redirects(Some(value))
+    }
+
+    #[test]
+    fn test_parse_legacy_redirects() {
+        assert!(parse_legacy_redirects(None).is_empty());
+        assert_eq!(
+            redirects("/file/:/files/, /sessions/ : /session/"),
+            vec![
+                ("/file/".to_string(), "/files/".to_string()),
+                ("/sessions/".to_string(), "/session/".to_string())
+            ]
+        );
+        // No colon, relative paths and self-redirects are dropped
+        assert!(redirects("/file/,file/:/files/,/x/:/x/,:/files/").is_empty());
+    }
+
+    #[test]
+    fn test_rewrite_legacy_path() {
+        let table = redirects("/file/:/files/,/v1:/api");
+        assert_eq!(
+            rewrite_legacy_path("/file/docs/a.txt", &table).as_deref(),
+            Some("/files/docs/a.txt")
+        );
+        assert_eq!(rewrite_legacy_path("/v1", &table).as_deref(), Some("/api"));
+        assert_eq!(
+            rewrite_legacy_path("/v1/counter", &table).as_deref(),
+            Some("/api/counter")
+        );
+    }
+
+    #[test]
+    fn test_rewrite_legacy_path_leaves_current_paths_alone() {
+        let table = redirects("/file/:/files/,/v1:/api,/api/:/api/v2/");
+        for path in [
+            "/files/a.txt",
+            "/files/",
+            "/file",
+            "/v10/x",
+            "/api/v2/x",
+            "/",
+        ] {
+            assert_eq!(rewrite_legacy_path(path, &table), None, "{}", path);
+        }
+        // Moving a prefix under itself redirects once, then stops
+        assert_eq!(
+            rewrite_legacy_path("/api/x", &table).as_deref(),
+            Some("/api/v2/x")
+        );
+        assert!(rewrite_legacy_path("/anything", &[]).is_none());
+    }
+}
@@ -1,25 +1,78 @@
+#![cfg_attr(test, allow(clippy::module_inception))]
+
 use wasm_bindgen::JsValue;
 use worker::*;
 
+mod access_log;
+mod analytics;
+mod budget;
+mod client_ip;
+mod client_rate_limiter;
+mod content_digest;
 mod counter_object;
+mod do_storage;
+mod error;
 mod file_mapping_object;
+mod gzip;
+mod html_listing;
+mod http_date;
+mod http_range;
+mod json_patch;
+mod json_schema;
+mod legacy_redirect;
+mod method_override;
+mod metrics;
+mod metrics_object;
+mod negotiate;
+mod not_found;
+mod presign;
+mod purge;
+mod quota;
+mod r2_rate_limiter;
 mod r2_storage;
+mod routes;
 mod security;
 mod session_object;
 mod sha256;
+mod stream_buffer;
+mod tenant;
+mod upload_object;
+mod zip_archive;
 
-use r2_storage::handle_r2_request;
-use security::sanitize_path;
+use analytics::AnalyticsEvent;
+use client_ip::client_ip;
+use client_rate_limiter::{check_client_rate_limit, client_rate_limit_enabled, is_exempt};
+use counter_object::{parse_batch_ids, CounterBatch, CounterData};
+use error::{method_not_allowed, service_unavailable};
+use http_date::{request_wants_iso_timestamps, timestamped_json};
+use legacy_redirect::{legacy_redirect_response, parse_legacy_redirects, rewrite_legacy_path};
+use method_override::apply_method_override;
+use metrics::{handle_metrics_request, route_label, MetricsEvent};
+use negotiate::message_response;
+use not_found::not_found_response;
+use purge::handle_purge_request;
+use r2_rate_limiter::rate_limited_response;
+use r2_storage::{handle_blob_request, handle_r2_request};
+use routes::{allowed_methods, options_response, root_response};
+use security::{normalize_path_nfc, sanitize_path};
+use session_object::{project_session, session_delete_path, validate_json_body, DATA_KEY_PATH};
 
 // Export Durable Objects
+pub use access_log::AccessLogObject;
+pub use client_rate_limiter::ClientRateLimiterObject;
 pub use counter_object::CounterObject;
 pub use file_mapping_object::FileMappingObject;
+pub use metrics_object::MetricsObject;
+pub use r2_rate_limiter::R2RateLimiterObject;
 pub use session_object::SessionObject;
+pub use upload_object::UploadObject;
 
 // Tests modules
 #[cfg(test)]
 mod counter_object_tests;
 #[cfg(test)]
+mod file_mapping_object_tests;
+#[cfg(test)]
 mod integration_tests;
 #[cfg(test)]
 mod lib_tests;
@@ -29,35 +82,152 @@ mod r2_storage_tests;
 mod session_object_tests;
 #[cfg(test)]
 mod tests;
+#[cfg(test)]
+mod upload_object_tests;
 
 #[event(fetch)]
-async fn fetch(req: Request, env: Env, _ctx: Context) -> Result<Response> {
+async fn fetch(req: Request, env: Env, ctx: Context) -> Result<Response> {
     let path = req.path();
+    let started = js_sys::Date::now();
+    let method = req.method().to_string();
+    // Let clients behind proxies that block PUT and DELETE send a POST instead
+    let req = apply_method_override(req)?;
+    // Fail open: a limiter that can't be reached shouldn't take the Worker down
+    let response = match client_retry_after(&req, &env, &path).await {
+        Ok(Some(retry_after)) => rate_limited_response(retry_after),
+        _ => route_request(req, env.clone(), &ctx, &path).await,
+    };
+
+    // Analytics Engine writes don't wait, and never fail the request
+    analytics::record(
+        &env,
+        &AnalyticsEvent {
+            route: route_label(&path),
+            method,
+            status: response.as_ref().map_or(500, |r| r.status_code()),
+            duration_ms: js_sys::Date::now() - started,
+            bytes: response
+                .as_ref()
+                .ok()
+                .and_then(|r| r.headers().get("Content-Length").ok().flatten())
+                .and_then(|length| length.parse().ok())
+                .unwrap_or(0),
+        },
+    );
+
+    // Count the request without holding up the response
+    let event = MetricsEvent::Request {
+        route: route_label(&path).to_string(),
+        status: response.as_ref().map_or(500, |r| r.status_code()),
+    };
+    ctx.wait_until(async move {
+        let _ = metrics::record(&env, &event).await;
+    });
+
+    response
+}
 
+/// Apply the per-client rate limit, if it's turned on
+///
+/// Returns `Some(retry_after_secs)` when the request should be rejected.
+/// Requests without a client IP header can't be attributed and aren't limited.
+async fn client_retry_after(req: &Request, env: &Env, path: &str) -> Result<Option<u64>> {
+    if !client_rate_limit_enabled(env) || is_exempt(path) {
+        return Ok(None);
+    }
+    match client_ip(req, env) {
+        Some(ip) => check_client_rate_limit(env, &ip).await,
+        None => Ok(None),
+    }
+}
+
+async fn route_request(req: Request, env: Env, ctx: &Context, path: &str) -> Result<Response> {
+    // Old links are sent on to where their routes live now
+    let redirects = parse_legacy_redirects(
+        env.var("LEGACY_REDIRECTS")
+            .ok()
+            .map(|v| v.to_string())
+            .as_deref(),
+    );
+    if let Some(new_path) = rewrite_legacy_path(path, &redirects) {
+        return legacy_redirect_response(&req, &new_path);
+    }
+    // `OPTIONS` says which methods a resource takes, from the route table
+    if req.method() == Method::Options {
+        if let Some(methods) = allowed_methods(path) {
+            return options_response(methods);
+        }
+    }
     // Handle different routes without Router
     if path.starts_with("/files/") {
         // R2 operations
         let file_path = path.strip_prefix("/files/").unwrap_or("");
+        // NFC and NFD spellings of a name map to the same file when enabled
+        let normalize = env
+            .var("NORMALIZE_UNICODE_KEYS")
+            .map(|v| v.to_string() == "true")
+            .unwrap_or(false);
+        let file_path = if normalize {
+            normalize_path_nfc(file_path)
+        } else {
+            file_path.into()
+        };
+        let file_path = file_path.as_ref();
+        if file_path.is_empty() {
+            // Bucket root: list files or delete everything
+            return handle_r2_request(req, env, ctx, "").await;
+        }
         // Sanitize the path to prevent directory traversal
         match sanitize_path(file_path) {
-            Ok(safe_path) => handle_r2_request(req, env, &safe_path).await,
+            Ok(mut safe_path) => {
+                // Keep the trailing slash so prefix operations can be told apart
+                if file_path.ends_with('/') {
+                    safe_path.push('/');
+                }
+                handle_r2_request(req, env, ctx, &safe_path).await
+            }
             Err(e) => Response::error(e, 400),
         }
+    } else if let Some(sha256) = path.strip_prefix("/blobs/") {
+        // Direct content-addressed blob reads
+        handle_blob_request(req, env, sha256).await
+    } else if path == "/counters/batch" {
+        // Many counters in one round trip
+        handle_counter_batch_request(req, env).await
     } else if path.starts_with("/counter") {
         // Counter Durable Object operations
-        handle_counter_request(req, env, &path).await
+        handle_counter_request(req, env, path).await
     } else if path.starts_with("/session") {
         // Session Durable Object operations
-        handle_session_request(req, env, &path).await
+        handle_session_request(req, env, path).await
+    } else if path == "/admin/purge" {
+        // Wipe everything, for test environments
+        handle_purge_request(req, env).await
+    } else if path == "/metrics" {
+        // Prometheus metrics
+        handle_metrics_request(req, env).await
     } else if path == "/" {
-        // Root path
-        Response::ok("Hello from Rust Workers! Available endpoints:\n/files/* - R2 operations\n/counter/* - Counter operations\n/session/* - Session operations")
+        // Root path: the route table, as live API documentation
+        root_response(&req)
     } else {
-        Response::error("Not found", 404)
+        // JSON by default, or the configured not-found page
+        not_found_response(&env, path).await
     }
 }
 
-async fn handle_counter_request(req: Request, env: Env, path: &str) -> Result<Response> {
+async fn handle_counter_request(mut req: Request, env: Env, path: &str) -> Result<Response> {
+    let iso = request_wants_iso_timestamps(&req);
+    // Compare-and-swap is addressed as /counter/{id}/cas
+    let (path, is_cas) = match path.strip_suffix("/cas") {
+        Some(p) => (p, true),
+        None => (path, false),
+    };
+    // Live updates are streamed over a WebSocket at /counter/{id}/ws
+    let (path, is_ws) = match path.strip_suffix("/ws") {
+        Some(p) => (p, true),
+        None => (path, false),
+    };
+
     // Get the counter ID from the path
     let counter_id = if path == "/counter" || path == "/counter/" {
         "default"
@@ -68,7 +238,7 @@ async fn handle_counter_request(req: Request, env: Env, path: &str) -> Result<Re
     // Get the Durable Object namespace
     let namespace = match env.durable_object("COUNTER_OBJECT") {
         Ok(ns) => ns,
-        Err(_) => return Response::error("Counter service not available", 503),
+        Err(_) => return service_unavailable("COUNTER_OBJECT"),
     };
 
     // Get the Durable Object stub
@@ -77,9 +247,95 @@ async fn handle_counter_request(req: Request, env: Env, path: &str) -> Result<Re
 
     // Forward the request to the Durable Object
     match req.method() {
+        Method::Get if is_ws => {
+            // Pass the upgrade through; the response carries the client socket
+            let headers = Headers::new();
+            if let Some(upgrade) = req.headers().get("Upgrade")? {
+                headers.set("Upgrade", &upgrade)?;
+            }
+            let request = Request::new_with_init(
+                "https://fake-host/ws",
+                RequestInit::new()
+                    .with_method(Method::Get)
+                    .with_headers(headers),
+            )?;
+            stub.fetch_with_request(request).await
+        }
+        _ if is_ws => method_not_allowed(&[Method::Get]),
+        Method::Post if is_cas => {
+            let body = req.text().await?;
+            let headers = Headers::new();
+            headers.set("content-type", "application/json")?;
+            let request = Request::new_with_init(
+                "https://fake-host/cas",
+                RequestInit::new()
+                    .with_method(Method::Post)
+                    .with_body(Some(JsValue::from_str(&body)))
+                    .with_headers(headers),
+            )?;
+            let mut response = stub.fetch_with_request(request).await?;
+            let status = response.status_code();
+            if status == 400 || status >= 500 {
+                return Ok(response);
+            }
+            // Keep the 409 status on mismatch so callers can tell the swap failed
+            Ok(
+                timestamped_json(&response.json::<serde_json::Value>().await?, iso)?
+                    .with_status(status),
+            )
+        }
+        _ if is_cas => method_not_allowed(&[Method::Post]),
+        Method::Put => {
+            let body = req.text().await?;
+            let headers = Headers::new();
+            headers.set("content-type", "application/json")?;
+            let request = Request::new_with_init(
+                "https://fake-host/",
+                RequestInit::new()
+                    .with_method(Method::Put)
+                    .with_body(Some(JsValue::from_str(&body)))
+                    .with_headers(headers),
+            )?;
+            let mut response = stub.fetch_with_request(request).await?;
+            if response.status_code() >= 400 {
+                return Ok(response);
+            }
+            timestamped_json(&response.json::<serde_json::Value>().await?, iso)
+        }
+        Method::Patch => {
+            // The Durable Object checks the content type, so pass it through
+            let body = req.text().await?;
+            let headers = Headers::new();
+            if let Some(content_type) = req.headers().get("Content-Type")? {
+                headers.set("content-type", &content_type)?;
+            }
+            let request = Request::new_with_init(
+                "https://fake-host/",
+                RequestInit::new()
+                    .with_method(Method::Patch)
+                    .with_body(Some(JsValue::from_str(&body)))
+                    .with_headers(headers),
+            )?;
+            let mut response = stub.fetch_with_request(request).await?;
+            if response.status_code() >= 400 {
+                return Ok(response);
+            }
+            timestamped_json(&response.json::<serde_json::Value>().await?, iso)
+        }
         Method::Get => {
-            let mut response = stub.fetch_with_str("https://fake-host/").await?;
-            Response::from_json(&response.json::<serde_json::Value>().await?)
+            // Pass the query on for `?stats=true`
+            let query = req
+                .url()?
+                .query()
+                .map(|q| format!("?{}", q))
+                .unwrap_or_default();
+            let mut response = stub
+                .fetch_with_str(&format!("https://fake-host/{}", query))
+                .await?;
+            if response.status_code() >= 400 {
+                return Ok(response);
+            }
+            timestamped_json(&response.json::<serde_json::Value>().await?, iso)
         }
         Method::Post => {
             let request = Request::new_with_init(
@@ -87,20 +343,82 @@ async fn handle_counter_request(req: Request, env: Env, path: &str) -> Result<Re
                 RequestInit::new().with_method(Method::Post),
             )?;
             let mut response = stub.fetch_with_request(request).await?;
-            Response::from_json(&response.json::<serde_json::Value>().await?)
+            if response.status_code() >= 400 {
+                return Ok(response);
+            }
+            timestamped_json(&response.json::<serde_json::Value>().await?, iso)
         }
         Method::Delete => {
             let request = Request::new_with_init(
                 "https://fake-host/",
                 RequestInit::new().with_method(Method::Delete),
             )?;
-            let response = stub.fetch_with_request(request).await?;
-            Ok(response)
+            let mut response = stub.fetch_with_request(request).await?;
+            if response.status_code() >= 400 {
+                return Ok(response);
+            }
+            message_response(&req, &response.text().await?)
         }
-        _ => Response::error("Method not allowed", 405),
+        _ => method_not_allowed(&[
+            Method::Get,
+            Method::Post,
+            Method::Put,
+            Method::Patch,
+            Method::Delete,
+        ]),
     }
 }
 
+async fn handle_counter_batch_request(mut req: Request, env: Env) -> Result<Response> {
+    if req.method() != Method::Post {
+        return method_not_allowed(&[Method::Post]);
+    }
+    let iso = request_wants_iso_timestamps(&req);
+    let body = match req.json::<serde_json::Value>().await {
+        Ok(body) => body,
+        Err(_) => return Response::error("Invalid JSON", 400),
+    };
+    let ids = match parse_batch_ids(&body) {
+        Ok(ids) => ids,
+        Err(e) => return Response::error(e, 400),
+    };
+    let namespace = match env.durable_object("COUNTER_OBJECT") {
+        Ok(ns) => ns,
+        Err(_) => return service_unavailable("COUNTER_OBJECT"),
+    };
+
+    // Each counter is its own Durable Object, so they're all asked at once
+    let reads = ids.iter().map(|id| {
+        let namespace = &namespace;
+        async move {
+            let stub = namespace.id_from_name(id)?.get_stub()?;
+            let mut response = stub.fetch_with_str("https://fake-host/").await?;
+            if response.status_code() >= 400 {
+                return Err(Error::RustError(format!(
+                    "Counter read failed with status {}",
+                    response.status_code()
+                )));
+            }
+            response.json::<CounterData>().await
+        }
+    });
+    let results = futures_util::future::join_all(reads).await;
+
+    // One unreachable counter doesn't fail the others
+    let mut batch = CounterBatch::default();
+    for (id, result) in ids.into_iter().zip(results) {
+        match result {
+            Ok(data) => {
+                batch.counters.insert(id, data);
+            }
+            Err(e) => {
+                batch.errors.insert(id, e.to_string());
+            }
+        }
+    }
+    timestamped_json(&batch, iso)
+}
+
 async fn handle_session_request(mut req: Request, env: Env, path: &str) -> Result<Response> {
     // Get the session ID from the path
     let session_path = path.strip_prefix("/session/").unwrap_or("");
@@ -116,13 +434,21 @@ async fn handle_session_request(mut req: Request, env: Env, path: &str) -> Resul
         return Response::error("Session ID required", 400);
     }
 
+    // Keys are top-level, so there's nothing deeper to address, except through
+    // `__data/{key}`, which reaches data keys named like endpoints
+    let is_data_key_path = parts.len() == 3 && parts[1] == "__data" && !parts[2].is_empty();
+    if parts.len() > 2 && !is_data_key_path {
+        return Response::error("Session paths take at most one key", 400);
+    }
+
     let session_id = parts[0];
-    let key = parts.get(1).copied();
+    let rest = parts[1..].join("/");
+    let key = (parts.len() > 1).then_some(rest.as_str());
 
     // Get the Durable Object namespace
     let namespace = match env.durable_object("SESSION_OBJECT") {
         Ok(ns) => ns,
-        Err(_) => return Response::error("Session service not available", 503),
+        Err(_) => return service_unavailable("SESSION_OBJECT"),
     };
 
     // Get the Durable Object stub
@@ -135,12 +461,22 @@ async fn handle_session_request(mut req: Request, env: Env, path: &str) -> Resul
         None => "/".to_string(),
     };
 
+    let iso = request_wants_iso_timestamps(&req);
+    // Forward If-Match so the Durable Object can reject stale writes
+    let if_match = req.headers().get("If-Match")?;
+
     // Forward the request to the Durable Object with the body if present
     let mut response = match req.method() {
         Method::Put => {
-            let body = req.text().await?;
+            let body = match validate_json_body(&req.bytes().await?) {
+                Ok(body) => body,
+                Err(e) => return Response::error(e, 400),
+            };
             let headers = Headers::new();
             headers.set("content-type", "application/json")?;
+            if let Some(tag) = &if_match {
+                headers.set("If-Match", tag)?;
+            }
             let request = Request::new_with_init(
                 &format!("https://fake-host{}", do_path),
                 RequestInit::new()
@@ -150,6 +486,50 @@ async fn handle_session_request(mut req: Request, env: Env, path: &str) -> Resul
             )?;
             stub.fetch_with_request(request).await?
         }
+        Method::Patch => {
+            // The Durable Object checks the content type, so pass it through
+            let body = match validate_json_body(&req.bytes().await?) {
+                Ok(body) => body,
+                Err(e) => return Response::error(e, 400),
+            };
+            let headers = Headers::new();
+            if let Some(content_type) = req.headers().get("Content-Type")? {
+                headers.set("content-type", &content_type)?;
+            }
+            if let Some(tag) = &if_match {
+                headers.set("If-Match", tag)?;
+            }
+            let request = Request::new_with_init(
+                &format!("https://fake-host{}", do_path),
+                RequestInit::new()
+                    .with_method(Method::Patch)
+                    .with_body(Some(JsValue::from_str(&body)))
+                    .with_headers(headers),
+            )?;
+            stub.fetch_with_request(request).await?
+        }
+        Method::Post => {
+            // Only `/import` takes a POST; pass the query on for `?overwrite`
+            let body = match validate_json_body(&req.bytes().await?) {
+                Ok(body) => body,
+                Err(e) => return Response::error(e, 400),
+            };
+            let query = req
+                .url()?
+                .query()
+                .map(|q| format!("?{}", q))
+                .unwrap_or_default();
+            let headers = Headers::new();
+            headers.set("content-type", "application/json")?;
+            let request = Request::new_with_init(
+                &format!("https://fake-host{}{}", do_path, query),
+                RequestInit::new()
+                    .with_method(Method::Post)
+                    .with_body(Some(JsValue::from_str(&body)))
+                    .with_headers(headers),
+            )?;
+            stub.fetch_with_request(request).await?
+        }
         Method::Get => {
             let request = Request::new_with_init(
                 &format!("https://fake-host{}", do_path),
@@ -158,25 +538,60 @@ async fn handle_session_request(mut req: Request, env: Env, path: &str) -> Resul
             stub.fetch_with_request(request).await?
         }
         Method::Delete => {
+            let headers = Headers::new();
+            if let Some(tag) = &if_match {
+                headers.set("If-Match", tag)?;
+            }
             let request = Request::new_with_init(
-                &format!("https://fake-host{}", do_path),
-                RequestInit::new().with_method(Method::Delete),
+                &format!("https://fake-host{}", session_delete_path(key)),
+                RequestInit::new()
+                    .with_method(Method::Delete)
+                    .with_headers(headers),
             )?;
             stub.fetch_with_request(request).await?
         }
-        _ => return Response::error("Method not allowed", 405),
+        _ => {
+            // Same as `OPTIONS` reports, from the route table
+            let allowed: Vec<Method> = allowed_methods(path)
+                .unwrap_or_default()
+                .iter()
+                .map(|m| Method::from(m.to_string()))
+                .collect();
+            return method_not_allowed(&allowed);
+        }
     };
 
     // Return the response
     match req.method() {
-        Method::Delete => Ok(response), // DELETE returns plain text
+        // Clearing a session or removing its schema returns a message, as plain
+        // text unless JSON is asked for; deleting one key returns its value like
+        // any other write
+        Method::Delete if !session_delete_path(key).starts_with(DATA_KEY_PATH) => {
+            if response.status_code() < 400 {
+                message_response(&req, &response.text().await?)
+            } else {
+                Ok(response)
+            }
+        }
         _ => {
             // Check if response is an error
             if response.status_code() >= 400 {
                 Ok(response)
             } else {
-                let json = response.json::<serde_json::Value>().await?;
-                Response::from_json(&json)
+                let etag = response.headers().get("ETag")?;
+                let mut json = response.json::<serde_json::Value>().await?;
+                // `?fields=a,b.c` trims a whole-session read to just those parts of `data`
+                if req.method() == Method::Get && key.is_none() {
+                    if let Some((_, fields)) = req.url()?.query_pairs().find(|(k, _)| k == "fields")
+                    {
+                        json = project_session(&json, &fields);
+                    }
+                }
+                let mut response = timestamped_json(&json, iso)?;
+                if let Some(etag) = etag {
+                    response.headers_mut().set("ETag", &etag)?;
+                }
+                Ok(response)
             }
         }
     }
@@ -8,6 +8,7 @@ mod lib_tests {
             ("/", "root"),
             ("/files/", "files"),
             ("/files/test.txt", "files"),
+            ("/blobs/abc123", "blobs"),
             ("/counter", "counter"),
             ("/counter/", "counter"),
             ("/counter/test", "counter"),
@@ -20,6 +21,7 @@ mod lib_tests {
             let route = match path {
                 "/" => "root",
                 p if p.starts_with("/files/") => "files",
+                p if p.starts_with("/blobs/") => "blobs",
                 p if p.starts_with("/counter") => "counter",
                 p if p.starts_with("/session") => "session",
                 _ => "404",
@@ -75,7 +77,7 @@ mod lib_tests {
                 .collect();
             let result = if !parts.is_empty() && !parts[0].is_empty() {
                 let session_id = parts[0];
-                let key = parts.get(1).filter(|k| !k.is_empty()).map(|s| *s);
+                let key = parts.get(1).filter(|k| !k.is_empty()).copied();
                 Some((session_id, key))
             } else {
                 None
@@ -0,0 +1,93 @@
+//! HTTP method override for clients that can only send GET and POST
+//!
+//! Some proxies block `PUT`, `PATCH` and `DELETE`. A `POST` carrying
+//! `X-HTTP-Method-Override: DELETE` is handled as if it were a `DELETE`, before
+//! any route sees it. Only `POST` can be overridden, so a `GET` (or a link
+//! followed by a crawler) can never turn into a write, and only to the methods
+//! in `OVERRIDABLE_METHODS`.
+
+use worker::wasm_bindgen::JsValue;
+use worker::{Method, Request, RequestInit, Result};
+
+/// Request header naming the method to use instead of `POST`
+pub const METHOD_OVERRIDE_HEADER: &str = "X-HTTP-Method-Override";
+
+/// Methods a `POST` may be overridden to
+pub const OVERRIDABLE_METHODS: [Method; 3] = [Method::Put, Method::Patch, Method::Delete];
+
+/// The method a request should be handled as, if its override applies
+///
+/// `None` means keep `method`: the request isn't a `POST`, has no override
+/// header, or names a method that can't be overridden to.
+pub fn override_method(method: &Method, header: Option<&str>) -> Option<Method> {
+    if *method != Method::Post {
+        return None;
+    }
+    let requested = header?.trim();
+    OVERRIDABLE_METHODS
+        .into_iter()
+        .find(|m| m.as_ref().eq_ignore_ascii_case(requested))
+}
+
+/// `req` with its method overridden, or unchanged if no override applies
+///
+/// The rebuilt request keeps the URL, headers and body, minus the override
+/// header itself.
+pub fn apply_method_override(req: Request) -> Result<Request> {
+    let header = req.headers().get(METHOD_OVERRIDE_HEADER)?;
+    let Some(method) = override_method(&req.method(), header.as_deref()) else {
+        return Ok(req);
+    };
+
+    let headers = req.headers().clone();
+    headers.delete(METHOD_OVERRIDE_HEADER)?;
+    let body = req.inner().body().map(JsValue::from);
+    Request::new_with_init(
+        req.url()?.as_str(),
+        RequestInit::new()
+            .with_method(method)
+            .with_headers(headers)
+            .with_body(body),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_override_method() {
+        assert_eq!(
+            override_method(&Method::Post, Some("DELETE")),
+            Some(Method::Delete)
+        );
+        assert_eq!(
+            override_method(&Method::Post, Some(" put ")),
+            Some(Method::Put)
+        );
+        assert_eq!(
+            override_method(&Method::Post, Some("Patch")),
+            Some(Method::Patch)
+        );
+        assert_eq!(override_method(&Method::Post, None), None);
+    }
+
+    #[test]
+    fn test_override_only_to_safe_methods() {
+        for method in ["GET", "HEAD", "OPTIONS", "CONNECT", "TRACE", "POST", ""] {
+            assert_eq!(
+                override_method(&Method::Post, Some(method)),
+                None,
+                "{}",
+                method
+            );
+        }
+    }
+
+    #[test]
+    fn test_override_only_applies_to_post() {
+        assert_eq!(override_method(&Method::Get, Some("DELETE")), None);
+        assert_eq!(override_method(&Method::Head, Some("PUT")), None);
+        assert_eq!(override_method(&Method::Put, Some("DELETE")), None);
+    }
+}
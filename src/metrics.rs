@@ -0,0 +1,390 @@
+//! Request counters and their Prometheus text exposition
+//!
+//! Counters are aggregated in `MetricsObject`s, since each Worker invocation
+//! is short-lived and can't keep totals of its own. Every event goes to one of
+//! `METRICS_SHARDS` instances picked at random, so no single object takes every
+//! request, and `GET /metrics` adds the shards' totals together.
+
+use crate::error::{method_not_allowed, service_unavailable};
+use futures_util::future::try_join_all;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use wasm_bindgen::JsValue;
+use worker::*;
+
+/// Number of `MetricsObject` instances events are spread over
+///
+/// Totals are only ever summed, so an event can go to any shard. Raising this
+/// is safe; lowering it would drop the higher shards' counts from `/metrics`.
+pub const METRICS_SHARDS: u32 = 16;
+/// The instance all events went to before sharding; its totals still count
+const LEGACY_METRICS_INSTANCE: &str = "global";
+
+/// Content type of the Prometheus text exposition format
+pub const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Something worth counting, sent to the `MetricsObject`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MetricsEvent {
+    /// A request was handled
+    Request { route: String, status: u16 },
+    /// A file was uploaded; `deduplicated` if its content was already stored
+    Upload { bytes: u64, deduplicated: bool },
+}
+
+/// Running totals kept by the `MetricsObject`
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct MetricsSnapshot {
+    pub requests_total: u64,
+    pub requests_by_route: BTreeMap<String, u64>,
+    pub responses_by_status: BTreeMap<u16, u64>,
+    pub uploads_total: u64,
+    pub upload_bytes_total: u64,
+    pub dedup_hits_total: u64,
+}
+
+impl MetricsSnapshot {
+    /// Add an event to the totals
+    pub fn record(&mut self, event: &MetricsEvent) {
+        match event {
+            MetricsEvent::Request { route, status } => {
+                self.requests_total += 1;
+                *self.requests_by_route.entry(route.clone()).or_default() += 1;
+                *self.responses_by_status.entry(*status).or_default() += 1;
+            }
+            MetricsEvent::Upload {
+                bytes,
+                deduplicated,
+            } => {
+                self.uploads_total += 1;
+                self.upload_bytes_total += bytes;
+                if *deduplicated {
+                    self.dedup_hits_total += 1;
+                }
+            }
+        }
+    }
+
+    /// Add another shard's totals to these
+    pub fn merge(&mut self, other: &MetricsSnapshot) {
+        self.requests_total += other.requests_total;
+        for (route, count) in &other.requests_by_route {
+            *self.requests_by_route.entry(route.clone()).or_default() += count;
+        }
+        for (status, count) in &other.responses_by_status {
+            *self.responses_by_status.entry(*status).or_default() += count;
+        }
+        self.uploads_total += other.uploads_total;
+        self.upload_bytes_total += other.upload_bytes_total;
+        self.dedup_hits_total += other.dedup_hits_total;
+    }
+}
+
+/// Durable Object instance name of metrics shard `n`
+pub fn metrics_shard_name(n: u32) -> String {
+    format!("metrics-shard-{}", n)
+}
+
+/// Route label for a request path
+///
+/// Labels come from a fixed set so arbitrary paths can't blow up the number
+/// of time series.
+pub fn route_label(path: &str) -> &'static str {
+    if path == "/" {
+        "root"
+    } else if path.starts_with("/files/") {
+        "files"
+    } else if path.starts_with("/blobs/") {
+        "blobs"
+    } else if path.starts_with("/counter") {
+        "counter"
+    } else if path.starts_with("/session") {
+        "session"
+    } else if path == "/metrics" {
+        "metrics"
+    } else {
+        "other"
+    }
+}
+
+/// Escape a label value for the exposition format
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_header(out: &mut String, name: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+}
+
+/// Format the totals in the Prometheus text exposition format
+pub fn format_prometheus(snapshot: &MetricsSnapshot) -> String {
+    let mut out = String::new();
+
+    write_header(
+        &mut out,
+        "work_rs_requests_total",
+        "Total requests handled.",
+    );
+    let _ = writeln!(out, "work_rs_requests_total {}", snapshot.requests_total);
+
+    write_header(
+        &mut out,
+        "work_rs_route_requests_total",
+        "Requests handled, by route.",
+    );
+    for (route, count) in &snapshot.requests_by_route {
+        let _ = writeln!(
+            out,
+            "work_rs_route_requests_total{{route=\"{}\"}} {}",
+            escape_label_value(route),
+            count
+        );
+    }
+
+    write_header(
+        &mut out,
+        "work_rs_responses_total",
+        "Responses sent, by HTTP status code.",
+    );
+    for (status, count) in &snapshot.responses_by_status {
+        let _ = writeln!(
+            out,
+            "work_rs_responses_total{{status=\"{}\"}} {}",
+            status, count
+        );
+    }
+
+    write_header(&mut out, "work_rs_uploads_total", "Files uploaded.");
+    let _ = writeln!(out, "work_rs_uploads_total {}", snapshot.uploads_total);
+
+    write_header(
+        &mut out,
+        "work_rs_upload_bytes_total",
+        "Bytes received in file uploads.",
+    );
+    let _ = writeln!(
+        out,
+        "work_rs_upload_bytes_total {}",
+        snapshot.upload_bytes_total
+    );
+
+    write_header(
+        &mut out,
+        "work_rs_dedup_hits_total",
+        "Uploads whose content was already stored.",
+    );
+    let _ = writeln!(
+        out,
+        "work_rs_dedup_hits_total {}",
+        snapshot.dedup_hits_total
+    );
+
+    out
+}
+
+async fn metrics_stub(env: &Env, name: &str) -> Result<worker::durable::Stub> {
+    let namespace = env.durable_object("METRICS_OBJECT")?;
+    namespace.id_from_name(name)?.get_stub()
+}
+
+/// Send an event to the `MetricsObject`
+pub async fn record(env: &Env, event: &MetricsEvent) -> Result<()> {
+    let headers = Headers::new();
+    headers.set("content-type", "application/json")?;
+    let request = Request::new_with_init(
+        "https://fake-host/record",
+        RequestInit::new()
+            .with_method(Method::Post)
+            .with_body(Some(JsValue::from_str(&serde_json::to_string(event)?)))
+            .with_headers(headers),
+    )?;
+
+    let shard = (js_sys::Math::random() * METRICS_SHARDS as f64) as u32 % METRICS_SHARDS;
+    metrics_stub(env, &metrics_shard_name(shard))
+        .await?
+        .fetch_with_request(request)
+        .await?;
+    Ok(())
+}
+
+/// Handle `GET /metrics`
+pub async fn handle_metrics_request(req: Request, env: Env) -> Result<Response> {
+    if req.method() != Method::Get {
+        return method_not_allowed(&[Method::Get]);
+    }
+
+    if env.durable_object("METRICS_OBJECT").is_err() {
+        return service_unavailable("METRICS_OBJECT");
+    }
+    // Read every shard at once; they're independent objects
+    let names = std::iter::once(LEGACY_METRICS_INSTANCE.to_string())
+        .chain((0..METRICS_SHARDS).map(metrics_shard_name));
+    let shards = try_join_all(names.map(|name| {
+        let env = &env;
+        async move {
+            metrics_stub(env, &name)
+                .await?
+                .fetch_with_str("https://fake-host/")
+                .await?
+                .json::<MetricsSnapshot>()
+                .await
+        }
+    }))
+    .await?;
+    let mut snapshot = MetricsSnapshot::default();
+    for shard in &shards {
+        snapshot.merge(shard);
+    }
+
+    let headers = Headers::new();
+    headers.set("Content-Type", PROMETHEUS_CONTENT_TYPE)?;
+    Ok(Response::ok(format_prometheus(&snapshot))?.with_headers(headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let mut snapshot = MetricsSnapshot::default();
+        for (route, status) in [("files", 200), ("files", 404), ("session", 200)] {
+            snapshot.record(&MetricsEvent::Request {
+                route: route.to_string(),
+                status,
+            });
+        }
+        snapshot.record(&MetricsEvent::Upload {
+            bytes: 100,
+            deduplicated: false,
+        });
+        snapshot.record(&MetricsEvent::Upload {
+            bytes: 50,
+            deduplicated: true,
+        });
+
+        assert_eq!(snapshot.requests_total, 3);
+        assert_eq!(snapshot.requests_by_route["files"], 2);
+        assert_eq!(snapshot.requests_by_route["session"], 1);
+        assert_eq!(snapshot.responses_by_status[&200], 2);
+        assert_eq!(snapshot.responses_by_status[&404], 1);
+        assert_eq!(snapshot.uploads_total, 2);
+        assert_eq!(snapshot.upload_bytes_total, 150);
+        assert_eq!(snapshot.dedup_hits_total, 1);
+    }
+
+    #[test]
+    fn test_merge_adds_shards() {
+        let mut a = MetricsSnapshot::default();
+        a.record(&MetricsEvent::Request {
+            route: "files".to_string(),
+            status: 200,
+        });
+        let mut b = MetricsSnapshot::default();
+        b.record(&MetricsEvent::Request {
+            route: "files".to_string(),
+            status: 404,
+        });
+        b.record(&MetricsEvent::Upload {
+            bytes: 10,
+            deduplicated: true,
+        });
+
+        // Summing shards gives what one object recording everything would have
+        let mut all = MetricsSnapshot::default();
+        for snapshot in [&a, &b] {
+            all.merge(snapshot);
+        }
+        let mut single = a.clone();
+        single.record(&MetricsEvent::Request {
+            route: "files".to_string(),
+            status: 404,
+        });
+        single.record(&MetricsEvent::Upload {
+            bytes: 10,
+            deduplicated: true,
+        });
+        assert_eq!(all, single);
+        assert_eq!(all.requests_by_route["files"], 2);
+    }
+
+    #[test]
+    fn test_metrics_shard_names() {
+        assert_eq!(metrics_shard_name(0), "metrics-shard-0");
+        assert_ne!(metrics_shard_name(0), LEGACY_METRICS_INSTANCE);
+    }
+
+    #[test]
+    fn test_route_label() {
+        assert_eq!(route_label("/"), "root");
+        assert_eq!(route_label("/files/a/b.txt"), "files");
+        assert_eq!(route_label("/blobs/abc"), "blobs");
+        assert_eq!(route_label("/counter"), "counter");
+        assert_eq!(route_label("/counter/visits"), "counter");
+        assert_eq!(route_label("/session/abc"), "session");
+        assert_eq!(route_label("/metrics"), "metrics");
+        assert_eq!(route_label("/wp-admin/secret"), "other");
+    }
+
+    #[test]
+    fn test_format_prometheus() {
+        let mut snapshot = MetricsSnapshot::default();
+        snapshot.record(&MetricsEvent::Request {
+            route: "files".to_string(),
+            status: 201,
+        });
+        snapshot.record(&MetricsEvent::Upload {
+            bytes: 42,
+            deduplicated: true,
+        });
+
+        let output = format_prometheus(&snapshot);
+        let expected = "\
+# HELP work_rs_requests_total Total requests handled.
+# TYPE work_rs_requests_total counter
+work_rs_requests_total 1
+# HELP work_rs_route_requests_total Requests handled, by route.
+# TYPE work_rs_route_requests_total counter
+work_rs_route_requests_total{route=\"files\"} 1
+# HELP work_rs_responses_total Responses sent, by HTTP status code.
+# TYPE work_rs_responses_total counter
+work_rs_responses_total{status=\"201\"} 1
+# HELP work_rs_uploads_total Files uploaded.
+# TYPE work_rs_uploads_total counter
+work_rs_uploads_total 1
+# HELP work_rs_upload_bytes_total Bytes received in file uploads.
+# TYPE work_rs_upload_bytes_total counter
+work_rs_upload_bytes_total 42
+# HELP work_rs_dedup_hits_total Uploads whose content was already stored.
+# TYPE work_rs_dedup_hits_total counter
+work_rs_dedup_hits_total 1
+";
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_escape_label_value() {
+        assert_eq!(escape_label_value("plain"), "plain");
+        assert_eq!(escape_label_value("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn test_event_serialization() {
+        let event = MetricsEvent::Upload {
+            bytes: 10,
+            deduplicated: true,
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"type": "upload", "bytes": 10, "deduplicated": true})
+        );
+        assert_eq!(serde_json::from_value::<MetricsEvent>(json).unwrap(), event);
+    }
+}
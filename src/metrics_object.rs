@@ -0,0 +1,48 @@
+use crate::metrics::{MetricsEvent, MetricsSnapshot};
+use worker::*;
+
+/// Durable Object aggregating request metrics
+///
+/// Each of the `METRICS_SHARDS` instances receives a share of the events and
+/// keeps running totals; `GET /metrics` adds them up.
+#[durable_object]
+pub struct MetricsObject {
+    state: State,
+    _env: Env,
+}
+
+impl DurableObject for MetricsObject {
+    fn new(state: State, env: Env) -> Self {
+        Self { state, _env: env }
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        let storage = self.state.storage();
+        let path = req.path();
+
+        match (req.method(), path.as_str()) {
+            (Method::Get, "/") => {
+                let snapshot = storage
+                    .get::<MetricsSnapshot>("metrics")
+                    .await
+                    .unwrap_or_default();
+                Response::from_json(&snapshot)
+            }
+            (Method::Post, "/record") => {
+                let event: MetricsEvent = match req.json().await {
+                    Ok(e) => e,
+                    Err(_) => return Response::error("Invalid metrics event", 400),
+                };
+
+                let mut snapshot = storage
+                    .get::<MetricsSnapshot>("metrics")
+                    .await
+                    .unwrap_or_default();
+                snapshot.record(&event);
+                storage.put("metrics", &snapshot).await?;
+                Response::ok("Recorded")
+            }
+            _ => Response::error("Not found", 404),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! Content negotiation for short status messages
+//!
+//! Deletes and resets answer with a one-line message like `File deleted`. That
+//! stays plain text unless the client's `Accept` header asks for JSON, in which
+//! case the message comes back as `{"message": "File deleted"}`. Either way the
+//! response carries `Vary: Accept` so caches keep the two apart.
+
+use worker::{Request, Response, Result};
+
+/// Whether an `Accept` header asks for `application/json`
+///
+/// Only an explicit `application/json` range counts, so `*/*` and missing
+/// headers keep the plain text default. A range with `q=0` is a refusal.
+pub fn accepts_json(accept: Option<&str>) -> bool {
+    accepts_media(accept, "application/json")
+}
+
+/// Whether an `Accept` header explicitly asks for the media type `media`
+///
+/// Wildcards don't count, and a range with `q=0` is a refusal.
+pub fn accepts_media(accept: Option<&str>, media: &str) -> bool {
+    accept.is_some_and(|accept| {
+        accept.split(',').any(|range| {
+            let mut parts = range.split(';');
+            let range_media = parts.next().unwrap_or("").trim();
+            let refused = parts.any(|param| {
+                let (name, value) = param.split_once('=').unwrap_or((param, ""));
+                name.trim().eq_ignore_ascii_case("q")
+                    && value.trim().parse::<f32>().is_ok_and(|q| q == 0.0)
+            });
+            range_media.eq_ignore_ascii_case(media) && !refused
+        })
+    })
+}
+
+/// Response carrying `message` as plain text or JSON, as `req` prefers
+pub fn message_response(req: &Request, message: &str) -> Result<Response> {
+    let accept = req.headers().get("Accept")?;
+    let mut response = if accepts_json(accept.as_deref()) {
+        Response::from_json(&serde_json::json!({ "message": message }))?
+    } else {
+        Response::ok(message)?
+    };
+    response.headers_mut().set("Vary", "Accept")?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accepts_json() {
+        assert!(accepts_json(Some("application/json")));
+        assert!(accepts_json(Some("text/html, Application/JSON;q=0.9")));
+
+        assert!(!accepts_json(None));
+        assert!(!accepts_json(Some("*/*")));
+        assert!(!accepts_json(Some("text/plain")));
+        assert!(!accepts_json(Some("application/json+iso")));
+        assert!(!accepts_json(Some("application/json; q=0")));
+    }
+
+    #[test]
+    fn test_accepts_media() {
+        assert!(accepts_media(Some("text/plain"), "text/plain"));
+        assert!(accepts_media(
+            Some("text/html, Text/Plain;q=0.5"),
+            "text/plain"
+        ));
+        assert!(!accepts_media(Some("text/*"), "text/plain"));
+        assert!(!accepts_media(Some("text/plain;q=0"), "text/plain"));
+        assert!(!accepts_media(None, "text/plain"));
+    }
+}
@@ -0,0 +1,111 @@
+//! The response for paths no route matches
+//!
+//! By default this is JSON naming the path, `{"error": "Not found", "path": "/x"}`.
+//! For a static site, a branded page can be served instead, with the `404`
+//! status kept:
+//!
+//! 1. `NOT_FOUND_BODY`, sent as `NOT_FOUND_CONTENT_TYPE` (default `text/html`)
+//! 2. Otherwise a file uploaded as `__404__.html`, if there is one
+//!
+//! A page that can't be read falls back to the JSON default rather than
+//! failing the request.
+
+use crate::r2_storage::{R2Storage, R2StorageImpl, DEFAULT_BUCKET_BINDING};
+use worker::{Env, Headers, Response, Result};
+
+/// File served as the not-found page when `NOT_FOUND_BODY` isn't set
+pub const NOT_FOUND_PAGE_KEY: &str = "__404__.html";
+
+/// Content type of `NOT_FOUND_BODY` when `NOT_FOUND_CONTENT_TYPE` isn't set
+pub const DEFAULT_NOT_FOUND_CONTENT_TYPE: &str = "text/html; charset=utf-8";
+
+/// The default JSON body for `path`
+pub fn not_found_body(path: &str) -> serde_json::Value {
+    serde_json::json!({ "error": "Not found", "path": path })
+}
+
+/// The configured body and content type, if `NOT_FOUND_BODY` is set
+///
+/// An empty body counts as unset.
+pub fn configured_page(
+    body: Option<String>,
+    content_type: Option<String>,
+) -> Option<(String, String)> {
+    let body = body.filter(|b| !b.is_empty())?;
+    let content_type = content_type
+        .filter(|ct| !ct.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_NOT_FOUND_CONTENT_TYPE.to_string());
+    Some((body, content_type))
+}
+
+/// `404` for `path`, using the custom page if one is configured
+pub async fn not_found_response(env: &Env, path: &str) -> Result<Response> {
+    let var = |name: &str| env.var(name).ok().map(|v| v.to_string());
+    if let Some((body, content_type)) =
+        configured_page(var("NOT_FOUND_BODY"), var("NOT_FOUND_CONTENT_TYPE"))
+    {
+        let headers = Headers::new();
+        headers.set("Content-Type", &content_type)?;
+        return Ok(Response::ok(body)?.with_status(404).with_headers(headers));
+    }
+
+    if let Ok(Some(response)) = stored_page(env).await {
+        return Ok(response);
+    }
+
+    Ok(Response::from_json(&not_found_body(path))?.with_status(404))
+}
+
+/// The `__404__.html` file as a `404`, if it's been uploaded
+async fn stored_page(env: &Env) -> Result<Option<Response>> {
+    let bucket = env.bucket(DEFAULT_BUCKET_BINDING)?;
+    let storage = R2StorageImpl::new(bucket, env.clone());
+    let Some((body, mapping)) = storage
+        .download_stream(NOT_FOUND_PAGE_KEY, None, false)
+        .await?
+    else {
+        return Ok(None);
+    };
+
+    let headers = Headers::new();
+    let content_type = mapping
+        .content_type
+        .as_deref()
+        .unwrap_or(DEFAULT_NOT_FOUND_CONTENT_TYPE);
+    headers.set("Content-Type", content_type)?;
+    Ok(Some(
+        Response::from_body(body)?
+            .with_status(404)
+            .with_headers(headers),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_not_found_body() {
+        assert_eq!(
+            not_found_body("/nope"),
+            serde_json::json!({ "error": "Not found", "path": "/nope" })
+        );
+    }
+
+    #[test]
+    fn test_configured_page() {
+        assert_eq!(configured_page(None, Some("text/plain".into())), None);
+        assert_eq!(configured_page(Some(String::new()), None), None);
+        assert_eq!(
+            configured_page(Some("<h1>Lost?</h1>".into()), None),
+            Some((
+                "<h1>Lost?</h1>".to_string(),
+                DEFAULT_NOT_FOUND_CONTENT_TYPE.to_string()
+            ))
+        );
+        assert_eq!(
+            configured_page(Some("gone".into()), Some("text/plain".into())),
+            Some(("gone".to_string(), "text/plain".to_string()))
+        );
+    }
+}
@@ -0,0 +1,245 @@
+//! Signed URLs
+//!
+//! `POST /files/{path}/download-url` hands out a link to a file that works
+//! without the admin token until it expires, for sharing a file for a while:
+//! `/files/{path}?exp=1700000000&sig=...`. The signature is an HMAC-SHA256,
+//! keyed with `URL_SIGNING_SECRET`, over the method, tenant, path and expiry,
+//! so it can't be moved to another file or tenant, or have its expiry pushed
+//! back. Expired or tampered links get `403`.
+//!
+//! A link signed for `GET` also allows `HEAD`, so clients can probe a file's
+//! size before fetching ranges of it. The query string isn't signed, so a
+//! signed request carrying anything but `exp` and `sig`, such as `?version=`
+//! or `?stat`, is refused rather than let it read more than was shared.
+
+use crate::security::constant_time_eq;
+use crate::sha256::{bytes_to_hex, Sha256};
+use hmac::{Hmac, Mac};
+
+/// How long a signed URL lasts when `X-Expires-In` isn't given, in seconds
+pub const DEFAULT_URL_EXPIRY_SECS: u64 = 3600;
+
+/// The longest a signed URL may last, in seconds (a week)
+pub const MAX_URL_EXPIRY_SECS: u64 = 7 * 24 * 3600;
+
+/// HMAC-SHA256 (RFC 2104) of `message` under `secret`
+pub fn hmac_sha256(secret: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+/// The only query parameters a signed request may carry
+pub const SIGNED_URL_PARAMS: [&str; 2] = ["exp", "sig"];
+
+/// The method a request's signature was made for
+///
+/// `HEAD` reads no more than `GET`, so it's checked against a `GET` signature.
+pub fn signed_method(method: &str) -> &str {
+    match method {
+        "HEAD" => "GET",
+        method => method,
+    }
+}
+
+/// The first query parameter a signed URL doesn't allow, if any
+pub fn unsigned_param<K: AsRef<str>>(keys: impl IntoIterator<Item = K>) -> Option<K> {
+    keys.into_iter()
+        .find(|key| !SIGNED_URL_PARAMS.contains(&key.as_ref()))
+}
+
+/// What a signature covers, one field per line
+///
+/// `path` is everything after `/files/`, bucket segment included.
+pub fn string_to_sign(method: &str, tenant: Option<&str>, path: &str, expires: u64) -> String {
+    format!(
+        "{}\n{}\n{}\n{}",
+        method,
+        tenant.unwrap_or(""),
+        path,
+        expires
+    )
+}
+
+/// The hex signature allowing `method` on `path` until `expires`, in epoch seconds
+pub fn sign(secret: &str, method: &str, tenant: Option<&str>, path: &str, expires: u64) -> String {
+    let message = string_to_sign(method, tenant, path, expires);
+    bytes_to_hex(&hmac_sha256(secret.as_bytes(), message.as_bytes()))
+}
+
+/// How long a new signed URL lasts, from its `X-Expires-In` header
+///
+/// No header gives the default; otherwise it must be between one second and
+/// `MAX_URL_EXPIRY_SECS`.
+pub fn url_expiry_secs(value: Option<&str>) -> Result<u64, &'static str> {
+    match value {
+        None => Ok(DEFAULT_URL_EXPIRY_SECS),
+        Some(value) => match value.trim().parse::<u64>() {
+            Ok(secs) if (1..=MAX_URL_EXPIRY_SECS).contains(&secs) => Ok(secs),
+            _ => Err("X-Expires-In must be between 1 and 604800 seconds"),
+        },
+    }
+}
+
+/// The outcome of checking a signed URL
+#[derive(Debug, PartialEq)]
+pub enum SignatureCheck {
+    Valid,
+    /// The signature is genuine but its time is up
+    Expired,
+    /// The signature doesn't match, or the expiry is missing or malformed
+    Invalid,
+}
+
+/// Check a signed URL's `exp` and `sig` against the request it came with
+///
+/// The signature is checked before the expiry, so `Expired` is only reported
+/// for links this service really issued.
+pub fn verify_signature(
+    secret: &str,
+    method: &str,
+    tenant: Option<&str>,
+    path: &str,
+    expires: Option<&str>,
+    signature: &str,
+    now_secs: u64,
+) -> SignatureCheck {
+    let Some(expires) = expires.and_then(|e| e.parse::<u64>().ok()) else {
+        return SignatureCheck::Invalid;
+    };
+    let expected = sign(secret, method, tenant, path, expires);
+    if !constant_time_eq(
+        signature.to_ascii_lowercase().as_bytes(),
+        expected.as_bytes(),
+    ) {
+        return SignatureCheck::Invalid;
+    }
+    if now_secs >= expires {
+        return SignatureCheck::Expired;
+    }
+    SignatureCheck::Valid
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "s3cret";
+
+    #[test]
+    fn test_hmac_sha256() {
+        // RFC 4231 test cases 1, 2 and 6 (a key longer than a block)
+        assert_eq!(
+            bytes_to_hex(&hmac_sha256(&[0x0b; 20], b"Hi There")),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+        assert_eq!(
+            bytes_to_hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            bytes_to_hex(&hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn test_signed_method() {
+        assert_eq!(signed_method("GET"), "GET");
+        assert_eq!(signed_method("HEAD"), "GET");
+        assert_eq!(signed_method("PUT"), "PUT");
+
+        // A HEAD on a link signed for GET verifies
+        let sig = sign(SECRET, "GET", None, "docs/a.pdf", 1000);
+        assert_eq!(
+            verify_signature(
+                SECRET,
+                signed_method("HEAD"),
+                None,
+                "docs/a.pdf",
+                Some("1000"),
+                &sig,
+                0
+            ),
+            SignatureCheck::Valid
+        );
+    }
+
+    #[test]
+    fn test_unsigned_param() {
+        assert_eq!(unsigned_param(["exp", "sig"]), None);
+        assert_eq!(unsigned_param(Vec::<&str>::new()), None);
+        assert_eq!(unsigned_param(["exp", "sig", "version"]), Some("version"));
+        assert_eq!(unsigned_param(["stat", "exp", "sig"]), Some("stat"));
+        assert_eq!(unsigned_param(["resolve"]), Some("resolve"));
+    }
+
+    #[test]
+    fn test_url_expiry_secs() {
+        assert_eq!(url_expiry_secs(None), Ok(DEFAULT_URL_EXPIRY_SECS));
+        assert_eq!(url_expiry_secs(Some("60")), Ok(60));
+        assert_eq!(url_expiry_secs(Some("604800")), Ok(MAX_URL_EXPIRY_SECS));
+        assert!(url_expiry_secs(Some("604801")).is_err());
+        assert!(url_expiry_secs(Some("0")).is_err());
+        assert!(url_expiry_secs(Some("soon")).is_err());
+    }
+
+    #[test]
+    fn test_verify_signature_accepts_until_expiry() {
+        let sig = sign(SECRET, "GET", None, "docs/a.pdf", 1_000);
+        let check = |sig: &str, now| {
+            verify_signature(SECRET, "GET", None, "docs/a.pdf", Some("1000"), sig, now)
+        };
+        assert_eq!(check(&sig, 999), SignatureCheck::Valid);
+        assert_eq!(check(&sig.to_ascii_uppercase(), 999), SignatureCheck::Valid);
+        assert_eq!(check(&sig, 1_000), SignatureCheck::Expired);
+        assert_eq!(check(&sig, 5_000), SignatureCheck::Expired);
+    }
+
+    #[test]
+    fn test_verify_signature_rejects_tampering() {
+        let sig = sign(SECRET, "GET", Some("acme"), "docs/a.pdf", 1_000);
+        let check = verify_signature;
+        let (acme, other) = (Some("acme"), Some("other"));
+        assert_eq!(
+            check(SECRET, "GET", acme, "docs/a.pdf", Some("1000"), &sig, 0),
+            SignatureCheck::Valid
+        );
+
+        // Every field is bound: expiry, path, method, tenant and secret
+        let tampered = [
+            check(SECRET, "GET", acme, "docs/a.pdf", Some("9999"), &sig, 0),
+            check(SECRET, "GET", acme, "docs/b.pdf", Some("1000"), &sig, 0),
+            check(SECRET, "PUT", acme, "docs/a.pdf", Some("1000"), &sig, 0),
+            check(SECRET, "GET", other, "docs/a.pdf", Some("1000"), &sig, 0),
+            check(SECRET, "GET", None, "docs/a.pdf", Some("1000"), &sig, 0),
+            check("guess", "GET", acme, "docs/a.pdf", Some("1000"), &sig, 0),
+        ];
+        assert!(tampered.iter().all(|c| *c == SignatureCheck::Invalid));
+
+        // A forged signature is reported as invalid even once "expired"
+        let forged = "0".repeat(64);
+        assert_eq!(
+            check(
+                SECRET,
+                "GET",
+                acme,
+                "docs/a.pdf",
+                Some("1000"),
+                &forged,
+                5_000
+            ),
+            SignatureCheck::Invalid
+        );
+        // A missing or malformed expiry can't be checked at all
+        for expires in [None, Some(""), Some("-1"), Some("tomorrow")] {
+            assert_eq!(
+                check(SECRET, "GET", acme, "docs/a.pdf", expires, &sig, 0),
+                SignatureCheck::Invalid
+            );
+        }
+    }
+}
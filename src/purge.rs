@@ -0,0 +1,408 @@
+//! Factory reset for test environments
+//!
+//! `POST /admin/purge` deletes every file mapping and blob, in the default
+//! bucket and each one in `R2_BUCKETS`, for every tenant in `TENANTS`, and
+//! resets the counters and sessions named in the request. Durable Objects
+//! can't be enumerated, so counters and sessions have to be listed:
+//!
+//! ```json
+//! {"counters": ["default", "visits"], "sessions": ["user123"]}
+//! ```
+//!
+//! Without a body only the `default` counter is reset. The endpoint is off,
+//! answering `403`, unless `PURGE_CONFIRM_TOKEN` is set, and then requires that
+//! value in an `X-Confirm-Token` header.
+//!
+//! Each subsystem is purged independently and reported separately, so one
+//! failing doesn't stop the rest and a partial purge is visible in the report.
+//! Blobs are deleted one at a time, so deleting them stops once
+//! `OPERATION_BUDGET_MS` is spent; sending the request again carries on.
+
+use crate::budget::{operation_budget_ms, TimeBudget};
+use crate::error::method_not_allowed;
+use crate::r2_storage::{
+    mapping_instance_name, parse_bucket_config, tenant_blob_isolation, R2StorageImpl,
+    DEFAULT_BUCKET_BINDING,
+};
+use crate::security::constant_time_eq;
+use crate::tenant::parse_tenant_allowlist;
+use serde::{Deserialize, Serialize};
+use worker::{js_sys, Env, Method, Request, RequestInit, Response, Result};
+
+/// Environment variable holding the token that enables purging
+pub const PURGE_TOKEN_VAR: &str = "PURGE_CONFIRM_TOKEN";
+
+/// Request header that must carry the purge token
+pub const CONFIRM_TOKEN_HEADER: &str = "X-Confirm-Token";
+
+/// Whether a purge request's confirm header matches the configured token
+///
+/// Always `false` when no token is configured, so purging is off by default.
+pub fn purge_authorized(header: Option<&str>, token: Option<&str>) -> bool {
+    match (header, token) {
+        (Some(presented), Some(token)) if !token.is_empty() => {
+            constant_time_eq(presented.trim().as_bytes(), token.as_bytes())
+        }
+        _ => false,
+    }
+}
+
+/// The counters and sessions a purge resets
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct PurgeTargets {
+    #[serde(default = "default_counters")]
+    pub counters: Vec<String>,
+    #[serde(default)]
+    pub sessions: Vec<String>,
+}
+
+fn default_counters() -> Vec<String> {
+    vec!["default".to_string()]
+}
+
+/// Parse a purge request body; an empty body resets only the default counter
+pub fn parse_purge_targets(body: &str) -> std::result::Result<PurgeTargets, String> {
+    let body = if body.trim().is_empty() { "{}" } else { body };
+    serde_json::from_str(body).map_err(|e| format!("Invalid purge request: {}", e))
+}
+
+/// One file mapping instance a purge clears
+#[derive(Debug, PartialEq)]
+pub struct PurgeScope {
+    /// Name from `R2_BUCKETS`, or `None` for the default bucket
+    pub bucket_name: Option<String>,
+    pub binding: String,
+    pub tenant: Option<String>,
+    /// Whether this scope's blobs are purged along with it; scopes sharing
+    /// an R2 bucket and blob prefix purge them once
+    pub purge_blobs: bool,
+}
+
+/// Every mapping instance there can be, given `R2_BUCKETS` and `TENANTS`
+///
+/// The untenanted instances are included even with tenants on, so mappings
+/// written before tenancy was turned on go too.
+pub fn purge_scopes(
+    buckets: &[(String, String)],
+    tenants: &[String],
+    isolate_blobs: bool,
+) -> Vec<PurgeScope> {
+    let bucket_scopes = std::iter::once((None, DEFAULT_BUCKET_BINDING.to_string())).chain(
+        buckets
+            .iter()
+            .map(|(name, binding)| (Some(name.clone()), binding.clone())),
+    );
+    let tenant_scopes: Vec<Option<String>> = std::iter::once(None)
+        .chain(tenants.iter().cloned().map(Some))
+        .collect();
+
+    let mut blob_scopes = Vec::new();
+    let mut scopes = Vec::new();
+    for (bucket_name, binding) in bucket_scopes {
+        for tenant in &tenant_scopes {
+            let blob_scope = (binding.clone(), tenant.clone().filter(|_| isolate_blobs));
+            let purge_blobs = !blob_scopes.contains(&blob_scope);
+            if purge_blobs {
+                blob_scopes.push(blob_scope);
+            }
+            scopes.push(PurgeScope {
+                bucket_name: bucket_name.clone(),
+                binding: binding.clone(),
+                tenant: tenant.clone(),
+                purge_blobs,
+            });
+        }
+    }
+    scopes
+}
+
+/// What one subsystem's purge removed, or why it failed
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SubsystemReport {
+    pub purged: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl SubsystemReport {
+    /// Report `purged` items, with the first error if the purge stopped early
+    pub fn new(purged: usize, error: Option<String>) -> Self {
+        Self { purged, error }
+    }
+}
+
+impl From<Result<usize>> for SubsystemReport {
+    fn from(result: Result<usize>) -> Self {
+        match result {
+            Ok(purged) => Self::new(purged, None),
+            Err(e) => Self::new(0, Some(e.to_string())),
+        }
+    }
+}
+
+/// Result of `POST /admin/purge`
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PurgeReport {
+    pub files: SubsystemReport,
+    pub blobs: SubsystemReport,
+    pub counters: SubsystemReport,
+    pub sessions: SubsystemReport,
+    /// `false` if deleting blobs ran out of `OPERATION_BUDGET_MS` with
+    /// blobs left; sending the same request again deletes the rest
+    pub complete: bool,
+}
+
+impl PurgeReport {
+    /// `200` if every subsystem was purged, else `500` so the failure isn't missed
+    pub fn status(&self) -> u16 {
+        let failed = [&self.files, &self.blobs, &self.counters, &self.sessions]
+            .iter()
+            .any(|report| report.error.is_some());
+        if failed {
+            500
+        } else {
+            200
+        }
+    }
+}
+
+/// Handle `POST /admin/purge`
+pub async fn handle_purge_request(mut req: Request, env: Env) -> Result<Response> {
+    if req.method() != Method::Post {
+        return method_not_allowed(&[Method::Post]);
+    }
+    let token = env.var(PURGE_TOKEN_VAR).ok().map(|v| v.to_string());
+    let header = req.headers().get(CONFIRM_TOKEN_HEADER)?;
+    if !purge_authorized(header.as_deref(), token.as_deref()) {
+        return Response::error("Purge is disabled or the confirm token is wrong", 403);
+    }
+    let targets = match parse_purge_targets(&req.text().await?) {
+        Ok(targets) => targets,
+        Err(e) => return Response::error(e, 400),
+    };
+
+    let buckets = parse_bucket_config(env.var("R2_BUCKETS").ok().map(|v| v.to_string()).as_deref());
+    let tenants = parse_tenant_allowlist(env.var("TENANTS").ok().map(|v| v.to_string()).as_deref());
+    let isolate_blobs = tenant_blob_isolation(&env);
+    let mut scopes = Vec::new();
+    let mut scope_error = None;
+    for scope in purge_scopes(&buckets, &tenants, isolate_blobs) {
+        match env.bucket(&scope.binding) {
+            Ok(bucket) => {
+                let storage = R2StorageImpl::new(bucket, env.clone())
+                    .with_bucket_name(scope.bucket_name.as_deref(), &scope.binding)
+                    .with_tenant(scope.tenant.clone(), isolate_blobs);
+                scopes.push((scope, storage));
+            }
+            Err(e) => {
+                scope_error.get_or_insert_with(|| format!("{}: {}", scope.binding, e));
+            }
+        }
+    }
+
+    // Mappings first, so no file is left pointing at a deleted blob
+    let mut files = SubsystemReport::new(0, scope_error.clone());
+    for (scope, storage) in &scopes {
+        match storage.purge_mappings().await {
+            Ok(purged) => files.purged += purged,
+            Err(e) => {
+                let instance =
+                    mapping_instance_name(scope.bucket_name.as_deref(), scope.tenant.as_deref());
+                files.error = Some(format!("{}: {}", instance, e));
+                break;
+            }
+        }
+    }
+
+    let now = || js_sys::Date::now() as u64;
+    let budget = TimeBudget::new(
+        operation_budget_ms(
+            env.var("OPERATION_BUDGET_MS")
+                .ok()
+                .map(|v| v.to_string())
+                .as_deref(),
+        ),
+        &now,
+    );
+    let mut blobs = SubsystemReport::new(0, scope_error);
+    let mut complete = true;
+    // Blobs some mapping may still point at are left alone
+    let blob_scopes = match files.error {
+        Some(_) => {
+            blobs.error = Some("Skipped: not every file mapping was purged".to_string());
+            &scopes[..0]
+        }
+        None => &scopes[..],
+    };
+    for (_, storage) in blob_scopes.iter().filter(|(scope, _)| scope.purge_blobs) {
+        match storage.purge_blobs(&budget).await {
+            Ok((purged, done)) => {
+                blobs.purged += purged;
+                if !done {
+                    complete = false;
+                    break;
+                }
+            }
+            Err(e) => {
+                blobs.error = Some(e.to_string());
+                break;
+            }
+        }
+    }
+
+    let report = PurgeReport {
+        files,
+        blobs,
+        counters: reset_objects(&env, "COUNTER_OBJECT", &targets.counters).await,
+        sessions: reset_objects(&env, "SESSION_OBJECT", &targets.sessions).await,
+        complete,
+    };
+
+    let status = report.status();
+    Ok(Response::from_json(&report)?.with_status(status))
+}
+
+/// Send a `DELETE` to each named object in `binding`, stopping at the first failure
+async fn reset_objects(env: &Env, binding: &str, names: &[String]) -> SubsystemReport {
+    let mut purged = 0;
+    for name in names {
+        if let Err(e) = reset_object(env, binding, name).await {
+            return SubsystemReport::new(purged, Some(format!("{}: {}", name, e)));
+        }
+        purged += 1;
+    }
+    SubsystemReport::new(purged, None)
+}
+
+async fn reset_object(env: &Env, binding: &str, name: &str) -> Result<()> {
+    let stub = env
+        .durable_object(binding)?
+        .id_from_name(name)?
+        .get_stub()?;
+    let request = Request::new_with_init(
+        "https://fake-host/",
+        RequestInit::new().with_method(Method::Delete),
+    )?;
+    let response = stub.fetch_with_request(request).await?;
+    match response.status_code() {
+        status if status >= 400 => Err(worker::Error::RustError(format!(
+            "reset failed with status {}",
+            status
+        ))),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_purge_authorized() {
+        assert!(purge_authorized(Some("s3cret"), Some("s3cret")));
+        assert!(purge_authorized(Some(" s3cret "), Some("s3cret")));
+
+        assert!(!purge_authorized(Some("wrong!"), Some("s3cret")));
+        assert!(!purge_authorized(None, Some("s3cret")));
+        // No token configured means purging is off, whatever the header says
+        assert!(!purge_authorized(Some("s3cret"), None));
+        assert!(!purge_authorized(Some(""), Some("")));
+    }
+
+    #[test]
+    fn test_parse_purge_targets() {
+        assert_eq!(
+            parse_purge_targets("").unwrap(),
+            PurgeTargets {
+                counters: vec!["default".to_string()],
+                sessions: vec![],
+            }
+        );
+        assert_eq!(
+            parse_purge_targets(r#"{"counters": [], "sessions": ["a", "b"]}"#).unwrap(),
+            PurgeTargets {
+                counters: vec![],
+                sessions: vec!["a".to_string(), "b".to_string()],
+            }
+        );
+        assert!(parse_purge_targets(r#"{"sessions": "a"}"#).is_err());
+    }
+
+    fn scope(
+        bucket_name: Option<&str>,
+        binding: &str,
+        tenant: Option<&str>,
+        purge_blobs: bool,
+    ) -> PurgeScope {
+        PurgeScope {
+            bucket_name: bucket_name.map(str::to_string),
+            binding: binding.to_string(),
+            tenant: tenant.map(str::to_string),
+            purge_blobs,
+        }
+    }
+
+    #[test]
+    fn test_purge_scopes() {
+        assert_eq!(
+            purge_scopes(&[], &[], false),
+            vec![scope(None, DEFAULT_BUCKET_BINDING, None, true)]
+        );
+
+        // Every bucket's mappings for every tenant, untenanted included
+        let buckets = vec![("media".to_string(), "MEDIA_BUCKET".to_string())];
+        let tenants = vec!["acme".to_string()];
+        assert_eq!(
+            purge_scopes(&buckets, &tenants, false),
+            vec![
+                scope(None, DEFAULT_BUCKET_BINDING, None, true),
+                scope(None, DEFAULT_BUCKET_BINDING, Some("acme"), false),
+                scope(Some("media"), "MEDIA_BUCKET", None, true),
+                scope(Some("media"), "MEDIA_BUCKET", Some("acme"), false),
+            ]
+        );
+
+        // Isolated tenants have blobs of their own
+        assert_eq!(
+            purge_scopes(&[], &tenants, true),
+            vec![
+                scope(None, DEFAULT_BUCKET_BINDING, None, true),
+                scope(None, DEFAULT_BUCKET_BINDING, Some("acme"), true),
+            ]
+        );
+
+        // A named bucket on the default binding shares its blobs
+        let aliased = vec![("main".to_string(), DEFAULT_BUCKET_BINDING.to_string())];
+        assert_eq!(
+            purge_scopes(&aliased, &[], false),
+            vec![
+                scope(None, DEFAULT_BUCKET_BINDING, None, true),
+                scope(Some("main"), DEFAULT_BUCKET_BINDING, None, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_purge_report_status() {
+        let ok = || SubsystemReport::new(1, None);
+        let mut report = PurgeReport {
+            files: ok(),
+            blobs: ok(),
+            counters: ok(),
+            sessions: ok(),
+            complete: true,
+        };
+        assert_eq!(report.status(), 200);
+
+        report.blobs = SubsystemReport::from(Err(worker::Error::RustError("R2 down".into())));
+        assert_eq!(report.status(), 500);
+        assert_eq!(
+            serde_json::to_value(&report.blobs).unwrap(),
+            serde_json::json!({ "purged": 0, "error": "R2 down" })
+        );
+        assert_eq!(
+            serde_json::to_value(&report.files).unwrap(),
+            serde_json::json!({ "purged": 1 })
+        );
+    }
+}
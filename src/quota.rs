@@ -0,0 +1,185 @@
+//! File count and storage quotas
+//!
+//! `MAX_FILES` caps how many files a bucket (or tenant) holds. A write that
+//! would create a file past the cap gets `507 Insufficient Storage`, with the
+//! current count and the limit in the body; writes to files that already
+//! exist still go through. The `FileMappingObject` enforces the cap when it
+//! writes a mapping, so uploads, copies and reindexes are all covered, and
+//! uploads are also checked before their body is read.
+//!
+//! `MAX_TOTAL_BYTES` caps the bytes stored the same way. By default that's
+//! the sum of file sizes; with `QUOTA_BYTES_METRIC=physical` it's the size of
+//! the unique blobs, so duplicate uploads are free. A write that would take
+//! the total past the cap gets a `507` with the usage and the limit.
+
+use serde::{Deserialize, Serialize};
+use worker::{Response, Result};
+
+/// Which bytes count against `MAX_TOTAL_BYTES`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BytesMetric {
+    /// The sum of every file's size
+    #[default]
+    Logical,
+    /// The sum of each unique blob's size, counted once however many files share it
+    Physical,
+}
+
+/// Body of a `507` for a write past a quota
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum QuotaExceeded {
+    Files {
+        error: String,
+        /// Files stored now
+        count: usize,
+        max_files: usize,
+    },
+    Bytes {
+        error: String,
+        metric: BytesMetric,
+        /// Bytes stored now
+        used_bytes: u64,
+        max_total_bytes: u64,
+    },
+}
+
+impl QuotaExceeded {
+    pub fn files(count: usize, max_files: usize) -> Self {
+        QuotaExceeded::Files {
+            error: "File quota exceeded".to_string(),
+            count,
+            max_files,
+        }
+    }
+
+    pub fn bytes(metric: BytesMetric, used_bytes: u64, max_total_bytes: u64) -> Self {
+        QuotaExceeded::Bytes {
+            error: "Storage quota exceeded".to_string(),
+            metric,
+            used_bytes,
+            max_total_bytes,
+        }
+    }
+}
+
+/// The file quota from `MAX_FILES`
+///
+/// Missing, unparsable or zero values mean no quota.
+pub fn max_files(value: Option<&str>) -> Option<usize> {
+    value
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .filter(|&max| max > 0)
+}
+
+/// Whether a new file would take the count past `max_files`
+pub fn file_quota_exceeded(count: usize, max_files: Option<usize>) -> bool {
+    max_files.is_some_and(|max| count >= max)
+}
+
+/// The storage quota from `MAX_TOTAL_BYTES`
+///
+/// Missing, unparsable or zero values mean no quota.
+pub fn max_total_bytes(value: Option<&str>) -> Option<u64> {
+    value
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .filter(|&max| max > 0)
+}
+
+/// What `QUOTA_BYTES_METRIC` counts; anything but `physical` means logical
+pub fn bytes_metric(value: Option<&str>) -> BytesMetric {
+    match value.map(|v| v.trim().to_ascii_lowercase()).as_deref() {
+        Some("physical") => BytesMetric::Physical,
+        _ => BytesMetric::Logical,
+    }
+}
+
+/// Whether a write taking usage from `before` to `after` bytes breaks the quota
+///
+/// Writes that don't add bytes always go through, so a bucket already over
+/// its quota (say, after the limit was lowered) can still be shrunk.
+pub fn bytes_quota_exceeded(before: u64, after: u64, max_total_bytes: Option<u64>) -> bool {
+    max_total_bytes.is_some_and(|max| after > max && after > before)
+}
+
+/// `507` telling the client a quota is used up
+pub fn quota_exceeded_response(quota: &QuotaExceeded) -> Result<Response> {
+    Ok(Response::from_json(quota)?.with_status(507))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_max_files() {
+        assert_eq!(max_files(None), None);
+        assert_eq!(max_files(Some("1000")), Some(1000));
+        assert_eq!(max_files(Some(" 5 ")), Some(5));
+        assert_eq!(max_files(Some("0")), None);
+        assert_eq!(max_files(Some("lots")), None);
+    }
+
+    #[test]
+    fn test_file_quota_exceeded() {
+        assert!(!file_quota_exceeded(1_000_000, None));
+        assert!(!file_quota_exceeded(9, Some(10)));
+        assert!(file_quota_exceeded(10, Some(10)));
+        assert!(file_quota_exceeded(11, Some(10)));
+    }
+
+    #[test]
+    fn test_quota_exceeded_body() {
+        assert_eq!(
+            serde_json::to_value(QuotaExceeded::files(10, 10)).unwrap(),
+            serde_json::json!({ "error": "File quota exceeded", "count": 10, "max_files": 10 })
+        );
+        assert_eq!(
+            serde_json::to_value(QuotaExceeded::bytes(BytesMetric::Physical, 900, 1000)).unwrap(),
+            serde_json::json!({
+                "error": "Storage quota exceeded",
+                "metric": "physical",
+                "used_bytes": 900,
+                "max_total_bytes": 1000
+            })
+        );
+    }
+
+    #[test]
+    fn test_quota_exceeded_round_trips() {
+        for quota in [
+            QuotaExceeded::files(3, 3),
+            QuotaExceeded::bytes(BytesMetric::Logical, 10, 20),
+        ] {
+            let json = serde_json::to_string(&quota).unwrap();
+            assert_eq!(serde_json::from_str::<QuotaExceeded>(&json).unwrap(), quota);
+        }
+    }
+
+    #[test]
+    fn test_max_total_bytes() {
+        assert_eq!(max_total_bytes(None), None);
+        assert_eq!(max_total_bytes(Some("1073741824")), Some(1 << 30));
+        assert_eq!(max_total_bytes(Some("0")), None);
+        assert_eq!(max_total_bytes(Some("1GB")), None);
+    }
+
+    #[test]
+    fn test_bytes_metric() {
+        assert_eq!(bytes_metric(None), BytesMetric::Logical);
+        assert_eq!(bytes_metric(Some("logical")), BytesMetric::Logical);
+        assert_eq!(bytes_metric(Some("Physical")), BytesMetric::Physical);
+        assert_eq!(bytes_metric(Some("unique")), BytesMetric::Logical);
+    }
+
+    #[test]
+    fn test_bytes_quota_exceeded() {
+        assert!(!bytes_quota_exceeded(0, u64::MAX, None));
+        assert!(!bytes_quota_exceeded(500, 1000, Some(1000)));
+        assert!(bytes_quota_exceeded(500, 1001, Some(1000)));
+        // Shrinking, or staying put, is fine even when over the limit
+        assert!(!bytes_quota_exceeded(1500, 1200, Some(1000)));
+        assert!(!bytes_quota_exceeded(1500, 1500, Some(1000)));
+    }
+}
@@ -0,0 +1,622 @@
+//! Per-key write rate limiting for R2
+//!
+//! R2 allows roughly one write per second to the same key. Content-addressable
+//! storage already avoids most collisions, since identical content maps to the
+//! same blob and is only written once, but concurrent uploads of new content
+//! can still race on the same blob key. This module tracks recent writes per
+//! key and rejects writes that would exceed the limit with a 429 and a
+//! `Retry-After` header.
+//!
+//! Design notes:
+//! - `RateLimiter` is plain Rust so it can be unit tested without the Workers runtime.
+//! - `R2RateLimiterObject` wraps it in a Durable Object so every Worker isolate
+//!   sees the same write history.
+//! - Checks are sharded across `R2_RATE_LIMITER_SHARDS` instances named
+//!   `rate-limiter-shard-{n}`, picked by hashing the whole key. A single instance
+//!   would funnel every write through one Durable Object. R2's limit is per key,
+//!   so sharding stays correct as long as a key always maps to the same shard.
+//!   The first path component isn't used because every blob key starts with `blobs/`.
+//! - `RateLimitMode::Sliding` (the default) keeps a timestamp per write.
+//!   `RateLimitMode::Fixed` keeps a window start and a count per key instead,
+//!   which is cheaper for hot keys but allows bursts across a window boundary.
+//!   Set `R2_RATE_LIMITER_MODE=fixed` to use it.
+//! - Cleanup runs on every `/check`, and also from a Durable Object alarm every
+//!   `R2_RATE_LIMITER_CLEANUP_INTERVAL_MS` (default one minute) so idle keys don't
+//!   hold memory. The alarm stops rescheduling once nothing is tracked, and the
+//!   next `/check` starts it again.
+//! - History is kept in memory only. Losing it on eviction just means a few
+//!   writes may get through early, which R2 itself will reject.
+
+use crate::error::{method_not_allowed, StorageError};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::Duration;
+use worker::*;
+
+/// Number of rate limiter shards when `R2_RATE_LIMITER_SHARDS` isn't set
+const DEFAULT_SHARDS: u32 = 16;
+/// Writes allowed per key within the window (R2's per-key limit)
+const DEFAULT_MAX_WRITES: usize = 1;
+/// Window length in milliseconds
+const DEFAULT_WINDOW_MS: u64 = 1000;
+/// How often the cleanup alarm runs when `R2_RATE_LIMITER_CLEANUP_INTERVAL_MS` isn't set
+const DEFAULT_CLEANUP_INTERVAL_MS: u64 = 60_000;
+/// Number of busiest keys reported by `/stats` when `?top=` isn't given
+const DEFAULT_STATS_TOP_N: usize = 10;
+
+/// How a `RateLimiter` counts writes within a window
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RateLimitMode {
+    /// Count writes in the `window_ms` leading up to now
+    #[default]
+    Sliding,
+    /// Count writes since the start of the current `window_ms`-aligned window
+    Fixed,
+}
+
+impl RateLimitMode {
+    /// Parse a mode name, case-insensitively
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "sliding" => Some(Self::Sliding),
+            "fixed" => Some(Self::Fixed),
+            _ => None,
+        }
+    }
+}
+
+/// Summary of a rate limiter's current state
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RateLimiterStats {
+    /// Number of keys with recorded writes
+    pub tracked_keys: usize,
+    /// Total writes recorded across all keys in their current windows
+    pub total_timestamps: usize,
+    /// Keys with the most recorded writes, busiest first
+    pub top_keys: Vec<KeyStats>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct KeyStats {
+    pub key: String,
+    pub writes: usize,
+}
+
+/// Rate limiter keyed by string
+pub struct RateLimiter {
+    mode: RateLimitMode,
+    /// Write timestamps per key (sliding mode)
+    write_history: HashMap<String, Vec<u64>>,
+    /// Window start and write count per key (fixed mode)
+    window_counts: HashMap<String, (u64, usize)>,
+    max_writes: usize,
+    window_ms: u64,
+}
+
+impl RateLimiter {
+    pub fn new(max_writes: usize, window_ms: u64) -> Self {
+        Self::with_mode(max_writes, window_ms, RateLimitMode::default())
+    }
+
+    pub fn with_mode(max_writes: usize, window_ms: u64, mode: RateLimitMode) -> Self {
+        Self {
+            mode,
+            write_history: HashMap::new(),
+            window_counts: HashMap::new(),
+            max_writes,
+            window_ms,
+        }
+    }
+
+    /// Record a write for `key` at `now` (milliseconds) if it is allowed
+    ///
+    /// Returns `Err(retry_after_ms)` when the key has already used its writes
+    /// for the current window.
+    pub fn check_rate_limit(&mut self, key: &str, now: u64) -> std::result::Result<(), u64> {
+        match self.mode {
+            RateLimitMode::Sliding => self.check_sliding(key, now),
+            RateLimitMode::Fixed => self.check_fixed(key, now),
+        }
+    }
+
+    fn check_fixed(&mut self, key: &str, now: u64) -> std::result::Result<(), u64> {
+        let window_start = now - now % self.window_ms.max(1);
+        let (start, count) = self
+            .window_counts
+            .entry(key.to_string())
+            .or_insert((window_start, 0));
+
+        if *start != window_start {
+            *start = window_start;
+            *count = 0;
+        }
+
+        if *count >= self.max_writes {
+            return Err((window_start + self.window_ms).saturating_sub(now).max(1));
+        }
+
+        *count += 1;
+        Ok(())
+    }
+
+    fn check_sliding(&mut self, key: &str, now: u64) -> std::result::Result<(), u64> {
+        let window_ms = self.window_ms;
+        let history = self.write_history.entry(key.to_string()).or_default();
+        history.retain(|&t| t + window_ms > now);
+
+        if history.len() >= self.max_writes {
+            // The oldest write in the window is the next one to expire
+            let oldest = history.iter().copied().min().unwrap_or(now);
+            return Err((oldest + self.window_ms).saturating_sub(now).max(1));
+        }
+
+        history.push(now);
+        Ok(())
+    }
+
+    /// Drop write history that has fallen outside the window
+    pub fn cleanup(&mut self, now: u64) {
+        let window_ms = self.window_ms;
+        self.write_history.retain(|_, history| {
+            history.retain(|&t| t + window_ms > now);
+            !history.is_empty()
+        });
+        self.window_counts
+            .retain(|_, (start, _)| *start + window_ms > now);
+    }
+
+    /// Whether any writes are currently recorded
+    pub fn is_empty(&self) -> bool {
+        self.write_history.is_empty() && self.window_counts.is_empty()
+    }
+
+    /// Summarize recorded writes, including the `top_n` busiest keys
+    pub fn stats(&self, top_n: usize) -> RateLimiterStats {
+        let mut keys: Vec<KeyStats> = self
+            .write_history
+            .iter()
+            .map(|(key, history)| (key, history.len()))
+            .chain(
+                self.window_counts
+                    .iter()
+                    .map(|(key, (_, count))| (key, *count)),
+            )
+            .map(|(key, writes)| KeyStats {
+                key: key.clone(),
+                writes,
+            })
+            .collect();
+
+        let tracked_keys = keys.len();
+        let total_timestamps = keys.iter().map(|k| k.writes).sum();
+
+        keys.sort_by(|a, b| b.writes.cmp(&a.writes).then_with(|| a.key.cmp(&b.key)));
+        keys.truncate(top_n);
+
+        RateLimiterStats {
+            tracked_keys,
+            total_timestamps,
+            top_keys: keys,
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_WRITES, DEFAULT_WINDOW_MS)
+    }
+}
+
+/// Durable Object holding the shared rate limiter state
+#[durable_object]
+pub struct R2RateLimiterObject {
+    state: State,
+    _env: Env,
+    limiter: RefCell<RateLimiter>,
+    cleanup_interval_ms: u64,
+}
+
+impl R2RateLimiterObject {
+    /// Schedule the cleanup alarm unless one is already pending
+    async fn ensure_cleanup_alarm(&self) -> Result<()> {
+        let storage = self.state.storage();
+        if storage.get_alarm().await?.is_none() {
+            storage
+                .set_alarm(Duration::from_millis(self.cleanup_interval_ms))
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+impl DurableObject for R2RateLimiterObject {
+    fn new(state: State, env: Env) -> Self {
+        let mode = env
+            .var("R2_RATE_LIMITER_MODE")
+            .ok()
+            .and_then(|v| RateLimitMode::parse(&v.to_string()))
+            .unwrap_or_default();
+        let cleanup_interval_ms = env
+            .var("R2_RATE_LIMITER_CLEANUP_INTERVAL_MS")
+            .ok()
+            .and_then(|v| v.to_string().parse().ok())
+            .filter(|&ms| ms > 0)
+            .unwrap_or(DEFAULT_CLEANUP_INTERVAL_MS);
+
+        Self {
+            state,
+            _env: env,
+            limiter: RefCell::new(RateLimiter::with_mode(
+                DEFAULT_MAX_WRITES,
+                DEFAULT_WINDOW_MS,
+                mode,
+            )),
+            cleanup_interval_ms,
+        }
+    }
+
+    async fn fetch(&self, req: Request) -> Result<Response> {
+        let path = req.path();
+
+        if req.method() == Method::Get && path == "/stats" {
+            // Read-only, so it doesn't go through the rate limiter itself
+            let top_n = req
+                .url()?
+                .query_pairs()
+                .find(|(k, _)| k == "top")
+                .and_then(|(_, v)| v.parse().ok())
+                .unwrap_or(DEFAULT_STATS_TOP_N);
+
+            let mut limiter = self.limiter.borrow_mut();
+            limiter.cleanup(js_sys::Date::now() as u64);
+            return Response::from_json(&limiter.stats(top_n));
+        }
+
+        match (req.method(), path.strip_prefix("/check/")) {
+            (Method::Post, Some(key)) if !key.is_empty() => {
+                let now = js_sys::Date::now() as u64;
+                // Release the borrow before awaiting so interleaved requests don't panic
+                let result = {
+                    let mut limiter = self.limiter.borrow_mut();
+                    limiter.cleanup(now);
+                    limiter.check_rate_limit(key, now)
+                };
+
+                self.ensure_cleanup_alarm().await?;
+
+                match result {
+                    Ok(()) => Response::ok("OK"),
+                    Err(retry_after_ms) => rate_limited_response(retry_after_secs(retry_after_ms)),
+                }
+            }
+            (Method::Post, _) => Response::error("Key required", 400),
+            _ => method_not_allowed(&[Method::Post]),
+        }
+    }
+
+    async fn alarm(&self) -> Result<Response> {
+        let now = js_sys::Date::now() as u64;
+        let idle = {
+            let mut limiter = self.limiter.borrow_mut();
+            limiter.cleanup(now);
+            limiter.is_empty()
+        };
+
+        if !idle {
+            self.state
+                .storage()
+                .set_alarm(Duration::from_millis(self.cleanup_interval_ms))
+                .await?;
+        }
+
+        Response::ok("Cleanup complete")
+    }
+}
+
+/// Pick the shard responsible for `key`
+///
+/// Uses FNV-1a so the mapping is stable across deployments and Rust versions.
+pub fn shard_for_key(key: &str, shards: u32) -> u32 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in key.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    (hash % shards.max(1) as u64) as u32
+}
+
+/// Durable Object instance name for the shard responsible for `key`
+pub fn shard_instance_name(key: &str, shards: u32) -> String {
+    format!("rate-limiter-shard-{}", shard_for_key(key, shards))
+}
+
+/// Number of rate limiter shards, from `R2_RATE_LIMITER_SHARDS`
+fn shard_count(env: &Env) -> u32 {
+    env.var("R2_RATE_LIMITER_SHARDS")
+        .ok()
+        .and_then(|v| v.to_string().parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(DEFAULT_SHARDS)
+}
+
+/// Check whether a write to `key` is allowed right now
+///
+/// Returns `Some(retry_after_secs)` when the write should be rejected. If the
+/// rate limiter Durable Object isn't bound, rate limiting is skipped.
+pub async fn check_r2_rate_limit(env: &Env, key: &str) -> Result<Option<u64>> {
+    let namespace = match env.durable_object("R2_RATE_LIMITER_OBJECT") {
+        Ok(ns) => ns,
+        Err(_) => return Ok(None),
+    };
+
+    let id = namespace.id_from_name(&shard_instance_name(key, shard_count(env)))?;
+    let stub = id.get_stub()?;
+
+    let request = Request::new_with_init(
+        &format!("https://fake-host/check/{}", key),
+        RequestInit::new().with_method(Method::Post),
+    )?;
+    let response = stub.fetch_with_request(request).await?;
+
+    if response.status_code() == 429 {
+        let retry_after = response
+            .headers()
+            .get("Retry-After")?
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(1);
+        return Ok(Some(retry_after));
+    }
+
+    Ok(None)
+}
+
+/// Build a 429 response with a `Retry-After` header
+pub fn rate_limited_response(retry_after_secs: u64) -> Result<Response> {
+    let headers = Headers::new();
+    headers.set("Retry-After", &retry_after_secs.to_string())?;
+    Ok(Response::error("Rate limited", 429)?.with_headers(headers))
+}
+
+/// Build the error returned by write paths when a key is rate limited
+///
+/// `worker::Error` can't carry custom types, so the `Retry-After` value travels
+/// in the message of a 429 `Error::Json`. See `rate_limit_retry_after`.
+pub fn rate_limited_error(retry_after_secs: u64) -> Error {
+    StorageError::RateLimited { retry_after_secs }.into()
+}
+
+/// Extract the `Retry-After` seconds from an error built by `rate_limited_error`
+pub fn rate_limit_retry_after(err: &Error) -> Option<u64> {
+    match err {
+        Error::Json((message, 429)) => message.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Convert a retry delay in milliseconds to whole `Retry-After` seconds
+pub fn retry_after_secs(retry_after_ms: u64) -> u64 {
+    ((retry_after_ms + 999) / 1000).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter_allows_first_write() {
+        let mut limiter = RateLimiter::default();
+        assert!(limiter.check_rate_limit("blobs/abc", 1000).is_ok());
+    }
+
+    #[test]
+    fn test_rate_limiter_rejects_within_window() {
+        let mut limiter = RateLimiter::default();
+        assert!(limiter.check_rate_limit("blobs/abc", 1000).is_ok());
+
+        // Second write 300ms later has to wait the remaining 700ms
+        assert_eq!(limiter.check_rate_limit("blobs/abc", 1300), Err(700));
+
+        // Rejected writes aren't recorded, so the window still ends at 2000
+        assert!(limiter.check_rate_limit("blobs/abc", 2000).is_ok());
+    }
+
+    #[test]
+    fn test_rate_limiter_keys_are_independent() {
+        let mut limiter = RateLimiter::default();
+        assert!(limiter.check_rate_limit("blobs/abc", 1000).is_ok());
+        assert!(limiter.check_rate_limit("blobs/def", 1000).is_ok());
+        assert!(limiter.check_rate_limit("blobs/abc", 1000).is_err());
+    }
+
+    #[test]
+    fn test_rate_limiter_multiple_writes_per_window() {
+        let mut limiter = RateLimiter::new(3, 1000);
+        assert!(limiter.check_rate_limit("key", 0).is_ok());
+        assert!(limiter.check_rate_limit("key", 100).is_ok());
+        assert!(limiter.check_rate_limit("key", 200).is_ok());
+        assert_eq!(limiter.check_rate_limit("key", 300), Err(700));
+        assert!(limiter.check_rate_limit("key", 1001).is_ok());
+    }
+
+    #[test]
+    fn test_rate_limiter_cleanup() {
+        let mut limiter = RateLimiter::default();
+        limiter.check_rate_limit("old", 1000).unwrap();
+        limiter.check_rate_limit("new", 2500).unwrap();
+
+        limiter.cleanup(3000);
+        assert!(!limiter.write_history.contains_key("old"));
+        assert!(limiter.write_history.contains_key("new"));
+
+        limiter.cleanup(10_000);
+        assert!(limiter.write_history.is_empty());
+    }
+
+    #[test]
+    fn test_fixed_window_rejects_within_window() {
+        let mut limiter = RateLimiter::with_mode(2, 1000, RateLimitMode::Fixed);
+        assert!(limiter.check_rate_limit("key", 1100).is_ok());
+        assert!(limiter.check_rate_limit("key", 1200).is_ok());
+
+        // Retry once the window that started at 1000 ends
+        assert_eq!(limiter.check_rate_limit("key", 1300), Err(700));
+        assert!(limiter.check_rate_limit("key", 2000).is_ok());
+    }
+
+    #[test]
+    fn test_sliding_and_fixed_differ_at_window_boundary() {
+        let mut sliding = RateLimiter::with_mode(1, 1000, RateLimitMode::Sliding);
+        let mut fixed = RateLimiter::with_mode(1, 1000, RateLimitMode::Fixed);
+
+        assert!(sliding.check_rate_limit("key", 1900).is_ok());
+        assert!(fixed.check_rate_limit("key", 1900).is_ok());
+
+        // 100ms later a new fixed window has started, but the sliding window
+        // still contains the previous write
+        assert_eq!(sliding.check_rate_limit("key", 2000), Err(900));
+        assert!(fixed.check_rate_limit("key", 2000).is_ok());
+    }
+
+    #[test]
+    fn test_fixed_window_cleanup() {
+        let mut limiter = RateLimiter::with_mode(1, 1000, RateLimitMode::Fixed);
+        limiter.check_rate_limit("old", 500).unwrap();
+        limiter.check_rate_limit("new", 1500).unwrap();
+
+        limiter.cleanup(1500);
+        assert!(!limiter.window_counts.contains_key("old"));
+        assert!(limiter.window_counts.contains_key("new"));
+        assert!(limiter.write_history.is_empty());
+    }
+
+    #[test]
+    fn test_rate_limiter_is_empty() {
+        let mut limiter = RateLimiter::default();
+        assert!(limiter.is_empty());
+
+        limiter.check_rate_limit("key", 1000).unwrap();
+        assert!(!limiter.is_empty());
+
+        limiter.cleanup(2000);
+        assert!(limiter.is_empty());
+
+        let mut fixed = RateLimiter::with_mode(1, 1000, RateLimitMode::Fixed);
+        fixed.check_rate_limit("key", 1000).unwrap();
+        assert!(!fixed.is_empty());
+    }
+
+    #[test]
+    fn test_rate_limiter_stats() {
+        let mut limiter = RateLimiter::new(3, 1000);
+        limiter.check_rate_limit("a", 0).unwrap();
+        limiter.check_rate_limit("b", 0).unwrap();
+        limiter.check_rate_limit("b", 10).unwrap();
+        limiter.check_rate_limit("c", 0).unwrap();
+        limiter.check_rate_limit("c", 10).unwrap();
+        limiter.check_rate_limit("c", 20).unwrap();
+
+        let stats = limiter.stats(2);
+        assert_eq!(stats.tracked_keys, 3);
+        assert_eq!(stats.total_timestamps, 6);
+        assert_eq!(
+            stats.top_keys,
+            vec![
+                KeyStats {
+                    key: "c".to_string(),
+                    writes: 3
+                },
+                KeyStats {
+                    key: "b".to_string(),
+                    writes: 2
+                },
+            ]
+        );
+
+        // Computing stats doesn't record writes
+        assert_eq!(limiter.stats(10).total_timestamps, 6);
+    }
+
+    #[test]
+    fn test_rate_limiter_stats_fixed_mode() {
+        let mut limiter = RateLimiter::with_mode(2, 1000, RateLimitMode::Fixed);
+        limiter.check_rate_limit("a", 0).unwrap();
+        limiter.check_rate_limit("a", 10).unwrap();
+
+        let stats = limiter.stats(10);
+        assert_eq!(stats.tracked_keys, 1);
+        assert_eq!(stats.total_timestamps, 2);
+
+        let empty = RateLimiter::default().stats(10);
+        assert_eq!(empty.tracked_keys, 0);
+        assert!(empty.top_keys.is_empty());
+    }
+
+    #[test]
+    fn test_rate_limit_mode_parse() {
+        assert_eq!(RateLimitMode::default(), RateLimitMode::Sliding);
+        assert_eq!(RateLimitMode::parse("fixed"), Some(RateLimitMode::Fixed));
+        assert_eq!(
+            RateLimitMode::parse("Sliding"),
+            Some(RateLimitMode::Sliding)
+        );
+        assert_eq!(RateLimitMode::parse("token-bucket"), None);
+    }
+
+    #[test]
+    fn test_retry_after_secs() {
+        assert_eq!(retry_after_secs(0), 1);
+        assert_eq!(retry_after_secs(1), 1);
+        assert_eq!(retry_after_secs(1000), 1);
+        assert_eq!(retry_after_secs(1001), 2);
+    }
+
+    #[test]
+    fn test_rate_limited_error_round_trip() {
+        let err = rate_limited_error(3);
+        assert_eq!(rate_limit_retry_after(&err), Some(3));
+
+        let other = Error::RustError("boom".to_string());
+        assert_eq!(rate_limit_retry_after(&other), None);
+
+        let other_status = Error::Json(("3".to_string(), 400));
+        assert_eq!(rate_limit_retry_after(&other_status), None);
+    }
+
+    #[test]
+    fn test_shard_for_key_is_stable() {
+        // Pinned values: changing the hash would move keys between shards and
+        // briefly let concurrent writes to the same key through
+        assert_eq!(shard_for_key("", 16), 5);
+        assert_eq!(shard_for_key("blobs/abc", 16), 4);
+        assert_eq!(
+            shard_for_key(
+                "blobs/e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+                16
+            ),
+            7
+        );
+        assert_eq!(shard_instance_name("blobs/abc", 16), "rate-limiter-shard-4");
+    }
+
+    #[test]
+    fn test_shard_for_key_range() {
+        for i in 0..100 {
+            let key = format!("blobs/{:064x}", i);
+            assert!(shard_for_key(&key, 16) < 16);
+            assert_eq!(shard_for_key(&key, 1), 0);
+        }
+
+        // Zero shards is treated as one rather than dividing by zero
+        assert_eq!(shard_for_key("blobs/abc", 0), 0);
+    }
+
+    #[test]
+    fn test_shard_for_key_spreads_blob_keys() {
+        // Blob keys share a prefix, so they must still spread across shards
+        let shards: std::collections::HashSet<u32> = (0..100)
+            .map(|i| shard_for_key(&format!("blobs/{:064x}", i), 16))
+            .collect();
+        assert!(shards.len() > 8);
+    }
+}
@@ -1,10 +1,112 @@
-use crate::file_mapping_object::FileMapping;
-use crate::sha256::compute_sha256;
+use crate::access_log::{record_access, AccessLogEntry};
+use crate::budget::{operation_budget_ms, TimeBudget};
+use crate::content_digest::{crc32c_digest_header, preferred_digest, DigestAlgorithm};
+use crate::error::{method_not_allowed, storage_error_response, StorageError};
+use crate::file_mapping_object::{
+    EncodedVariant, FileMapping, FileVersion, ListQuery, MappingPage, StorageStats, StorageUsage,
+};
+use crate::gzip::{gzip_compress, gzip_decompress};
+use crate::html_listing::listing_html;
+use crate::http_date::{
+    format_http_date, parse_http_date, request_wants_iso_timestamps, timestamped_json,
+};
+use crate::http_range::{
+    accept_ranges, parse_range, unsatisfiable_content_range, ByteRange, RangeRequest,
+};
+use crate::metrics::{self, MetricsEvent};
+use crate::negotiate::{accepts_media, message_response};
+use crate::presign::{
+    sign, signed_method, unsigned_param, url_expiry_secs, verify_signature, SignatureCheck,
+};
+use crate::quota::{file_quota_exceeded, max_files, quota_exceeded_response, QuotaExceeded};
+use crate::r2_rate_limiter::{check_r2_rate_limit, rate_limited_error};
+use crate::security::{fold_path_case, is_admin_authorized, is_valid_sha256, sanitize_path};
+use crate::sha256::{bytes_to_hex, compute_sha256, Digest, Sha256};
+use crate::stream_buffer::{max_buffered_chunks, read_ahead};
+use crate::tenant::{
+    parse_tenant_allowlist, resolve_tenant, tenant_blob_prefix, TenantResolution, TENANT_HEADER,
+};
+use crate::upload_object::{
+    new_upload_id, parse_upload_route, staging_key, UploadChunk, UploadRoute, UploadState,
+};
+use crate::zip_archive::{archive_filename, fits_zip32, Crc32, ZipWriter};
 use async_trait::async_trait;
+use futures_util::{stream, Stream, StreamExt};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
 use wasm_bindgen::JsValue;
 use worker::*;
 
+/// Upper bound on total time spent sleeping between rate-limit retries, in seconds
+///
+/// Keeps a retrying upload well inside the Worker's wall-time budget.
+pub const MAX_RETRY_WAIT_SECS: u64 = 10;
+/// Files up to this size are buffered and verified on download unless
+/// `VERIFY_MAX_BYTES` says otherwise; larger files are streamed
+pub const DEFAULT_VERIFY_MAX_BYTES: usize = 10 * 1024 * 1024;
+/// File served for directory-like paths unless `INDEX_FILE` says otherwise
+pub const DEFAULT_INDEX_FILE: &str = "index.html";
+/// `Cache-Control` for `/blobs/{sha256}` unless `BLOB_CACHE_CONTROL` says
+/// otherwise; a blob's content can never change
+pub const DEFAULT_BLOB_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+/// `Cache-Control` for named files unless `FILE_CACHE_CONTROL` says otherwise;
+/// a key can be overwritten, so caches revalidate using the ETag
+pub const DEFAULT_FILE_CACHE_CONTROL: &str = "public, max-age=0, must-revalidate";
+/// Charset added to textual content types unless `TEXT_CHARSET` says otherwise
+pub const DEFAULT_TEXT_CHARSET: &str = "utf-8";
+/// Deepest supported `BLOB_SHARD_DEPTH`; each level is two hex characters
+pub const MAX_BLOB_SHARD_DEPTH: usize = 4;
+/// Largest file that can be stored inline in its mapping, whatever `INLINE_MAX_BYTES` says
+pub const MAX_INLINE_BYTES: usize = 256;
+/// Where a reindex maps blobs that no file points at, as `recovered/{sha256}`
+pub const RECOVERED_PREFIX: &str = "recovered/";
+/// Binding of the bucket used when a path doesn't name one from `R2_BUCKETS`
+pub const DEFAULT_BUCKET_BINDING: &str = "FILES_BUCKET";
+
+/// Maximum number of keys accepted by one `POST /files/exists` request
+pub const MAX_EXISTS_KEYS: usize = 100;
+/// Blobs checked per `POST /files/verify` call when `?limit` isn't given
+pub const DEFAULT_VERIFY_SCAN_LIMIT: usize = 100;
+/// Most blobs one `POST /files/verify` call may check
+pub const MAX_VERIFY_SCAN_LIMIT: usize = 1000;
+/// Bytes one `POST /files/verify` call reads before handing back a cursor
+pub const VERIFY_SCAN_MAX_BYTES: usize = 64 * 1024 * 1024;
+/// Top-level keys under `/files/` that name an endpoint rather than a file
+///
+/// `GET /files/stats` and `POST /files/verify` and friends are matched before
+/// downloads and uploads, so a file stored under one of these could never be
+/// reached. Uploads, copies and reindexing refuse them instead.
+pub const RESERVED_FILE_KEYS: [&str; 5] = ["stats", "verify", "reindex", "hash", "exists"];
+
+/// Whether `key` is one of `RESERVED_FILE_KEYS`
+pub fn is_reserved_file_key(key: &str) -> bool {
+    RESERVED_FILE_KEYS.contains(&key)
+}
+
+/// `400` for a write to a reserved key
+fn reserved_key_response(key: &str) -> Result<Response> {
+    Response::error(
+        format!("'{}' is reserved and can't be a filename", key),
+        400,
+    )
+}
+
+/// Header prefixes accepted for user metadata on upload
+const METADATA_HEADER_PREFIXES: [&str; 2] = ["x-amz-meta-", "x-meta-"];
+/// Header prefix used when echoing user metadata on download
+const METADATA_RESPONSE_PREFIX: &str = "X-Meta-";
+/// Upload header giving the number of seconds until the file expires
+pub const EXPIRES_IN_HEADER: &str = "X-Expires-In";
+
+/// Metadata entry holding the name a file was uploaded as, before case folding
+pub const ORIGINAL_NAME_METADATA: &str = "original-name";
+/// Maximum number of user metadata entries per file
+pub const MAX_METADATA_ENTRIES: usize = 32;
+/// Maximum combined size of user metadata keys and values, in bytes
+pub const MAX_METADATA_BYTES: usize = 2048;
+
 #[derive(Serialize, Deserialize)]
 pub struct FileMetadata {
     pub key: String,
@@ -12,6 +114,12 @@ pub struct FileMetadata {
     pub content_type: Option<String>,
     pub uploaded_at: u64,
     pub sha256: Option<String>,
+    pub metadata: HashMap<String, String>,
+    /// Whether the upload created a new file rather than overwriting one
+    #[serde(skip)]
+    pub was_created: bool,
+    /// Whether the content was already stored, so no new blob was written
+    pub deduplicated: bool,
 }
 
 /// Trait for R2 operations to enable testing
@@ -22,75 +130,550 @@ pub trait R2Storage {
         key: &str,
         data: Vec<u8>,
         content_type: Option<&str>,
+        metadata: HashMap<String, String>,
+        expires_at: Option<u64>,
     ) -> Result<FileMetadata>;
-    async fn download(&self, key: &str) -> Result<Option<(Vec<u8>, String, Option<String>)>>;
+    async fn stat(&self, key: &str) -> Result<Option<FileMapping>>;
+    /// Download a file for streaming to a client
+    ///
+    /// With `accept_gzip`, a compressed blob's bytes are returned as stored;
+    /// the returned mapping's `compressed` flag says whether that happened.
+    async fn download_stream(
+        &self,
+        key: &str,
+        verify_limit: Option<usize>,
+        accept_gzip: bool,
+    ) -> Result<Option<(ResponseBody, FileMapping)>>;
+    async fn download_version(
+        &self,
+        key: &str,
+        version: u32,
+    ) -> Result<Option<(Vec<u8>, FileMapping)>>;
+    async fn list_versions(&self, key: &str) -> Result<Vec<FileVersion>>;
+    async fn download_by_hash(&self, sha256: &str) -> Result<Option<(Vec<u8>, Option<String>)>>;
+    async fn stat_blob(&self, sha256: &str) -> Result<Option<BlobInfo>>;
+    async fn read_blob_range(&self, sha256: &str, range: ByteRange)
+        -> Result<Option<ResponseBody>>;
     async fn delete(&self, key: &str) -> Result<()>;
     async fn list(&self, prefix: Option<&str>) -> Result<Vec<String>>;
+    async fn list_mappings(&self, prefix: Option<&str>) -> Result<Vec<FileMapping>>;
+    async fn list_page(&self, prefix: Option<&str>, query: &ListQuery) -> Result<FilePage>;
+    async fn stats(&self) -> Result<StorageStats>;
+    async fn copy(&self, source: &str, destination: &str, overwrite: bool) -> Result<CopyResult>;
+    async fn restore(&self, key: &str) -> Result<Option<FileMapping>>;
+    /// Every blob stored in R2, by SHA256
+    async fn list_blobs(&self) -> Result<BTreeMap<String, BlobInfo>>;
+    /// Point `key` at an existing blob without writing to R2
+    async fn recreate_mapping(
+        &self,
+        key: &str,
+        sha256: &str,
+        size: usize,
+        blob: &BlobInfo,
+    ) -> Result<()>;
+}
+
+/// A stored blob's size and type, from its R2 object metadata
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlobInfo {
+    /// Size in R2, which is the compressed size if `compressed`
+    pub size: u64,
+    pub content_type: Option<String>,
+    pub compressed: bool,
+}
+
+/// A blob the integrity scan found a problem with, and the files using it
+#[derive(Serialize, Debug, PartialEq)]
+pub struct BlobProblem {
+    pub sha256: String,
+    pub files: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Result of one `POST /files/verify` call
+#[derive(Serialize, Debug, PartialEq)]
+pub struct IntegrityReport {
+    /// Blobs checked by this call
+    pub scanned: usize,
+    pub ok: usize,
+    /// Blobs whose content doesn't match their hash or can't be decoded
+    pub corrupt: Vec<BlobProblem>,
+    /// Blobs missing from R2, leaving their mappings orphaned
+    pub missing: Vec<BlobProblem>,
+    /// Pass as `?cursor` to continue the scan; `None` once it's complete
+    pub next_cursor: Option<String>,
+    /// Whether this call stopped before its batch was done to stay inside
+    /// `OPERATION_BUDGET_MS`; `next_cursor` resumes after the last blob checked
+    pub budget_exhausted: bool,
+}
+
+/// Where a file's content is stored, from `GET /files/{path}?resolve=blob`
+#[derive(Serialize, Debug, PartialEq)]
+pub struct BlobResolution {
+    pub filename: String,
+    pub sha256: String,
+    /// R2 key holding the blob, or where it should be if it's missing
+    pub blob_key: String,
+    /// Whether the blob is in R2; `false` leaves the mapping orphaned
+    pub exists: bool,
+    /// Content kept in the mapping itself, so there's no blob to find
+    pub inline: bool,
+}
+
+impl BlobResolution {
+    /// Describe `mapping`'s blob, found at `found_key` or expected at `expected_key`
+    pub fn new(mapping: &FileMapping, found_key: Option<String>, expected_key: String) -> Self {
+        BlobResolution {
+            filename: mapping.filename.clone(),
+            sha256: mapping.sha256.clone(),
+            exists: found_key.is_some(),
+            blob_key: found_key.unwrap_or(expected_key),
+            inline: mapping.inline.is_some(),
+        }
+    }
+}
+
+/// Mappings a reindex would recreate, and what it leaves alone
+#[derive(Debug, PartialEq)]
+pub struct ReindexPlan {
+    /// `(filename, sha256)` pairs from the manifest whose blob exists
+    pub restore: Vec<(String, String)>,
+    /// Blobs no file points at, to be mapped under `RECOVERED_PREFIX`
+    pub recover: Vec<String>,
+    /// Manifest entries for files that already exist
+    pub unchanged: usize,
+    /// Manifest filenames whose blob isn't in R2
+    pub missing: Vec<String>,
+}
+
+/// Result of `POST /files/reindex`
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ReindexReport {
+    /// Blobs found in R2
+    pub blobs: usize,
+    /// Mappings recreated from the manifest
+    pub restored: usize,
+    /// Mappings created under `recovered/` for blobs no file pointed at
+    pub recovered: usize,
+    /// Total mappings written, `restored` plus `recovered`
+    pub rebuilt: usize,
+    pub unchanged: usize,
+    pub missing: Vec<String>,
+    /// `false` if the call ran out of `OPERATION_BUDGET_MS` with mappings
+    /// still to write; sending the same manifest again picks up the rest
+    pub complete: bool,
+}
+
+/// One page of a sorted file listing, as returned by `GET /files/?sort=...`
+#[derive(Serialize, Debug, PartialEq)]
+pub struct FilePage {
+    pub files: Vec<String>,
+    /// Number of files across all pages
+    pub total: usize,
+    /// `?offset` of the next page, or `None` on the last one
+    pub next_offset: Option<usize>,
+}
+
+impl From<MappingPage> for FilePage {
+    fn from(page: MappingPage) -> Self {
+        FilePage {
+            files: page.mappings.into_iter().map(|m| m.filename).collect(),
+            total: page.total,
+            next_offset: page.next_offset,
+        }
+    }
+}
+
+/// Whether a key exists, as reported by `POST /files/exists`
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ExistsStatus {
+    pub exists: bool,
+    pub sha256: Option<String>,
+}
+
+/// Outcome of copying one file mapping to another key
+pub enum CopyResult {
+    Copied(Box<FileMapping>),
+    SourceNotFound,
+    DestinationExists,
+}
+
+/// A file's mapping and content in one JSON object, for `?envelope=true`
+#[derive(Serialize, Debug)]
+pub struct FileEnvelope {
+    pub metadata: FileMapping,
+    pub content_base64: String,
+}
+
+/// Outcome of fetching a file as an envelope
+pub enum EnvelopeResult {
+    Found(Box<FileEnvelope>),
+    NotFound,
+    /// The file is too big to base64 into a JSON response
+    TooLarge {
+        size: usize,
+        max_bytes: usize,
+    },
+}
+
+/// Request body for the copy and move operations
+#[derive(Deserialize)]
+pub struct CopyRequest {
+    pub destination: String,
+    #[serde(default)]
+    pub overwrite: bool,
 }
 
 pub struct R2StorageImpl {
     bucket: Bucket,
     env: Env,
+    /// Name of the bucket from `R2_BUCKETS`, or `None` for the default bucket
+    bucket_name: Option<String>,
+    /// Binding the bucket was resolved from, for work that outlives the request
+    binding: String,
+    /// Tenant from `TENANTS` whose files this serves, if tenancy is on
+    tenant: Option<String>,
+    /// Prepended to blob keys; empty unless blobs are isolated per tenant
+    blob_prefix: String,
 }
 
 impl R2StorageImpl {
     pub fn new(bucket: Bucket, env: Env) -> Self {
-        Self { bucket, env }
+        Self {
+            bucket,
+            env,
+            bucket_name: None,
+            binding: DEFAULT_BUCKET_BINDING.to_string(),
+            tenant: None,
+            blob_prefix: String::new(),
+        }
     }
 
-    async fn get_file_mapping_stub(&self) -> Result<worker::durable::Stub> {
-        let namespace = self.env.durable_object("FILE_MAPPING_OBJECT")?;
-        let id = namespace.id_from_name("global")?; // Single global mapping instance
-        id.get_stub()
+    /// Serve one tenant's files, optionally with blobs kept apart from others'
+    pub fn with_tenant(mut self, tenant: Option<String>, isolate_blobs: bool) -> Self {
+        self.blob_prefix = match &tenant {
+            Some(t) if isolate_blobs => tenant_blob_prefix(t),
+            _ => String::new(),
+        };
+        self.tenant = tenant;
+        self
     }
-}
 
-#[async_trait(?Send)]
-impl R2Storage for R2StorageImpl {
-    async fn upload(
+    /// R2 key of a blob, within this tenant's blobs if they're isolated
+    fn scoped_blob_key(&self, sha256: &str, depth: usize) -> String {
+        format!("{}{}", self.blob_prefix, blob_key(sha256, depth))
+    }
+
+    /// Use a named bucket, with file mappings kept apart from other buckets'
+    pub fn with_bucket_name(mut self, name: Option<&str>, binding: &str) -> Self {
+        self.bucket_name = name.map(str::to_string);
+        self.binding = binding.to_string();
+        self
+    }
+
+    /// Record a mutating operation on `key` in the access log
+    ///
+    /// Never fails; see `record_access`.
+    async fn log_access(
         &self,
+        req: &Request,
+        operation: &str,
         key: &str,
-        data: Vec<u8>,
-        content_type: Option<&str>,
-    ) -> Result<FileMetadata> {
-        let size = data.len();
+        source: Option<&str>,
+        file: Option<(&str, usize)>,
+    ) {
+        let qualify = |key: &str| {
+            self.file_path(key)
+                .trim_start_matches("/files/")
+                .to_string()
+        };
+        let entry = AccessLogEntry {
+            timestamp: js_sys::Date::now() as u64,
+            method: req.method().to_string(),
+            operation: operation.to_string(),
+            key: qualify(key),
+            source: source.map(qualify),
+            sha256: file.map(|(sha256, _)| sha256.to_string()),
+            size: file.map(|(_, size)| size),
+            request_id: req.headers().get("CF-Ray").ok().flatten(),
+        };
+        record_access(&self.env, &entry).await;
+    }
 
-        // Compute SHA256 of the content
-        console_log!("Computing SHA256 for key: {}", key);
-        let sha256 = compute_sha256(&data).await?;
-        let blob_key = format!("blobs/{}", &sha256);
+    /// Public path of a file in this bucket, e.g. for `Location` headers
+    fn file_path(&self, key: &str) -> String {
+        match &self.bucket_name {
+            Some(name) => format!("/files/{}/{}", name, key),
+            None => format!("/files/{}", key),
+        }
+    }
 
-        console_log!("SHA256 for {}: {}", key, &sha256);
+    /// Number of times to retry a rate-limited blob write, from `R2_WRITE_RETRIES`
+    fn write_retries(&self) -> u32 {
+        self.env
+            .var("R2_WRITE_RETRIES")
+            .ok()
+            .and_then(|v| v.to_string().parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Largest file verified on download, from `VERIFY_MAX_BYTES`
+    fn verify_max_bytes(&self) -> usize {
+        self.env
+            .var("VERIFY_MAX_BYTES")
+            .ok()
+            .and_then(|v| v.to_string().parse().ok())
+            .unwrap_or(DEFAULT_VERIFY_MAX_BYTES)
+    }
 
-        // Check if blob already exists using conditional put
-        let existing_object = self.bucket.get(&blob_key).execute().await?;
+    /// Filename served for directory-like paths, from `INDEX_FILE`
+    fn index_file(&self) -> String {
+        self.env_string("INDEX_FILE")
+            .unwrap_or_else(|| DEFAULT_INDEX_FILE.to_string())
+    }
 
-        if existing_object.is_none() {
-            // Blob doesn't exist, write it
-            console_log!("Writing new blob: {}", blob_key);
-            let mut put_request = self.bucket.put(&blob_key, data);
+    /// `Cache-Control` for content-addressed blobs, from `BLOB_CACHE_CONTROL`
+    fn blob_cache_control(&self) -> String {
+        self.env_string("BLOB_CACHE_CONTROL")
+            .unwrap_or_else(|| DEFAULT_BLOB_CACHE_CONTROL.to_string())
+    }
 
-            // Add content-type to blob metadata if provided
-            if let Some(ct) = content_type {
-                let metadata = HttpMetadata {
-                    content_type: Some(ct.to_string()),
-                    ..Default::default()
-                };
-                put_request = put_request.http_metadata(metadata);
-            }
+    /// `Cache-Control` for named files, from `FILE_CACHE_CONTROL`
+    fn file_cache_control(&self) -> String {
+        self.env_string("FILE_CACHE_CONTROL")
+            .unwrap_or_else(|| DEFAULT_FILE_CACHE_CONTROL.to_string())
+    }
+
+    /// Charset added to textual content types on download, from `TEXT_CHARSET`
+    ///
+    /// `none` turns it off, serving the stored content type unchanged.
+    fn text_charset(&self) -> Option<String> {
+        match self.env_string("TEXT_CHARSET") {
+            Some(v) if v.eq_ignore_ascii_case("none") => None,
+            Some(v) => Some(v),
+            None => Some(DEFAULT_TEXT_CHARSET.to_string()),
+        }
+    }
+
+    /// Whether new text blobs are gzip-compressed in R2, from `GZIP_ON_STORE`
+    fn gzip_on_store(&self) -> bool {
+        self.env_string("GZIP_ON_STORE").as_deref() == Some("true")
+    }
+
+    /// Largest file accepted by a single upload, from `MAX_UPLOAD_BYTES`
+    fn max_upload_bytes(&self) -> Option<u64> {
+        max_upload_bytes(self.env_string("MAX_UPLOAD_BYTES").as_deref())
+    }
+
+    /// Whether keys are lowercased so they match case-insensitively, from `CASE_INSENSITIVE_KEYS`
+    fn case_insensitive_keys(&self) -> bool {
+        self.env_string("CASE_INSENSITIVE_KEYS").as_deref() == Some("true")
+    }
 
-            put_request.execute().await?;
+    /// Fold `key` to lowercase if keys are case-insensitive
+    fn fold_key<'k>(&self, key: &'k str) -> Cow<'k, str> {
+        if self.case_insensitive_keys() {
+            fold_path_case(key)
         } else {
-            console_log!("Blob already exists: {}", blob_key);
+            Cow::Borrowed(key)
         }
+    }
 
-        // Update the filename->SHA256 mapping in the Durable Object
+    /// Whether reads need a signed URL or the admin token, from `REQUIRE_SIGNED_READS`
+    fn signed_reads_required(&self) -> bool {
+        self.env_string("REQUIRE_SIGNED_READS").as_deref() == Some("true")
+    }
+
+    /// Most files the bucket may hold, from `MAX_FILES`
+    fn max_files(&self) -> Option<usize> {
+        max_files(self.env_string("MAX_FILES").as_deref())
+    }
+
+    /// Content types uploads may declare, from `ALLOWED_CONTENT_TYPES`
+    fn allowed_content_types(&self) -> Option<Vec<String>> {
+        parse_content_type_allowlist(self.env_string("ALLOWED_CONTENT_TYPES").as_deref())
+    }
+
+    /// Files up to this size are stored in their mapping, from `INLINE_MAX_BYTES`
+    fn inline_max_bytes(&self) -> usize {
+        inline_max_bytes(self.env_string("INLINE_MAX_BYTES").as_deref())
+    }
+
+    /// A non-empty string environment variable
+    fn env_string(&self, name: &str) -> Option<String> {
+        self.env
+            .var(name)
+            .map(|v| v.to_string())
+            .ok()
+            .filter(|v| !v.is_empty())
+    }
+
+    /// Number of hash-prefix directories in blob keys, from `BLOB_SHARD_DEPTH`
+    fn blob_shard_depth(&self) -> usize {
+        self.env
+            .var("BLOB_SHARD_DEPTH")
+            .ok()
+            .and_then(|v| v.to_string().parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Get the Durable Object tracking a resumable upload
+    fn upload_stub(&self, upload_id: &str) -> Result<Stub> {
+        self.env
+            .durable_object("UPLOAD_OBJECT")
+            .map_err(|_| StorageError::Unbound("UPLOAD_OBJECT".to_string()))?
+            .id_from_name(upload_id)?
+            .get_stub()
+    }
+
+    /// Load an upload's state, or `None` if it doesn't exist or is for another key
+    async fn get_upload(&self, upload_id: &str, key: &str) -> Result<Option<UploadState>> {
+        let mut response = self
+            .upload_stub(upload_id)?
+            .fetch_with_str("https://fake-host/")
+            .await?;
+        if response.status_code() == 404 {
+            return Ok(None);
+        }
+        if response.status_code() >= 400 {
+            return Err(StorageError::MappingUnavailable(format!(
+                "Failed to get upload: {}",
+                response.status_code()
+            ))
+            .into());
+        }
+
+        let upload: UploadState = response.json().await?;
+        Ok((upload.key == key).then_some(upload))
+    }
+
+    /// Delete an upload's staged chunks and its Durable Object state
+    async fn discard_upload(&self, upload_id: &str, upload: &UploadState) -> Result<()> {
+        for chunk in &upload.chunks {
+            self.bucket
+                .delete(staging_key(upload_id, chunk.offset))
+                .await?;
+        }
+
+        let request = Request::new_with_init(
+            "https://fake-host/",
+            RequestInit::new().with_method(Method::Delete),
+        )?;
+        self.upload_stub(upload_id)?
+            .fetch_with_request(request)
+            .await?;
+        Ok(())
+    }
+
+    /// Keys a blob may be stored under: the current layout, then the flat one
+    fn blob_candidates(&self, sha256: &str) -> Vec<String> {
+        let depth = self.blob_shard_depth();
+        let mut candidates = vec![self.scoped_blob_key(sha256, depth)];
+        if depth > 0 {
+            candidates.push(self.scoped_blob_key(sha256, 0));
+        }
+        candidates
+    }
+
+    /// Fetch a blob by SHA256 from the configured layout
+    ///
+    /// When sharding is enabled, blobs written before it was turned on are still
+    /// found at the flat `blobs/<sha256>` key.
+    async fn get_blob(&self, sha256: &str) -> Result<Option<(String, Object)>> {
+        for candidate in self.blob_candidates(sha256) {
+            if let Some(object) = self.bucket.get(&candidate).execute().await? {
+                return Ok(Some((candidate, object)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Wait until the rate limiter allows a write to `blob_key`
+    ///
+    /// Retries up to `R2_WRITE_RETRIES` times, sleeping for the `Retry-After`
+    /// duration between attempts. Fails with a rate-limited error once retries
+    /// or the total wait budget are used up.
+    async fn wait_for_rate_limit(&self, blob_key: &str) -> Result<()> {
+        let max_retries = self.write_retries();
+        let mut attempt = 0;
+        let mut waited_secs = 0;
+
+        while let Some(retry_after) = check_r2_rate_limit(&self.env, blob_key).await? {
+            match next_retry_wait(attempt, max_retries, retry_after, waited_secs) {
+                Some(wait_secs) => {
+                    console_log!(
+                        "Rate limited writing blob: {}. Retrying in {}s",
+                        blob_key,
+                        wait_secs
+                    );
+                    Delay::from(Duration::from_secs(wait_secs)).await;
+                    attempt += 1;
+                    waited_secs += wait_secs;
+                }
+                None => {
+                    console_log!("Rate limited writing blob: {}", blob_key);
+                    return Err(rate_limited_error(retry_after));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fetch a mapping from the Durable Object, returning `None` if it doesn't exist
+    ///
+    /// `path` is the filename, optionally followed by a query string.
+    async fn get_mapping(&self, path: &str) -> Result<Option<FileMapping>> {
+        let stub = self.get_file_mapping_stub().await?;
+
+        let request = Request::new_with_init(
+            &format!("https://fake-host/{}", path),
+            RequestInit::new().with_method(Method::Get),
+        )?;
+
+        let mut response = stub.fetch_with_request(request).await?;
+
+        if response.status_code() == 404 {
+            return Ok(None);
+        }
+
+        if response.status_code() >= 400 {
+            return Err(StorageError::MappingUnavailable(format!(
+                "Failed to get file mapping: {}",
+                response.status_code()
+            ))
+            .into());
+        }
+
+        Ok(Some(response.json().await?))
+    }
+
+    /// Create or update the mapping for `key` in the Durable Object
+    ///
+    /// Returns the stored mapping and whether it was newly created.
+    #[allow(clippy::too_many_arguments)]
+    async fn put_mapping(
+        &self,
+        key: &str,
+        sha256: &str,
+        size: usize,
+        content_type: Option<&str>,
+        metadata: &HashMap<String, String>,
+        compressed: bool,
+        expires_at: Option<u64>,
+        inline: Option<&str>,
+    ) -> Result<(FileMapping, bool)> {
         let stub = self.get_file_mapping_stub().await?;
 
         let mapping_request = serde_json::json!({
-            "sha256": &sha256,
+            "sha256": sha256,
             "size": size,
-            "content_type": content_type
+            "content_type": content_type,
+            "metadata": metadata,
+            "compressed": compressed,
+            "expires_at": expires_at,
+            "inline": inline
         });
 
         let request = Request::new_with_init(
@@ -105,226 +688,3059 @@ impl R2Storage for R2StorageImpl {
                 }),
         )?;
 
-        let response = stub.fetch_with_request(request).await?;
+        let mut response = stub.fetch_with_request(request).await?;
 
+        if response.status_code() == 507 {
+            let quota: QuotaExceeded = response.json().await?;
+            return Err(StorageError::QuotaExceeded(quota).into());
+        }
         if response.status_code() >= 400 {
-            return Err(Error::RustError(format!(
+            return Err(StorageError::MappingUnavailable(format!(
                 "Failed to update file mapping: {}",
                 response.status_code()
-            )));
+            ))
+            .into());
         }
 
-        Ok(FileMetadata {
-            key: key.to_string(),
-            size,
-            content_type: content_type.map(|s| s.to_string()),
-            uploaded_at: js_sys::Date::now() as u64,
-            sha256: Some(sha256),
-        })
+        let created = response.status_code() == 201;
+        Ok((response.json().await?, created))
     }
 
-    async fn download(&self, key: &str) -> Result<Option<(Vec<u8>, String, Option<String>)>> {
-        // First, get the SHA256 from the mapping
+    /// List mappings from the Durable Object, filtered there by prefix
+    ///
+    /// With `keys_only`, the Durable Object sends just the filenames.
+    async fn fetch_mapping_list<T: for<'de> Deserialize<'de>>(
+        &self,
+        prefix: Option<&str>,
+        keys_only: bool,
+    ) -> Result<T> {
         let stub = self.get_file_mapping_stub().await?;
 
-        let request = Request::new_with_init(
-            &format!("https://fake-host/{}", key),
-            RequestInit::new().with_method(Method::Get),
-        )?;
+        let url = mapping_list_url(prefix, keys_only)?;
+        let request =
+            Request::new_with_init(url.as_str(), RequestInit::new().with_method(Method::Get))?;
 
         let mut response = stub.fetch_with_request(request).await?;
 
-        if response.status_code() == 404 {
-            // No mapping found, file doesn't exist
-            return Ok(None);
-        }
-
         if response.status_code() >= 400 {
-            return Err(Error::RustError(format!(
-                "Failed to get file mapping: {}",
+            return Err(StorageError::MappingUnavailable(format!(
+                "Failed to list file mappings: {}",
                 response.status_code()
-            )));
+            ))
+            .into());
         }
 
-        let mapping: FileMapping = response.json().await?;
-        let sha256 = mapping.sha256.clone();
-        let blob_key = format!("blobs/{}", sha256);
-
-        // Now fetch the actual blob
-        let object = self.bucket.get(&blob_key).execute().await?;
-
-        if let Some(object) = object {
-            let body = object
-                .body()
-                .ok_or(Error::RustError("No body".to_string()))?;
-            let bytes = body.bytes().await?;
+        response.json().await
+    }
 
-            // Verify the blob content matches the expected SHA256
-            let actual_sha256 = compute_sha256(&bytes).await?;
-            if actual_sha256 != sha256 {
-                return Err(Error::RustError(format!(
-                    "Blob integrity check failed for {}. Expected: {}, Actual: {}",
-                    key, sha256, actual_sha256
-                )));
+    /// Resolve a file to its blob's R2 key, checking the blob exists without reading it
+    ///
+    /// Returns `None` if there's no mapping for `key`.
+    pub async fn resolve_blob(&self, key: &str) -> Result<Option<BlobResolution>> {
+        let mapping = match self.stat(key).await? {
+            Some(m) => m,
+            None => return Ok(None),
+        };
+        let candidates = self.blob_candidates(&mapping.sha256);
+        let mut found = None;
+        if mapping.inline.is_none() {
+            for candidate in &candidates {
+                if self.bucket.head(candidate).await?.is_some() {
+                    found = Some(candidate.clone());
+                    break;
+                }
             }
-
-            Ok(Some((bytes, sha256, mapping.content_type)))
-        } else {
-            // Blob is missing but mapping exists - likely deleted by R2 lifecycle
-            // Clean up the orphaned mapping
-            console_log!(
-                "Blob {} not found for file {}. Cleaning up orphaned mapping.",
-                sha256,
-                key
-            );
-
-            let delete_request = Request::new_with_init(
-                &format!("https://fake-host/{}", key),
-                RequestInit::new().with_method(Method::Delete),
-            )?;
-
-            let _ = stub.fetch_with_request(delete_request).await;
-
-            // Return None to indicate file not found
-            Ok(None)
         }
+        Ok(Some(BlobResolution::new(
+            &mapping,
+            found,
+            candidates[0].clone(),
+        )))
     }
 
-    async fn delete(&self, key: &str) -> Result<()> {
-        // Delete the mapping from the Durable Object
+    /// Refresh a file's `updated_at`, and its expiry, without writing to R2
+    ///
+    /// `expires_at` replaces the expiry; otherwise an expiring file keeps
+    /// its lifetime. Returns `None` if the file doesn't exist.
+    pub async fn touch(&self, key: &str, expires_at: Option<u64>) -> Result<Option<FileMapping>> {
         let stub = self.get_file_mapping_stub().await?;
-
+        let query = match expires_at {
+            Some(at) => format!("touch&expires_at={}", at),
+            None => "touch".to_string(),
+        };
         let request = Request::new_with_init(
-            &format!("https://fake-host/{}", key),
-            RequestInit::new().with_method(Method::Delete),
+            &format!("https://fake-host/{}?{}", key, query),
+            RequestInit::new().with_method(Method::Post),
         )?;
 
-        stub.fetch_with_request(request).await?;
-
-        // Note: We don't delete the blob itself as it might be referenced by other files
-        // A garbage collection process could be implemented separately
-
-        Ok(())
+        let mut response = stub.fetch_with_request(request).await?;
+        match response.status_code() {
+            404 => Ok(None),
+            status if status >= 400 => Err(StorageError::MappingUnavailable(format!(
+                "Failed to touch file mapping: {}",
+                status
+            ))
+            .into()),
+            _ => Ok(Some(response.json().await?)),
+        }
     }
 
-    async fn list(&self, prefix: Option<&str>) -> Result<Vec<String>> {
-        // Get all mappings from the Durable Object
+    /// Delete every file mapping, version and stat, returning how many files there were
+    pub async fn purge_mappings(&self) -> Result<usize> {
         let stub = self.get_file_mapping_stub().await?;
-
         let request = Request::new_with_init(
-            "https://fake-host/",
-            RequestInit::new().with_method(Method::Get),
+            "https://fake-host/?all",
+            RequestInit::new().with_method(Method::Delete),
         )?;
-
         let mut response = stub.fetch_with_request(request).await?;
-
         if response.status_code() >= 400 {
-            return Err(Error::RustError(format!(
-                "Failed to list file mappings: {}",
+            return Err(StorageError::MappingUnavailable(format!(
+                "Failed to purge file mappings: {}",
                 response.status_code()
-            )));
+            ))
+            .into());
         }
 
-        let mappings: Vec<FileMapping> = response.json().await?;
+        #[derive(Deserialize)]
+        struct Purged {
+            deleted: usize,
+        }
+        Ok(response.json::<Purged>().await?.deleted)
+    }
 
-        // Filter by prefix if provided
-        let files: Vec<String> = mappings
-            .into_iter()
-            .filter(|m| {
-                if let Some(p) = prefix {
-                    m.filename.starts_with(p)
-                } else {
-                    true
+    /// Delete every blob from R2, in both the flat and sharded layouts
+    ///
+    /// Returns how many were deleted and whether that was all of them; it
+    /// stops early once `budget` is spent, after deleting at least one blob.
+    pub async fn purge_blobs(&self, budget: &TimeBudget<'_>) -> Result<(usize, bool)> {
+        let prefix = format!("{}blobs/", self.blob_prefix);
+        let mut deleted = 0;
+        loop {
+            // Deleting as we go means each listing starts over at what's left
+            let page = self.bucket.list().prefix(prefix.clone()).execute().await?;
+            for object in page.objects() {
+                if deleted > 0 && budget.exhausted() {
+                    return Ok((deleted, false));
                 }
-            })
-            .map(|m| m.filename)
-            .collect();
+                self.bucket.delete(object.key()).await?;
+                deleted += 1;
+            }
+            if !page.truncated() {
+                return Ok((deleted, true));
+            }
+        }
+    }
 
-        Ok(files)
+    /// Stream a blob's body to the client, hashing it on the way
+    fn verified_stream(&self, body: ObjectBody, key: &str, sha256: &str) -> Result<ResponseBody> {
+        let verified = self.verified_chunks(body, key, sha256)?;
+        Ok(Response::from_stream(verified)?.body().clone())
     }
-}
 
-/// Handle R2 file operations via HTTP endpoints
-pub async fn handle_r2_request(mut req: Request, env: Env, path: &str) -> Result<Response> {
-    let bucket = env.bucket("FILES_BUCKET")?;
-    let storage = R2StorageImpl::new(bucket, env);
+    /// A blob's chunks, read ahead and checked against `sha256` by `verify_stream`
+    fn verified_chunks(
+        &self,
+        body: ObjectBody,
+        key: &str,
+        sha256: &str,
+    ) -> Result<impl Stream<Item = Result<Vec<u8>>>> {
+        let max_chunks =
+            max_buffered_chunks(self.env_string("STREAM_MAX_BUFFERED_CHUNKS").as_deref());
+        Ok(verify_stream(
+            read_ahead(Box::pin(body.stream()?), max_chunks),
+            key.to_string(),
+            sha256.to_string(),
+        ))
+    }
 
-    // Extract file key from path (e.g., /files/my-file.txt -> my-file.txt)
-    let key = path.strip_prefix("/files/").unwrap_or(path);
+    /// `download_stream` for a mapping that's already been fetched
+    async fn download_mapping(
+        &self,
+        key: &str,
+        mut mapping: FileMapping,
+        verify_limit: Option<usize>,
+        accept_gzip: bool,
+    ) -> Result<Option<(ResponseBody, FileMapping)>> {
+        if let Some(bytes) = inline_content(&mapping)? {
+            return Ok(Some((ResponseBody::Body(bytes), mapping)));
+        }
 
-    match req.method() {
-        Method::Get => {
-            if key.is_empty() {
-                // List files
-                let files = storage.list(None).await?;
-                Response::from_json(&files)
-            } else {
-                // Download specific file
-                match storage.download(key).await? {
-                    Some((data, sha256, content_type)) => {
-                        let headers = Headers::new();
-                        headers.set(
-                            "Content-Type",
-                            content_type
-                                .as_deref()
-                                .unwrap_or("application/octet-stream"),
-                        )?;
-                        // Add Content-Digest header with SHA-256
-                        headers.set(
-                            "Content-Digest",
-                            &format!("sha-256=:{}:", base64_encode(&hex_to_bytes(&sha256)?)),
-                        )?;
+        let (blob_key, object) = match self.get_blob(&mapping.sha256).await? {
+            Some(found) => found,
+            None => {
+                self.remove_orphaned_mapping(key, &mapping).await?;
+                return Ok(None);
+            }
+        };
 
-                        Ok(Response::from_bytes(data)?.with_headers(headers))
-                    }
-                    None => Response::error("File not found", 404),
-                }
+        let Some(body) = object.body() else {
+            let bytes = empty_object_content(object.size(), &format!("blob {}", blob_key))?;
+            verify_content(key, &mapping.sha256, &bytes).await?;
+            return Ok(Some((ResponseBody::Body(bytes), mapping)));
+        };
+        // Trust the blob over the mapping, in case they disagree
+        mapping.compressed = is_gzip_encoded(&object);
+        let verify = should_verify(mapping.size, verify_limit);
+
+        if mapping.compressed && accept_gzip {
+            // Send the compressed bytes as stored; the client decompresses them
+            if !verify {
+                return Ok(Some((body.response_body()?, mapping)));
             }
+            let bytes = body.bytes().await?;
+            verify_content(key, &mapping.sha256, &decode_blob(&object, bytes.clone())?).await?;
+            return Ok(Some((ResponseBody::Body(bytes), mapping)));
         }
-        Method::Put | Method::Post => {
-            // Upload file
-            let content_type = req.headers().get("Content-Type")?;
-
-            let data = req.bytes().await?;
-            let metadata = storage.upload(key, data, content_type.as_deref()).await?;
 
-            Response::from_json(&metadata)
+        // Verification needs every byte in memory, so only small files get it.
+        // Compressed blobs are decompressed in memory either way.
+        if verify || mapping.compressed {
+            let bytes = decode_blob(&object, body.bytes().await?)?;
+            if verify {
+                verify_content(key, &mapping.sha256, &bytes).await?;
+            }
+            return Ok(Some((ResponseBody::Body(bytes), mapping)));
         }
-        Method::Delete => {
-            // Delete file
-            storage.delete(key).await?;
-            Response::ok("File deleted")
+
+        // Too big to buffer: hash the chunks on their way to the client instead
+        if verify_limit.is_some() {
+            let body = self.verified_stream(body, key, &mapping.sha256)?;
+            return Ok(Some((body, mapping)));
         }
-        Method::Head => {
-            // HEAD request - return headers without body
-            if key.is_empty() {
-                Response::error("Method not allowed for listing", 405)
+
+        Ok(Some((body.response_body()?, mapping)))
+    }
+    /// Download a file in the best encoding `accept_encoding` allows
+    ///
+    /// A precompressed variant the client accepts is preferred, in the order of
+    /// `PRECOMPRESSED_ENCODINGS`; otherwise this is `download_stream`. Returns
+    /// the `Content-Encoding` the body is in, if any.
+    pub async fn download_negotiated(
+        &self,
+        key: &str,
+        verify_limit: Option<usize>,
+        accept_encoding: Option<&str>,
+    ) -> Result<Option<(ResponseBody, FileMapping, Option<&'static str>)>> {
+        let mapping = match self.get_mapping(key).await? {
+            Some(mapping) => mapping,
+            None => return Ok(None),
+        };
+
+        let chosen = choose_encoding(
+            accept_encoding,
+            mapping.encodings.keys().map(String::as_str),
+        );
+        if let Some(encoding) = chosen {
+            let variant = &mapping.encodings[encoding];
+            // A variant whose blob is gone falls back to the identity content
+            if let Some((_, object)) = self.get_blob(&variant.sha256).await? {
+                let body = object.body().ok_or_else(|| {
+                    StorageError::NotFound(format!("Body of {} variant of {}", encoding, key))
+                })?;
+                let body = if should_verify(variant.size, verify_limit) {
+                    let bytes = body.bytes().await?;
+                    verify_content(key, &variant.sha256, &bytes).await?;
+                    ResponseBody::Body(bytes)
+                } else if verify_limit.is_some() {
+                    self.verified_stream(body, key, &variant.sha256)?
+                } else {
+                    body.response_body()?
+                };
+                return Ok(Some((body, mapping, Some(encoding))));
+            }
+        }
+
+        let accept_gzip = accepts_gzip(accept_encoding);
+        Ok(self
+            .download_mapping(key, mapping, verify_limit, accept_gzip)
+            .await?
+            .map(|(body, mapping)| {
+                let encoding = (accept_gzip && mapping.compressed).then_some("gzip");
+                (body, mapping, encoding)
+            }))
+    }
+
+    /// Store a precompressed variant of an existing file, in `encoding`
+    ///
+    /// `data` is already compressed; it's stored as its own blob, and the
+    /// file's identity content is left alone. Returns `None` if the file
+    /// doesn't exist.
+    pub async fn upload_variant(
+        &self,
+        key: &str,
+        encoding: &str,
+        data: Vec<u8>,
+    ) -> Result<Option<FileMapping>> {
+        // Nothing is written for a file that doesn't exist
+        if self.stat(key).await?.is_none() {
+            return Ok(None);
+        }
+
+        let size = data.len();
+        let sha256 = compute_sha256(&data).await?;
+        if self.get_blob(&sha256).await?.is_none() {
+            let blob_key = self.scoped_blob_key(&sha256, self.blob_shard_depth());
+            self.wait_for_rate_limit(&blob_key).await?;
+            // No Content-Encoding on the blob: its hash is of the compressed bytes
+            self.bucket.put(&blob_key, data).execute().await?;
+        }
+
+        let stub = self.get_file_mapping_stub().await?;
+        let url = Url::parse_with_params(
+            &format!("https://fake-host/{}", key),
+            &[("encoding", encoding)],
+        )?;
+        let variant = EncodedVariant { sha256, size };
+        let request = Request::new_with_init(
+            url.as_str(),
+            RequestInit::new()
+                .with_method(Method::Put)
+                .with_body(Some(JsValue::from_str(&serde_json::to_string(&variant)?))),
+        )?;
+        let mut response = stub.fetch_with_request(request).await?;
+        match response.status_code() {
+            404 => Ok(None),
+            507 => {
+                let quota: QuotaExceeded = response.json().await?;
+                Err(StorageError::QuotaExceeded(quota).into())
+            }
+            status if status >= 400 => Err(StorageError::MappingUnavailable(format!(
+                "Failed to store {} variant: {}",
+                encoding, status
+            ))
+            .into()),
+            _ => Ok(Some(response.json().await?)),
+        }
+    }
+
+    /// Delete a mapping whose blob is gone, likely removed by an R2 lifecycle rule
+    async fn remove_orphaned_mapping(&self, key: &str, mapping: &FileMapping) -> Result<()> {
+        console_log!(
+            "Blob {} not found for file {}. Cleaning up orphaned mapping.",
+            mapping.sha256,
+            key
+        );
+
+        // Purge rather than soft-delete, since the file can't be restored
+        let stub = self.get_file_mapping_stub().await?;
+        let delete_request = Request::new_with_init(
+            &format!("https://fake-host/{}?purge", key),
+            RequestInit::new().with_method(Method::Delete),
+        )?;
+
+        let _ = stub.fetch_with_request(delete_request).await;
+        Ok(())
+    }
+
+    /// Read a blob by SHA256, verifying its content against the hash
+    ///
+    /// Returns the bytes and the content type stored with the blob, or `None`
+    /// if the blob doesn't exist in R2. `key` is only used in error messages.
+    async fn read_blob(
+        &self,
+        key: &str,
+        sha256: &str,
+    ) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        let object = match self.get_blob(sha256).await? {
+            Some((_, object)) => object,
+            None => return Ok(None),
+        };
+
+        let bytes = match object.body() {
+            Some(body) => decode_blob(&object, body.bytes().await?)?,
+            None => empty_object_content(object.size(), &format!("blob {}", sha256))?,
+        };
+        verify_content(key, sha256, &bytes).await?;
+
+        Ok(Some((bytes, object.http_metadata().content_type)))
+    }
+
+    /// The mapping object's running totals, which it keeps without listing files
+    async fn usage(&self) -> Result<StorageUsage> {
+        let stub = self.get_file_mapping_stub().await?;
+        let mut response = stub.fetch_with_str("https://fake-host/?usage").await?;
+        if response.status_code() >= 400 {
+            return Err(StorageError::MappingUnavailable(format!(
+                "Failed to get storage usage: {}",
+                response.status_code()
+            ))
+            .into());
+        }
+        response.json().await
+    }
+
+    async fn get_file_mapping_stub(&self) -> Result<worker::durable::Stub> {
+        let namespace = self
+            .env
+            .durable_object("FILE_MAPPING_OBJECT")
+            .map_err(|_| StorageError::Unbound("FILE_MAPPING_OBJECT".to_string()))?;
+        // One mapping instance per bucket and tenant
+        let id = namespace.id_from_name(&mapping_instance_name(
+            self.bucket_name.as_deref(),
+            self.tenant.as_deref(),
+        ))?;
+        id.get_stub()
+    }
+}
+
+#[async_trait(?Send)]
+impl R2Storage for R2StorageImpl {
+    async fn upload(
+        &self,
+        key: &str,
+        data: Vec<u8>,
+        content_type: Option<&str>,
+        metadata: HashMap<String, String>,
+        expires_at: Option<u64>,
+    ) -> Result<FileMetadata> {
+        let size = data.len();
+
+        // Fill in a content type from the extension or the content itself
+        let content_type = match content_type {
+            Some(ct) => ct.to_string(),
+            None => content_type_from_extension(key)
+                .or_else(|| sniff_content_type(&data))
+                .unwrap_or("application/octet-stream")
+                .to_string(),
+        };
+        let content_type = Some(content_type.as_str());
+
+        // Compute SHA256 of the content
+        console_log!("Computing SHA256 for key: {}", key);
+        let sha256 = compute_sha256(&data).await?;
+        let blob_key = self.scoped_blob_key(&sha256, self.blob_shard_depth());
+
+        console_log!("SHA256 for {}: {}", key, &sha256);
+
+        // Small files are kept in the mapping itself, with no blob in R2
+        let inline_max = self.inline_max_bytes();
+        let inline = (inline_max > 0 && size <= inline_max).then(|| base64_encode(&data));
+
+        // Check if blob already exists, in either layout
+        let existing_object = match inline {
+            Some(_) => None,
+            None => self.get_blob(&sha256).await?,
+        };
+
+        let compressed = match &existing_object {
+            // An existing blob keeps whatever encoding it was written with
+            Some((_, object)) => is_gzip_encoded(object),
+            None if inline.is_some() => false,
+            None => {
+                // Blob doesn't exist, write it once the rate limiter allows it
+                self.wait_for_rate_limit(&blob_key).await?;
+
+                // The hash is of the original content, so dedup ignores compression
+                let (data, compressed) =
+                    if self.gzip_on_store() && content_type.is_some_and(is_textual_content_type) {
+                        compress_if_smaller(data)
+                    } else {
+                        (data, false)
+                    };
+
+                console_log!("Writing new blob: {}", blob_key);
+                let mut put_request = self.bucket.put(&blob_key, data);
+
+                // Add content-type (and encoding) to blob metadata if provided
+                if content_type.is_some() || compressed {
+                    let metadata = HttpMetadata {
+                        content_type: content_type.map(|ct| ct.to_string()),
+                        content_encoding: compressed.then(|| "gzip".to_string()),
+                        ..Default::default()
+                    };
+                    put_request = put_request.http_metadata(metadata);
+                }
+
+                put_request.execute().await?;
+                compressed
+            }
+        };
+        if existing_object.is_some() {
+            console_log!("Blob already exists: {}", blob_key);
+        }
+
+        // Update the filename->SHA256 mapping in the Durable Object
+        let (_, was_created) = self
+            .put_mapping(
+                key,
+                &sha256,
+                size,
+                content_type,
+                &metadata,
+                compressed,
+                expires_at,
+                inline.as_deref(),
+            )
+            .await?;
+
+        Ok(FileMetadata {
+            key: key.to_string(),
+            size,
+            content_type: content_type.map(|s| s.to_string()),
+            uploaded_at: js_sys::Date::now() as u64,
+            sha256: Some(sha256),
+            metadata,
+            was_created,
+            deduplicated: existing_object.is_some(),
+        })
+    }
+
+    async fn stat(&self, key: &str) -> Result<Option<FileMapping>> {
+        self.get_mapping(key).await
+    }
+
+    async fn download_stream(
+        &self,
+        key: &str,
+        verify_limit: Option<usize>,
+        accept_gzip: bool,
+    ) -> Result<Option<(ResponseBody, FileMapping)>> {
+        match self.get_mapping(key).await? {
+            Some(mapping) => {
+                self.download_mapping(key, mapping, verify_limit, accept_gzip)
+                    .await
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn download_version(
+        &self,
+        key: &str,
+        version: u32,
+    ) -> Result<Option<(Vec<u8>, FileMapping)>> {
+        let mapping = match self
+            .get_mapping(&format!("{}?version={}", key, version))
+            .await?
+        {
+            Some(mapping) => mapping,
+            None => return Ok(None),
+        };
+
+        if let Some(bytes) = inline_content(&mapping)? {
+            return Ok(Some((bytes, mapping)));
+        }
+
+        // Old versions may outlive their blob; treat that as not found without
+        // touching the current mapping
+        Ok(self
+            .read_blob(key, &mapping.sha256)
+            .await?
+            .map(|(bytes, _)| (bytes, mapping)))
+    }
+
+    async fn download_by_hash(&self, sha256: &str) -> Result<Option<(Vec<u8>, Option<String>)>> {
+        self.read_blob(&self.scoped_blob_key(sha256, 0), sha256)
+            .await
+    }
+
+    async fn stat_blob(&self, sha256: &str) -> Result<Option<BlobInfo>> {
+        for candidate in self.blob_candidates(sha256) {
+            if let Some(object) = self.bucket.head(&candidate).await? {
+                return Ok(Some(BlobInfo {
+                    size: object.size(),
+                    content_type: object.http_metadata().content_type,
+                    compressed: is_gzip_encoded(&object),
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn read_blob_range(
+        &self,
+        sha256: &str,
+        range: ByteRange,
+    ) -> Result<Option<ResponseBody>> {
+        // Part of a blob can't be checked against its hash, so this isn't verified
+        for candidate in self.blob_candidates(sha256) {
+            let object = self
+                .bucket
+                .get(&candidate)
+                .range(Range::OffsetWithLength {
+                    offset: range.start,
+                    length: range.length(),
+                })
+                .execute()
+                .await?;
+            if let Some(object) = object {
+                let body = object
+                    .body()
+                    .ok_or_else(|| StorageError::NotFound(format!("Body of blob {}", sha256)))?;
+                return Ok(Some(body.response_body()?));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn list_versions(&self, key: &str) -> Result<Vec<FileVersion>> {
+        let stub = self.get_file_mapping_stub().await?;
+
+        let request = Request::new_with_init(
+            &format!("https://fake-host/{}?versions", key),
+            RequestInit::new().with_method(Method::Get),
+        )?;
+
+        let mut response = stub.fetch_with_request(request).await?;
+
+        if response.status_code() >= 400 {
+            return Err(StorageError::MappingUnavailable(format!(
+                "Failed to list file versions: {}",
+                response.status_code()
+            ))
+            .into());
+        }
+
+        response.json().await
+    }
+
+    async fn copy(&self, source: &str, destination: &str, overwrite: bool) -> Result<CopyResult> {
+        let mapping = match self.get_mapping(source).await? {
+            Some(mapping) => mapping,
+            None => return Ok(CopyResult::SourceNotFound),
+        };
+
+        if !overwrite && self.get_mapping(destination).await?.is_some() {
+            return Ok(CopyResult::DestinationExists);
+        }
+
+        // The destination points at the same blob, so no R2 write is needed
+        let (copied, _) = self
+            .put_mapping(
+                destination,
+                &mapping.sha256,
+                mapping.size,
+                mapping.content_type.as_deref(),
+                &mapping.metadata,
+                mapping.compressed,
+                mapping.expires_at,
+                mapping.inline.as_deref(),
+            )
+            .await?;
+
+        Ok(CopyResult::Copied(Box::new(copied)))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        // Delete the mapping from the Durable Object
+        let stub = self.get_file_mapping_stub().await?;
+
+        let request = Request::new_with_init(
+            &format!("https://fake-host/{}", key),
+            RequestInit::new().with_method(Method::Delete),
+        )?;
+
+        stub.fetch_with_request(request).await?;
+
+        // Note: We don't delete the blob itself as it might be referenced by other files
+        // A garbage collection process could be implemented separately
+
+        Ok(())
+    }
+
+    async fn list(&self, prefix: Option<&str>) -> Result<Vec<String>> {
+        // Only the names come back, not whole mappings
+        self.fetch_mapping_list(prefix, true).await
+    }
+
+    async fn list_mappings(&self, prefix: Option<&str>) -> Result<Vec<FileMapping>> {
+        self.fetch_mapping_list(prefix, false).await
+    }
+
+    async fn list_page(&self, prefix: Option<&str>, query: &ListQuery) -> Result<FilePage> {
+        // The Durable Object sorts and slices, so only one page comes back
+        let stub = self.get_file_mapping_stub().await?;
+
+        let mut params = query.to_query();
+        if let Some(p) = prefix {
+            params.push(("prefix", p.to_string()));
+        }
+        let url = Url::parse_with_params("https://fake-host/", &params)?;
+        let request =
+            Request::new_with_init(url.as_str(), RequestInit::new().with_method(Method::Get))?;
+
+        let mut response = stub.fetch_with_request(request).await?;
+        if response.status_code() >= 400 {
+            return Err(StorageError::MappingUnavailable(format!(
+                "Failed to list file mappings: {}",
+                response.status_code()
+            ))
+            .into());
+        }
+
+        let page: MappingPage = response.json().await?;
+        Ok(page.into())
+    }
+
+    async fn restore(&self, key: &str) -> Result<Option<FileMapping>> {
+        let stub = self.get_file_mapping_stub().await?;
+        let request = Request::new_with_init(
+            &format!("https://fake-host/{}?restore", key),
+            RequestInit::new().with_method(Method::Post),
+        )?;
+
+        let mut response = stub.fetch_with_request(request).await?;
+        match response.status_code() {
+            // Missing, or not deleted
+            404 | 409 => Ok(None),
+            status if status >= 400 => Err(StorageError::MappingUnavailable(format!(
+                "Failed to restore file mapping: {}",
+                status
+            ))
+            .into()),
+            _ => Ok(Some(response.json().await?)),
+        }
+    }
+
+    async fn list_blobs(&self) -> Result<BTreeMap<String, BlobInfo>> {
+        let prefix = format!("{}blobs/", self.blob_prefix);
+        let mut blobs = BTreeMap::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut list = self
+                .bucket
+                .list()
+                .prefix(prefix.clone())
+                .include(vec![Include::HttpMetadata]);
+            if let Some(c) = cursor.take() {
+                list = list.cursor(c);
+            }
+            let page = list.execute().await?;
+
+            for object in page.objects() {
+                // The hash is the last key segment, in both the flat and sharded layouts
+                let key = object.key();
+                let sha256 = key.rsplit('/').next().unwrap_or("");
+                if is_valid_sha256(sha256) {
+                    let info = BlobInfo {
+                        size: object.size(),
+                        content_type: object.http_metadata().content_type,
+                        compressed: is_gzip_encoded(&object),
+                    };
+                    blobs.insert(sha256.to_ascii_lowercase(), info);
+                }
+            }
+
+            match page.cursor() {
+                Some(c) if page.truncated() => cursor = Some(c),
+                _ => break,
+            }
+        }
+        Ok(blobs)
+    }
+
+    async fn recreate_mapping(
+        &self,
+        key: &str,
+        sha256: &str,
+        size: usize,
+        blob: &BlobInfo,
+    ) -> Result<()> {
+        self.put_mapping(
+            key,
+            sha256,
+            size,
+            blob.content_type.as_deref(),
+            &HashMap::new(),
+            blob.compressed,
+            None,
+            None,
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn stats(&self) -> Result<StorageStats> {
+        let stub = self.get_file_mapping_stub().await?;
+        let mut response = stub.fetch_with_str("https://fake-host/?stats").await?;
+
+        if response.status_code() >= 400 {
+            return Err(StorageError::MappingUnavailable(format!(
+                "Failed to get storage stats: {}",
+                response.status_code()
+            ))
+            .into());
+        }
+
+        response.json().await
+    }
+}
+
+/// Handle R2 file operations via HTTP endpoints
+///
+/// Storage errors are reported with their own status codes, e.g. 502 for an
+/// integrity failure and 429 when rate limited.
+pub async fn handle_r2_request(
+    req: Request,
+    env: Env,
+    ctx: &Context,
+    path: &str,
+) -> Result<Response> {
+    route_r2_request(req, env, ctx, path)
+        .await
+        .or_else(storage_error_response)
+}
+
+async fn route_r2_request(
+    mut req: Request,
+    env: Env,
+    ctx: &Context,
+    path: &str,
+) -> Result<Response> {
+    // Extract file key from path (e.g., /files/my-file.txt -> my-file.txt)
+    let key = path.strip_prefix("/files/").unwrap_or(path);
+    // Signed URLs cover the whole path, bucket segment included
+    let signed_path = key;
+
+    // A leading segment naming a configured bucket selects that bucket
+    let buckets = parse_bucket_config(env.var("R2_BUCKETS").ok().map(|v| v.to_string()).as_deref());
+    let (bucket_name, binding, key) = match split_bucket(key, &buckets) {
+        Some((name, binding, rest)) => (Some(name), binding, rest),
+        None => (None, DEFAULT_BUCKET_BINDING, key),
+    };
+    let bucket = match env.bucket(binding) {
+        Ok(b) => b,
+        Err(_) if bucket_name.is_some() => return Response::error("Unknown bucket", 404),
+        Err(e) => return Err(e),
+    };
+    let tenant = match resolve_request_tenant(&req, &env)? {
+        TenantResolution::Disabled => None,
+        TenantResolution::Tenant(t) => Some(t),
+        TenantResolution::Missing => return Response::error("Tenant required", 400),
+        TenantResolution::Unknown => return Response::error("Unknown tenant", 403),
+        TenantResolution::Conflict => {
+            return Response::error("Tenant header doesn't match host", 403)
+        }
+    };
+    let isolate_blobs = tenant_blob_isolation(&env);
+    let storage = R2StorageImpl::new(bucket, env)
+        .with_bucket_name(bucket_name, binding)
+        .with_tenant(tenant, isolate_blobs);
+    // `Photo.JPG` and `photo.jpg` are one file with `CASE_INSENSITIVE_KEYS`
+    let original_key = key;
+    let folded_key = storage.fold_key(key);
+    let key = folded_key.as_ref();
+    // `?pretty` or `Accept: application/json+iso` adds ISO-8601 timestamps
+    let iso = request_wants_iso_timestamps(&req);
+    // Compressed blobs are sent as stored to clients that accept gzip
+    let accept_gzip = accepts_gzip(req.headers().get("Accept-Encoding")?.as_deref());
+
+    // A signed URL stands in for the admin token; a bad one is refused, not ignored
+    let signed = match query_param(&req, "sig")? {
+        Some(signature) => {
+            if let Some(response) = signed_url_error(&req, &storage, signed_path, &signature)? {
+                return Ok(response);
+            }
+            true
+        }
+        None => false,
+    };
+    if !signed
+        && matches!(req.method(), Method::Get | Method::Head)
+        && storage.signed_reads_required()
+    {
+        if let Some(response) = admin_auth_error(&req, &storage)? {
+            return Ok(response);
+        }
+    }
+
+    // `?content_type=` replaces the stored type on the response only
+    if let Some(content_type) = query_param(&req, "content_type")? {
+        if !is_valid_content_type_override(&content_type) {
+            return Response::error("Invalid content_type", 400);
+        }
+        // Anyone can add the parameter, so it mustn't turn a file into a page
+        if is_script_capable_content_type(&content_type) {
+            return Response::error("content_type can't be a type that runs scripts", 400);
+        }
+        if let Some(allowed) = storage.allowed_content_types() {
+            if !content_type_allowed(Some(&content_type), &allowed) {
+                return Response::error("content_type not allowed", 400);
+            }
+        }
+    }
+
+    // A trailing slash (or the bucket root) addresses a prefix rather than a file
+    let is_prefix = key.is_empty() || key.ends_with('/');
+    if is_prefix && req.method() == Method::Delete {
+        // `?dry_run=true` reports what would be deleted without deleting it
+        let dry_run = has_query_flag(&req, "dry_run")?;
+        if key.is_empty() && !dry_run && !has_query_flag(&req, "confirm")? {
+            return Response::error("Deleting all files requires ?confirm=true", 400);
+        }
+        let keys = delete_prefix(&storage, key, dry_run).await?;
+        if !dry_run {
+            for deleted in &keys {
+                storage
+                    .log_access(&req, "delete", deleted, None, None)
+                    .await;
+            }
+        }
+        return Response::from_json(&serde_json::json!({
+            "prefix": key,
+            "deleted": keys.len(),
+            "keys": keys,
+            "dry_run": dry_run
+        }));
+    }
+    if is_prefix && req.method() == Method::Get {
+        let prefix = (!key.is_empty()).then_some(key);
+
+        // `?archive=zip` downloads everything under the prefix as one file
+        if let Some(format) = query_param(&req, "archive")? {
+            if format != "zip" {
+                return Response::error("Unsupported archive format; use ?archive=zip", 400);
+            }
+            if key.is_empty() && !has_query_flag(&req, "confirm")? {
+                return Response::error("Archiving all files requires ?confirm=true", 400);
+            }
+            let key = key.to_string();
+            return archive_response(storage, &key).await;
+        }
+
+        // `?sort`, `?order`, `?limit` or `?offset` asks for a sorted page of the listing
+        match ListQuery::from_query(req.url()?.query_pairs()) {
+            Ok(Some(query)) => {
+                return Response::from_json(&storage.list_page(prefix, &query).await?)
+            }
+            Ok(None) => {}
+            Err(e) => return Response::error(e, 400),
+        }
+
+        // Serve the directory's index file if it has one, else list it
+        let index = index_key(key, &storage.index_file());
+        let verify_limit = Some(storage.verify_max_bytes());
+        if let Some((body, mapping)) = storage
+            .download_stream(&index, verify_limit, accept_gzip)
+            .await?
+        {
+            let encoding = (accept_gzip && mapping.compressed).then_some("gzip");
+            return file_response(&req, &storage, body, &mapping, encoding);
+        }
+
+        // Browsers get a page of links; everything else gets JSON
+        let accept = req.headers().get("Accept")?;
+        if accepts_media(accept.as_deref(), "text/html") {
+            let base = match bucket_name {
+                Some(name) => format!("/files/{}/", name),
+                None => "/files/".to_string(),
+            };
+            let mappings = storage.list_mappings(prefix).await?;
+            let mut response = Response::from_html(listing_html(&base, key, &mappings))?;
+            response.headers_mut().set("Vary", "Accept")?;
+            return Ok(response);
+        }
+
+        let mut response = timestamped_json(&storage.list(prefix).await?, iso)?;
+        response.headers_mut().set("Vary", "Accept")?;
+        return Ok(response);
+    }
+    let key = key.strip_suffix('/').unwrap_or(key);
+    let original_key = original_key.strip_suffix('/').unwrap_or(original_key);
+
+    if let Some(route) = parse_upload_route(key) {
+        return handle_upload_request(req, &storage, ctx, route, original_key).await;
+    }
+
+    match req.method() {
+        Method::Get => {
+            if key == "stats" {
+                // Deduplication stats across all files
+                Response::from_json(&storage.stats().await?)
+            } else if query_param(&req, "versions")?.is_some() {
+                // List retained versions of a file
+                timestamped_json(&storage.list_versions(key).await?, iso)
+            } else if query_param(&req, "envelope")?.as_deref() == Some("true") {
+                // Content and mapping in one JSON response, for small files
+                let max_bytes = storage.verify_max_bytes();
+                match download_envelope(&storage, key, max_bytes).await? {
+                    EnvelopeResult::Found(envelope) => timestamped_json(&envelope, iso),
+                    EnvelopeResult::NotFound => Ok(Response::from_json(
+                        &serde_json::json!({ "error": "File not found" }),
+                    )?
+                    .with_status(404)),
+                    EnvelopeResult::TooLarge { size, max_bytes } => {
+                        Ok(Response::from_json(&serde_json::json!({
+                            "error": "File too large for envelope",
+                            "size": size,
+                            "max_bytes": max_bytes
+                        }))?
+                        .with_status(413))
+                    }
+                }
+            } else if let Some(resolve) = query_param(&req, "resolve")? {
+                // Follow the filename to its blob, for debugging orphaned mappings
+                if resolve != "blob" {
+                    return Response::error("Unsupported resolve: only blob is supported", 400);
+                }
+                match storage.resolve_blob(key).await? {
+                    Some(resolution) => Response::from_json(&resolution),
+                    None => Response::error("File not found", 404),
+                }
+            } else if query_param(&req, "stat")?.is_some() {
+                // The file's mapping from the Durable Object, without reading R2
+                match storage.stat(key).await? {
+                    Some(mapping) => timestamped_json(&mapping, iso),
+                    None => Response::error("File not found", 404),
+                }
             } else {
-                match storage.download(key).await? {
-                    Some((data, sha256, content_type)) => {
-                        let headers = Headers::new();
-                        headers.set(
-                            "Content-Type",
-                            content_type
-                                .as_deref()
-                                .unwrap_or("application/octet-stream"),
-                        )?;
-                        headers.set("Content-Length", &data.len().to_string())?;
-                        // Add Content-Digest header with SHA-256
-                        headers.set(
-                            "Content-Digest",
-                            &format!("sha-256=:{}:", base64_encode(&hex_to_bytes(&sha256)?)),
+                // Download specific file, or a specific version of it
+                let file = match query_param(&req, "version")? {
+                    Some(v) => match v.parse() {
+                        Ok(version) => storage
+                            .download_version(key, version)
+                            .await?
+                            .map(|(data, mapping)| (ResponseBody::Body(data), mapping, None)),
+                        Err(_) => return Response::error("Invalid version", 400),
+                    },
+                    None => {
+                        if let Some(response) = file_range_response(&req, &storage, key).await? {
+                            return Ok(response);
+                        }
+
+                        // `?verify=false` streams without checking the content hash
+                        let verify_limit = match query_param(&req, "verify")?.as_deref() {
+                            Some("false") => None,
+                            _ => Some(storage.verify_max_bytes()),
+                        };
+                        let accept_encoding = req.headers().get("Accept-Encoding")?;
+                        storage
+                            .download_negotiated(key, verify_limit, accept_encoding.as_deref())
+                            .await?
+                    }
+                };
+
+                match file {
+                    Some((body, mapping, encoding)) => {
+                        file_response(&req, &storage, body, &mapping, encoding)
+                    }
+                    None => Response::error("File not found", 404),
+                }
+            }
+        }
+        Method::Post if key.ends_with("/restore") => {
+            // Undo a soft delete within the recovery window
+            let source = key.strip_suffix("/restore").unwrap_or(key);
+            match storage.restore(source).await? {
+                Some(mapping) => timestamped_json(&mapping, iso),
+                None => Response::error("No deleted file to restore", 404),
+            }
+        }
+        Method::Post if key.ends_with("/download-url") => {
+            // A link that reads the file without the admin token until it expires
+            if let Some(response) = admin_auth_error(&req, &storage)? {
+                return Ok(response);
+            }
+            let Some(secret) = storage.env_string("URL_SIGNING_SECRET") else {
+                return Response::error("Signed URLs are disabled", 403);
+            };
+            let source = key.strip_suffix("/download-url").unwrap_or(key);
+            let expires_in = match url_expiry_secs(req.headers().get(EXPIRES_IN_HEADER)?.as_deref())
+            {
+                Ok(secs) => secs,
+                Err(e) => return Response::error(e, 400),
+            };
+            let Some(mapping) = storage.stat(source).await? else {
+                return Response::error("File not found", 404);
+            };
+
+            let expires = js_sys::Date::now() as u64 / 1000 + expires_in;
+            let path = signed_path
+                .strip_suffix("/download-url")
+                .unwrap_or(signed_path);
+            let signature = sign(&secret, "GET", storage.tenant.as_deref(), path, expires);
+            let mut url = req.url()?;
+            let url_path = url.path().strip_suffix("/download-url").map(str::to_string);
+            if let Some(url_path) = url_path {
+                url.set_path(&url_path);
+            }
+            url.set_query(None);
+            url.query_pairs_mut()
+                .append_pair("exp", &expires.to_string())
+                .append_pair("sig", &signature);
+
+            let file = Some((mapping.sha256.as_str(), mapping.size));
+            storage
+                .log_access(&req, "download-url", source, None, file)
+                .await;
+            Response::from_json(&serde_json::json!({ "url": url.as_str(), "expires_at": expires }))
+        }
+        Method::Post if key.ends_with("/touch") => {
+            // Refresh a file's timestamps, e.g. to keep a temporary file alive
+            if let Some(response) = admin_auth_error(&req, &storage)? {
+                return Ok(response);
+            }
+            let source = key.strip_suffix("/touch").unwrap_or(key);
+            let expires_in = req.headers().get(EXPIRES_IN_HEADER)?;
+            let expires_at =
+                match parse_expires_in(expires_in.as_deref(), js_sys::Date::now() as u64) {
+                    Ok(at) => at,
+                    Err(e) => return Response::error(e, 400),
+                };
+            match storage.touch(source, expires_at).await? {
+                Some(mapping) => {
+                    let file = Some((mapping.sha256.as_str(), mapping.size));
+                    storage.log_access(&req, "touch", source, None, file).await;
+                    timestamped_json(&mapping, iso)
+                }
+                None => Response::error("File not found", 404),
+            }
+        }
+        Method::Post if key == "verify" => {
+            // Check a batch of blobs against their hashes, resuming from `?cursor`
+            if let Some(response) = admin_auth_error(&req, &storage)? {
+                return Ok(response);
+            }
+            let limit = match query_param(&req, "limit")? {
+                Some(v) => match v.parse::<usize>() {
+                    Ok(n) if (1..=MAX_VERIFY_SCAN_LIMIT).contains(&n) => n,
+                    _ => {
+                        return Response::error(
+                            format!("limit must be between 1 and {}", MAX_VERIFY_SCAN_LIMIT),
+                            400,
+                        )
+                    }
+                },
+                None => DEFAULT_VERIFY_SCAN_LIMIT,
+            };
+            let cursor = query_param(&req, "cursor")?;
+            let now = || js_sys::Date::now() as u64;
+            let budget = TimeBudget::new(
+                operation_budget_ms(storage.env_string("OPERATION_BUDGET_MS").as_deref()),
+                &now,
+            );
+            Response::from_json(&verify_blobs(&storage, cursor.as_deref(), limit, &budget).await?)
+        }
+        Method::Post if key == "reindex" => {
+            // Rebuild lost mappings from the blobs in R2
+            if let Some(response) = admin_auth_error(&req, &storage)? {
+                return Ok(response);
+            }
+            let body = req.text().await?;
+            let manifest = match parse_reindex_manifest(&body) {
+                Ok(m) => m,
+                Err(e) => return Response::error(e, 400),
+            };
+            let now = || js_sys::Date::now() as u64;
+            let budget = TimeBudget::new(
+                operation_budget_ms(storage.env_string("OPERATION_BUDGET_MS").as_deref()),
+                &now,
+            );
+            Response::from_json(&reindex(&storage, &manifest, &budget).await?)
+        }
+        Method::Post if key == "hash" => {
+            // Hash content the client may upload, to see if it would deduplicate
+            let max_upload = storage.max_upload_bytes();
+            let expect_continue = expects_continue(req.headers().get("Expect")?.as_deref());
+            let content_length = req.headers().get("Content-Length")?;
+            if let Err((message, status)) =
+                check_upload_length(content_length.as_deref(), expect_continue, max_upload)
+            {
+                return Response::error(message, status);
+            }
+            let data = req.bytes().await?;
+            if max_upload.is_some_and(|max| data.len() as u64 > max) {
+                return Response::error("File too large", 413);
+            }
+
+            Response::from_json(&check_hash(&storage, &data).await?)
+        }
+        Method::Post if key == "exists" => {
+            // Check which of a batch of keys exist in one request
+            let keys: Vec<String> = match req.json().await {
+                Ok(k) => k,
+                Err(_) => return Response::error("Expected a JSON array of keys", 400),
+            };
+            if keys.len() > MAX_EXISTS_KEYS {
+                return Response::error(
+                    format!("Too many keys: at most {} per request", MAX_EXISTS_KEYS),
+                    400,
+                );
+            }
+
+            Response::from_json(&check_exists(&storage, &keys).await?)
+        }
+        Method::Post if key.ends_with("/copy") || key.ends_with("/move") => {
+            // Copy or move a file to a new key without re-uploading
+            let (source, operation) = key.rsplit_once('/').unwrap_or((key, ""));
+
+            let request: CopyRequest = match req.json().await {
+                Ok(r) => r,
+                Err(_) => return Response::error("Invalid JSON", 400),
+            };
+            let destination = match sanitize_path(&request.destination) {
+                Ok(d) => storage.fold_key(&d).into_owned(),
+                Err(e) => return Response::error(e, 400),
+            };
+            if destination == source {
+                return Response::error("Destination must differ from source", 400);
+            }
+            if is_reserved_file_key(&destination) {
+                return reserved_key_response(&destination);
+            }
+
+            match storage
+                .copy(source, &destination, request.overwrite)
+                .await?
+            {
+                CopyResult::Copied(mapping) => {
+                    if operation == "move" {
+                        storage.delete(source).await?;
+                    }
+                    let file = Some((mapping.sha256.as_str(), mapping.size));
+                    storage
+                        .log_access(&req, operation, &destination, Some(source), file)
+                        .await;
+                    timestamped_json(&mapping, iso)
+                }
+                CopyResult::SourceNotFound => Response::error("File not found", 404),
+                CopyResult::DestinationExists => Response::error("Destination already exists", 409),
+            }
+        }
+        Method::Put | Method::Post => {
+            // Upload file
+            if is_reserved_file_key(key) {
+                return reserved_key_response(key);
+            }
+            let content_type = req.headers().get("Content-Type")?;
+            let mut user_metadata = match extract_user_metadata(req.headers().entries()) {
+                Ok(m) => m,
+                Err(e) => return Response::error(e, 400),
+            };
+            record_original_name(&mut user_metadata, key, original_key);
+            let expires_in = req.headers().get(EXPIRES_IN_HEADER)?;
+            let expires_at =
+                match parse_expires_in(expires_in.as_deref(), js_sys::Date::now() as u64) {
+                    Ok(at) => at,
+                    Err(e) => return Response::error(e, 400),
+                };
+
+            // Reject what we can before reading the body. Workers can't send
+            // `100 Continue` themselves: Cloudflare answers `Expect` for us and
+            // the body is only transferred as it's read, so a client waiting
+            // on 100-continue gets these errors without sending it.
+            let max_upload = storage.max_upload_bytes();
+            let expect_continue = expects_continue(req.headers().get("Expect")?.as_deref());
+            let content_length = req.headers().get("Content-Length")?;
+            if let Err((message, status)) =
+                check_upload_length(content_length.as_deref(), expect_continue, max_upload)
+            {
+                return Response::error(message, status);
+            }
+            if let Some(allowed) = storage.allowed_content_types() {
+                if !content_type_allowed(content_type.as_deref(), &allowed) {
+                    return Response::error("Content-Type not allowed", 415);
+                }
+            }
+
+            if let Some(response) = upload_rejection(&req, &storage, key).await? {
+                return Ok(response);
+            }
+
+            let data = req.bytes().await?;
+            // Bodies sent without a Content-Length are only measured here
+            if max_upload.is_some_and(|max| data.len() as u64 > max) {
+                return Response::error("File too large", 413);
+            }
+
+            // `?encoding=br` adds a precompressed variant to an existing file
+            if let Some(encoding) = query_param(&req, "encoding")? {
+                if !PRECOMPRESSED_ENCODINGS.contains(&encoding.as_str()) {
+                    return Response::error("Unsupported encoding", 400);
+                }
+                return match storage.upload_variant(key, &encoding, data).await? {
+                    Some(mapping) => timestamped_json(&mapping, iso),
+                    None => Response::error("File not found; upload it before its variants", 404),
+                };
+            }
+            // Rate limits become a 429 in `handle_r2_request`
+            let metadata = storage
+                .upload(
+                    key,
+                    data,
+                    content_type.as_deref(),
+                    user_metadata,
+                    expires_at,
+                )
+                .await?;
+
+            let event = MetricsEvent::Upload {
+                bytes: metadata.size as u64,
+                deduplicated: metadata.deduplicated,
+            };
+            // Counted after the response, like every request is
+            let env = storage.env.clone();
+            ctx.wait_until(async move {
+                let _ = metrics::record(&env, &event).await;
+            });
+            let file = metadata
+                .sha256
+                .as_deref()
+                .map(|sha256| (sha256, metadata.size));
+            storage.log_access(&req, "upload", key, None, file).await;
+
+            uploaded_response(&storage.file_path(key), &metadata, iso)
+        }
+        Method::Delete => {
+            // Refuse to delete a file that changed after the client last saw it
+            let unmodified_since = req
+                .headers()
+                .get("If-Unmodified-Since")?
+                .and_then(|value| parse_http_date(&value));
+            if let Some(since) = unmodified_since {
+                if let Some(mapping) = storage.stat(key).await? {
+                    if !is_unmodified_since(mapping.updated_at, since) {
+                        return Response::error("File has been modified since the given date", 412);
+                    }
+                }
+            }
+
+            // Delete file
+            storage.delete(key).await?;
+            storage.log_access(&req, "delete", key, None, None).await;
+            message_response(&req, "File deleted")
+        }
+        Method::Head => {
+            // HEAD request - answered from the mapping alone, without reading the blob
+            if key.is_empty() {
+                method_not_allowed(&[Method::Get, Method::Delete])
+            } else {
+                match storage.stat(key).await? {
+                    Some(mapping) => {
+                        let headers = file_headers(
+                            &mapping,
+                            &storage.file_cache_control(),
+                            storage.text_charset().as_deref(),
                         )?;
+                        set_content_type_override(&req, &headers)?;
+
+                        if not_modified(&req, &mapping)? {
+                            return Ok(Response::empty()?.with_status(304).with_headers(headers));
+                        }
+
+                        headers.set("Content-Length", &mapping.size.to_string())?;
+                        // Tell clients probing before a ranged GET whether it will work
+                        headers.set("Accept-Ranges", accept_ranges(mapping.compressed))?;
+
+                        // Return empty response with headers only
+                        Ok(Response::empty()?.with_headers(headers))
+                    }
+                    None => Response::error("File not found", 404),
+                }
+            }
+        }
+        _ => method_not_allowed(&[
+            Method::Get,
+            Method::Head,
+            Method::Put,
+            Method::Post,
+            Method::Delete,
+        ]),
+    }
+}
+
+/// Serve a file with its headers, or 304 if the client's copy is current
+///
+/// `gzip` means `body` holds gzip-compressed bytes, which are passed through
+/// with `Content-Encoding: gzip` rather than being compressed again.
+fn file_response(
+    req: &Request,
+    storage: &R2StorageImpl,
+    body: ResponseBody,
+    mapping: &FileMapping,
+    content_encoding: Option<&str>,
+) -> Result<Response> {
+    let headers = file_headers(
+        mapping,
+        &storage.file_cache_control(),
+        storage.text_charset().as_deref(),
+    )?;
+    if mapping.compressed || !mapping.encodings.is_empty() {
+        // The representation sent depends on the encodings the client accepts
+        headers.set("Vary", "Accept-Encoding")?;
+    }
+    set_attachment_header(req, mapping, &headers)?;
+    set_content_type_override(req, &headers)?;
+
+    if not_modified(req, mapping)? {
+        return Ok(Response::empty()?.with_status(304).with_headers(headers));
+    }
+
+    if let (ResponseBody::Body(bytes), None) = (&body, content_encoding) {
+        set_negotiated_digest(req, &headers, bytes)?;
+    }
+    if let Some(encoding) = content_encoding {
+        headers.set("Content-Encoding", encoding)?;
+        return Ok(Response::from_body(body)?
+            .with_headers(headers)
+            .with_encode_body(EncodeBody::Manual));
+    }
+    Ok(Response::from_body(body)?.with_headers(headers))
+}
+
+/// Whether an R2 object was stored gzip-compressed
+fn is_gzip_encoded(object: &Object) -> bool {
+    object
+        .http_metadata()
+        .content_encoding
+        .is_some_and(|e| e.eq_ignore_ascii_case("gzip"))
+}
+
+/// The content of an R2 object that came back without a body
+///
+/// R2 can leave out the body of a zero-length object, which is valid empty
+/// content. A missing body on anything bigger means the read failed; `what`
+/// names the object in that error.
+pub fn empty_object_content(size: u64, what: &str) -> Result<Vec<u8>> {
+    if size == 0 {
+        return Ok(Vec::new());
+    }
+    Err(StorageError::NotFound(format!("Body of {}", what)).into())
+}
+
+/// A blob's original content: its stored bytes, decompressed if need be
+fn decode_blob(object: &Object, bytes: Vec<u8>) -> Result<Vec<u8>> {
+    if !is_gzip_encoded(object) {
+        return Ok(bytes);
+    }
+    gzip_decompress(&bytes).map_err(|e| StorageError::Corrupt(e).into())
+}
+
+/// Check content against the SHA256 it's stored under
+///
+/// `key` is only used in the error message.
+async fn verify_content(key: &str, sha256: &str, content: &[u8]) -> Result<()> {
+    let actual_sha256 = compute_sha256(content).await?;
+    if actual_sha256 != sha256 {
+        return Err(StorageError::IntegrityMismatch {
+            key: key.to_string(),
+            expected: sha256.to_string(),
+            actual: actual_sha256,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Pass a blob's chunks through while hashing them, failing on a wrong digest
+///
+/// A streamed response can't be taken back, so the last chunk is held until
+/// the digest has been checked. On a mismatch the stream ends with an
+/// `IntegrityMismatch` error in place of that chunk, and the runtime aborts
+/// the response: the client sees a truncated body, never the whole of a
+/// corrupt one. `key` is only used in the error.
+pub fn verify_stream<S>(
+    chunks: S,
+    key: String,
+    sha256: String,
+) -> impl Stream<Item = Result<Vec<u8>>>
+where
+    S: Stream<Item = Result<Vec<u8>>> + Unpin,
+{
+    let state = VerifyState {
+        chunks,
+        hasher: Sha256::new(),
+        held: None,
+        key,
+        sha256,
+    };
+    stream::unfold(Some(state), |state| async move {
+        let mut state = state?;
+        loop {
+            match state.chunks.next().await {
+                Some(Ok(chunk)) if chunk.is_empty() => continue,
+                Some(Ok(chunk)) => {
+                    state.hasher.update(&chunk);
+                    if let Some(previous) = state.held.replace(chunk) {
+                        return Some((Ok(previous), Some(state)));
+                    }
+                }
+                Some(Err(e)) => return Some((Err(e), None)),
+                None => {
+                    let actual = bytes_to_hex(&state.hasher.finalize());
+                    if actual != state.sha256 {
+                        let err = StorageError::IntegrityMismatch {
+                            key: state.key,
+                            expected: state.sha256,
+                            actual,
+                        };
+                        return Some((Err(err.into()), None));
+                    }
+                    return state.held.map(|last| (Ok(last), None));
+                }
+            }
+        }
+    })
+}
+
+/// Progress of `verify_stream`
+struct VerifyState<S> {
+    chunks: S,
+    hasher: Sha256,
+    /// The latest chunk, sent once the next one arrives or the digest checks out
+    held: Option<Vec<u8>>,
+    key: String,
+    sha256: String,
+}
+
+/// Gzip `data`, keeping the original when compression doesn't make it smaller
+///
+/// Returns the bytes to store and whether they're compressed.
+pub fn compress_if_smaller(data: Vec<u8>) -> (Vec<u8>, bool) {
+    let compressed = gzip_compress(&data);
+    if compressed.len() < data.len() {
+        (compressed, true)
+    } else {
+        (data, false)
+    }
+}
+
+/// Add `Content-Disposition: attachment` if the request asked for it
+///
+/// `?download=true` saves the file under its own name; `?filename=...` picks
+/// another one.
+fn set_attachment_header(req: &Request, mapping: &FileMapping, headers: &Headers) -> Result<()> {
+    let filename = query_param(req, "filename")?.filter(|f| !f.is_empty());
+    if filename.is_some() || has_query_flag(req, "download")? {
+        let default_name = mapping.filename.rsplit('/').next().unwrap_or("");
+        headers.set(
+            "Content-Disposition",
+            &attachment_disposition(filename.as_deref().unwrap_or(default_name)),
+        )?;
+    }
+    Ok(())
+}
+
+/// Replace `Content-Type` with the request's `?content_type=`, if given
+///
+/// The value is checked by `route_r2_request` before the file is fetched.
+fn set_content_type_override(req: &Request, headers: &Headers) -> Result<()> {
+    if let Some(content_type) = query_param(req, "content_type")? {
+        headers.set("Content-Type", &content_type)?;
+    }
+    Ok(())
+}
+
+/// Whether a `?content_type=` value looks like a MIME type
+///
+/// It needs a non-empty type and subtype around a `/`, and may not contain
+/// control characters (CR or LF would split the header) or non-ASCII text.
+pub fn is_valid_content_type_override(value: &str) -> bool {
+    let media = value.split(';').next().unwrap_or("").trim();
+    let valid_media = match media.split_once('/') {
+        Some((kind, subtype)) => {
+            !kind.is_empty()
+                && !subtype.is_empty()
+                && !subtype.contains('/')
+                && !media.contains(char::is_whitespace)
+        }
+        None => false,
+    };
+    valid_media && value.is_ascii() && !value.chars().any(|c| c.is_ascii_control())
+}
+
+/// Types a browser would run scripts from if served on the Worker's origin
+const SCRIPT_CAPABLE_CONTENT_TYPES: [&str; 10] = [
+    "text/html",
+    "application/xhtml+xml",
+    "image/svg+xml",
+    "text/xml",
+    "application/xml",
+    "text/javascript",
+    "application/javascript",
+    "application/x-javascript",
+    "text/ecmascript",
+    "application/ecmascript",
+];
+
+/// Whether a `?content_type=` value would make the file active content
+///
+/// Parameters and case are ignored, so `Text/HTML; charset=utf-8` counts.
+pub fn is_script_capable_content_type(value: &str) -> bool {
+    let media = value.split(';').next().unwrap_or("").trim();
+    SCRIPT_CAPABLE_CONTENT_TYPES
+        .iter()
+        .any(|t| t.eq_ignore_ascii_case(media))
+}
+
+/// Media type patterns from an `ALLOWED_CONTENT_TYPES` value
+///
+/// A comma-separated list such as `image/*, application/pdf`. Missing or
+/// empty values mean any content type may be uploaded.
+pub fn parse_content_type_allowlist(value: Option<&str>) -> Option<Vec<String>> {
+    let patterns: Vec<String> = value?
+        .split(',')
+        .map(|p| p.trim().to_ascii_lowercase())
+        .filter(|p| !p.is_empty())
+        .collect();
+    (!patterns.is_empty()).then_some(patterns)
+}
+
+/// Whether `content_type` matches a pattern like `image/png`, `image/*` or `*/*`
+///
+/// Case and media type parameters such as `charset` are ignored.
+pub fn mime_matches(pattern: &str, content_type: &str) -> bool {
+    let media = content_type.split(';').next().unwrap_or("").trim();
+    let (Some((kind, subtype)), Some((want_kind, want_subtype))) =
+        (media.split_once('/'), pattern.trim().split_once('/'))
+    else {
+        return false;
+    };
+    if kind.is_empty() || subtype.is_empty() {
+        return false;
+    }
+    let part_matches = |want: &str, actual: &str| want == "*" || want.eq_ignore_ascii_case(actual);
+    (want_kind != "*" || want_subtype == "*")
+        && part_matches(want_kind, kind)
+        && part_matches(want_subtype, subtype)
+}
+
+/// Whether an upload's declared `Content-Type` is on the allowlist
+///
+/// An upload that declares no content type isn't allowed, since whatever
+/// would be inferred for it hasn't been vouched for by the client.
+pub fn content_type_allowed(declared: Option<&str>, allowed: &[String]) -> bool {
+    declared.is_some_and(|ct| allowed.iter().any(|pattern| mime_matches(pattern, ct)))
+}
+
+/// `Content-Disposition` value that makes browsers save a file as `filename`
+///
+/// Quotes, backslashes and control characters (including CR and LF) are
+/// dropped so the name can't break out of the header. Non-ASCII names are
+/// also given as an RFC 5987 `filename*`, with an ASCII fallback.
+pub fn attachment_disposition(filename: &str) -> String {
+    let safe: String = filename
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"' && *c != '\\')
+        .collect();
+    let safe = if safe.trim().is_empty() {
+        "download".to_string()
+    } else {
+        safe
+    };
+
+    if safe.is_ascii() {
+        return format!("attachment; filename=\"{}\"", safe);
+    }
+
+    let fallback: String = safe
+        .chars()
+        .map(|c| if c.is_ascii() { c } else { '_' })
+        .collect();
+    let encoded: String = safe
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'-' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect();
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+/// Content codings a file can have precompressed variants in, most preferred first
+pub const PRECOMPRESSED_ENCODINGS: [&str; 3] = ["br", "zstd", "gzip"];
+
+/// The precompressed variant to serve, from those `available`
+///
+/// Picks the first of `PRECOMPRESSED_ENCODINGS` that is both available and
+/// allowed by `accept_encoding`, or `None` to serve the identity content.
+pub fn choose_encoding<'a>(
+    accept_encoding: Option<&str>,
+    available: impl Iterator<Item = &'a str> + Clone,
+) -> Option<&'static str> {
+    PRECOMPRESSED_ENCODINGS.into_iter().find(|&encoding| {
+        available.clone().any(|a| a == encoding) && accepts_coding(accept_encoding, encoding)
+    })
+}
+
+/// Whether an `Accept-Encoding` header allows a gzip-encoded response
+pub fn accepts_gzip(accept_encoding: Option<&str>) -> bool {
+    accepts_coding(accept_encoding, "gzip")
+}
+
+/// Whether an `Accept-Encoding` header allows the content coding `coding`
+///
+/// `*` allows any coding, and `q=0` refuses one.
+pub fn accepts_coding(accept_encoding: Option<&str>, coding: &str) -> bool {
+    accept_encoding.is_some_and(|header| {
+        header.split(',').any(|range| {
+            let mut parts = range.split(';');
+            let name = parts.next().unwrap_or("").trim();
+            let rejected = parts.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (name.eq_ignore_ascii_case(coding) || name == "*") && !rejected
+        })
+    })
+}
+
+/// Named buckets from `R2_BUCKETS`, as `(name, binding)` pairs
+///
+/// The value is a comma-separated list of `name=BINDING` entries, e.g.
+/// `private=PRIVATE_BUCKET`. Entries without both parts, or whose name isn't a
+/// single path segment, are skipped.
+pub fn parse_bucket_config(value: Option<&str>) -> Vec<(String, String)> {
+    value
+        .unwrap_or("")
+        .split(',')
+        .filter_map(|entry| {
+            let (name, binding) = entry.split_once('=')?;
+            let (name, binding) = (name.trim(), binding.trim());
+            if name.is_empty()
+                || binding.is_empty()
+                || name.contains('/')
+                || name == "."
+                || name == ".."
+            {
+                return None;
+            }
+            Some((name.to_string(), binding.to_string()))
+        })
+        .collect()
+}
+
+/// Split a configured bucket name off the front of a file key
+///
+/// Returns the bucket's name, its binding and the rest of the key, or `None`
+/// if the first segment isn't a bucket name and the key is in the default bucket.
+pub fn split_bucket<'a, 'b>(
+    key: &'a str,
+    buckets: &'b [(String, String)],
+) -> Option<(&'b str, &'b str, &'a str)> {
+    let (first, rest) = key.split_once('/').unwrap_or((key, ""));
+    buckets
+        .iter()
+        .find(|(name, _)| name == first)
+        .map(|(name, binding)| (name.as_str(), binding.as_str(), rest))
+}
+
+/// Name of the `FileMappingObject` instance holding a bucket's mappings
+///
+/// The default bucket keeps the original "global" instance, so existing
+/// mappings stay where they are. Each tenant gets its own instance per bucket.
+pub fn mapping_instance_name(bucket_name: Option<&str>, tenant: Option<&str>) -> String {
+    let bucket = match bucket_name {
+        Some(name) => format!("bucket-{}", name),
+        None => "global".to_string(),
+    };
+    match tenant {
+        Some(tenant) => format!("{}/tenant-{}", bucket, tenant),
+        None => bucket,
+    }
+}
+
+/// Which tenant a request is for, given the `TENANTS` allowlist
+fn resolve_request_tenant(req: &Request, env: &Env) -> Result<TenantResolution> {
+    let allowlist =
+        parse_tenant_allowlist(env.var("TENANTS").ok().map(|v| v.to_string()).as_deref());
+    let host = req.url()?.host_str().map(str::to_string);
+    Ok(resolve_tenant(
+        req.headers().get(TENANT_HEADER)?.as_deref(),
+        host.as_deref(),
+        &allowlist,
+    ))
+}
+
+/// Whether tenants' blobs are kept apart, from `TENANT_BLOB_ISOLATION`
+pub fn tenant_blob_isolation(env: &Env) -> bool {
+    env.var("TENANT_BLOB_ISOLATION")
+        .map(|v| v.to_string() == "true")
+        .unwrap_or(false)
+}
+
+/// Key of the index file for a directory-like prefix (`""` or ending in `/`)
+pub fn index_key(prefix: &str, index_file: &str) -> String {
+    format!("{}{}", prefix, index_file)
+}
+
+/// Respond to a finished upload: 201 with a Location for new files, else 200
+fn uploaded_response(location: &str, metadata: &FileMetadata, iso: bool) -> Result<Response> {
+    if metadata.was_created {
+        let headers = Headers::new();
+        headers.set("Location", location)?;
+        Ok(timestamped_json(metadata, iso)?
+            .with_status(201)
+            .with_headers(headers))
+    } else {
+        timestamped_json(metadata, iso)
+    }
+}
+
+/// Why an upload to `key` can't go ahead, checked before any blob is written
+///
+/// `If-None-Match: *` only creates, and a new file mustn't go past
+/// `MAX_FILES`; the mapping write checks the quota again.
+async fn upload_rejection(
+    req: &Request,
+    storage: &R2StorageImpl,
+    key: &str,
+) -> Result<Option<Response>> {
+    if let Some(if_none_match) = req.headers().get("If-None-Match")? {
+        let existing = storage.stat(key).await?;
+        if upload_precondition_failed(&if_none_match, existing.as_ref()) {
+            return Response::error("File already exists", 412).map(Some);
+        }
+    }
+    if let Some(max) = storage.max_files() {
+        if storage.stat(key).await?.is_none() {
+            let count = storage.usage().await?.files as usize;
+            if file_quota_exceeded(count, Some(max)) {
+                return quota_exceeded_response(&QuotaExceeded::files(count, max)).map(Some);
+            }
+        }
+    }
+    Ok(None)
+}
 
-                        // Return empty response with headers only
-                        Ok(Response::empty()?.with_headers(headers))
+/// Handle the resumable upload protocol
+///
+/// Chunks are staged in R2 under `staging/{upload_id}/{offset}` and tracked by
+/// an `UploadObject`. Completing the upload assembles them in memory, so the
+/// final file must fit within the Worker's memory limit.
+async fn handle_upload_request(
+    mut req: Request,
+    storage: &R2StorageImpl,
+    ctx: &Context,
+    route: UploadRoute<'_>,
+    original_path: &str,
+) -> Result<Response> {
+    match (req.method(), route) {
+        (Method::Post, UploadRoute::Start { key }) => {
+            if is_reserved_file_key(key) {
+                return reserved_key_response(key);
+            }
+            let upload_id = new_upload_id();
+            let headers = Headers::new();
+            headers.set("content-type", "application/json")?;
+            let request = Request::new_with_init(
+                "https://fake-host/",
+                RequestInit::new()
+                    .with_method(Method::Put)
+                    .with_body(Some(JsValue::from_str(
+                        &serde_json::json!({
+                            "key": key,
+                            "upload_id": upload_id,
+                            "binding": storage.binding
+                        })
+                        .to_string(),
+                    )))
+                    .with_headers(headers),
+            )?;
+            let response = storage
+                .upload_stub(&upload_id)?
+                .fetch_with_request(request)
+                .await?;
+            if response.status_code() >= 400 {
+                return Ok(response);
+            }
+
+            let location = format!("{}/uploads/{}", storage.file_path(key), upload_id);
+            let headers = Headers::new();
+            headers.set("Location", &location)?;
+            Ok(Response::from_json(&serde_json::json!({
+                "upload_id": upload_id,
+                "key": key
+            }))?
+            .with_status(201)
+            .with_headers(headers))
+        }
+        (Method::Put, UploadRoute::Chunk { key, upload_id }) => {
+            let offset: u64 = match query_param(&req, "offset")?.map(|o| o.parse()) {
+                Some(Ok(o)) => o,
+                _ => return Response::error("offset query parameter required", 400),
+            };
+            let mut upload = match storage.get_upload(upload_id, key).await? {
+                Some(u) => u,
+                None => return Response::error("Upload not found", 404),
+            };
+
+            // The whole upload, not each chunk, is held to `MAX_UPLOAD_BYTES`
+            let max_upload = storage.max_upload_bytes();
+            let room =
+                max_upload.map(|max| max.saturating_sub(upload.received_bytes_excluding(offset)));
+            let expect_continue = expects_continue(req.headers().get("Expect")?.as_deref());
+            let content_length = req.headers().get("Content-Length")?;
+            if let Err((message, status)) =
+                check_upload_length(content_length.as_deref(), expect_continue, room)
+            {
+                return Response::error(message, status);
+            }
+
+            let data = req.bytes().await?;
+            let size = data.len() as u64;
+            let retry = upload.chunks.iter().any(|c| c.offset == offset);
+            // Check before staging so rejected chunks never reach R2
+            if let Err(e) = upload.add_chunk(offset, size) {
+                let status = if size == 0 { 400 } else { 409 };
+                return Response::error(e, status);
+            }
+            if max_upload.is_some_and(|max| upload.received_bytes() > max) {
+                return Response::error("File too large", 413);
+            }
+
+            storage
+                .bucket
+                .put(staging_key(upload_id, offset), data)
+                .execute()
+                .await?;
+
+            let headers = Headers::new();
+            headers.set("content-type", "application/json")?;
+            let request = Request::new_with_init(
+                "https://fake-host/chunks",
+                RequestInit::new()
+                    .with_method(Method::Put)
+                    .with_body(Some(JsValue::from_str(&serde_json::to_string(
+                        &UploadChunk { offset, size },
+                    )?)))
+                    .with_headers(headers),
+            )?;
+            let recorded = storage
+                .upload_stub(upload_id)?
+                .fetch_with_request(request)
+                .await;
+            let mut response = match recorded {
+                Ok(response) if response.status_code() < 400 => response,
+                failed => {
+                    // Unless an earlier attempt at this offset was recorded, nothing
+                    // tracks the staged chunk, so an abort wouldn't delete it
+                    if !retry {
+                        storage
+                            .bucket
+                            .delete(staging_key(upload_id, offset))
+                            .await?;
+                    }
+                    return failed;
+                }
+            };
+
+            let upload: UploadState = response.json().await?;
+            Response::from_json(&serde_json::json!({
+                "upload_id": upload_id,
+                "received_bytes": upload.received_bytes(),
+                "chunks": upload.chunks
+            }))
+        }
+        (Method::Delete, UploadRoute::Chunk { key, upload_id }) => {
+            // Abort the upload and clean up staged chunks
+            match storage.get_upload(upload_id, key).await? {
+                Some(upload) => {
+                    storage.discard_upload(upload_id, &upload).await?;
+                    message_response(&req, "Upload aborted")
+                }
+                None => Response::error("Upload not found", 404),
+            }
+        }
+        (Method::Post, UploadRoute::Complete { key, upload_id }) => {
+            let upload = match storage.get_upload(upload_id, key).await? {
+                Some(u) => u,
+                None => return Response::error("Upload not found", 404),
+            };
+            if let Err(e) = upload.check_complete() {
+                return Response::error(e, 400);
+            }
+            // The limits may have changed, or the file appeared, since the chunks arrived
+            if storage
+                .max_upload_bytes()
+                .is_some_and(|max| upload.received_bytes() > max)
+            {
+                return Response::error("File too large", 413);
+            }
+            if let Some(response) = upload_rejection(&req, storage, key).await? {
+                return Ok(response);
+            }
+
+            let content_type = req.headers().get("Content-Type")?;
+            if let Some(allowed) = storage.allowed_content_types() {
+                if !content_type_allowed(content_type.as_deref(), &allowed) {
+                    return Response::error("Content-Type not allowed", 415);
+                }
+            }
+            let mut user_metadata = match extract_user_metadata(req.headers().entries()) {
+                Ok(m) => m,
+                Err(e) => return Response::error(e, 400),
+            };
+            if let Some(UploadRoute::Complete { key: original, .. }) =
+                parse_upload_route(original_path)
+            {
+                record_original_name(&mut user_metadata, key, original);
+            }
+            let expires_in = req.headers().get(EXPIRES_IN_HEADER)?;
+            let expires_at =
+                match parse_expires_in(expires_in.as_deref(), js_sys::Date::now() as u64) {
+                    Ok(at) => at,
+                    Err(e) => return Response::error(e, 400),
+                };
+
+            // Assemble the chunks in offset order
+            let mut data = Vec::with_capacity(upload.received_bytes() as usize);
+            for chunk in &upload.chunks {
+                let chunk_key = staging_key(upload_id, chunk.offset);
+                let object = storage
+                    .bucket
+                    .get(&chunk_key)
+                    .execute()
+                    .await?
+                    .ok_or_else(|| StorageError::NotFound(format!("Staged chunk {}", chunk_key)))?;
+                let body = object.body().ok_or_else(|| {
+                    StorageError::NotFound(format!("Body of staged chunk {}", chunk_key))
+                })?;
+                data.extend_from_slice(&body.bytes().await?);
+            }
+
+            // On a rate limit the chunks stay staged so the client can retry completion
+            let metadata = storage
+                .upload(
+                    key,
+                    data,
+                    content_type.as_deref(),
+                    user_metadata,
+                    expires_at,
+                )
+                .await?;
+
+            storage.discard_upload(upload_id, &upload).await?;
+            let event = MetricsEvent::Upload {
+                bytes: metadata.size as u64,
+                deduplicated: metadata.deduplicated,
+            };
+            let env = storage.env.clone();
+            ctx.wait_until(async move {
+                let _ = metrics::record(&env, &event).await;
+            });
+            let file = metadata
+                .sha256
+                .as_deref()
+                .map(|sha256| (sha256, metadata.size));
+            storage.log_access(&req, "upload", key, None, file).await;
+            uploaded_response(
+                &storage.file_path(key),
+                &metadata,
+                request_wants_iso_timestamps(&req),
+            )
+        }
+        (_, route) => method_not_allowed(route.allowed_methods()),
+    }
+}
+
+/// Handle direct blob reads by content hash
+///
+/// Blobs never change once written, so responses can be cached forever.
+pub async fn handle_blob_request(req: Request, env: Env, sha256: &str) -> Result<Response> {
+    serve_blob(req, env, sha256)
+        .await
+        .or_else(storage_error_response)
+}
+
+async fn serve_blob(req: Request, env: Env, sha256: &str) -> Result<Response> {
+    if !is_valid_sha256(sha256) {
+        return Response::error("Invalid SHA256: expected 64 hex characters", 400);
+    }
+    let sha256 = sha256.to_ascii_lowercase();
+
+    let method = req.method();
+    if method != Method::Get && method != Method::Head {
+        return method_not_allowed(&[Method::Get, Method::Head]);
+    }
+
+    let tenant = match resolve_request_tenant(&req, &env)? {
+        TenantResolution::Disabled => None,
+        TenantResolution::Tenant(t) => Some(t),
+        TenantResolution::Missing => return Response::error("Tenant required", 400),
+        TenantResolution::Unknown => return Response::error("Unknown tenant", 403),
+        TenantResolution::Conflict => {
+            return Response::error("Tenant header doesn't match host", 403)
+        }
+    };
+    let isolate_blobs = tenant_blob_isolation(&env);
+    let bucket = env.bucket(DEFAULT_BUCKET_BINDING)?;
+    let storage = R2StorageImpl::new(bucket, env).with_tenant(tenant, isolate_blobs);
+    let cache_control = storage.blob_cache_control();
+
+    if method == Method::Head {
+        // R2's object metadata has everything a HEAD needs, so the blob isn't read
+        let info = match storage.stat_blob(&sha256).await? {
+            Some(info) => info,
+            None => return Response::error("Blob not found", 404),
+        };
+        let headers = blob_headers(&sha256, info.content_type.as_deref(), &cache_control)?;
+        // A compressed blob's stored size isn't the size a GET sends once decoded
+        if !info.compressed {
+            headers.set("Content-Length", &info.size.to_string())?;
+        }
+        headers.set("Accept-Ranges", accept_ranges(info.compressed))?;
+        return Ok(Response::empty()?.with_headers(headers));
+    }
+
+    if let Some(header) = req.headers().get("Range")? {
+        let info = match storage.stat_blob(&sha256).await? {
+            Some(info) => info,
+            None => return Response::error("Blob not found", 404),
+        };
+        let size = info.size;
+        let headers = blob_headers(&sha256, info.content_type.as_deref(), &cache_control)?;
+
+        // Compressed blobs are served whole, since offsets wouldn't line up
+        let range = if info.compressed {
+            RangeRequest::Full
+        } else {
+            parse_range(Some(&header), size)
+        };
+        match range {
+            RangeRequest::Partial(range) => {
+                return match storage.read_blob_range(&sha256, range).await? {
+                    Some(body) => partial_response(headers, body, range, size),
+                    None => Response::error("Blob not found", 404),
+                };
+            }
+            RangeRequest::Unsatisfiable => return range_not_satisfiable(size),
+            RangeRequest::Full => {}
+        }
+    }
+
+    match storage.download_by_hash(&sha256).await? {
+        Some((data, content_type)) => {
+            let headers = blob_headers(&sha256, content_type.as_deref(), &cache_control)?;
+            set_negotiated_digest(&req, &headers, &data)?;
+            Ok(Response::from_bytes(data)?.with_headers(headers))
+        }
+        None => Response::error("Blob not found", 404),
+    }
+}
+
+/// Build the headers for a `/blobs/{sha256}` response
+fn blob_headers(sha256: &str, content_type: Option<&str>, cache_control: &str) -> Result<Headers> {
+    let headers = Headers::new();
+    headers.set(
+        "Content-Type",
+        content_type.unwrap_or("application/octet-stream"),
+    )?;
+    headers.set(
+        "Content-Digest",
+        &format!("sha-256=:{}:", base64_encode(&hex_to_bytes(sha256)?)),
+    )?;
+    headers.set("ETag", &format!("\"{}\"", sha256))?;
+    headers.set("Cache-Control", cache_control)?;
+    Ok(headers)
+}
+
+/// Switch `Content-Digest` to CRC32C if `Want-Content-Digest` prefers it
+///
+/// `headers` already carry the stored SHA256 digest, which is kept otherwise.
+fn set_negotiated_digest(req: &Request, headers: &Headers, body: &[u8]) -> Result<()> {
+    let want = req.headers().get("Want-Content-Digest")?;
+    if preferred_digest(want.as_deref()) == DigestAlgorithm::Crc32c {
+        headers.set("Content-Digest", &crc32c_digest_header(body))?;
+    }
+    Ok(())
+}
+
+/// Answer a `Range` request for a named file, or `None` to serve it whole
+///
+/// Missing files also give `None`, so the normal download path reports them.
+async fn file_range_response(
+    req: &Request,
+    storage: &R2StorageImpl,
+    key: &str,
+) -> Result<Option<Response>> {
+    let header = match req.headers().get("Range")? {
+        Some(h) => h,
+        None => return Ok(None),
+    };
+    let mapping = match storage.stat(key).await? {
+        Some(m) => m,
+        None => return Ok(None),
+    };
+    // Offsets into a compressed blob don't match offsets into the file
+    if mapping.compressed {
+        return Ok(None);
+    }
+
+    let headers = file_headers(
+        &mapping,
+        &storage.file_cache_control(),
+        storage.text_charset().as_deref(),
+    )?;
+    set_attachment_header(req, &mapping, &headers)?;
+    set_content_type_override(req, &headers)?;
+    if not_modified(req, &mapping)? {
+        return Ok(Some(
+            Response::empty()?.with_status(304).with_headers(headers),
+        ));
+    }
+
+    let size = mapping.size as u64;
+    match parse_range(Some(&header), size) {
+        RangeRequest::Partial(range) => {
+            if let Some(bytes) = inline_content(&mapping)? {
+                return Ok(match bytes.get(range.start as usize..=range.end as usize) {
+                    Some(part) => {
+                        let body = ResponseBody::Body(part.to_vec());
+                        Some(partial_response(headers, body, range, size)?)
+                    }
+                    None => None,
+                });
+            }
+            match storage.read_blob_range(&mapping.sha256, range).await? {
+                Some(body) => Ok(Some(partial_response(headers, body, range, size)?)),
+                None => Ok(None),
+            }
+        }
+        RangeRequest::Unsatisfiable => Ok(Some(range_not_satisfiable(size)?)),
+        RangeRequest::Full => Ok(None),
+    }
+}
+
+/// Send part of a `size`-byte representation with 206
+fn partial_response(
+    headers: Headers,
+    body: ResponseBody,
+    range: ByteRange,
+    size: u64,
+) -> Result<Response> {
+    headers.set("Content-Range", &range.content_range(size))?;
+    headers.set("Content-Length", &range.length().to_string())?;
+    Ok(Response::from_body(body)?
+        .with_status(206)
+        .with_headers(headers))
+}
+
+/// Reject a range that lies outside a `size`-byte representation with 416
+fn range_not_satisfiable(size: u64) -> Result<Response> {
+    let headers = Headers::new();
+    headers.set("Content-Range", &unsatisfiable_content_range(size))?;
+    Ok(Response::empty()?.with_status(416).with_headers(headers))
+}
+
+/// Stream every file under `prefix` as a ZIP, entries named relative to it
+///
+/// Files are added in key order as they're read from R2, so only the chunk in
+/// flight is held in memory; compressed blobs are the exception, as they're
+/// decompressed whole. Files whose blob has gone missing are left out. Any
+/// other failure part way ends the stream early, which the client sees as a
+/// truncated download rather than a corrupt archive.
+async fn archive_response(storage: R2StorageImpl, prefix: &str) -> Result<Response> {
+    let mut mappings = storage.list_mappings(Some(prefix)).await?;
+    mappings.sort_by(|a, b| a.filename.cmp(&b.filename));
+    let sizes = mappings
+        .iter()
+        .map(|m| (m.filename.len().saturating_sub(prefix.len()), m.size as u64));
+    if !fits_zip32(sizes) {
+        return Response::error("Too many or too large files for a ZIP archive", 413);
+    }
+
+    let state = ArchiveState {
+        storage,
+        prefix: prefix.to_string(),
+        mappings: mappings.into_iter(),
+        writer: ZipWriter::new(),
+        entry: None,
+    };
+    let archive = stream::unfold(Some(state), |state| async move {
+        let mut state = state?;
+        loop {
+            if let Some(entry) = state.entry.as_mut() {
+                match entry.chunks.next().await {
+                    Some(Ok(chunk)) => {
+                        entry.crc.update(&chunk);
+                        entry.size += chunk.len() as u64;
+                        return Some((Ok(chunk), Some(state)));
+                    }
+                    Some(Err(e)) => return Some((Err(e), None)),
+                    None => {
+                        let (crc, size) = (entry.crc.finish(), entry.size);
+                        state.entry = None;
+                        let descriptor = state.writer.finish_entry(crc, size);
+                        return Some((Ok(descriptor), Some(state)));
                     }
-                    None => Response::error("File not found", 404),
                 }
             }
+
+            let Some(mapping) = state.mappings.next() else {
+                return Some((Ok(state.writer.finish()), None));
+            };
+            let chunks = match archive_entry_chunks(&state.storage, &mapping).await {
+                Ok(Some(chunks)) => chunks,
+                Ok(None) => continue,
+                Err(e) => return Some((Err(e), None)),
+            };
+            let name = mapping.filename.strip_prefix(state.prefix.as_str());
+            let header = state
+                .writer
+                .start_entry(name.unwrap_or(&mapping.filename), mapping.updated_at);
+            state.entry = Some(ArchiveEntry {
+                chunks,
+                crc: Crc32::new(),
+                size: 0,
+            });
+            return Some((Ok(header), Some(state)));
+        }
+    });
+
+    let headers = Headers::new();
+    headers.set("Content-Type", "application/zip")?;
+    headers.set(
+        "Content-Disposition",
+        &format!("attachment; filename=\"{}\"", archive_filename(prefix)),
+    )?;
+    Ok(Response::from_stream(archive)?.with_headers(headers))
+}
+
+/// The content of one archived file, or `None` if its blob is gone
+async fn archive_entry_chunks(
+    storage: &R2StorageImpl,
+    mapping: &FileMapping,
+) -> Result<Option<ArchiveChunks>> {
+    fn whole(bytes: Vec<u8>) -> ArchiveChunks {
+        Box::pin(stream::once(async { Ok(bytes) }))
+    }
+
+    if let Some(bytes) = inline_content(mapping)? {
+        return Ok(Some(whole(bytes)));
+    }
+    let Some((blob_key, object)) = storage.get_blob(&mapping.sha256).await? else {
+        return Ok(None);
+    };
+    let Some(body) = object.body() else {
+        let bytes = empty_object_content(object.size(), &format!("blob {}", blob_key))?;
+        return Ok(Some(whole(bytes)));
+    };
+    if is_gzip_encoded(&object) {
+        let bytes = decode_blob(&object, body.bytes().await?)?;
+        verify_content(&mapping.filename, &mapping.sha256, &bytes).await?;
+        return Ok(Some(whole(bytes)));
+    }
+    let chunks = storage.verified_chunks(body, &mapping.filename, &mapping.sha256)?;
+    Ok(Some(Box::pin(chunks)))
+}
+
+type ArchiveChunks = std::pin::Pin<Box<dyn Stream<Item = Result<Vec<u8>>>>>;
+
+/// Progress of `archive_response`
+struct ArchiveState {
+    storage: R2StorageImpl,
+    prefix: String,
+    mappings: std::vec::IntoIter<FileMapping>,
+    writer: ZipWriter,
+    /// The file being sent, if its header has gone out
+    entry: Option<ArchiveEntry>,
+}
+
+/// A file part way into the archive
+struct ArchiveEntry {
+    chunks: ArchiveChunks,
+    crc: Crc32,
+    size: u64,
+}
+
+/// Build the R2 key for a blob, nesting it under `depth` hash-prefix directories
+///
+/// Depth 0 is the flat `blobs/<sha256>` layout; depth 2 gives
+/// `blobs/ab/cd/<sha256>`. Depth is capped at `MAX_BLOB_SHARD_DEPTH`.
+pub fn blob_key(sha256: &str, depth: usize) -> String {
+    let mut key = String::from("blobs/");
+    for level in 0..depth.min(MAX_BLOB_SHARD_DEPTH) {
+        match sha256.get(level * 2..level * 2 + 2) {
+            Some(prefix) => {
+                key.push_str(prefix);
+                key.push('/');
+            }
+            None => break,
+        }
+    }
+    key.push_str(sha256);
+    key
+}
+
+/// URL asking the `FileMappingObject` for a listing
+///
+/// `keys_only` adds `fields=key`, for filenames rather than whole mappings.
+pub fn mapping_list_url(prefix: Option<&str>, keys_only: bool) -> Result<Url> {
+    let mut url = Url::parse("https://fake-host/")?;
+    {
+        let mut query = url.query_pairs_mut();
+        if let Some(prefix) = prefix {
+            query.append_pair("prefix", prefix);
+        }
+        if keys_only {
+            query.append_pair("fields", "key");
+        }
+    }
+    if url.query() == Some("") {
+        url.set_query(None);
+    }
+    Ok(url)
+}
+
+/// Whether a download of `size` bytes should be buffered and verified
+///
+/// `verify_limit` is the largest size to verify, or `None` to never verify.
+pub fn should_verify(size: usize, verify_limit: Option<usize>) -> bool {
+    verify_limit.is_some_and(|limit| size <= limit)
+}
+
+/// Decide how long to wait before retrying a rate-limited write
+///
+/// Returns `None` when `max_retries` attempts have been made or when waiting
+/// `retry_after_secs` more would exceed `MAX_RETRY_WAIT_SECS` in total.
+pub fn next_retry_wait(
+    attempt: u32,
+    max_retries: u32,
+    retry_after_secs: u64,
+    waited_secs: u64,
+) -> Option<u64> {
+    if attempt >= max_retries || waited_secs + retry_after_secs > MAX_RETRY_WAIT_SECS {
+        return None;
+    }
+    Some(retry_after_secs)
+}
+
+/// Fetch a file's mapping and content together, if it's at most `max_bytes`
+///
+/// The size is checked from the mapping, so an oversized file is never read.
+pub async fn download_envelope(
+    storage: &impl R2Storage,
+    key: &str,
+    max_bytes: usize,
+) -> Result<EnvelopeResult> {
+    let size = match storage.stat(key).await? {
+        Some(mapping) => mapping.size,
+        None => return Ok(EnvelopeResult::NotFound),
+    };
+    if size > max_bytes {
+        return Ok(EnvelopeResult::TooLarge { size, max_bytes });
+    }
+
+    // Within the verify limit the content comes back in memory, already checked
+    let (data, mut metadata) = match storage.download_stream(key, Some(max_bytes), false).await? {
+        Some((ResponseBody::Body(data), mapping)) => (data, mapping),
+        Some((ResponseBody::Empty, mapping)) => (Vec::new(), mapping),
+        Some((ResponseBody::Stream(_), mapping)) => {
+            return Ok(EnvelopeResult::TooLarge {
+                size: mapping.size,
+                max_bytes,
+            })
+        }
+        None => return Ok(EnvelopeResult::NotFound),
+    };
+    // The content is already in the envelope
+    metadata.inline = None;
+    Ok(EnvelopeResult::Found(Box::new(FileEnvelope {
+        metadata,
+        content_base64: base64_encode(&data),
+    })))
+}
+
+/// Delete every file mapping whose name starts with `prefix`
+///
+/// An empty prefix matches every file. Only mappings are removed; blobs stay in
+/// R2 since they may still be referenced by other files.
+///
+/// Returns the keys deleted. With `dry_run`, nothing is deleted and the keys
+/// that would have been are returned.
+pub async fn delete_prefix(
+    storage: &impl R2Storage,
+    prefix: &str,
+    dry_run: bool,
+) -> Result<Vec<String>> {
+    let prefix = (!prefix.is_empty()).then_some(prefix);
+    let keys = storage.list(prefix).await?;
+
+    if !dry_run {
+        for key in &keys {
+            storage.delete(key).await?;
+        }
+    }
+
+    Ok(keys)
+}
+
+/// Pick the next blobs for the integrity scan, grouped with the files using them
+///
+/// Blobs are visited in hash order, starting after `cursor`. A batch stops at
+/// `limit` blobs, or once it would read more than `max_bytes`, though it always
+/// takes at least one blob so the scan makes progress. Returns the batch and
+/// the cursor to resume from, or `None` if the batch reaches the end.
+pub fn plan_integrity_scan(
+    mappings: &[FileMapping],
+    cursor: Option<&str>,
+    limit: usize,
+    max_bytes: usize,
+) -> (Vec<(String, Vec<String>)>, Option<String>) {
+    let mut blobs: BTreeMap<&str, (usize, Vec<String>)> = BTreeMap::new();
+    for mapping in mappings {
+        // Inline files have no blob to check
+        if mapping.inline.is_some() || cursor.is_some_and(|c| mapping.sha256.as_str() <= c) {
+            continue;
+        }
+        let (_, files) = blobs
+            .entry(&mapping.sha256)
+            .or_insert_with(|| (mapping.size, Vec::new()));
+        files.push(mapping.filename.clone());
+    }
+
+    let total = blobs.len();
+    let mut batch = Vec::new();
+    let mut bytes = 0;
+    for (sha256, (size, mut files)) in blobs {
+        if batch.len() == limit || (!batch.is_empty() && bytes + size > max_bytes) {
+            break;
+        }
+        bytes += size;
+        files.sort();
+        batch.push((sha256.to_string(), files));
+    }
+
+    let next_cursor = (batch.len() < total)
+        .then(|| batch.last().map(|(sha256, _)| sha256.clone()))
+        .flatten();
+    (batch, next_cursor)
+}
+
+/// Re-hash the next batch of blobs and report any that are corrupt or missing
+///
+/// Blobs are read one at a time and each call is bounded by `limit` and
+/// `VERIFY_SCAN_MAX_BYTES`, so a scan of a large store runs as a series of
+/// short calls that leave R2 capacity for live traffic.
+pub async fn verify_blobs(
+    storage: &impl R2Storage,
+    cursor: Option<&str>,
+    limit: usize,
+    budget: &TimeBudget<'_>,
+) -> Result<IntegrityReport> {
+    let mappings = storage.list_mappings(None).await?;
+    let (batch, next_cursor) = plan_integrity_scan(&mappings, cursor, limit, VERIFY_SCAN_MAX_BYTES);
+
+    let mut report = IntegrityReport {
+        scanned: 0,
+        ok: 0,
+        corrupt: Vec::new(),
+        missing: Vec::new(),
+        next_cursor,
+        budget_exhausted: false,
+    };
+    let mut last_checked: Option<String> = None;
+    for (sha256, files) in batch {
+        // Always check one blob, so a resumed scan makes progress
+        if let Some(last) = &last_checked {
+            if budget.exhausted() {
+                report.next_cursor = Some(last.clone());
+                report.budget_exhausted = true;
+                break;
+            }
+        }
+        report.scanned += 1;
+        last_checked = Some(sha256.clone());
+        match storage.download_by_hash(&sha256).await {
+            Ok(Some(_)) => report.ok += 1,
+            Ok(None) => report.missing.push(BlobProblem {
+                sha256,
+                files,
+                error: None,
+            }),
+            // Integrity and decoding failures are reported as 502s
+            Err(Error::Json((message, 502))) => report.corrupt.push(BlobProblem {
+                sha256,
+                files,
+                error: Some(message),
+            }),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(report)
+}
+
+/// Decide which mappings a reindex should write
+///
+/// Each manifest entry is restored if its blob is in R2 and the file doesn't
+/// already exist. Blobs that no file, existing or restored, points at are
+/// recovered under `RECOVERED_PREFIX`. Running it again finds everything in
+/// place, so a repeated reindex writes nothing.
+pub fn plan_reindex(
+    blobs: &BTreeMap<String, BlobInfo>,
+    existing: &[FileMapping],
+    manifest: &BTreeMap<String, String>,
+) -> ReindexPlan {
+    let existing_files: HashSet<&str> = existing.iter().map(|m| m.filename.as_str()).collect();
+    let mut referenced: HashSet<&str> = existing.iter().map(|m| m.sha256.as_str()).collect();
+
+    let mut plan = ReindexPlan {
+        restore: Vec::new(),
+        recover: Vec::new(),
+        unchanged: 0,
+        missing: Vec::new(),
+    };
+    for (filename, sha256) in manifest {
+        if existing_files.contains(filename.as_str()) {
+            plan.unchanged += 1;
+        } else if blobs.contains_key(sha256) {
+            plan.restore.push((filename.clone(), sha256.clone()));
+            referenced.insert(sha256);
+        } else {
+            plan.missing.push(filename.clone());
+        }
+    }
+
+    plan.recover = blobs
+        .keys()
+        .filter(|sha256| !referenced.contains(sha256.as_str()))
+        .cloned()
+        .collect();
+    plan
+}
+
+/// Parse a reindex request body: a JSON object of filenames to SHA256 hashes
+///
+/// An empty body is an empty manifest. Filenames are sanitized like any other
+/// key and hashes lowercased.
+pub fn parse_reindex_manifest(body: &str) -> std::result::Result<BTreeMap<String, String>, String> {
+    if body.trim().is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    let entries: BTreeMap<String, String> = serde_json::from_str(body)
+        .map_err(|_| "Expected a JSON object of filenames to SHA256 hashes".to_string())?;
+
+    let mut manifest = BTreeMap::new();
+    for (filename, sha256) in entries {
+        let filename = sanitize_path(&filename).map_err(|e| format!("{}: {}", e, filename))?;
+        if is_reserved_file_key(&filename) {
+            return Err(format!(
+                "'{}' is reserved and can't be a filename",
+                filename
+            ));
+        }
+        if !is_valid_sha256(&sha256) {
+            return Err(format!("Invalid SHA256 for {}", filename));
+        }
+        manifest.insert(filename, sha256.to_ascii_lowercase());
+    }
+    Ok(manifest)
+}
+
+/// Recreate mappings from the blobs in R2, as planned by `plan_reindex`
+///
+/// Restored and recovered files get the content type stored with the blob and
+/// no user metadata. A compressed blob is read once to learn its original size.
+pub async fn reindex(
+    storage: &impl R2Storage,
+    manifest: &BTreeMap<String, String>,
+    budget: &TimeBudget<'_>,
+) -> Result<ReindexReport> {
+    let blobs = storage.list_blobs().await?;
+    let existing = storage.list_mappings(None).await?;
+    let plan = plan_reindex(&blobs, &existing, manifest);
+
+    let mut report = ReindexReport {
+        blobs: blobs.len(),
+        restored: 0,
+        recovered: 0,
+        rebuilt: 0,
+        unchanged: plan.unchanged,
+        missing: plan.missing,
+        complete: true,
+    };
+    // Written mappings count as existing next time, so stopping early is safe:
+    // the next call's plan only holds what this one didn't get to
+    let restores = plan
+        .restore
+        .iter()
+        .map(|(filename, sha256)| (filename.clone(), sha256, true));
+    let recovers = plan
+        .recover
+        .iter()
+        .map(|sha256| (format!("{}{}", RECOVERED_PREFIX, sha256), sha256, false));
+    for (i, (filename, sha256, restore)) in restores.chain(recovers).enumerate() {
+        if i > 0 && budget.exhausted() {
+            report.complete = false;
+            break;
+        }
+        if rebuild_mapping(storage, &blobs, &filename, sha256).await? {
+            if restore {
+                report.restored += 1;
+            } else {
+                report.recovered += 1;
+            }
+        }
+    }
+    report.rebuilt = report.restored + report.recovered;
+    Ok(report)
+}
+
+/// Map `filename` to a listed blob, returning `false` if the blob has since gone
+async fn rebuild_mapping(
+    storage: &impl R2Storage,
+    blobs: &BTreeMap<String, BlobInfo>,
+    filename: &str,
+    sha256: &str,
+) -> Result<bool> {
+    let blob = &blobs[sha256];
+    let size = if blob.compressed {
+        match storage.download_by_hash(sha256).await? {
+            Some((bytes, _)) => bytes.len(),
+            None => return Ok(false),
+        }
+    } else {
+        blob.size as usize
+    };
+    storage
+        .recreate_mapping(filename, sha256, size, blob)
+        .await?;
+    Ok(true)
+}
+
+/// Reject a request without the admin token, or `None` to let it through
+///
+/// Admin endpoints are off, answering `403`, unless `ADMIN_TOKEN` is set.
+fn admin_auth_error(req: &Request, storage: &R2StorageImpl) -> Result<Option<Response>> {
+    let token = match storage.env_string("ADMIN_TOKEN") {
+        Some(token) => token,
+        None => return Ok(Some(Response::error("Admin endpoints are disabled", 403)?)),
+    };
+    let authorization = req.headers().get("Authorization")?;
+    if is_admin_authorized(authorization.as_deref(), &token) {
+        return Ok(None);
+    }
+    let headers = Headers::new();
+    headers.set("WWW-Authenticate", "Bearer")?;
+    Ok(Some(
+        Response::error("Unauthorized", 401)?.with_headers(headers),
+    ))
+}
+
+/// Reject a request whose signed URL has expired or doesn't verify
+///
+/// `None` lets it through. Without `URL_SIGNING_SECRET` nothing verifies.
+fn signed_url_error(
+    req: &Request,
+    storage: &R2StorageImpl,
+    path: &str,
+    signature: &str,
+) -> Result<Option<Response>> {
+    let Some(secret) = storage.env_string("URL_SIGNING_SECRET") else {
+        return Ok(Some(Response::error("Signed URLs are disabled", 403)?));
+    };
+    let url = req.url()?;
+    if let Some(param) = unsigned_param(url.query_pairs().map(|(k, _)| k)) {
+        return Ok(Some(Response::error(
+            format!("Signed URLs don't allow ?{}", param),
+            403,
+        )?));
+    }
+    let expires = query_param(req, "exp")?;
+    let check = verify_signature(
+        &secret,
+        signed_method(req.method().as_ref()),
+        storage.tenant.as_deref(),
+        path,
+        expires.as_deref(),
+        signature,
+        js_sys::Date::now() as u64 / 1000,
+    );
+    match check {
+        SignatureCheck::Valid => Ok(None),
+        SignatureCheck::Expired => Ok(Some(Response::error("Signed URL has expired", 403)?)),
+        SignatureCheck::Invalid => Ok(Some(Response::error("Invalid signature", 403)?)),
+    }
+}
+
+/// Infer a content type from the key's file extension
+pub fn content_type_from_extension(key: &str) -> Option<&'static str> {
+    let filename = key.rsplit('/').next().unwrap_or(key);
+    let (_, extension) = filename.rsplit_once('.')?;
+
+    let content_type = match extension.to_ascii_lowercase().as_str() {
+        "txt" => "text/plain",
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "csv" => "text/csv",
+        "md" => "text/markdown",
+        "js" => "text/javascript",
+        "json" => "application/json",
+        "xml" => "application/xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "wasm" => "application/wasm",
+        _ => return None,
+    };
+    Some(content_type)
+}
+
+/// Detect common formats from the magic bytes at the start of the content
+pub fn sniff_content_type(data: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"PK\x05\x06", "application/zip"), // empty archive
+        (b"\x1f\x8b", "application/gzip"),
+    ];
+
+    SIGNATURES
+        .iter()
+        .find(|(magic, _)| data.starts_with(magic))
+        .map(|(_, content_type)| *content_type)
+}
+
+/// Result of `POST /files/hash`
+#[derive(Serialize, Debug, PartialEq)]
+pub struct HashCheck {
+    pub sha256: String,
+    /// Whether a blob with this content is already in R2
+    pub exists: bool,
+}
+
+/// Hash `data` and check whether its blob is already stored
+///
+/// Nothing is written, neither a blob nor a mapping.
+pub async fn check_hash(storage: &impl R2Storage, data: &[u8]) -> Result<HashCheck> {
+    let sha256 = compute_sha256(data).await?;
+    let exists = storage.stat_blob(&sha256).await?.is_some();
+    Ok(HashCheck { sha256, exists })
+}
+
+/// Look up whether each key exists, keyed by the key as given
+///
+/// Keys rejected by `sanitize_path` can't name a file, so they're reported as
+/// missing without a lookup.
+pub async fn check_exists(
+    storage: &impl R2Storage,
+    keys: &[String],
+) -> Result<HashMap<String, ExistsStatus>> {
+    let mut results = HashMap::new();
+
+    for key in keys {
+        if results.contains_key(key) {
+            continue;
+        }
+
+        let mapping = match sanitize_path(key) {
+            Ok(path) => storage.stat(&path).await?,
+            Err(_) => None,
+        };
+        results.insert(
+            key.clone(),
+            ExistsStatus {
+                exists: mapping.is_some(),
+                sha256: mapping.map(|m| m.sha256),
+            },
+        );
+    }
+
+    Ok(results)
+}
+
+/// Get the value of a query parameter, if present
+fn query_param(req: &Request, name: &str) -> Result<Option<String>> {
+    Ok(req
+        .url()?
+        .query_pairs()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.into_owned()))
+}
+
+/// Check whether a query parameter is set to `true`
+fn has_query_flag(req: &Request, name: &str) -> Result<bool> {
+    Ok(query_param(req, name)?.as_deref() == Some("true"))
+}
+
+/// Build the headers shared by GET and HEAD responses for a file
+///
+/// `charset` is added to textual content types that don't already name one.
+fn file_headers(
+    mapping: &FileMapping,
+    cache_control: &str,
+    charset: Option<&str>,
+) -> Result<Headers> {
+    let headers = Headers::new();
+    let content_type = mapping
+        .content_type
+        .as_deref()
+        .unwrap_or("application/octet-stream");
+    let content_type = match charset {
+        Some(charset) => with_charset(content_type, charset),
+        None => content_type.to_string(),
+    };
+    headers.set("Content-Type", &content_type)?;
+    // Browsers go by the type sent, never one guessed from the content
+    headers.set("X-Content-Type-Options", "nosniff")?;
+    // Add Content-Digest header with SHA-256
+    headers.set(
+        "Content-Digest",
+        &format!(
+            "sha-256=:{}:",
+            base64_encode(&hex_to_bytes(&mapping.sha256)?)
+        ),
+    )?;
+    headers.set("Last-Modified", &format_http_date(mapping.updated_at))?;
+    // The content hash identifies this exact version of the file
+    headers.set("ETag", &format!("\"{}\"", mapping.sha256))?;
+    headers.set("Cache-Control", cache_control)?;
+    for (name, value) in &mapping.metadata {
+        headers.set(&format!("{}{}", METADATA_RESPONSE_PREFIX, name), value)?;
+    }
+    Ok(headers)
+}
+
+/// Whether a content type holds text, so it should carry a charset
+///
+/// Covers `text/*`, `application/json` and `application/javascript`; media
+/// type parameters and case are ignored.
+pub fn is_textual_content_type(content_type: &str) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    media_type.starts_with("text/")
+        || media_type == "application/json"
+        || media_type == "application/javascript"
+}
+
+/// Append `; charset=...` to a textual content type that doesn't have one
+pub fn with_charset(content_type: &str, charset: &str) -> String {
+    let has_charset = content_type
+        .split(';')
+        .skip(1)
+        .any(|param| param.trim().to_ascii_lowercase().starts_with("charset="));
+    if is_textual_content_type(content_type) && !has_charset {
+        format!("{}; charset={}", content_type, charset)
+    } else {
+        content_type.to_string()
+    }
+}
+
+/// Note the name a file was uploaded as, if case folding changed it
+///
+/// Goes in the `original-name` metadata entry for display, unless the upload
+/// set that entry itself.
+pub fn record_original_name(metadata: &mut HashMap<String, String>, key: &str, original: &str) {
+    if key != original {
+        metadata
+            .entry(ORIGINAL_NAME_METADATA.to_string())
+            .or_insert_with(|| original.to_string());
+    }
+}
+
+/// Collect user metadata from `X-Amz-Meta-*` or `X-Meta-*` request headers
+///
+/// Metadata keys are lowercased with the prefix removed. Uploads exceeding
+/// `MAX_METADATA_ENTRIES` or `MAX_METADATA_BYTES` are rejected.
+pub fn extract_user_metadata(
+    headers: impl Iterator<Item = (String, String)>,
+) -> std::result::Result<HashMap<String, String>, &'static str> {
+    let mut metadata = HashMap::new();
+
+    for (name, value) in headers {
+        let name = name.to_ascii_lowercase();
+        let key = METADATA_HEADER_PREFIXES
+            .iter()
+            .find_map(|prefix| name.strip_prefix(prefix));
+
+        if let Some(key) = key {
+            if key.is_empty() {
+                return Err("Invalid metadata: empty key");
+            }
+            metadata.insert(key.to_string(), value);
         }
-        _ => Response::error("Method not allowed", 405),
     }
+
+    if metadata.len() > MAX_METADATA_ENTRIES {
+        return Err("Invalid metadata: too many entries");
+    }
+
+    let total_bytes: usize = metadata.iter().map(|(k, v)| k.len() + v.len()).sum();
+    if total_bytes > MAX_METADATA_BYTES {
+        return Err("Invalid metadata: total size exceeds limit");
+    }
+
+    Ok(metadata)
+}
+
+/// Check whether the request's If-Modified-Since allows a 304 response
+fn not_modified(req: &Request, mapping: &FileMapping) -> Result<bool> {
+    // If-None-Match takes precedence over If-Modified-Since (RFC 9110)
+    if let Some(if_none_match) = req.headers().get("If-None-Match")? {
+        return Ok(etag_matches(&if_none_match, &mapping.sha256));
+    }
+
+    let since = req
+        .headers()
+        .get("If-Modified-Since")?
+        .and_then(|value| parse_http_date(&value));
+    Ok(is_not_modified(mapping.updated_at, since))
+}
+
+/// Check an `If-None-Match` header against a file's content hash
+///
+/// Uses weak comparison, as `If-None-Match` requires, so `W/` tags match too.
+pub fn etag_matches(if_none_match: &str, sha256: &str) -> bool {
+    let current = format!("\"{}\"", sha256);
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == current)
+}
+
+/// Expiry time of an upload from its `X-Expires-In` header, in epoch milliseconds
+///
+/// The header is a whole number of seconds from `now`. No header means the
+/// file never expires.
+pub fn parse_expires_in(
+    value: Option<&str>,
+    now: u64,
+) -> std::result::Result<Option<u64>, &'static str> {
+    match value {
+        None => Ok(None),
+        Some(value) => match value.trim().parse::<u64>() {
+            Ok(secs) if secs > 0 => Ok(Some(now.saturating_add(secs.saturating_mul(1000)))),
+            _ => Err("Invalid X-Expires-In"),
+        },
+    }
+}
+
+/// Whether an upload's `If-None-Match` forbids replacing the existing file
+///
+/// `*` matches any existing file, making the upload create-only; an ETag
+/// matches only a file with that content. A new key always passes.
+pub fn upload_precondition_failed(if_none_match: &str, existing: Option<&FileMapping>) -> bool {
+    existing.is_some_and(|mapping| etag_matches(if_none_match, &mapping.sha256))
+}
+
+/// Compare a modification time against an If-Modified-Since timestamp
+///
+/// HTTP dates only have second precision, so the comparison ignores milliseconds.
+pub fn is_not_modified(updated_at: u64, if_modified_since: Option<u64>) -> bool {
+    match if_modified_since {
+        Some(since) => updated_at / 1000 <= since / 1000,
+        None => false,
+    }
+}
+
+/// Whether a file last changed at `updated_at` satisfies `If-Unmodified-Since`
+///
+/// Like `is_not_modified`, this compares whole seconds.
+pub fn is_unmodified_since(updated_at: u64, if_unmodified_since: u64) -> bool {
+    updated_at / 1000 <= if_unmodified_since / 1000
 }
 
 /// Convert hex string to bytes
@@ -333,7 +3749,7 @@ fn hex_to_bytes(hex: &str) -> Result<Vec<u8>> {
         .step_by(2)
         .map(|i| {
             u8::from_str_radix(&hex[i..i + 2], 16)
-                .map_err(|_| Error::RustError("Invalid hex string".to_string()))
+                .map_err(|_| StorageError::Invalid("invalid hex string".to_string()).into())
         })
         .collect()
 }
@@ -343,3 +3759,60 @@ fn base64_encode(bytes: &[u8]) -> String {
     use base64::{engine::general_purpose::STANDARD, Engine as _};
     STANDARD.encode(bytes)
 }
+
+/// Content of a file stored inline in its mapping, or `None` if it has a blob
+pub fn inline_content(mapping: &FileMapping) -> Result<Option<Vec<u8>>> {
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    match &mapping.inline {
+        Some(encoded) => STANDARD.decode(encoded).map(Some).map_err(|_| {
+            StorageError::Invalid(format!("Invalid inline content for {}", mapping.filename)).into()
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Upload size limit from a `MAX_UPLOAD_BYTES` value
+///
+/// Missing, invalid or zero values mean no limit.
+pub fn max_upload_bytes(value: Option<&str>) -> Option<u64> {
+    value
+        .and_then(|v| v.parse().ok())
+        .filter(|&max: &u64| max > 0)
+}
+
+/// Whether an `Expect` header asks for `100-continue`
+pub fn expects_continue(expect: Option<&str>) -> bool {
+    expect.is_some_and(|v| v.trim().eq_ignore_ascii_case("100-continue"))
+}
+
+/// Check an upload's declared size before its body is read
+///
+/// A `Content-Length` over `max` is rejected with 413. A client that sends
+/// `Expect: 100-continue` is waiting to hear whether to send the body, so it
+/// must declare a length when there's a limit to check it against.
+pub fn check_upload_length(
+    content_length: Option<&str>,
+    expect_continue: bool,
+    max: Option<u64>,
+) -> std::result::Result<(), (&'static str, u16)> {
+    let Some(max) = max else {
+        return Ok(());
+    };
+    match content_length.and_then(|v| v.trim().parse::<u64>().ok()) {
+        Some(length) if length > max => Err(("File too large", 413)),
+        Some(_) => Ok(()),
+        None if expect_continue => Err(("Content-Length required with Expect: 100-continue", 411)),
+        None => Ok(()),
+    }
+}
+
+/// Inline size threshold from an `INLINE_MAX_BYTES` value
+///
+/// Missing or invalid values turn inlining off (`0`); larger values are
+/// capped at `MAX_INLINE_BYTES`.
+pub fn inline_max_bytes(value: Option<&str>) -> usize {
+    value
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(0)
+        .min(MAX_INLINE_BYTES)
+}
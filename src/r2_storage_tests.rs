@@ -1,7 +1,17 @@
 #[cfg(test)]
 mod r2_storage_tests {
+    use crate::budget::TimeBudget;
+    use crate::file_mapping_object::{
+        paginate_mappings, FileMapping, FileVersion, ListQuery, StorageStats,
+    };
+    use crate::http_range::ByteRange;
     use crate::r2_storage::*;
-    use std::collections::HashMap;
+    use crate::security::fold_path_case;
+    use crate::sha256::compute_sha256;
+    use async_trait::async_trait;
+    use std::cell::RefCell;
+    use std::collections::{BTreeMap, HashMap};
+    use worker::ResponseBody;
 
     // Note: These tests use mocks rather than the real R2StorageImpl
     // This means they won't catch compilation errors in the actual implementation
@@ -54,6 +64,9 @@ mod r2_storage_tests {
             content_type: Some("text/plain".to_string()),
             uploaded_at: 1234567890,
             sha256: Some("abc123".to_string()),
+            metadata: HashMap::from([("owner".to_string(), "user123".to_string())]),
+            was_created: true,
+            deduplicated: false,
         };
 
         assert_eq!(metadata.key, "test.txt");
@@ -61,6 +74,13 @@ mod r2_storage_tests {
         assert_eq!(metadata.content_type, Some("text/plain".to_string()));
         assert_eq!(metadata.uploaded_at, 1234567890);
         assert_eq!(metadata.sha256, Some("abc123".to_string()));
+        assert_eq!(metadata.metadata.get("owner"), Some(&"user123".to_string()));
+        assert!(metadata.was_created);
+
+        // was_created is conveyed by the status code, not the JSON body
+        let json = serde_json::to_value(&metadata).unwrap();
+        assert!(json.get("was_created").is_none());
+        assert_eq!(json["deduplicated"], false);
     }
 
     #[test]
@@ -153,7 +173,7 @@ mod r2_storage_tests {
         ];
 
         for (filename, expected_type) in test_cases {
-            let inferred = match filename.split('.').last() {
+            let inferred = match filename.split('.').next_back() {
                 Some("txt") => "text/plain",
                 Some("html") => "text/html",
                 Some("json") => "application/json",
@@ -166,4 +186,1332 @@ mod r2_storage_tests {
             assert_eq!(inferred, expected_type, "Failed for file: {}", filename);
         }
     }
+
+    #[test]
+    fn test_is_not_modified() {
+        let updated_at = 1_234_567_890_500;
+
+        // No If-Modified-Since header means always send the body
+        assert!(!is_not_modified(updated_at, None));
+
+        // Same second (HTTP dates drop milliseconds) is not modified
+        assert!(is_not_modified(updated_at, Some(1_234_567_890_000)));
+
+        // Client copy is newer than the file
+        assert!(is_not_modified(updated_at, Some(1_234_567_900_000)));
+
+        // File changed after the client's copy
+        assert!(!is_not_modified(updated_at, Some(1_234_567_889_000)));
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        let sha = "a".repeat(64);
+        let tag = format!("\"{}\"", sha);
+
+        assert!(etag_matches(&tag, &sha));
+        assert!(etag_matches("*", &sha));
+        assert!(etag_matches(&format!("W/{}", tag), &sha));
+        assert!(etag_matches(&format!("\"other\", {}", tag), &sha));
+
+        assert!(!etag_matches("\"other\"", &sha));
+        // Unquoted hashes aren't valid entity tags
+        assert!(!etag_matches(&sha, &sha));
+    }
+
+    #[test]
+    fn test_is_textual_content_type() {
+        for textual in [
+            "text/plain",
+            "text/html; charset=iso-8859-1",
+            "Text/CSS",
+            "application/json",
+            "application/javascript",
+        ] {
+            assert!(is_textual_content_type(textual), "{}", textual);
+        }
+        for binary in [
+            "application/octet-stream",
+            "image/png",
+            "application/pdf",
+            "application/jsonl",
+            "",
+        ] {
+            assert!(!is_textual_content_type(binary), "{}", binary);
+        }
+    }
+
+    #[test]
+    fn test_with_charset() {
+        assert_eq!(
+            with_charset("text/plain", "utf-8"),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(
+            with_charset("application/json", "utf-8"),
+            "application/json; charset=utf-8"
+        );
+        // An explicit charset is kept
+        assert_eq!(
+            with_charset("text/html; Charset=iso-8859-1", "utf-8"),
+            "text/html; Charset=iso-8859-1"
+        );
+        // Binary types are left alone
+        assert_eq!(with_charset("image/png", "utf-8"), "image/png");
+    }
+
+    fn header_list(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_extract_user_metadata() {
+        let headers = header_list(&[
+            ("content-type", "text/plain"),
+            ("x-amz-meta-owner", "user123"),
+            ("X-Meta-Original-Name", "report.pdf"),
+        ]);
+
+        let metadata = extract_user_metadata(headers.into_iter()).unwrap();
+        assert_eq!(metadata.len(), 2);
+        assert_eq!(metadata.get("owner"), Some(&"user123".to_string()));
+        assert_eq!(
+            metadata.get("original-name"),
+            Some(&"report.pdf".to_string())
+        );
+
+        // No metadata headers at all
+        let headers = header_list(&[("content-type", "text/plain")]);
+        assert!(extract_user_metadata(headers.into_iter())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_extract_user_metadata_limits() {
+        // Empty key after the prefix
+        let headers = header_list(&[("x-meta-", "value")]);
+        assert_eq!(
+            extract_user_metadata(headers.into_iter()).unwrap_err(),
+            "Invalid metadata: empty key"
+        );
+
+        // Too many entries
+        let headers: Vec<(String, String)> = (0..=MAX_METADATA_ENTRIES)
+            .map(|i| (format!("x-meta-key{}", i), "v".to_string()))
+            .collect();
+        assert_eq!(
+            extract_user_metadata(headers.into_iter()).unwrap_err(),
+            "Invalid metadata: too many entries"
+        );
+
+        // Total size over the limit
+        let headers = vec![("x-meta-big".to_string(), "a".repeat(MAX_METADATA_BYTES))];
+        assert_eq!(
+            extract_user_metadata(headers.into_iter()).unwrap_err(),
+            "Invalid metadata: total size exceeds limit"
+        );
+
+        // Exactly at the limit is fine
+        let headers = vec![("x-meta-ok".to_string(), "a".repeat(MAX_METADATA_BYTES - 2))];
+        assert!(extract_user_metadata(headers.into_iter()).is_ok());
+    }
+
+    // Mock implementing the R2Storage trait for testing helpers built on it
+    struct MockStorage {
+        files: RefCell<HashMap<String, Vec<u8>>>,
+    }
+
+    impl MockStorage {
+        fn with_files(keys: &[&str]) -> Self {
+            Self {
+                files: RefCell::new(keys.iter().map(|k| (k.to_string(), vec![])).collect()),
+            }
+        }
+
+        async fn download(&self, key: &str) -> worker::Result<Option<(Vec<u8>, FileMapping)>> {
+            Ok(self.files.borrow().get(key).map(|data| {
+                let mapping = FileMapping {
+                    filename: key.to_string(),
+                    sha256: String::new(),
+                    size: data.len(),
+                    content_type: None,
+                    created_at: 0,
+                    updated_at: 0,
+                    metadata: HashMap::new(),
+                    deleted_at: None,
+                    compressed: false,
+                    expires_at: None,
+                    inline: None,
+                    encodings: BTreeMap::new(),
+                };
+                (data.clone(), mapping)
+            }))
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl R2Storage for MockStorage {
+        async fn upload(
+            &self,
+            key: &str,
+            data: Vec<u8>,
+            content_type: Option<&str>,
+            metadata: HashMap<String, String>,
+            _expires_at: Option<u64>,
+        ) -> worker::Result<FileMetadata> {
+            let size = data.len();
+            let deduplicated = self.files.borrow().values().any(|stored| *stored == data);
+            let was_created = self
+                .files
+                .borrow_mut()
+                .insert(key.to_string(), data)
+                .is_none();
+            Ok(FileMetadata {
+                key: key.to_string(),
+                size,
+                content_type: content_type.map(|s| s.to_string()),
+                uploaded_at: 0,
+                sha256: None,
+                metadata,
+                was_created,
+                deduplicated,
+            })
+        }
+
+        async fn stat(&self, key: &str) -> worker::Result<Option<FileMapping>> {
+            Ok(self.download(key).await?.map(|(_, mapping)| mapping))
+        }
+
+        async fn download_stream(
+            &self,
+            key: &str,
+            _verify_limit: Option<usize>,
+            _accept_gzip: bool,
+        ) -> worker::Result<Option<(ResponseBody, FileMapping)>> {
+            Ok(self
+                .download(key)
+                .await?
+                .map(|(data, mapping)| (ResponseBody::Body(data), mapping)))
+        }
+
+        async fn download_version(
+            &self,
+            _key: &str,
+            _version: u32,
+        ) -> worker::Result<Option<(Vec<u8>, FileMapping)>> {
+            Ok(None)
+        }
+
+        async fn list_versions(&self, _key: &str) -> worker::Result<Vec<FileVersion>> {
+            Ok(vec![])
+        }
+
+        async fn download_by_hash(
+            &self,
+            _sha256: &str,
+        ) -> worker::Result<Option<(Vec<u8>, Option<String>)>> {
+            Ok(None)
+        }
+
+        async fn stat_blob(&self, sha256: &str) -> worker::Result<Option<BlobInfo>> {
+            // Every stored file's content counts as a blob
+            let contents: Vec<Vec<u8>> = self.files.borrow().values().cloned().collect();
+            for data in contents {
+                if compute_sha256(&data).await? == sha256 {
+                    return Ok(Some(BlobInfo {
+                        size: data.len() as u64,
+                        content_type: None,
+                        compressed: false,
+                    }));
+                }
+            }
+            Ok(None)
+        }
+
+        async fn read_blob_range(
+            &self,
+            _sha256: &str,
+            _range: ByteRange,
+        ) -> worker::Result<Option<ResponseBody>> {
+            Ok(None)
+        }
+
+        async fn delete(&self, key: &str) -> worker::Result<()> {
+            self.files.borrow_mut().remove(key);
+            Ok(())
+        }
+
+        async fn list(&self, prefix: Option<&str>) -> worker::Result<Vec<String>> {
+            Ok(self
+                .files
+                .borrow()
+                .keys()
+                .filter(|k| prefix.map_or(true, |p| k.starts_with(p)))
+                .cloned()
+                .collect())
+        }
+
+        async fn list_mappings(&self, prefix: Option<&str>) -> worker::Result<Vec<FileMapping>> {
+            let mut mappings = Vec::new();
+            for key in self.list(prefix).await? {
+                mappings.extend(self.stat(&key).await?);
+            }
+            Ok(mappings)
+        }
+
+        async fn list_page(
+            &self,
+            prefix: Option<&str>,
+            query: &ListQuery,
+        ) -> worker::Result<FilePage> {
+            let mappings = self.list_mappings(prefix).await?;
+            Ok(paginate_mappings(mappings, query).into())
+        }
+
+        async fn restore(&self, _key: &str) -> worker::Result<Option<FileMapping>> {
+            Ok(None)
+        }
+
+        async fn list_blobs(&self) -> worker::Result<BTreeMap<String, BlobInfo>> {
+            Ok(BTreeMap::new())
+        }
+
+        async fn recreate_mapping(
+            &self,
+            key: &str,
+            _sha256: &str,
+            size: usize,
+            _blob: &BlobInfo,
+        ) -> worker::Result<()> {
+            self.files
+                .borrow_mut()
+                .insert(key.to_string(), vec![0; size]);
+            Ok(())
+        }
+
+        async fn stats(&self) -> worker::Result<StorageStats> {
+            Ok(StorageStats {
+                total_files: self.files.borrow().len(),
+                unique_blobs: 0,
+                logical_bytes: 0,
+                physical_bytes: 0,
+                dedup_ratio: 1.0,
+            })
+        }
+
+        async fn copy(
+            &self,
+            source: &str,
+            destination: &str,
+            overwrite: bool,
+        ) -> worker::Result<CopyResult> {
+            let mut files = self.files.borrow_mut();
+            let data = match files.get(source) {
+                Some(data) => data.clone(),
+                None => return Ok(CopyResult::SourceNotFound),
+            };
+            if !overwrite && files.contains_key(destination) {
+                return Ok(CopyResult::DestinationExists);
+            }
+            files.insert(destination.to_string(), data.clone());
+            Ok(CopyResult::Copied(Box::new(FileMapping {
+                filename: destination.to_string(),
+                sha256: String::new(),
+                size: data.len(),
+                content_type: None,
+                created_at: 0,
+                updated_at: 0,
+                metadata: HashMap::new(),
+                deleted_at: None,
+                compressed: false,
+                expires_at: None,
+                inline: None,
+                encodings: BTreeMap::new(),
+            })))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delete_prefix() {
+        let storage = MockStorage::with_files(&[
+            "images/a.png",
+            "images/b.png",
+            "images-old/c.png",
+            "docs/readme.md",
+        ]);
+
+        let mut deleted = delete_prefix(&storage, "images/", false).await.unwrap();
+        deleted.sort();
+        assert_eq!(deleted, vec!["images/a.png", "images/b.png"]);
+
+        let mut remaining = storage.list(None).await.unwrap();
+        remaining.sort();
+        assert_eq!(remaining, vec!["docs/readme.md", "images-old/c.png"]);
+
+        // Nothing matches
+        assert!(delete_prefix(&storage, "videos/", false)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Empty prefix deletes everything
+        assert_eq!(delete_prefix(&storage, "", false).await.unwrap().len(), 2);
+        assert!(storage.list(None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_delete_prefix_dry_run() {
+        let storage = MockStorage::with_files(&["images/a.png", "images/b.png", "docs/readme.md"]);
+
+        let mut would_delete = delete_prefix(&storage, "images/", true).await.unwrap();
+        would_delete.sort();
+        assert_eq!(would_delete, vec!["images/a.png", "images/b.png"]);
+
+        // Nothing was actually deleted
+        assert_eq!(storage.list(None).await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_check_exists() {
+        let storage = MockStorage::with_files(&["docs/readme.md"]);
+        let keys: Vec<String> = ["docs/readme.md", "docs/missing.md", "../etc/passwd", ""]
+            .iter()
+            .map(|k| k.to_string())
+            .collect();
+
+        let results = check_exists(&storage, &keys).await.unwrap();
+        assert_eq!(results.len(), 4);
+        assert_eq!(
+            results["docs/readme.md"],
+            ExistsStatus {
+                exists: true,
+                sha256: Some(String::new()),
+            }
+        );
+        assert!(!results["docs/missing.md"].exists);
+        assert!(!results["../etc/passwd"].exists);
+        assert_eq!(results[""].sha256, None);
+
+        let json = serde_json::to_value(&results["docs/missing.md"]).unwrap();
+        assert_eq!(json, serde_json::json!({"exists": false, "sha256": null}));
+    }
+
+    #[test]
+    fn test_next_retry_wait() {
+        // Retries disabled by default
+        assert_eq!(next_retry_wait(0, 0, 1, 0), None);
+
+        // Waits for the Retry-After duration while attempts remain
+        assert_eq!(next_retry_wait(0, 3, 1, 0), Some(1));
+        assert_eq!(next_retry_wait(2, 3, 2, 1), Some(2));
+        assert_eq!(next_retry_wait(3, 3, 1, 3), None);
+
+        // Total wait is bounded regardless of the retry count
+        assert_eq!(
+            next_retry_wait(0, 100, MAX_RETRY_WAIT_SECS, 0),
+            Some(MAX_RETRY_WAIT_SECS)
+        );
+        assert_eq!(next_retry_wait(5, 100, 1, MAX_RETRY_WAIT_SECS), None);
+        assert_eq!(next_retry_wait(0, 100, MAX_RETRY_WAIT_SECS + 1, 0), None);
+    }
+
+    #[test]
+    fn test_copy_request_parsing() {
+        let request: CopyRequest = serde_json::from_str(r#"{"destination": "b.txt"}"#).unwrap();
+        assert_eq!(request.destination, "b.txt");
+        assert!(!request.overwrite);
+
+        let request: CopyRequest =
+            serde_json::from_str(r#"{"destination": "b.txt", "overwrite": true}"#).unwrap();
+        assert!(request.overwrite);
+
+        assert!(serde_json::from_str::<CopyRequest>(r#"{"overwrite": true}"#).is_err());
+    }
+
+    #[test]
+    fn test_content_type_from_extension() {
+        assert_eq!(content_type_from_extension("a.txt"), Some("text/plain"));
+        assert_eq!(
+            content_type_from_extension("dir/photo.JPG"),
+            Some("image/jpeg")
+        );
+        assert_eq!(
+            content_type_from_extension("archive.tar.gz"),
+            Some("application/gzip")
+        );
+
+        assert_eq!(content_type_from_extension("README"), None);
+        assert_eq!(content_type_from_extension("data.bin"), None);
+        // Dots in directory names aren't extensions
+        assert_eq!(content_type_from_extension("v1.2/file"), None);
+    }
+
+    #[test]
+    fn test_sniff_content_type() {
+        assert_eq!(
+            sniff_content_type(b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR"),
+            Some("image/png")
+        );
+        assert_eq!(
+            sniff_content_type(b"\xff\xd8\xff\xe0\x00\x10JFIF"),
+            Some("image/jpeg")
+        );
+        assert_eq!(sniff_content_type(b"GIF87a..."), Some("image/gif"));
+        assert_eq!(sniff_content_type(b"GIF89a..."), Some("image/gif"));
+        assert_eq!(sniff_content_type(b"%PDF-1.7\n"), Some("application/pdf"));
+        assert_eq!(
+            sniff_content_type(b"PK\x03\x04\x14\x00"),
+            Some("application/zip")
+        );
+        assert_eq!(
+            sniff_content_type(b"\x1f\x8b\x08\x00"),
+            Some("application/gzip")
+        );
+
+        assert_eq!(sniff_content_type(b""), None);
+        assert_eq!(sniff_content_type(b"hello world"), None);
+        // Truncated signatures don't match
+        assert_eq!(sniff_content_type(b"\x89PN"), None);
+        assert_eq!(sniff_content_type(b"GIF8"), None);
+    }
+
+    #[test]
+    fn test_should_verify() {
+        assert!(should_verify(0, Some(DEFAULT_VERIFY_MAX_BYTES)));
+        assert!(should_verify(1024, Some(1024)));
+        assert!(!should_verify(1025, Some(1024)));
+
+        // `?verify=false` disables verification regardless of size
+        assert!(!should_verify(0, None));
+        assert!(!should_verify(1024, None));
+    }
+
+    #[test]
+    fn test_blob_key() {
+        let sha256 = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+        assert_eq!(blob_key(sha256, 0), format!("blobs/{}", sha256));
+        assert_eq!(blob_key(sha256, 1), format!("blobs/e3/{}", sha256));
+        assert_eq!(blob_key(sha256, 2), format!("blobs/e3/b0/{}", sha256));
+
+        // Depth is capped
+        assert_eq!(
+            blob_key(sha256, 100),
+            blob_key(sha256, MAX_BLOB_SHARD_DEPTH)
+        );
+        assert_eq!(blob_key(sha256, 4), format!("blobs/e3/b0/c4/42/{}", sha256));
+
+        // Short input doesn't panic
+        assert_eq!(blob_key("abc", 2), "blobs/ab/abc");
+    }
+
+    #[test]
+    fn test_index_key() {
+        assert_eq!(index_key("docs/", DEFAULT_INDEX_FILE), "docs/index.html");
+        assert_eq!(index_key("", DEFAULT_INDEX_FILE), "index.html");
+        assert_eq!(
+            index_key("site/blog/", "default.htm"),
+            "site/blog/default.htm"
+        );
+    }
+
+    #[test]
+    fn test_parse_bucket_config() {
+        assert!(parse_bucket_config(None).is_empty());
+        assert_eq!(
+            parse_bucket_config(Some("private=PRIVATE_BUCKET, public = PUBLIC_BUCKET")),
+            vec![
+                ("private".to_string(), "PRIVATE_BUCKET".to_string()),
+                ("public".to_string(), "PUBLIC_BUCKET".to_string()),
+            ]
+        );
+        // Malformed entries are skipped
+        assert!(parse_bucket_config(Some("private,=X,y=,a/b=Z,..=W")).is_empty());
+    }
+
+    #[test]
+    fn test_split_bucket() {
+        let buckets = parse_bucket_config(Some("private=PRIVATE_BUCKET"));
+
+        assert_eq!(
+            split_bucket("private/docs/a.txt", &buckets),
+            Some(("private", "PRIVATE_BUCKET", "docs/a.txt"))
+        );
+        assert_eq!(
+            split_bucket("private", &buckets),
+            Some(("private", "PRIVATE_BUCKET", ""))
+        );
+        // Anything else is a key in the default bucket
+        assert_eq!(split_bucket("docs/a.txt", &buckets), None);
+        assert_eq!(split_bucket("private-notes.txt", &buckets), None);
+        assert_eq!(split_bucket("", &buckets), None);
+    }
+
+    #[test]
+    fn test_mapping_instance_name() {
+        assert_eq!(mapping_instance_name(None, None), "global");
+        assert_eq!(
+            mapping_instance_name(Some("private"), None),
+            "bucket-private"
+        );
+        assert_eq!(
+            mapping_instance_name(None, Some("acme")),
+            "global/tenant-acme"
+        );
+        assert_eq!(
+            mapping_instance_name(Some("private"), Some("acme")),
+            "bucket-private/tenant-acme"
+        );
+    }
+
+    #[test]
+    fn test_accepts_gzip() {
+        assert!(accepts_gzip(Some("gzip")));
+        assert!(accepts_gzip(Some("br, GZIP;q=0.8")));
+        assert!(accepts_gzip(Some("*")));
+
+        assert!(!accepts_gzip(None));
+        assert!(!accepts_gzip(Some("br, deflate")));
+        assert!(!accepts_gzip(Some("gzip;q=0")));
+        assert!(!accepts_gzip(Some("identity")));
+    }
+
+    #[test]
+    fn test_compress_if_smaller() {
+        let text = b"hello hello hello hello hello hello hello".to_vec();
+        let (stored, compressed) = compress_if_smaller(text.clone());
+        assert!(compressed);
+        assert!(stored.len() < text.len());
+
+        // Tiny inputs grow under gzip, so they're stored as they are
+        assert_eq!(compress_if_smaller(b"hi".to_vec()), (b"hi".to_vec(), false));
+    }
+
+    #[test]
+    fn test_attachment_disposition() {
+        assert_eq!(
+            attachment_disposition("report.pdf"),
+            "attachment; filename=\"report.pdf\""
+        );
+        // Quotes, backslashes and line breaks can't escape the header
+        assert_eq!(
+            attachment_disposition("a\"b\\c\r\nSet-Cookie: x=1.txt"),
+            "attachment; filename=\"abcSet-Cookie: x=1.txt\""
+        );
+        // Nothing usable left falls back to a generic name
+        assert_eq!(
+            attachment_disposition("\"\r\n"),
+            "attachment; filename=\"download\""
+        );
+    }
+
+    #[test]
+    fn test_attachment_disposition_non_ascii() {
+        assert_eq!(
+            attachment_disposition("résumé 1.pdf"),
+            "attachment; filename=\"r_sum_ 1.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9%201.pdf"
+        );
+    }
+
+    #[test]
+    fn test_is_unmodified_since() {
+        let updated_at = 1_234_567_890_500;
+
+        // Same second or later is fine
+        assert!(is_unmodified_since(updated_at, 1_234_567_890_000));
+        assert!(is_unmodified_since(updated_at, 1_234_567_891_000));
+
+        // A change after the given date blocks the delete
+        assert!(!is_unmodified_since(updated_at, 1_234_567_889_000));
+    }
+
+    fn blob_mapping(filename: &str, sha256: &str, size: usize) -> FileMapping {
+        FileMapping {
+            filename: filename.to_string(),
+            sha256: sha256.to_string(),
+            size,
+            content_type: None,
+            created_at: 0,
+            updated_at: 0,
+            metadata: HashMap::new(),
+            deleted_at: None,
+            compressed: false,
+            expires_at: None,
+            inline: None,
+            encodings: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_plan_integrity_scan() {
+        let mappings = [
+            blob_mapping("c.txt", "bbbb", 10),
+            blob_mapping("a.txt", "aaaa", 10),
+            blob_mapping("b.txt", "bbbb", 10),
+            blob_mapping("d.txt", "cccc", 10),
+        ];
+
+        // Blobs come in hash order, each listed once with all its files
+        let (batch, cursor) = plan_integrity_scan(&mappings, None, 2, usize::MAX);
+        assert_eq!(
+            batch,
+            vec![
+                ("aaaa".to_string(), vec!["a.txt".to_string()]),
+                (
+                    "bbbb".to_string(),
+                    vec!["b.txt".to_string(), "c.txt".to_string()]
+                ),
+            ]
+        );
+        assert_eq!(cursor.as_deref(), Some("bbbb"));
+
+        // Resuming picks up after the cursor and finishes the scan
+        let (batch, cursor) = plan_integrity_scan(&mappings, Some("bbbb"), 2, usize::MAX);
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].0, "cccc");
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    fn test_plan_integrity_scan_byte_budget() {
+        let mappings = [
+            blob_mapping("big.bin", "aaaa", 100),
+            blob_mapping("small.txt", "bbbb", 10),
+        ];
+
+        // The budget stops a batch early, but never before its first blob
+        let (batch, cursor) = plan_integrity_scan(&mappings, None, 10, 50);
+        assert_eq!(batch.len(), 1);
+        assert_eq!(cursor.as_deref(), Some("aaaa"));
+
+        let (batch, cursor) = plan_integrity_scan(&mappings, None, 10, 110);
+        assert_eq!(batch.len(), 2);
+        assert_eq!(cursor, None);
+    }
+
+    #[tokio::test]
+    async fn test_verify_blobs_reports_missing() {
+        // The mock stores no blobs, so every mapping is orphaned
+        let storage = MockStorage::with_files(&["a.txt", "b.txt"]);
+
+        let now = || 0;
+        let report = verify_blobs(&storage, None, 10, &TimeBudget::new(u64::MAX, &now))
+            .await
+            .unwrap();
+        assert_eq!(report.scanned, 1);
+        assert_eq!(report.ok, 0);
+        assert!(report.corrupt.is_empty());
+        assert_eq!(report.missing.len(), 1);
+        assert_eq!(report.missing[0].files, vec!["a.txt", "b.txt"]);
+        assert_eq!(report.next_cursor, None);
+        assert!(!report.budget_exhausted);
+    }
+
+    #[tokio::test]
+    async fn test_verify_blobs_checks_one_blob_past_its_budget() {
+        let storage = MockStorage::with_files(&["a.txt"]);
+        let clock = std::cell::Cell::new(0);
+        let now = || clock.get();
+        let budget = TimeBudget::new(10, &now);
+        clock.set(1_000);
+
+        let report = verify_blobs(&storage, None, 10, &budget).await.unwrap();
+        assert_eq!(report.scanned, 1);
+        assert_eq!(report.missing.len(), 1);
+        assert_eq!(report.next_cursor, None);
+        assert!(!report.budget_exhausted);
+    }
+
+    #[test]
+    fn test_is_script_capable_content_type() {
+        assert!(is_script_capable_content_type("text/html"));
+        assert!(is_script_capable_content_type("Text/HTML; charset=utf-8"));
+        assert!(is_script_capable_content_type("image/svg+xml"));
+        assert!(is_script_capable_content_type("application/javascript"));
+        assert!(is_script_capable_content_type("application/xhtml+xml"));
+
+        assert!(!is_script_capable_content_type("text/plain"));
+        assert!(!is_script_capable_content_type("image/png"));
+        assert!(!is_script_capable_content_type("application/json"));
+    }
+
+    #[test]
+    fn test_is_valid_content_type_override() {
+        assert!(is_valid_content_type_override("image/png"));
+        assert!(is_valid_content_type_override("text/plain; charset=latin1"));
+        assert!(is_valid_content_type_override("application/vnd.api+json"));
+
+        assert!(!is_valid_content_type_override(""));
+        assert!(!is_valid_content_type_override("png"));
+        assert!(!is_valid_content_type_override("/png"));
+        assert!(!is_valid_content_type_override("image/"));
+        assert!(!is_valid_content_type_override("image/png/x"));
+        assert!(!is_valid_content_type_override("image /png"));
+        assert!(!is_valid_content_type_override(
+            "text/html\r\nSet-Cookie: a=b"
+        ));
+        assert!(!is_valid_content_type_override("text/plain\n"));
+        assert!(!is_valid_content_type_override("text/plaın"));
+    }
+
+    #[tokio::test]
+    async fn test_list_page() {
+        let storage = MockStorage::with_files(&["c.txt", "a.txt", "b.txt"]);
+        let query = ListQuery::from_query([("limit", "2"), ("order", "desc")])
+            .unwrap()
+            .unwrap();
+
+        let page = storage.list_page(None, &query).await.unwrap();
+        assert_eq!(
+            page,
+            FilePage {
+                files: vec!["c.txt".to_string(), "b.txt".to_string()],
+                total: 3,
+                next_offset: Some(2),
+            }
+        );
+    }
+
+    #[test]
+    fn test_upload_precondition_failed() {
+        let existing = blob_mapping("a.txt", "abc", 1);
+
+        // Create-only: fails for an existing key, passes for a new one
+        assert!(upload_precondition_failed("*", Some(&existing)));
+        assert!(!upload_precondition_failed("*", None));
+
+        // An ETag only blocks overwriting that exact content
+        assert!(upload_precondition_failed("\"abc\"", Some(&existing)));
+        assert!(!upload_precondition_failed("\"def\"", Some(&existing)));
+    }
+
+    #[tokio::test]
+    async fn test_upload_reports_deduplication() {
+        let storage = MockStorage::with_files(&[]);
+
+        let first = storage
+            .upload("a.txt", b"same".to_vec(), None, HashMap::new(), None)
+            .await
+            .unwrap();
+        assert!(!first.deduplicated);
+
+        let second = storage
+            .upload("b.txt", b"same".to_vec(), None, HashMap::new(), None)
+            .await
+            .unwrap();
+        assert!(second.deduplicated);
+        assert_eq!(serde_json::to_value(&second).unwrap()["deduplicated"], true);
+
+        let different = storage
+            .upload("c.txt", b"other".to_vec(), None, HashMap::new(), None)
+            .await
+            .unwrap();
+        assert!(!different.deduplicated);
+    }
+
+    #[test]
+    fn test_parse_expires_in() {
+        assert_eq!(parse_expires_in(None, 1000), Ok(None));
+        assert_eq!(parse_expires_in(Some("3600"), 1000), Ok(Some(3_601_000)));
+        assert_eq!(parse_expires_in(Some(" 1 "), 0), Ok(Some(1000)));
+
+        for invalid in ["0", "-5", "1.5", "soon", ""] {
+            assert!(
+                parse_expires_in(Some(invalid), 1000).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_max_upload_bytes() {
+        assert_eq!(max_upload_bytes(None), None);
+        assert_eq!(max_upload_bytes(Some("0")), None);
+        assert_eq!(max_upload_bytes(Some("lots")), None);
+        assert_eq!(max_upload_bytes(Some("1048576")), Some(1_048_576));
+    }
+
+    #[test]
+    fn test_expects_continue() {
+        assert!(expects_continue(Some("100-continue")));
+        assert!(expects_continue(Some(" 100-Continue ")));
+        assert!(!expects_continue(None));
+        assert!(!expects_continue(Some("200-ok")));
+    }
+
+    #[test]
+    fn test_check_upload_length() {
+        // No limit accepts anything, declared or not
+        assert_eq!(check_upload_length(Some("999999"), true, None), Ok(()));
+        assert_eq!(check_upload_length(None, true, None), Ok(()));
+
+        let max = Some(100);
+        assert_eq!(check_upload_length(Some("100"), true, max), Ok(()));
+        assert_eq!(
+            check_upload_length(Some("101"), false, max),
+            Err(("File too large", 413))
+        );
+        assert_eq!(
+            check_upload_length(Some("101"), true, max),
+            Err(("File too large", 413))
+        );
+
+        // Without a length, only 100-continue clients must declare one up front
+        assert_eq!(check_upload_length(None, false, max), Ok(()));
+        assert_eq!(
+            check_upload_length(None, true, max),
+            Err(("Content-Length required with Expect: 100-continue", 411))
+        );
+    }
+
+    #[test]
+    fn test_inline_max_bytes() {
+        assert_eq!(inline_max_bytes(None), 0);
+        assert_eq!(inline_max_bytes(Some("nope")), 0);
+        assert_eq!(inline_max_bytes(Some("64")), 64);
+        assert_eq!(inline_max_bytes(Some("100000")), MAX_INLINE_BYTES);
+    }
+
+    #[test]
+    fn test_inline_content() {
+        let mut mapping = blob_mapping("tiny.txt", "aaaa", 2);
+        assert_eq!(inline_content(&mapping).unwrap(), None);
+
+        mapping.inline = Some("aGk=".to_string());
+        assert_eq!(inline_content(&mapping).unwrap(), Some(b"hi".to_vec()));
+
+        mapping.inline = Some("not base64!".to_string());
+        assert!(inline_content(&mapping).is_err());
+    }
+
+    #[test]
+    fn test_plan_integrity_scan_skips_inline_files() {
+        let mut inline = blob_mapping("tiny.txt", "aaaa", 2);
+        inline.inline = Some("aGk=".to_string());
+        let mappings = [inline, blob_mapping("big.bin", "bbbb", 1000)];
+
+        let (batch, cursor) = plan_integrity_scan(&mappings, None, 10, usize::MAX);
+        assert_eq!(
+            batch,
+            vec![("bbbb".to_string(), vec!["big.bin".to_string()])]
+        );
+        assert_eq!(cursor, None);
+    }
+
+    fn blob_info(size: u64) -> BlobInfo {
+        BlobInfo {
+            size,
+            content_type: Some("text/plain".to_string()),
+            compressed: false,
+        }
+    }
+
+    #[test]
+    fn test_plan_reindex() {
+        let blobs = BTreeMap::from([
+            ("aaaa".to_string(), blob_info(1)),
+            ("bbbb".to_string(), blob_info(2)),
+            ("cccc".to_string(), blob_info(3)),
+        ]);
+        let existing = [blob_mapping("kept.txt", "aaaa", 1)];
+        let manifest = BTreeMap::from([
+            ("kept.txt".to_string(), "aaaa".to_string()),
+            ("named.txt".to_string(), "bbbb".to_string()),
+            ("gone.txt".to_string(), "dddd".to_string()),
+        ]);
+
+        let plan = plan_reindex(&blobs, &existing, &manifest);
+        assert_eq!(
+            plan,
+            ReindexPlan {
+                restore: vec![("named.txt".to_string(), "bbbb".to_string())],
+                // Only the blob nothing points at is recovered
+                recover: vec!["cccc".to_string()],
+                unchanged: 1,
+                missing: vec!["gone.txt".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_plan_reindex_is_idempotent() {
+        let blobs = BTreeMap::from([
+            ("aaaa".to_string(), blob_info(1)),
+            ("bbbb".to_string(), blob_info(2)),
+        ]);
+        let manifest = BTreeMap::from([("named.txt".to_string(), "aaaa".to_string())]);
+
+        // Once the first run's mappings exist, a second run has nothing to do
+        let existing = [
+            blob_mapping("named.txt", "aaaa", 1),
+            blob_mapping(&format!("{}bbbb", RECOVERED_PREFIX), "bbbb", 2),
+        ];
+        let plan = plan_reindex(&blobs, &existing, &manifest);
+        assert!(plan.restore.is_empty());
+        assert!(plan.recover.is_empty());
+        assert_eq!(plan.unchanged, 1);
+    }
+
+    #[test]
+    fn test_parse_reindex_manifest() {
+        assert!(parse_reindex_manifest("").unwrap().is_empty());
+        assert!(parse_reindex_manifest("  \n").unwrap().is_empty());
+
+        let sha = "E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855";
+        let manifest =
+            parse_reindex_manifest(&format!(r#"{{"/docs//a.txt": "{}"}}"#, sha)).unwrap();
+        assert_eq!(manifest["docs/a.txt"], sha.to_ascii_lowercase());
+
+        assert!(parse_reindex_manifest(r#"["a.txt"]"#).is_err());
+        assert!(parse_reindex_manifest(r#"{"a.txt": "abc"}"#).is_err());
+        assert!(parse_reindex_manifest(&format!(r#"{{"../a.txt": "{}"}}"#, sha)).is_err());
+        assert!(parse_reindex_manifest(&format!(r#"{{"stats": "{}"}}"#, sha)).is_err());
+    }
+
+    #[test]
+    fn test_is_reserved_file_key() {
+        for key in RESERVED_FILE_KEYS {
+            assert!(is_reserved_file_key(key), "{}", key);
+        }
+        // Only the top-level names collide with the endpoints
+        assert!(!is_reserved_file_key("docs/stats"));
+        assert!(!is_reserved_file_key("stats.txt"));
+        assert!(!is_reserved_file_key("Stats"));
+    }
+
+    #[tokio::test]
+    async fn test_reindex_reports_missing_blobs() {
+        let storage = MockStorage::with_files(&["kept.txt"]);
+        let manifest = BTreeMap::from([
+            ("kept.txt".to_string(), "aaaa".to_string()),
+            ("gone.txt".to_string(), "bbbb".to_string()),
+        ]);
+
+        let now = || 0;
+        let report = reindex(&storage, &manifest, &TimeBudget::new(u64::MAX, &now))
+            .await
+            .unwrap();
+        assert_eq!(report.blobs, 0);
+        assert_eq!(report.rebuilt, 0);
+        assert_eq!(report.unchanged, 1);
+        assert_eq!(report.missing, vec!["gone.txt"]);
+        assert!(report.complete);
+    }
+
+    #[tokio::test]
+    async fn test_download_envelope() {
+        let storage = MockStorage::with_files(&[]);
+        storage
+            .upload("hello.txt", b"hello".to_vec(), None, HashMap::new(), None)
+            .await
+            .unwrap();
+
+        match download_envelope(&storage, "hello.txt", 1024)
+            .await
+            .unwrap()
+        {
+            EnvelopeResult::Found(envelope) => {
+                assert_eq!(envelope.content_base64, "aGVsbG8=");
+                assert_eq!(envelope.metadata.filename, "hello.txt");
+                assert_eq!(envelope.metadata.size, 5);
+            }
+            _ => panic!("expected an envelope"),
+        }
+
+        assert!(matches!(
+            download_envelope(&storage, "hello.txt", 4).await.unwrap(),
+            EnvelopeResult::TooLarge {
+                size: 5,
+                max_bytes: 4
+            }
+        ));
+        assert!(matches!(
+            download_envelope(&storage, "missing.txt", 1024)
+                .await
+                .unwrap(),
+            EnvelopeResult::NotFound
+        ));
+    }
+
+    async fn collect_verified(chunks: &[&[u8]], sha256: &str) -> Vec<worker::Result<Vec<u8>>> {
+        use futures_util::StreamExt;
+        let chunks: Vec<worker::Result<Vec<u8>>> = chunks.iter().map(|c| Ok(c.to_vec())).collect();
+        verify_stream(
+            futures_util::stream::iter(chunks),
+            "a.txt".to_string(),
+            sha256.to_string(),
+        )
+        .collect()
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_verify_stream_passes_chunks_through() {
+        let sha256 = compute_sha256(b"hello world").await.unwrap();
+        let out = collect_verified(&[b"hello", b"", b" wor", b"ld"], &sha256).await;
+        let chunks: Vec<Vec<u8>> = out.into_iter().map(|c| c.unwrap()).collect();
+        assert_eq!(
+            chunks,
+            vec![b"hello".to_vec(), b" wor".to_vec(), b"ld".to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_verify_stream_withholds_last_chunk_on_mismatch() {
+        let sha256 = compute_sha256(b"something else").await.unwrap();
+        let out = collect_verified(&[b"hello", b" wor", b"ld"], &sha256).await;
+
+        // Everything but the last chunk goes out, then the error
+        assert_eq!(out.len(), 3);
+        assert_eq!(out[0].as_ref().unwrap(), b"hello");
+        assert_eq!(out[1].as_ref().unwrap(), b" wor");
+        match &out[2] {
+            Err(worker::Error::Json((message, 502))) => assert!(message.contains("a.txt")),
+            other => panic!("expected an integrity error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_stream_empty() {
+        let sha256 = compute_sha256(b"").await.unwrap();
+        assert!(collect_verified(&[], &sha256).await.is_empty());
+        assert_eq!(collect_verified(&[], "00").await.len(), 1);
+    }
+
+    #[test]
+    fn test_mapping_list_url() {
+        assert_eq!(
+            mapping_list_url(None, false).unwrap().as_str(),
+            "https://fake-host/"
+        );
+        assert_eq!(
+            mapping_list_url(None, true).unwrap().as_str(),
+            "https://fake-host/?fields=key"
+        );
+        assert_eq!(
+            mapping_list_url(Some("docs/a b"), true).unwrap().as_str(),
+            "https://fake-host/?prefix=docs%2Fa+b&fields=key"
+        );
+    }
+
+    #[test]
+    fn test_accepts_coding() {
+        assert!(accepts_coding(Some("gzip, br"), "br"));
+        assert!(accepts_coding(Some("BR;q=0.5"), "br"));
+        assert!(accepts_coding(Some("*"), "zstd"));
+        assert!(!accepts_coding(Some("gzip"), "br"));
+        assert!(!accepts_coding(Some("br;q=0"), "br"));
+        assert!(!accepts_coding(None, "br"));
+    }
+
+    #[test]
+    fn test_choose_encoding() {
+        let available = ["gzip", "br"];
+        let choose = |accept| choose_encoding(accept, available.iter().copied());
+
+        // Brotli is preferred over gzip when the client takes both
+        assert_eq!(choose(Some("gzip, deflate, br")), Some("br"));
+        assert_eq!(choose(Some("gzip")), Some("gzip"));
+        assert_eq!(choose(Some("gzip, br;q=0")), Some("gzip"));
+        // Nothing acceptable, or no header, means the identity content
+        assert_eq!(choose(Some("zstd")), None);
+        assert_eq!(choose(None), None);
+        assert_eq!(choose_encoding(Some("br"), std::iter::empty()), None);
+    }
+
+    #[tokio::test]
+    async fn test_check_hash() {
+        let storage = MockStorage::with_files(&[]);
+        storage
+            .upload("a.txt", b"stored".to_vec(), None, HashMap::new(), None)
+            .await
+            .unwrap();
+        let files_before = storage.files.borrow().len();
+
+        let stored = check_hash(&storage, b"stored").await.unwrap();
+        assert_eq!(stored.sha256, compute_sha256(b"stored").await.unwrap());
+        assert!(stored.exists);
+
+        let fresh = check_hash(&storage, b"not stored").await.unwrap();
+        assert_eq!(fresh.sha256, compute_sha256(b"not stored").await.unwrap());
+        assert!(!fresh.exists);
+
+        // Checking never writes
+        assert_eq!(storage.files.borrow().len(), files_before);
+        assert_eq!(
+            serde_json::to_value(&fresh).unwrap(),
+            serde_json::json!({ "sha256": fresh.sha256, "exists": false })
+        );
+    }
+
+    #[test]
+    fn test_empty_object_content() {
+        assert_eq!(empty_object_content(0, "blob x").unwrap(), Vec::<u8>::new());
+        let err = empty_object_content(5, "blob x").unwrap_err();
+        assert!(err.to_string().contains("Body of blob x"));
+    }
+
+    #[tokio::test]
+    async fn test_upload_download_empty_file() {
+        let storage = MockStorage::with_files(&[]);
+        let uploaded = storage
+            .upload("empty.txt", Vec::new(), None, HashMap::new(), None)
+            .await
+            .unwrap();
+        assert_eq!(uploaded.size, 0);
+        assert!(uploaded.was_created);
+
+        let (data, mapping) = storage.download("empty.txt").await.unwrap().unwrap();
+        assert!(data.is_empty());
+        assert_eq!(mapping.size, 0);
+
+        // Empty content hashes to the well-known empty SHA256, and passes the integrity check
+        let sha256 = compute_sha256(&data).await.unwrap();
+        assert_eq!(
+            sha256,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert!(collect_verified(&[], &sha256).await.is_empty());
+    }
+
+    #[test]
+    fn test_parse_content_type_allowlist() {
+        assert_eq!(parse_content_type_allowlist(None), None);
+        assert_eq!(parse_content_type_allowlist(Some(" , ")), None);
+        assert_eq!(
+            parse_content_type_allowlist(Some("image/*, Application/PDF")),
+            Some(vec!["image/*".to_string(), "application/pdf".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_mime_matches() {
+        // Exact matches ignore case and parameters
+        assert!(mime_matches("application/pdf", "application/pdf"));
+        assert!(mime_matches("text/plain", "Text/Plain; charset=utf-8"));
+        assert!(!mime_matches("application/pdf", "application/json"));
+
+        // Wildcard subtypes
+        assert!(mime_matches("image/*", "image/png"));
+        assert!(mime_matches("image/*", "IMAGE/svg+xml"));
+        assert!(!mime_matches("image/*", "video/mp4"));
+        assert!(!mime_matches("image/*", "imagex/png"));
+        assert!(mime_matches("*/*", "application/octet-stream"));
+        // `*/png` isn't a valid range
+        assert!(!mime_matches("*/png", "image/png"));
+
+        // Malformed content types never match
+        assert!(!mime_matches("*/*", "image"));
+        assert!(!mime_matches("image/*", "image/"));
+        assert!(!mime_matches("*/*", ""));
+    }
+
+    #[test]
+    fn test_content_type_allowed() {
+        let allowed = vec!["image/*".to_string(), "application/pdf".to_string()];
+        assert!(content_type_allowed(Some("image/jpeg"), &allowed));
+        assert!(content_type_allowed(Some("application/pdf"), &allowed));
+        assert!(!content_type_allowed(Some("text/html"), &allowed));
+        assert!(!content_type_allowed(None, &allowed));
+    }
+
+    #[test]
+    fn test_blob_resolution() {
+        let sha256 = "ab".repeat(32);
+        let mut mapping = FileMapping {
+            filename: "docs/a.txt".to_string(),
+            sha256: sha256.clone(),
+            size: 5,
+            content_type: None,
+            created_at: 0,
+            updated_at: 0,
+            metadata: HashMap::new(),
+            deleted_at: None,
+            compressed: false,
+            expires_at: None,
+            inline: None,
+            encodings: BTreeMap::new(),
+        };
+        let expected = blob_key(&sha256, 1);
+
+        // A blob written before sharding is found at its flat key
+        let flat = blob_key(&sha256, 0);
+        let found = BlobResolution::new(&mapping, Some(flat.clone()), expected.clone());
+        assert_eq!(
+            serde_json::to_value(&found).unwrap(),
+            serde_json::json!({
+                "filename": "docs/a.txt",
+                "sha256": sha256,
+                "blob_key": flat,
+                "exists": true,
+                "inline": false
+            })
+        );
+
+        let orphaned = BlobResolution::new(&mapping, None, expected.clone());
+        assert!(!orphaned.exists);
+        assert_eq!(orphaned.blob_key, expected);
+
+        mapping.inline = Some("aGVsbG8=".to_string());
+        let inline = BlobResolution::new(&mapping, None, expected);
+        assert!(inline.inline);
+        assert!(!inline.exists);
+    }
+
+    #[test]
+    fn test_record_original_name() {
+        let mut metadata = HashMap::new();
+        record_original_name(&mut metadata, "photo.jpg", "photo.jpg");
+        assert!(metadata.is_empty());
+
+        record_original_name(&mut metadata, "photo.jpg", "Photo.JPG");
+        assert_eq!(metadata[ORIGINAL_NAME_METADATA], "Photo.JPG");
+
+        // A name the uploader supplied wins
+        let mut metadata =
+            HashMap::from([(ORIGINAL_NAME_METADATA.to_string(), "mine".to_string())]);
+        record_original_name(&mut metadata, "photo.jpg", "Photo.JPG");
+        assert_eq!(metadata[ORIGINAL_NAME_METADATA], "mine");
+    }
+
+    #[tokio::test]
+    async fn test_case_folded_keys_share_one_file() {
+        let storage = MockStorage::with_files(&[]);
+        let upload = |name: &'static str, data: &'static [u8]| {
+            let key = fold_path_case(name).into_owned();
+            let mut metadata = HashMap::new();
+            record_original_name(&mut metadata, &key, name);
+            let storage = &storage;
+            async move {
+                storage
+                    .upload(&key, data.to_vec(), None, metadata, None)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let first = upload("Photos/Photo.JPG", b"pixels").await;
+        assert!(first.was_created);
+        assert_eq!(first.key, "photos/photo.jpg");
+        assert_eq!(first.metadata[ORIGINAL_NAME_METADATA], "Photos/Photo.JPG");
+
+        // Another spelling is the same file
+        let second = upload("photos/photo.jpg", b"pixels v2").await;
+        assert!(!second.was_created);
+        assert!(storage
+            .download("photos/photo.jpg")
+            .await
+            .unwrap()
+            .is_some());
+
+        // Dedup still goes by content, whatever the names
+        let third = upload("Backup/PHOTO.jpg", b"pixels v2").await;
+        assert!(third.was_created);
+        assert!(third.deduplicated);
+    }
 }
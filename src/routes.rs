@@ -0,0 +1,368 @@
+//! The table of routes served by the Worker, listed at `/`
+//!
+//! `route_request` dispatches by hand, so this table is what documents it:
+//! add a row here when adding a route. `/` returns the table as JSON, or as
+//! plain text to clients that ask for `text/plain`.
+//!
+//! The table also answers `OPTIONS`: any path it matches gets `204` with an
+//! `Allow` header listing the row's methods. A CORS preflight is an `OPTIONS`
+//! request too, and gets the same answer; with no `Access-Control-Allow-*`
+//! headers it grants nothing, so a CORS layer should answer preflights (those
+//! with `Access-Control-Request-Method`) before this, and can take its
+//! `Access-Control-Allow-Methods` from `allowed_methods`.
+
+use crate::negotiate::accepts_media;
+use crate::upload_object::is_valid_upload_id;
+use serde::Serialize;
+use worker::{Headers, Request, Response, Result};
+
+/// One path pattern and what it does
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RouteInfo {
+    /// The path, with `{placeholders}` for variable segments
+    pub path: &'static str,
+    pub methods: &'static [&'static str],
+    pub description: &'static str,
+}
+
+const fn route(
+    path: &'static str,
+    methods: &'static [&'static str],
+    description: &'static str,
+) -> RouteInfo {
+    RouteInfo {
+        path,
+        methods,
+        description,
+    }
+}
+
+/// Every route the Worker serves, in the order they're listed
+pub const ROUTES: &[RouteInfo] = &[
+    route("/", &["GET"], "This list of routes"),
+    route(
+        "/files/",
+        &["GET", "DELETE"],
+        "List all files, or delete them all with ?confirm=true",
+    ),
+    route(
+        "/files/{prefix}/",
+        &["GET", "DELETE"],
+        "List or delete the files under a prefix",
+    ),
+    route(
+        "/files/{path}",
+        &["GET", "HEAD", "PUT", "POST", "DELETE"],
+        "Download, upload or delete a file",
+    ),
+    route(
+        "/files/{path}/copy",
+        &["POST"],
+        "Copy a file to another key",
+    ),
+    route(
+        "/files/{path}/move",
+        &["POST"],
+        "Move a file to another key",
+    ),
+    route(
+        "/files/{path}/download-url",
+        &["POST"],
+        "Create a signed, expiring download URL (admin)",
+    ),
+    route(
+        "/files/{path}/touch",
+        &["POST"],
+        "Refresh a file's timestamps and expiry (admin)",
+    ),
+    route(
+        "/files/{path}/restore",
+        &["POST"],
+        "Restore a soft-deleted file",
+    ),
+    route(
+        "/files/{path}/uploads",
+        &["POST"],
+        "Start a resumable upload",
+    ),
+    route(
+        "/files/{path}/uploads/{upload_id}",
+        &["PUT", "DELETE"],
+        "Send a chunk of a resumable upload, or abort it",
+    ),
+    route(
+        "/files/{path}/uploads/{upload_id}/complete",
+        &["POST"],
+        "Assemble a resumable upload's chunks",
+    ),
+    route("/files/stats", &["GET"], "Deduplication statistics"),
+    route(
+        "/files/hash",
+        &["POST"],
+        "Hash content and check whether it's already stored, without storing it",
+    ),
+    route(
+        "/files/exists",
+        &["POST"],
+        "Check which of a list of keys exist",
+    ),
+    route(
+        "/files/verify",
+        &["POST"],
+        "Check stored blobs against their hashes",
+    ),
+    route(
+        "/files/reindex",
+        &["POST"],
+        "Rebuild file mappings from the blobs in R2 (admin)",
+    ),
+    route(
+        "/blobs/{sha256}",
+        &["GET", "HEAD"],
+        "Read a blob by its content hash",
+    ),
+    route(
+        "/counter/{id}",
+        &["GET", "POST", "PUT", "PATCH", "DELETE"],
+        "Read, increment, set, patch or reset a counter",
+    ),
+    route(
+        "/counter/{id}/cas",
+        &["POST"],
+        "Compare-and-swap a counter's value",
+    ),
+    route(
+        "/counter/{id}/ws",
+        &["GET"],
+        "Watch a counter over a WebSocket",
+    ),
+    route("/counters/batch", &["POST"], "Read many counters at once"),
+    route(
+        "/session/{id}",
+        &["GET", "PUT", "PATCH", "DELETE"],
+        "Read, write, patch or clear a session",
+    ),
+    route(
+        "/session/{id}/{key}",
+        &["DELETE"],
+        "Remove one top-level key from a session's data",
+    ),
+    route(
+        "/session/{id}/__data/{key}",
+        &["DELETE"],
+        "Remove a data key, including one named like an endpoint",
+    ),
+    route(
+        "/session/{id}/__keys",
+        &["GET"],
+        "List the top-level keys in a session",
+    ),
+    route(
+        "/session/{id}/schema",
+        &["GET", "PUT", "DELETE"],
+        "Read, set or remove a session's JSON Schema",
+    ),
+    route("/session/{id}/export", &["GET"], "Export a whole session"),
+    route(
+        "/session/{id}/import",
+        &["POST"],
+        "Restore a session from an export",
+    ),
+    route("/metrics", &["GET"], "Prometheus metrics"),
+    route(
+        "/admin/purge",
+        &["POST"],
+        "Delete all files, blobs, counters and sessions (test environments)",
+    ),
+];
+
+/// Whether `path` fits a route's pattern
+///
+/// `{path}` and `{prefix}` stand for one or more segments, other placeholders
+/// for exactly one, and `{upload_id}` only for a well-formed upload ID, as
+/// elsewhere the segment is part of a plain file's key.
+fn matches_pattern(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"{path}" | &"{prefix}", rest)) => (1..=path.len())
+            .take_while(|&n| !path[n - 1].is_empty())
+            .any(|n| matches_pattern(rest, &path[n..])),
+        Some((placeholder, rest)) if placeholder.starts_with('{') => match path.split_first() {
+            Some((segment, path)) if !segment.is_empty() => {
+                (*placeholder != "{upload_id}" || is_valid_upload_id(segment))
+                    && matches_pattern(rest, path)
+            }
+            _ => false,
+        },
+        Some((literal, rest)) => path.first() == Some(literal) && matches_pattern(rest, &path[1..]),
+    }
+}
+
+/// The methods `path` accepts, from the most specific route it matches
+///
+/// Routes with more fixed segments win, so `/files/stats` isn't taken for a
+/// file named `stats`. The default counter is also served at `/counter`.
+pub fn allowed_methods(path: &str) -> Option<&'static [&'static str]> {
+    let path = match path {
+        "/counter" | "/counter/" => "/counter/default",
+        _ => path,
+    };
+    let segments: Vec<&str> = path.split('/').collect();
+    let literals = |route: &RouteInfo| {
+        route
+            .path
+            .split('/')
+            .filter(|s| !s.is_empty() && !s.starts_with('{'))
+            .count()
+    };
+    ROUTES
+        .iter()
+        .filter(|route| {
+            let pattern: Vec<&str> = route.path.split('/').collect();
+            matches_pattern(&pattern, &segments)
+        })
+        .min_by_key(|route| std::cmp::Reverse(literals(route)))
+        .map(|route| route.methods)
+}
+
+/// `Allow` value for an `OPTIONS` request, which is itself always allowed
+pub fn options_allow_header(methods: &[&str]) -> String {
+    let mut allowed = methods.to_vec();
+    allowed.push("OPTIONS");
+    allowed.join(", ")
+}
+
+/// `204` for `OPTIONS`, with `Allow` listing what `methods` the resource takes
+pub fn options_response(methods: &[&str]) -> Result<Response> {
+    let headers = Headers::new();
+    headers.set("Allow", &options_allow_header(methods))?;
+    Ok(Response::empty()?.with_status(204).with_headers(headers))
+}
+
+/// Human-readable listing of `routes`, one per line
+pub fn routes_text(routes: &[RouteInfo]) -> String {
+    let mut text = String::from("Hello from Rust Workers! Available endpoints:\n");
+    for route in routes {
+        text.push_str(&format!(
+            "{} {} - {}\n",
+            route.methods.join(", "),
+            route.path,
+            route.description
+        ));
+    }
+    text
+}
+
+/// Response for `/`: the route table as JSON, or as text if `req` asks for it
+pub fn root_response(req: &Request) -> Result<Response> {
+    let accept = req.headers().get("Accept")?;
+    let mut response = if accepts_media(accept.as_deref(), "text/plain") {
+        Response::ok(routes_text(ROUTES))?
+    } else {
+        Response::from_json(&serde_json::json!({ "routes": ROUTES }))?
+    };
+    response.headers_mut().set("Vary", "Accept")?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_routes_are_well_formed() {
+        let mut paths = HashSet::new();
+        for route in ROUTES {
+            assert!(route.path.starts_with('/'), "{}", route.path);
+            assert!(!route.methods.is_empty(), "{}", route.path);
+            assert!(!route.description.is_empty(), "{}", route.path);
+            assert!(paths.insert(route.path), "{} listed twice", route.path);
+        }
+    }
+
+    #[test]
+    fn test_routes_text() {
+        let routes = [
+            route("/", &["GET"], "This list"),
+            route("/counter/{id}", &["GET", "POST"], "A counter"),
+        ];
+        assert_eq!(
+            routes_text(&routes),
+            "Hello from Rust Workers! Available endpoints:\n\
+             GET / - This list\n\
+             GET, POST /counter/{id} - A counter\n"
+        );
+    }
+
+    #[test]
+    fn test_route_serialization() {
+        assert_eq!(
+            serde_json::to_value(&ROUTES[0]).unwrap(),
+            serde_json::json!({
+                "path": "/",
+                "methods": ["GET"],
+                "description": "This list of routes"
+            })
+        );
+    }
+
+    #[test]
+    fn test_allowed_methods() {
+        let file: &[&str] = &["GET", "HEAD", "PUT", "POST", "DELETE"];
+        assert_eq!(allowed_methods("/files/a.txt"), Some(file));
+        assert_eq!(allowed_methods("/files/docs/a.txt"), Some(file));
+        assert_eq!(
+            allowed_methods("/files/docs/"),
+            Some(&["GET", "DELETE"][..])
+        );
+        assert_eq!(allowed_methods("/files/"), Some(&["GET", "DELETE"][..]));
+        assert_eq!(
+            allowed_methods("/files/docs/a.txt/copy"),
+            Some(&["POST"][..])
+        );
+        assert_eq!(allowed_methods("/files/stats"), Some(&["GET"][..]));
+        assert_eq!(allowed_methods("/blobs/abc"), Some(&["GET", "HEAD"][..]));
+        assert_eq!(
+            allowed_methods("/session/s1/schema"),
+            Some(&["GET", "PUT", "DELETE"][..])
+        );
+        assert_eq!(
+            allowed_methods("/session/s1"),
+            Some(&["GET", "PUT", "PATCH", "DELETE"][..])
+        );
+        assert_eq!(allowed_methods("/session/s1/theme"), Some(&["DELETE"][..]));
+        assert_eq!(
+            allowed_methods("/session/s1/__data/schema"),
+            Some(&["DELETE"][..])
+        );
+        assert_eq!(allowed_methods("/"), Some(&["GET"][..]));
+    }
+
+    #[test]
+    fn test_allowed_methods_placeholders() {
+        let counter: &[&str] = &["GET", "POST", "PUT", "PATCH", "DELETE"];
+        assert_eq!(allowed_methods("/counter/hits"), Some(counter));
+        assert_eq!(allowed_methods("/counter"), Some(counter));
+        assert_eq!(allowed_methods("/counter/hits/cas"), Some(&["POST"][..]));
+
+        // Only a real upload ID makes a path an upload
+        let upload = format!("/files/a.bin/uploads/{}", "ab".repeat(16));
+        assert_eq!(allowed_methods(&upload), Some(&["PUT", "DELETE"][..]));
+        assert_eq!(
+            allowed_methods("/files/a.bin/uploads/notes.txt"),
+            Some(&["GET", "HEAD", "PUT", "POST", "DELETE"][..])
+        );
+
+        // Empty segments and unknown paths match nothing
+        assert_eq!(allowed_methods("/counter/hits/"), None);
+        assert_eq!(allowed_methods("/files//a.txt"), None);
+        assert_eq!(allowed_methods("/session/"), None);
+        assert_eq!(allowed_methods("/nope"), None);
+    }
+
+    #[test]
+    fn test_options_allow_header() {
+        assert_eq!(options_allow_header(&["GET", "HEAD"]), "GET, HEAD, OPTIONS");
+    }
+}
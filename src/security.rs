@@ -1,5 +1,8 @@
 //! Security utilities for input validation and sanitization
 
+use std::borrow::Cow;
+use unicode_normalization::UnicodeNormalization;
+
 /// Sanitize a file path to prevent directory traversal attacks
 ///
 /// This function:
@@ -36,6 +39,120 @@ pub fn sanitize_path(path: &str) -> Result<String, &'static str> {
     Ok(components.join("/"))
 }
 
+/// Normalize the Unicode in a percent-encoded URL path to NFC
+///
+/// `café` arrives as `caf%C3%A9` in NFC or `cafe%CC%81` in NFD; both come out
+/// as `caf%C3%A9`. Only escapes of non-ASCII bytes are decoded, so ASCII and
+/// escapes like `%20` stay exactly as sent. A path already in NFC, or whose
+/// escapes aren't valid UTF-8, is returned unchanged.
+pub fn normalize_path_nfc(path: &str) -> Cow<'_, str> {
+    let decoded = match decode_non_ascii_escapes(path) {
+        Some(s) if !s.is_ascii() => s,
+        _ => return Cow::Borrowed(path),
+    };
+    let normalized: String = decoded.nfc().collect();
+    if normalized == decoded {
+        return Cow::Borrowed(path);
+    }
+    Cow::Owned(encode_non_ascii(&normalized))
+}
+
+/// Lowercase a percent-encoded URL path, so keys differing only in case match
+///
+/// `Photo.JPG` becomes `photo.jpg`, and non-ASCII letters are lowercased too:
+/// `%C3%89t%C3%A9` (`Été`) becomes `%C3%A9t%C3%A9`. Escapes of ASCII bytes,
+/// like `%2F`, stay exactly as sent. A path that's already lowercase is
+/// returned unchanged.
+pub fn fold_path_case(path: &str) -> Cow<'_, str> {
+    // Escapes that aren't valid UTF-8 are left alone and only the rest is folded
+    let decoded = decode_non_ascii_escapes(path).unwrap_or_else(|| path.to_string());
+    let mut folded = String::with_capacity(decoded.len());
+    let mut chars = decoded.chars();
+    while let Some(c) = chars.next() {
+        let rest = chars.as_str().as_bytes();
+        if c == '%' && rest.len() >= 2 && rest[..2].iter().all(u8::is_ascii_hexdigit) {
+            folded.push(c);
+            folded.extend(chars.by_ref().take(2));
+        } else {
+            folded.extend(c.to_lowercase());
+        }
+    }
+
+    let folded = encode_non_ascii(&folded);
+    if folded == path {
+        Cow::Borrowed(path)
+    } else {
+        Cow::Owned(folded)
+    }
+}
+
+/// Decode the escapes of non-ASCII bytes in a percent-encoded path
+///
+/// Returns `None` if the decoded bytes aren't valid UTF-8.
+fn decode_non_ascii_escapes(path: &str) -> Option<String> {
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(hi), Some(lo)) = (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                let byte = hi << 4 | lo;
+                if byte >= 0x80 {
+                    decoded.push(byte);
+                    i += 3;
+                    continue;
+                }
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Percent-encode the non-ASCII characters of `path`, leaving the rest as is
+fn encode_non_ascii(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len() * 3);
+    for c in path.chars() {
+        if c.is_ascii() {
+            encoded.push(c);
+        } else {
+            for b in c.to_string().bytes() {
+                encoded.push_str(&format!("%{:02X}", b));
+            }
+        }
+    }
+    encoded
+}
+
+/// Check that a string is a SHA-256 digest in hex (64 hex characters)
+pub fn is_valid_sha256(value: &str) -> bool {
+    value.len() == 64 && value.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Check an `Authorization` header against the admin token
+///
+/// The header must be `Bearer <token>`. The token is compared in constant time,
+/// so response timing doesn't reveal how much of a guess was right.
+pub fn is_admin_authorized(authorization: Option<&str>, admin_token: &str) -> bool {
+    let presented = match authorization.and_then(|h| h.strip_prefix("Bearer ")) {
+        Some(token) => token.trim().as_bytes(),
+        None => return false,
+    };
+    !admin_token.is_empty() && constant_time_eq(presented, admin_token.as_bytes())
+}
+
+/// Compare two secrets without the time taken revealing where they differ
+pub fn constant_time_eq(presented: &[u8], expected: &[u8]) -> bool {
+    presented.len() == expected.len()
+        && presented
+            .iter()
+            .zip(expected)
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -116,4 +233,75 @@ mod tests {
             "Invalid path: empty after sanitization"
         );
     }
+
+    #[test]
+    fn test_is_valid_sha256() {
+        assert!(is_valid_sha256(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        ));
+        assert!(is_valid_sha256(
+            "E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855"
+        ));
+
+        assert!(!is_valid_sha256(""));
+        assert!(!is_valid_sha256("abc123"));
+        // 63 and 65 characters
+        assert!(!is_valid_sha256(&"a".repeat(63)));
+        assert!(!is_valid_sha256(&"a".repeat(65)));
+        // Non-hex characters, including path tricks
+        assert!(!is_valid_sha256(&"g".repeat(64)));
+        assert!(!is_valid_sha256(&format!("../{}", "a".repeat(61))));
+    }
+
+    #[test]
+    fn test_is_admin_authorized() {
+        assert!(is_admin_authorized(Some("Bearer s3cret"), "s3cret"));
+
+        assert!(!is_admin_authorized(None, "s3cret"));
+        assert!(!is_admin_authorized(Some("s3cret"), "s3cret"));
+        assert!(!is_admin_authorized(Some("Basic s3cret"), "s3cret"));
+        assert!(!is_admin_authorized(Some("Bearer s3cre"), "s3cret"));
+        assert!(!is_admin_authorized(Some("Bearer s3creT"), "s3cret"));
+        // An empty token never authorizes anything
+        assert!(!is_admin_authorized(Some("Bearer "), ""));
+    }
+
+    #[test]
+    fn test_normalize_path_nfc() {
+        // `é` as one code point (NFC) and as `e` plus a combining accent (NFD)
+        let nfc = "docs/caf%C3%A9.txt";
+        let nfd = "docs/cafe%CC%81.txt";
+        assert_eq!(normalize_path_nfc(nfd), nfc);
+        assert_eq!(normalize_path_nfc(nfc), nfc);
+        assert!(matches!(normalize_path_nfc(nfc), Cow::Borrowed(_)));
+
+        // ASCII paths and ASCII escapes are left exactly as sent
+        for path in ["docs/readme.md", "my%20file.txt", "a%2fb", "100%", "x%zz"] {
+            assert!(matches!(normalize_path_nfc(path), Cow::Borrowed(p) if p == path));
+        }
+
+        // Escapes that aren't valid UTF-8 can't be normalized
+        assert_eq!(normalize_path_nfc("bad%FFe%CC%81"), "bad%FFe%CC%81");
+        // Lowercase hex in a path that needs no change isn't rewritten
+        assert_eq!(normalize_path_nfc("caf%c3%a9"), "caf%c3%a9");
+    }
+
+    #[test]
+    fn test_fold_path_case() {
+        assert_eq!(fold_path_case("Photos/Photo.JPG"), "photos/photo.jpg");
+        assert!(matches!(
+            fold_path_case("photos/photo.jpg"),
+            Cow::Borrowed(_)
+        ));
+        // Non-ASCII letters fold too: `Été` to `été`
+        assert_eq!(fold_path_case("%C3%89t%C3%A9.txt"), "%C3%A9t%C3%A9.txt");
+        assert_eq!(fold_path_case("%c3%a9t%c3%a9.txt"), "%C3%A9t%C3%A9.txt");
+
+        // ASCII escapes are kept as sent rather than having their hex lowercased
+        assert_eq!(fold_path_case("My%2FFile%20A.TXT"), "my%2Ffile%20a.txt");
+        assert_eq!(fold_path_case("100%"), "100%");
+        assert_eq!(fold_path_case("X%ZZ"), "x%zz");
+        // Invalid UTF-8 escapes survive while the letters around them fold
+        assert_eq!(fold_path_case("Bad%FF.TXT"), "bad%FF.txt");
+    }
 }
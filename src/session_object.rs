@@ -1,29 +1,383 @@
+use crate::do_storage::StorageWrites;
+use crate::error::{method_not_allowed, storage_error_response};
+use crate::json_patch::{self, PatchError, PatchOperation};
+use crate::json_schema;
 use serde::{Deserialize, Serialize};
 use worker::*;
 
+/// Storage key holding the optional JSON Schema for session data
+const SCHEMA_KEY: &str = "schema";
+
+/// Prefix reserved for built-in session paths like `__keys`, so it can't be
+/// used for top-level keys in session data
+pub const RESERVED_KEY_PREFIX: &str = "__";
+
+/// Largest serialized session data accepted when `MAX_SESSION_BYTES` isn't set
+pub const DEFAULT_MAX_SESSION_BYTES: usize = 128 * 1024;
+
+/// Media type for JSON Patch documents (RFC 6902)
+pub const JSON_PATCH_CONTENT_TYPE: &str = "application/json-patch+json";
+
+/// Check a `Content-Type` header names JSON Patch, ignoring any parameters
+pub fn is_json_patch_content_type(content_type: &str) -> bool {
+    content_type
+        .split(';')
+        .next()
+        .is_some_and(|media| media.trim().eq_ignore_ascii_case(JSON_PATCH_CONTENT_TYPE))
+}
+
+/// Format a session version as a strong ETag
+pub fn version_etag(version: u64) -> String {
+    format!("\"{}\"", version)
+}
+
+/// Check an `If-Match` header against the current session version
+///
+/// A missing header always matches so clients that don't use ETags keep
+/// working. `*` matches any version.
+pub fn if_match_satisfied(if_match: Option<&str>, version: u64) -> bool {
+    let if_match = match if_match {
+        Some(h) => h,
+        None => return true,
+    };
+
+    let current = version_etag(version);
+    if_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag == current)
+}
+
+/// Session data size limit in bytes, from a `MAX_SESSION_BYTES` value
+///
+/// Missing, zero or invalid values fall back to the default.
+pub fn max_session_bytes(value: Option<&str>) -> usize {
+    value
+        .and_then(|v| v.parse().ok())
+        .filter(|&n: &usize| n > 0)
+        .unwrap_or(DEFAULT_MAX_SESSION_BYTES)
+}
+
+/// Size of session data once serialized to JSON, as it's stored
+pub fn serialized_size(data: &serde_json::Value) -> Result<usize> {
+    Ok(serde_json::to_vec(data)?.len())
+}
+
+/// Top-level keys of session data, or none if the data isn't an object
+pub fn top_level_keys(data: &serde_json::Value) -> Vec<String> {
+    data.as_object()
+        .map(|object| object.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Path under which a `DELETE` removes one data key, whatever its name
+///
+/// It starts with `RESERVED_KEY_PREFIX`, so a data key can't collide with it.
+/// Keys named after built-in paths, like `schema`, are removed through it.
+pub const DATA_KEY_PATH: &str = "/__data/";
+
+/// Session paths that name an endpoint rather than a data key
+pub const BUILT_IN_PATHS: [&str; 3] = ["schema", "export", "import"];
+
+/// The Durable Object path a `DELETE /session/{id}/{key}` is sent to
+///
+/// No key clears the session, and a built-in or reserved name, such as
+/// `schema` or `__data/{key}`, goes to that path. Any other key is removed
+/// from `data`.
+pub fn session_delete_path(key: Option<&str>) -> String {
+    match key {
+        None => "/".to_string(),
+        Some(key) if key.starts_with(RESERVED_KEY_PREFIX) || BUILT_IN_PATHS.contains(&key) => {
+            format!("/{}", key)
+        }
+        Some(key) => format!("{}{}", DATA_KEY_PATH, key),
+    }
+}
+
+/// The data key a `DELETE` to `path` removes, if it addresses one
+pub fn deleted_key(path: &str) -> Option<&str> {
+    path.strip_prefix(DATA_KEY_PATH)
+        .filter(|key| !key.is_empty())
+}
+
+/// Remove `key` from the top level of session data, returning its value
+pub fn remove_data_key(data: &mut serde_json::Value, key: &str) -> Option<serde_json::Value> {
+    data.as_object_mut()?.remove(key)
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct SessionData {
     pub user_id: String,
     pub data: serde_json::Value,
     pub created_at: u64,
     pub updated_at: u64,
+    /// Incremented on every write; sent as the session's ETag
+    #[serde(default)]
+    pub version: u64,
+}
+
+/// A whole session, as returned by `/export` and accepted by `/import`
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SessionExport {
+    pub user_id: String,
+    pub data: serde_json::Value,
+    pub created_at: u64,
+    pub updated_at: u64,
+    #[serde(default)]
+    pub version: u64,
+    /// The session's data schema, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<serde_json::Value>,
+}
+
+/// Parse and check a session export document for import
+///
+/// Every field but `version` and `schema` is required, so a truncated or
+/// hand-edited document is rejected rather than half restored.
+pub fn parse_session_export(body: &str) -> std::result::Result<SessionExport, String> {
+    let export: SessionExport = serde_json::from_str(body).map_err(|e| e.to_string())?;
+    if export.data.is_null() {
+        return Err("missing field `data`".to_string());
+    }
+    if export.updated_at < export.created_at {
+        return Err("`updated_at` is before `created_at`".to_string());
+    }
+    if let Some(schema) = &export.schema {
+        json_schema::check_schema(schema).map_err(|e| format!("invalid schema: {}", e))?;
+    }
+    Ok(export)
+}
+
+/// Check that a request body is UTF-8 JSON, returning it as text
+///
+/// The error is a message fit for a 400 response.
+pub fn validate_json_body(body: &[u8]) -> std::result::Result<String, String> {
+    let text = String::from_utf8(body.to_vec())
+        .map_err(|e| format!("Request body is not valid UTF-8: {}", e.utf8_error()))?;
+    serde_json::from_str::<serde_json::Value>(&text).map_err(|e| format!("Invalid JSON: {}", e))?;
+    Ok(text)
+}
+
+/// What a session `PUT` writes
+#[derive(Debug, PartialEq)]
+pub struct SessionWrite {
+    pub user_id: Option<String>,
+    pub data: Option<serde_json::Value>,
+}
+
+/// Work out what a `PUT` body writes, given whether the session exists yet
+///
+/// Creating a session requires a `user_id`, and its data starts from the
+/// body's `defaults` object with any supplied `data` merged over it, so a new
+/// session is never missing either. Updates need neither and ignore
+/// `defaults`. The error is a message fit for a 400 response.
+pub fn plan_session_put(
+    body: &serde_json::Value,
+    exists: bool,
+) -> std::result::Result<SessionWrite, String> {
+    let user_id = body
+        .get("user_id")
+        .and_then(|v| v.as_str())
+        .filter(|id| !id.is_empty())
+        .map(str::to_string);
+    let data = body.get("data").cloned();
+    if exists {
+        return Ok(SessionWrite { user_id, data });
+    }
+
+    if user_id.is_none() {
+        return Err("user_id is required to create a session".to_string());
+    }
+    let defaults = match body.get("defaults") {
+        None | Some(serde_json::Value::Null) => serde_json::json!({}),
+        Some(defaults @ serde_json::Value::Object(_)) => defaults.clone(),
+        Some(_) => return Err("defaults must be an object".to_string()),
+    };
+    let data = match data {
+        Some(data) => merge_defaults(&defaults, &data),
+        None => defaults,
+    };
+    Ok(SessionWrite {
+        user_id,
+        data: Some(data),
+    })
+}
+
+/// `data` with any keys it lacks filled in from `defaults`, recursively
+///
+/// Where both have a key, `data` wins unless both values are objects, in
+/// which case they're merged the same way.
+pub fn merge_defaults(defaults: &serde_json::Value, data: &serde_json::Value) -> serde_json::Value {
+    match (defaults, data) {
+        (serde_json::Value::Object(defaults), serde_json::Value::Object(data)) => {
+            let mut merged = defaults.clone();
+            for (key, value) in data {
+                let value = match merged.get(key) {
+                    Some(default) => merge_defaults(default, value),
+                    None => value.clone(),
+                };
+                merged.insert(key.clone(), value);
+            }
+            serde_json::Value::Object(merged)
+        }
+        _ => data.clone(),
+    }
+}
+
+/// Only the dot-separated `paths` of `value`, e.g. `["a", "b.c"]`
+///
+/// Paths walk through objects only; one that's missing, or runs into
+/// anything else, is left out. A path covering another, like `b` and `b.c`,
+/// keeps all of `b`.
+pub fn project_fields(value: &serde_json::Value, paths: &[&str]) -> serde_json::Value {
+    let mut projected = serde_json::Map::new();
+    if let Some(source) = value.as_object() {
+        for path in paths {
+            let segments: Vec<&str> = path.split('.').collect();
+            if let Some(found) = get_path(source, &segments) {
+                insert_path(&mut projected, &segments, found);
+            }
+        }
+    }
+    serde_json::Value::Object(projected)
+}
+
+/// The value at `segments` under `source`, if every segment is an object key
+fn get_path<'a>(
+    source: &'a serde_json::Map<String, serde_json::Value>,
+    segments: &[&str],
+) -> Option<&'a serde_json::Value> {
+    let (last, parents) = segments.split_last()?;
+    let mut object = source;
+    for segment in parents {
+        object = object.get(*segment)?.as_object()?;
+    }
+    object.get(*last)
+}
+
+/// Put `value` at `segments` in `target`, creating objects along the way
+fn insert_path(
+    target: &mut serde_json::Map<String, serde_json::Value>,
+    segments: &[&str],
+    value: &serde_json::Value,
+) {
+    let Some((first, rest)) = segments.split_first() else {
+        return;
+    };
+    if rest.is_empty() {
+        target.insert(first.to_string(), value.clone());
+        return;
+    }
+    let child = target
+        .entry(first.to_string())
+        .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+    // Anything but an object here was copied whole by a shorter path
+    if let Some(child) = child.as_object_mut() {
+        insert_path(child, rest, value);
+    }
+}
+
+/// A session response cut down to the comma-separated `fields`
+///
+/// Plain entries are dot-paths into `data`, which keeps only those. The
+/// other top-level fields, like `user_id` and the timestamps, stay unless an
+/// entry names them with a leading `-`, as in `fields=theme,-user_id`. With
+/// no paths, `data` is returned whole.
+pub fn project_session(session: &serde_json::Value, fields: &str) -> serde_json::Value {
+    let entries: Vec<&str> = fields
+        .split(',')
+        .map(str::trim)
+        .filter(|e| !e.is_empty())
+        .collect();
+    let (excluded, paths): (Vec<&str>, Vec<&str>) =
+        entries.into_iter().partition(|e| e.starts_with('-'));
+
+    let mut projected = match session {
+        serde_json::Value::Object(object) => object.clone(),
+        other => return other.clone(),
+    };
+    if !paths.is_empty() {
+        if let Some(data) = projected.get_mut("data") {
+            *data = project_fields(data, &paths);
+        }
+    }
+    for field in excluded {
+        projected.remove(&field[1..]);
+    }
+    serde_json::Value::Object(projected)
+}
+
+/// Whether stored session data holds anything an import would overwrite
+pub fn has_session_data(data: Option<&serde_json::Value>) -> bool {
+    match data {
+        None | Some(serde_json::Value::Null) => false,
+        Some(serde_json::Value::Object(object)) => !object.is_empty(),
+        Some(_) => true,
+    }
 }
 
 /// A session storage Durable Object for maintaining user sessions
 #[durable_object]
 pub struct SessionObject {
     state: State,
-    _env: Env,
+    env: Env,
 }
 
 impl DurableObject for SessionObject {
     fn new(state: State, env: Env) -> Self {
-        Self { state, _env: env }
+        Self { state, env }
     }
 
-    async fn fetch(&self, mut req: Request) -> Result<Response> {
+    async fn fetch(&self, req: Request) -> Result<Response> {
+        // Storage failures become a 503 rather than an opaque 500
+        self.route(req).await.or_else(storage_error_response)
+    }
+}
+
+impl SessionObject {
+    async fn route(&self, mut req: Request) -> Result<Response> {
         let storage = self.state.storage();
 
+        let path = req.path();
+        if let Some(key) = deleted_key(&path) {
+            if req.method() != Method::Delete {
+                return method_not_allowed(&[Method::Delete]);
+            }
+            return self.delete_key(&req, key).await;
+        }
+        if path == "/schema" {
+            return self.handle_schema(req).await;
+        }
+        if path == "/export" {
+            if req.method() != Method::Get {
+                return method_not_allowed(&[Method::Get]);
+            }
+            return match self.export().await? {
+                Some(export) => Response::from_json(&export),
+                None => Response::error("Session not found", 404),
+            };
+        }
+        if path == "/import" {
+            if req.method() != Method::Post {
+                return method_not_allowed(&[Method::Post]);
+            }
+            return self.handle_import(req).await;
+        }
+        if path == "/__keys" {
+            if req.method() != Method::Get {
+                return method_not_allowed(&[Method::Get]);
+            }
+            // List what's stored without sending the values
+            return match storage.get::<serde_json::Value>("data").await {
+                Ok(data) => Response::from_json(&top_level_keys(&data)),
+                Err(_) => Response::error("Session not found", 404),
+            };
+        }
+        if path.starts_with(&format!("/{}", RESERVED_KEY_PREFIX)) {
+            return Response::error("Not found", 404);
+        }
+
         match req.method() {
             Method::Get => {
                 // Get all session data
@@ -43,53 +397,373 @@ impl DurableObject for SessionObject {
                     Ok(t) => t,
                     Err(_) => return Response::error("Session not found", 404),
                 };
+                // Sessions written before versioning start at 0
+                let version = storage.get::<u64>("version").await.unwrap_or_default();
 
                 let session = SessionData {
                     user_id,
                     data,
                     created_at,
                     updated_at,
+                    version,
                 };
-                Response::from_json(&session)
+                let mut response = Response::from_json(&session)?;
+                response.headers_mut().set("ETag", &version_etag(version))?;
+                Ok(response)
             }
             Method::Put => {
                 // Update session data
-                let body = req.json::<serde_json::Value>().await?;
+                let body = match req.json::<serde_json::Value>().await {
+                    Ok(body) => body,
+                    Err(_) => return Response::error("Invalid JSON", 400),
+                };
+
+                // Reject writes based on a stale read
+                let version = storage.get::<u64>("version").await.unwrap_or_default();
+                let if_match = req.headers().get("If-Match")?;
+                if !if_match_satisfied(if_match.as_deref(), version) {
+                    return Response::error("Session was modified; re-read and retry", 412);
+                }
 
                 let now = js_sys::Date::now() as u64;
 
-                // If session doesn't exist, create it
-                let created_at = match storage.get::<u64>("created_at").await {
-                    Ok(t) => t,
-                    Err(_) => now,
+                // A session without `created_at` doesn't exist yet, so this creates it
+                let existing_created_at = storage.get::<u64>("created_at").await.ok();
+                let write = match plan_session_put(&body, existing_created_at.is_some()) {
+                    Ok(write) => write,
+                    Err(e) => return Response::error(e, 400),
                 };
+                let created_at = existing_created_at.unwrap_or(now);
 
-                if let Some(user_id) = body.get("user_id").and_then(|v| v.as_str()) {
-                    storage.put("user_id", user_id).await?;
+                let mut writes = StorageWrites::new(&storage);
+                if let Some(data) = &write.data {
+                    if let Some(rejection) = self.check_data(data).await? {
+                        return Ok(rejection);
+                    }
+                    writes.put("data", data).await?;
                 }
 
-                if let Some(data) = body.get("data") {
-                    storage.put("data", data).await?;
-                }
+                let user_id = match &write.user_id {
+                    Some(user_id) => {
+                        writes.put("user_id", user_id).await?;
+                        user_id.clone()
+                    }
+                    None => storage.get::<String>("user_id").await.unwrap_or_default(),
+                };
 
-                storage.put("created_at", created_at).await?;
-                storage.put("updated_at", now).await?;
+                let version = version + 1;
+                writes.put("created_at", created_at).await?;
+                writes.put("updated_at", now).await?;
+                writes.put("version", version).await?;
 
-                Response::from_json(&serde_json::json!({
+                let mut response = Response::from_json(&serde_json::json!({
                     "status": "updated",
-                    "user_id": body.get("user_id").and_then(|v| v.as_str()).unwrap_or(""),
-                    "timestamp": now
-                }))
+                    "user_id": user_id,
+                    "timestamp": now,
+                    "version": version
+                }))?;
+                response.headers_mut().set("ETag", &version_etag(version))?;
+                Ok(response)
             }
+            Method::Patch => self.handle_patch(req).await,
             Method::Delete => {
+                if path != "/" {
+                    return Response::error("Not found", 404);
+                }
+
                 // Clear session
-                storage.delete("user_id").await?;
-                storage.delete("data").await?;
-                storage.delete("created_at").await?;
-                storage.delete("updated_at").await?;
+                let version = storage.get::<u64>("version").await.unwrap_or_default();
+                let if_match = req.headers().get("If-Match")?;
+                if !if_match_satisfied(if_match.as_deref(), version) {
+                    return Response::error("Session was modified; re-read and retry", 412);
+                }
+
+                // The version survives clearing so stale ETags can't match a new session
+                let mut writes = StorageWrites::new(&storage);
+                writes.put("version", version + 1).await?;
+                writes.delete("user_id").await?;
+                writes.delete("data").await?;
+                writes.delete("created_at").await?;
+                writes.delete("updated_at").await?;
                 Response::ok("Session cleared")
             }
-            _ => Response::error("Method not allowed", 405),
+            _ => method_not_allowed(&[Method::Get, Method::Put, Method::Patch, Method::Delete]),
+        }
+    }
+
+    /// Check data about to be stored against the size limit, reserved prefix and schema
+    ///
+    /// Returns the error response to send if the data is rejected.
+    async fn check_data(&self, data: &serde_json::Value) -> Result<Option<Response>> {
+        let schema = self
+            .state
+            .storage()
+            .get::<serde_json::Value>(SCHEMA_KEY)
+            .await
+            .ok();
+        self.check_data_against(data, schema.as_ref())
+    }
+
+    /// Check data against the size limit, reserved prefix and the given schema
+    fn check_data_against(
+        &self,
+        data: &serde_json::Value,
+        schema: Option<&serde_json::Value>,
+    ) -> Result<Option<Response>> {
+        // Refuse oversized data up front rather than failing opaquely in storage
+        let limit = max_session_bytes(
+            self.env
+                .var("MAX_SESSION_BYTES")
+                .ok()
+                .map(|v| v.to_string())
+                .as_deref(),
+        );
+        let size = serialized_size(data)?;
+        if size > limit {
+            return Ok(Some(
+                Response::from_json(&serde_json::json!({
+                    "error": "Session data too large",
+                    "size": size,
+                    "max_bytes": limit
+                }))?
+                .with_status(413),
+            ));
+        }
+
+        if top_level_keys(data)
+            .iter()
+            .any(|k| k.starts_with(RESERVED_KEY_PREFIX))
+        {
+            return Response::error(
+                format!("Keys starting with '{}' are reserved", RESERVED_KEY_PREFIX),
+                400,
+            )
+            .map(Some);
+        }
+
+        // Sessions with a schema only accept data that satisfies it
+        if let Some(schema) = schema {
+            let errors = json_schema::validate(schema, data);
+            if !errors.is_empty() {
+                return Ok(Some(
+                    Response::from_json(&serde_json::json!({
+                        "error": "Session data does not match schema",
+                        "errors": errors
+                    }))?
+                    .with_status(422),
+                ));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The whole session, or `None` if it doesn't exist
+    async fn export(&self) -> Result<Option<SessionExport>> {
+        let storage = self.state.storage();
+        let (user_id, data, created_at, updated_at) = match (
+            storage.get::<String>("user_id").await,
+            storage.get::<serde_json::Value>("data").await,
+            storage.get::<u64>("created_at").await,
+            storage.get::<u64>("updated_at").await,
+        ) {
+            (Ok(user_id), Ok(data), Ok(created_at), Ok(updated_at)) => {
+                (user_id, data, created_at, updated_at)
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(SessionExport {
+            user_id,
+            data,
+            created_at,
+            updated_at,
+            version: storage.get::<u64>("version").await.unwrap_or_default(),
+            schema: storage.get::<serde_json::Value>(SCHEMA_KEY).await.ok(),
+        }))
+    }
+
+    /// Restore a session from an export document
+    ///
+    /// Refuses to replace a session that already holds data unless
+    /// `?overwrite=true` is passed.
+    async fn handle_import(&self, mut req: Request) -> Result<Response> {
+        let storage = self.state.storage();
+        let overwrite = req
+            .url()?
+            .query_pairs()
+            .any(|(k, v)| k == "overwrite" && v == "true");
+
+        let body = req.text().await?;
+        let export = match parse_session_export(&body) {
+            Ok(e) => e,
+            Err(e) => return Response::error(format!("Invalid session export: {}", e), 400),
+        };
+
+        let existing = storage.get::<serde_json::Value>("data").await.ok();
+        if has_session_data(existing.as_ref()) && !overwrite {
+            return Response::error(
+                "Session already has data; pass ?overwrite=true to replace it",
+                409,
+            );
+        }
+        if let Some(rejection) = self.check_data_against(&export.data, export.schema.as_ref())? {
+            return Ok(rejection);
+        }
+
+        // Never reuse a version this session has already had, so stale ETags can't match
+        let current = storage.get::<u64>("version").await.unwrap_or_default();
+        let version = export.version.max(current + 1);
+        let mut writes = StorageWrites::new(&storage);
+        writes.put("user_id", &export.user_id).await?;
+        writes.put("data", &export.data).await?;
+        writes.put("created_at", export.created_at).await?;
+        writes.put("updated_at", export.updated_at).await?;
+        writes.put("version", version).await?;
+        match &export.schema {
+            Some(schema) => writes.put(SCHEMA_KEY, schema).await?,
+            None => writes.delete(SCHEMA_KEY).await?,
+        }
+
+        let mut response = Response::from_json(&serde_json::json!({
+            "status": "imported",
+            "version": version
+        }))?;
+        response.headers_mut().set("ETag", &version_etag(version))?;
+        Ok(response)
+    }
+
+    /// Apply a JSON Patch document to the session data
+    ///
+    /// The patch applies atomically: if any operation fails, including a
+    /// `test`, nothing is written.
+    async fn handle_patch(&self, mut req: Request) -> Result<Response> {
+        let storage = self.state.storage();
+
+        let content_type = req.headers().get("Content-Type")?.unwrap_or_default();
+        if !is_json_patch_content_type(&content_type) {
+            return Response::error(
+                format!("Content-Type must be {}", JSON_PATCH_CONTENT_TYPE),
+                415,
+            );
+        }
+
+        let operations = match req.json::<Vec<PatchOperation>>().await {
+            Ok(ops) => ops,
+            Err(_) => return Response::error("Invalid JSON Patch document", 400),
+        };
+
+        let data = match storage.get::<serde_json::Value>("data").await {
+            Ok(d) => d,
+            Err(_) => return Response::error("Session not found", 404),
+        };
+
+        let version = storage.get::<u64>("version").await.unwrap_or_default();
+        let if_match = req.headers().get("If-Match")?;
+        if !if_match_satisfied(if_match.as_deref(), version) {
+            return Response::error("Session was modified; re-read and retry", 412);
+        }
+
+        let patched = match json_patch::apply_patch(&data, &operations) {
+            Ok(p) => p,
+            Err(e @ PatchError::TestFailed(_)) => return Response::error(e.to_string(), 409),
+            Err(e @ PatchError::Invalid(_)) => return Response::error(e.to_string(), 422),
+        };
+        if let Some(rejection) = self.check_data(&patched).await? {
+            return Ok(rejection);
+        }
+
+        let now = js_sys::Date::now() as u64;
+        let version = version + 1;
+        let mut writes = StorageWrites::new(&storage);
+        writes.put("data", &patched).await?;
+        writes.put("updated_at", now).await?;
+        writes.put("version", version).await?;
+
+        let mut response = Response::from_json(&serde_json::json!({
+            "status": "patched",
+            "data": patched,
+            "timestamp": now,
+            "version": version
+        }))?;
+        response.headers_mut().set("ETag", &version_etag(version))?;
+        Ok(response)
+    }
+
+    /// Remove one key from the session's data, answering with the value it had
+    ///
+    /// `encoded` is the key as it appears in the path, percent-encoded.
+    async fn delete_key(&self, req: &Request, encoded: &str) -> Result<Response> {
+        let storage = self.state.storage();
+        let key = js_sys::decode_uri_component(encoded)
+            .map(String::from)
+            .unwrap_or_else(|_| encoded.to_string());
+
+        let mut data = match storage.get::<serde_json::Value>("data").await {
+            Ok(d) => d,
+            Err(_) => return Response::error("Session not found", 404),
+        };
+
+        let version = storage.get::<u64>("version").await.unwrap_or_default();
+        let if_match = req.headers().get("If-Match")?;
+        if !if_match_satisfied(if_match.as_deref(), version) {
+            return Response::error("Session was modified; re-read and retry", 412);
+        }
+
+        let Some(removed) = remove_data_key(&mut data, &key) else {
+            return Response::error("Key not found", 404);
+        };
+        // A schema can require the key, so what's left must still pass
+        if let Some(rejection) = self.check_data(&data).await? {
+            return Ok(rejection);
+        }
+
+        let now = js_sys::Date::now() as u64;
+        let version = version + 1;
+        let mut writes = StorageWrites::new(&storage);
+        writes.put("data", &data).await?;
+        writes.put("updated_at", now).await?;
+        writes.put("version", version).await?;
+
+        let mut response = Response::from_json(&serde_json::json!({
+            "status": "deleted",
+            "key": key,
+            "value": removed,
+            "timestamp": now,
+            "version": version
+        }))?;
+        response.headers_mut().set("ETag", &version_etag(version))?;
+        Ok(response)
+    }
+
+    /// Handle `/schema`, which sets, reads or removes the session's data schema
+    async fn handle_schema(&self, mut req: Request) -> Result<Response> {
+        let storage = self.state.storage();
+
+        match req.method() {
+            Method::Get => match storage.get::<serde_json::Value>(SCHEMA_KEY).await {
+                Ok(schema) => Response::from_json(&schema),
+                Err(_) => Response::error("Schema not found", 404),
+            },
+            Method::Put => {
+                let schema = match req.json::<serde_json::Value>().await {
+                    Ok(s) => s,
+                    Err(_) => return Response::error("Invalid JSON", 400),
+                };
+                if let Err(e) = json_schema::check_schema(&schema) {
+                    return Response::error(format!("Invalid schema: {}", e), 400);
+                }
+
+                StorageWrites::new(&storage)
+                    .put(SCHEMA_KEY, &schema)
+                    .await?;
+                Response::from_json(&serde_json::json!({ "status": "schema updated" }))
+            }
+            Method::Delete => {
+                StorageWrites::new(&storage).delete(SCHEMA_KEY).await?;
+                Response::ok("Schema removed")
+            }
+            _ => method_not_allowed(&[Method::Get, Method::Put, Method::Delete]),
         }
     }
 }
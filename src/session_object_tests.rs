@@ -1,6 +1,12 @@
 #[cfg(test)]
 mod session_object_tests {
-    use crate::session_object::SessionData;
+    use crate::session_object::{
+        deleted_key, has_session_data, if_match_satisfied, is_json_patch_content_type,
+        max_session_bytes, merge_defaults, parse_session_export, plan_session_put, project_fields,
+        project_session, remove_data_key, serialized_size, session_delete_path, top_level_keys,
+        validate_json_body, version_etag, SessionData, SessionExport, SessionWrite,
+        DEFAULT_MAX_SESSION_BYTES,
+    };
     use serde_json::json;
 
     #[test]
@@ -10,6 +16,7 @@ mod session_object_tests {
             data: json!({"preferences": {"theme": "dark"}}),
             created_at: 1234567890,
             updated_at: 1234567900,
+            version: 3,
         };
 
         assert_eq!(data.user_id, "user123");
@@ -92,6 +99,7 @@ mod session_object_tests {
             data: json!({"key": "value"}),
             created_at: 1234567890,
             updated_at: 1234567900,
+            version: 1,
         };
 
         let json = serde_json::to_value(&session_response).unwrap();
@@ -99,6 +107,7 @@ mod session_object_tests {
         assert!(json["data"].is_object());
         assert!(json["created_at"].is_u64());
         assert!(json["updated_at"].is_u64());
+        assert!(json["version"].is_u64());
     }
 
     #[test]
@@ -122,4 +131,337 @@ mod session_object_tests {
         // Test missing key
         assert!(session_data.get("missing").is_none());
     }
+
+    #[test]
+    fn test_top_level_keys() {
+        let data = json!({
+            "preferences": {"theme": "dark"},
+            "cart": [1, 2, 3],
+            "last_page": "/home"
+        });
+
+        let mut keys = top_level_keys(&data);
+        keys.sort();
+        assert_eq!(keys, vec!["cart", "last_page", "preferences"]);
+
+        // Nested keys aren't included
+        assert!(!keys.contains(&"theme".to_string()));
+
+        // Non-object data has no keys
+        assert!(top_level_keys(&json!([1, 2])).is_empty());
+        assert!(top_level_keys(&json!("value")).is_empty());
+        assert!(top_level_keys(&json!(null)).is_empty());
+    }
+
+    #[test]
+    fn test_session_data_without_version() {
+        // Sessions stored before versioning deserialize as version 0
+        let parsed: SessionData = serde_json::from_str(
+            r#"{"user_id": "u", "data": {}, "created_at": 1, "updated_at": 2}"#,
+        )
+        .unwrap();
+        assert_eq!(parsed.version, 0);
+    }
+
+    #[test]
+    fn test_if_match_satisfied() {
+        assert_eq!(version_etag(5), "\"5\"");
+
+        // No header means no precondition
+        assert!(if_match_satisfied(None, 5));
+
+        assert!(if_match_satisfied(Some("\"5\""), 5));
+        assert!(if_match_satisfied(Some("*"), 5));
+        assert!(if_match_satisfied(Some("\"3\", \"5\""), 5));
+
+        assert!(!if_match_satisfied(Some("\"4\""), 5));
+        assert!(!if_match_satisfied(Some("5"), 5));
+        // If-Match uses strong comparison, so weak tags never match
+        assert!(!if_match_satisfied(Some("W/\"5\""), 5));
+        assert!(!if_match_satisfied(Some(""), 5));
+    }
+
+    #[test]
+    fn test_is_json_patch_content_type() {
+        assert!(is_json_patch_content_type("application/json-patch+json"));
+        assert!(is_json_patch_content_type(
+            "Application/JSON-Patch+JSON; charset=utf-8"
+        ));
+
+        assert!(!is_json_patch_content_type("application/json"));
+        assert!(!is_json_patch_content_type("application/merge-patch+json"));
+        assert!(!is_json_patch_content_type(""));
+    }
+
+    #[test]
+    fn test_max_session_bytes() {
+        assert_eq!(max_session_bytes(None), DEFAULT_MAX_SESSION_BYTES);
+        assert_eq!(max_session_bytes(Some("1024")), 1024);
+        assert_eq!(max_session_bytes(Some("0")), DEFAULT_MAX_SESSION_BYTES);
+        assert_eq!(max_session_bytes(Some("big")), DEFAULT_MAX_SESSION_BYTES);
+    }
+
+    #[test]
+    fn test_serialized_size_at_limit() {
+        // {"k":"..."} is 8 bytes plus the string
+        let limit = 64;
+        let at_limit = json!({"k": "x".repeat(limit - 8)});
+        let over_limit = json!({"k": "x".repeat(limit - 7)});
+
+        assert_eq!(serialized_size(&at_limit).unwrap(), limit);
+        assert_eq!(serialized_size(&over_limit).unwrap(), limit + 1);
+        // Escapes count at their serialized length
+        assert_eq!(serialized_size(&json!("a\nb")).unwrap(), 6);
+    }
+
+    #[test]
+    fn test_session_export_round_trip() {
+        let export = SessionExport {
+            user_id: "user123".to_string(),
+            data: json!({"theme": "dark"}),
+            created_at: 1000,
+            updated_at: 2000,
+            version: 4,
+            schema: Some(json!({"type": "object"})),
+        };
+        let json = serde_json::to_string(&export).unwrap();
+        assert_eq!(parse_session_export(&json).unwrap(), export);
+
+        // Version and schema are optional
+        let minimal = parse_session_export(
+            r#"{"user_id": "u", "data": {}, "created_at": 1, "updated_at": 1}"#,
+        )
+        .unwrap();
+        assert_eq!(minimal.version, 0);
+        assert_eq!(minimal.schema, None);
+    }
+
+    #[test]
+    fn test_parse_session_export_rejects_invalid() {
+        for body in [
+            "not json",
+            r#"{"user_id": "u", "created_at": 1, "updated_at": 1}"#,
+            r#"{"user_id": "u", "data": {}, "updated_at": 1}"#,
+            r#"{"user_id": "u", "data": {}, "created_at": 5, "updated_at": 1}"#,
+            r#"{"user_id": "u", "data": {}, "created_at": 1, "updated_at": 1, "extra": 1}"#,
+            r#"{"user_id": "u", "data": {}, "created_at": 1, "updated_at": 1, "schema": {"type": "widget"}}"#,
+        ] {
+            assert!(parse_session_export(body).is_err(), "accepted {}", body);
+        }
+    }
+
+    #[test]
+    fn test_has_session_data() {
+        assert!(!has_session_data(None));
+        assert!(!has_session_data(Some(&json!(null))));
+        assert!(!has_session_data(Some(&json!({}))));
+        assert!(has_session_data(Some(&json!({"a": 1}))));
+        assert!(has_session_data(Some(&json!([]))));
+    }
+
+    #[test]
+    fn test_validate_json_body() {
+        let body = br#"{"user_id": "u1", "data": {"theme": "dark"}}"#;
+        assert_eq!(
+            validate_json_body(body).unwrap(),
+            String::from_utf8(body.to_vec()).unwrap()
+        );
+
+        let err = validate_json_body(br#"{"user_id": "u1", "data": "#).unwrap_err();
+        assert!(err.starts_with("Invalid JSON"), "{}", err);
+
+        // Invalid UTF-8 is caught before JSON parsing
+        let err = validate_json_body(b"{\"user_id\": \"\xff\"}").unwrap_err();
+        assert!(
+            err.starts_with("Request body is not valid UTF-8"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_plan_session_put_create() {
+        let body = json!({
+            "user_id": "user123",
+            "defaults": {"theme": "light", "prefs": {"lang": "en", "tz": "UTC"}},
+            "data": {"prefs": {"tz": "PST"}, "cart": []}
+        });
+        assert_eq!(
+            plan_session_put(&body, false),
+            Ok(SessionWrite {
+                user_id: Some("user123".to_string()),
+                data: Some(json!({
+                    "theme": "light",
+                    "prefs": {"lang": "en", "tz": "PST"},
+                    "cart": []
+                })),
+            })
+        );
+
+        // A new session always gets data, even if none was sent
+        let write = plan_session_put(&json!({"user_id": "user123"}), false).unwrap();
+        assert_eq!(write.data, Some(json!({})));
+
+        assert!(plan_session_put(&json!({"data": {"a": 1}}), false).is_err());
+        assert!(plan_session_put(&json!({"user_id": ""}), false).is_err());
+        assert!(plan_session_put(&json!({"user_id": "u", "defaults": [1]}), false).is_err());
+    }
+
+    #[test]
+    fn test_plan_session_put_update() {
+        // Updates don't need a user_id and don't apply defaults
+        let body = json!({"data": {"a": 1}, "defaults": {"b": 2}});
+        assert_eq!(
+            plan_session_put(&body, true),
+            Ok(SessionWrite {
+                user_id: None,
+                data: Some(json!({"a": 1})),
+            })
+        );
+        assert_eq!(
+            plan_session_put(&json!({"user_id": "user456"}), true),
+            Ok(SessionWrite {
+                user_id: Some("user456".to_string()),
+                data: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_merge_defaults() {
+        assert_eq!(
+            merge_defaults(&json!({"a": 1, "b": {"c": 2}}), &json!({"b": {"d": 3}})),
+            json!({"a": 1, "b": {"c": 2, "d": 3}})
+        );
+        // Non-object values in data replace the default outright
+        assert_eq!(
+            merge_defaults(&json!({"b": {"c": 2}}), &json!({"b": null})),
+            json!({"b": null})
+        );
+        assert_eq!(merge_defaults(&json!({"a": 1}), &json!([1])), json!([1]));
+    }
+
+    #[test]
+    fn test_project_fields_nested_paths() {
+        let data = json!({
+            "a": 1,
+            "b": {"c": 2, "d": {"e": 3, "f": 4}},
+            "g": [1, 2, 3]
+        });
+        assert_eq!(
+            project_fields(&data, &["a", "b.d.e"]),
+            json!({"a": 1, "b": {"d": {"e": 3}}})
+        );
+        assert_eq!(
+            project_fields(&data, &["b.c", "b.d.f", "g"]),
+            json!({"b": {"c": 2, "d": {"f": 4}}, "g": [1, 2, 3]})
+        );
+        // A path covering another keeps the whole subtree, in either order
+        assert_eq!(
+            project_fields(&data, &["b.c", "b"]),
+            json!({"b": data["b"]})
+        );
+        assert_eq!(
+            project_fields(&data, &["b", "b.c"]),
+            json!({"b": data["b"]})
+        );
+    }
+
+    #[test]
+    fn test_project_fields_omits_unknown_paths() {
+        let data = json!({"a": 1, "b": {"c": 2}, "g": [{"x": 1}]});
+        assert_eq!(
+            project_fields(&data, &["missing", "b.nope", "a.deeper", "g.0.x"]),
+            json!({})
+        );
+        assert_eq!(project_fields(&data, &["", "b.", ".c"]), json!({}));
+        assert_eq!(project_fields(&json!("not an object"), &["a"]), json!({}));
+    }
+
+    #[test]
+    fn test_project_session_keeps_metadata() {
+        let session = json!({
+            "user_id": "user123",
+            "data": {"theme": "dark", "cart": {"items": 3, "total": 42}},
+            "created_at": 1000,
+            "updated_at": 2000,
+            "version": 5
+        });
+        assert_eq!(
+            project_session(&session, "cart.total, nope"),
+            json!({
+                "user_id": "user123",
+                "data": {"cart": {"total": 42}},
+                "created_at": 1000,
+                "updated_at": 2000,
+                "version": 5
+            })
+        );
+
+        // Metadata only goes when excluded by name
+        assert_eq!(
+            project_session(&session, "theme,-user_id,-created_at"),
+            json!({"data": {"theme": "dark"}, "updated_at": 2000, "version": 5})
+        );
+        // Exclusions alone leave the data whole
+        assert_eq!(
+            project_session(&session, "-version")["data"],
+            session["data"]
+        );
+        assert_eq!(project_session(&session, ""), session);
+    }
+
+    #[test]
+    fn test_deleted_key() {
+        // The session path clears everything; a data key path removes just that key
+        assert_eq!(deleted_key("/"), None);
+        assert_eq!(deleted_key(""), None);
+        assert_eq!(deleted_key("/__data/"), None);
+        assert_eq!(deleted_key("/__data/theme"), Some("theme"));
+        assert_eq!(deleted_key("/__data/dark%20mode"), Some("dark%20mode"));
+        // Built-in paths aren't data keys
+        assert_eq!(deleted_key("/theme"), None);
+        assert_eq!(deleted_key("/schema"), None);
+    }
+
+    #[test]
+    fn test_session_delete_path() {
+        assert_eq!(session_delete_path(None), "/");
+        assert_eq!(session_delete_path(Some("theme")), "/__data/theme");
+
+        // Built-in endpoints keep their meaning, so `DELETE .../schema` removes the schema
+        for name in ["schema", "export", "import"] {
+            let path = session_delete_path(Some(name));
+            assert_eq!(path, format!("/{}", name));
+            assert_eq!(deleted_key(&path), None);
+        }
+
+        // Data keys named like them are removed through the reserved path
+        for name in ["schema", "export", "import", "theme"] {
+            let path = session_delete_path(Some(&format!("__data/{}", name)));
+            assert_eq!(deleted_key(&path), Some(name));
+        }
+        assert_eq!(session_delete_path(Some("__keys")), "/__keys");
+    }
+
+    #[test]
+    fn test_remove_data_key() {
+        let mut data = json!({"theme": "dark", "cart": {"items": [1, 2]}});
+        assert_eq!(
+            remove_data_key(&mut data, "cart"),
+            Some(json!({"items": [1, 2]}))
+        );
+        assert_eq!(data, json!({"theme": "dark"}));
+
+        // Missing keys, nested paths and non-object data remove nothing
+        assert_eq!(remove_data_key(&mut data, "cart"), None);
+        assert_eq!(remove_data_key(&mut data, "theme/color"), None);
+        assert_eq!(data, json!({"theme": "dark"}));
+        assert_eq!(remove_data_key(&mut json!([1, 2]), "0"), None);
+
+        // A key holding null is still there to remove
+        let mut data = json!({"note": null});
+        assert_eq!(remove_data_key(&mut data, "note"), Some(json!(null)));
+        assert_eq!(data, json!({}));
+    }
 }
@@ -1,50 +1,100 @@
+use async_trait::async_trait;
 use js_sys::Uint8Array;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::JsFuture;
 use worker::*;
 
-/// Compute SHA-256 hash of data using Web Crypto API
-pub async fn compute_sha256(data: &[u8]) -> Result<String> {
-    // In Cloudflare Workers, we use the global crypto object
-    let global = js_sys::global();
-    let crypto = js_sys::Reflect::get(&global, &JsValue::from_str("crypto"))
-        .map_err(|_| Error::RustError("Failed to get crypto".to_string()))?;
+/// Incremental SHA-256, for hashing data that arrives in pieces
+///
+/// Web Crypto can only digest a whole buffer, so streams are hashed with this
+/// instead, on every target.
+pub use sha2::{Digest, Sha256};
+
+/// A SHA-256 implementation
+#[async_trait(?Send)]
+pub trait Digester {
+    /// Compute the raw 32-byte SHA-256 digest of `data`
+    async fn sha256(&self, data: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// SHA-256 via the Web Crypto API, available in Workers
+///
+/// Still compiled on native targets so it stays type-checked, but unused there.
+#[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+pub struct WebCryptoDigester;
 
-    let subtle = js_sys::Reflect::get(&crypto, &JsValue::from_str("subtle"))
-        .map_err(|_| Error::RustError("Failed to get subtle crypto".to_string()))?;
+#[async_trait(?Send)]
+impl Digester for WebCryptoDigester {
+    async fn sha256(&self, data: &[u8]) -> Result<Vec<u8>> {
+        // In Cloudflare Workers, we use the global crypto object
+        let global = js_sys::global();
+        let crypto = js_sys::Reflect::get(&global, &JsValue::from_str("crypto"))
+            .map_err(|_| Error::RustError("Failed to get crypto".to_string()))?;
 
-    // Create a Uint8Array from our data
-    let data_array = Uint8Array::new_with_length(data.len() as u32);
-    data_array.copy_from(data);
+        let subtle = js_sys::Reflect::get(&crypto, &JsValue::from_str("subtle"))
+            .map_err(|_| Error::RustError("Failed to get subtle crypto".to_string()))?;
 
-    // Compute SHA-256
-    let digest_fn = js_sys::Reflect::get(&subtle, &JsValue::from_str("digest"))
-        .map_err(|_| Error::RustError("Failed to get digest function".to_string()))?;
+        // Create a Uint8Array from our data
+        let data_array = Uint8Array::new_with_length(data.len() as u32);
+        data_array.copy_from(data);
 
-    let digest_fn = digest_fn
-        .dyn_ref::<js_sys::Function>()
-        .ok_or_else(|| Error::RustError("digest is not a function".to_string()))?;
+        // Compute SHA-256
+        let digest_fn = js_sys::Reflect::get(&subtle, &JsValue::from_str("digest"))
+            .map_err(|_| Error::RustError("Failed to get digest function".to_string()))?;
 
-    let promise = digest_fn
-        .call2(&subtle, &JsValue::from_str("SHA-256"), &data_array)
-        .map_err(|_| Error::RustError("Failed to call digest".to_string()))?;
+        let digest_fn = digest_fn
+            .dyn_ref::<js_sys::Function>()
+            .ok_or_else(|| Error::RustError("digest is not a function".to_string()))?;
 
-    let promise = js_sys::Promise::from(promise);
-    let result = JsFuture::from(promise)
-        .await
-        .map_err(|_| Error::RustError("Failed to compute hash".to_string()))?;
+        let promise = digest_fn
+            .call2(&subtle, &JsValue::from_str("SHA-256"), &data_array)
+            .map_err(|_| Error::RustError("Failed to call digest".to_string()))?;
 
-    // Convert result to Uint8Array
-    let array = Uint8Array::new(&result);
-    let mut bytes = vec![0u8; array.length() as usize];
-    array.copy_to(&mut bytes);
+        let promise = js_sys::Promise::from(promise);
+        let result = JsFuture::from(promise)
+            .await
+            .map_err(|_| Error::RustError("Failed to compute hash".to_string()))?;
+
+        // Convert result to Uint8Array
+        let array = Uint8Array::new(&result);
+        let mut bytes = vec![0u8; array.length() as usize];
+        array.copy_to(&mut bytes);
+
+        Ok(bytes)
+    }
+}
+
+/// Pure-Rust SHA-256 from the `sha2` crate, so the hashing pipeline can run in native tests
+///
+/// Web Crypto is only reachable from WASM.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct SoftwareDigester;
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait(?Send)]
+impl Digester for SoftwareDigester {
+    async fn sha256(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(Sha256::digest(data).to_vec())
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+const DEFAULT_DIGESTER: WebCryptoDigester = WebCryptoDigester;
+#[cfg(not(target_arch = "wasm32"))]
+const DEFAULT_DIGESTER: SoftwareDigester = SoftwareDigester;
+
+/// Compute SHA-256 hash of data as lowercase hex
+///
+/// Uses Web Crypto in Workers and the `sha2` crate on native targets.
+pub async fn compute_sha256(data: &[u8]) -> Result<String> {
+    let digest = DEFAULT_DIGESTER.sha256(data).await?;
 
     // Convert to hex string
-    Ok(bytes_to_hex(&bytes))
+    Ok(bytes_to_hex(&digest))
 }
 
 /// Convert bytes to lowercase hex string
-fn bytes_to_hex(bytes: &[u8]) -> String {
+pub fn bytes_to_hex(bytes: &[u8]) -> String {
     bytes
         .iter()
         .map(|b| format!("{:02x}", b))
@@ -61,4 +111,89 @@ mod tests {
         assert_eq!(bytes_to_hex(&[]), "");
         assert_eq!(bytes_to_hex(&[0xDE, 0xAD, 0xBE, 0xEF]), "deadbeef");
     }
+
+    #[tokio::test]
+    async fn test_compute_sha256_vectors() {
+        // Standard test vectors from FIPS 180-2 and NIST
+        let vectors: [(&[u8], &str); 3] = [
+            (
+                b"",
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+            (
+                b"abc",
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+            ),
+        ];
+
+        for (input, expected) in vectors {
+            assert_eq!(compute_sha256(input).await.unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compute_sha256_padding_boundaries() {
+        // 55 bytes fits the length in one block; 56 and 64 need a second block
+        let vectors = [
+            (
+                55,
+                "9f4390f8d30c2dd92ec9f095b65e2b9ae9b0a925a5258e241c9f1e910f734318",
+            ),
+            (
+                56,
+                "b35439a4ac6f0948b6d6f9e3c6af0f5f590ce20f1bde7090ef7970686ec6738a",
+            ),
+            (
+                64,
+                "ffe054fe7ae0cb6dc65c3af9b61d5209f439851db43d0ba5997337df154668eb",
+            ),
+        ];
+
+        for (len, expected) in vectors {
+            assert_eq!(compute_sha256(&vec![b'a'; len]).await.unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_compute_sha256_million_a() {
+        let data = vec![b'a'; 1_000_000];
+        assert_eq!(
+            compute_sha256(&data).await.unwrap(),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    #[test]
+    fn test_incremental_matches_one_shot() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+        let mut one_shot = Sha256::new();
+        one_shot.update(&data);
+        let expected = one_shot.finalize();
+
+        // Splits that straddle, fill and skip past block boundaries
+        for chunk_size in [1, 7, 63, 64, 65, 200, 999] {
+            let mut hasher = Sha256::new();
+            for chunk in data.chunks(chunk_size) {
+                hasher.update(chunk);
+            }
+            assert_eq!(hasher.finalize(), expected, "chunk size {}", chunk_size);
+        }
+    }
+
+    #[test]
+    fn test_incremental_empty_chunks() {
+        let mut hasher = Sha256::new();
+        hasher.update(b"");
+        hasher.update(b"ab");
+        hasher.update(b"");
+        hasher.update(b"c");
+        assert_eq!(
+            bytes_to_hex(&hasher.finalize()),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
 }
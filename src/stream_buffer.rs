@@ -0,0 +1,199 @@
+//! Bounded read-ahead for streamed downloads
+//!
+//! When a download is streamed through the Worker, chunks are read from R2
+//! while the client is still receiving earlier ones. `read_ahead` keeps at
+//! most `STREAM_MAX_BUFFERED_CHUNKS` of them waiting (default
+//! `DEFAULT_MAX_BUFFERED_CHUNKS`), so a slow client stops the reads from R2
+//! instead of piling chunks up in the isolate's memory.
+
+use futures_util::{Stream, StreamExt};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use worker::Result;
+
+/// Chunks buffered ahead of the client when `STREAM_MAX_BUFFERED_CHUNKS` isn't set
+pub const DEFAULT_MAX_BUFFERED_CHUNKS: usize = 4;
+
+/// Upper limit on `STREAM_MAX_BUFFERED_CHUNKS`, whatever it's set to
+pub const MAX_BUFFERED_CHUNKS: usize = 64;
+
+/// How many chunks may wait between R2 and the client, from `STREAM_MAX_BUFFERED_CHUNKS`
+///
+/// Missing, unparsable or zero values fall back to the default.
+pub fn max_buffered_chunks(value: Option<&str>) -> usize {
+    value
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .filter(|&n| n > 0)
+        .map_or(DEFAULT_MAX_BUFFERED_CHUNKS, |n| n.min(MAX_BUFFERED_CHUNKS))
+}
+
+/// A first-in, first-out queue holding at most `capacity` chunks
+#[derive(Debug)]
+pub struct ChunkQueue {
+    chunks: VecDeque<Vec<u8>>,
+    capacity: usize,
+}
+
+impl ChunkQueue {
+    /// An empty queue; a `capacity` of zero is treated as one
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            chunks: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Add a chunk at the back, or hand it back if the queue is full
+    pub fn push(&mut self, chunk: Vec<u8>) -> std::result::Result<(), Vec<u8>> {
+        if self.is_full() {
+            return Err(chunk);
+        }
+        self.chunks.push_back(chunk);
+        Ok(())
+    }
+
+    /// Take the oldest chunk
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        self.chunks.pop_front()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.chunks.len() >= self.capacity
+    }
+}
+
+/// Stream returned by `read_ahead`
+pub struct ReadAhead<S> {
+    source: S,
+    queue: ChunkQueue,
+    /// An error from `source`, sent after the chunks read before it
+    error: Option<worker::Error>,
+    done: bool,
+}
+
+/// Read up to `max_chunks` chunks from `chunks` ahead of the consumer
+///
+/// Each time the consumer asks for a chunk, whatever `chunks` has ready is
+/// queued, up to the limit; once the queue is full, `chunks` isn't polled
+/// again until the consumer takes one.
+pub fn read_ahead<S>(chunks: S, max_chunks: usize) -> ReadAhead<S>
+where
+    S: Stream<Item = Result<Vec<u8>>> + Unpin,
+{
+    ReadAhead {
+        source: chunks,
+        queue: ChunkQueue::new(max_chunks),
+        error: None,
+        done: false,
+    }
+}
+
+impl<S> Stream for ReadAhead<S>
+where
+    S: Stream<Item = Result<Vec<u8>>> + Unpin,
+{
+    type Item = Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        while !this.done && !this.queue.is_full() {
+            match this.source.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    // Can't fail: the loop only runs while there's room
+                    let _ = this.queue.push(chunk);
+                }
+                Poll::Ready(Some(Err(e))) => {
+                    this.error = Some(e);
+                    this.done = true;
+                }
+                Poll::Ready(None) => this.done = true,
+                Poll::Pending => break,
+            }
+        }
+
+        if let Some(chunk) = this.queue.pop() {
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+        if let Some(e) = this.error.take() {
+            return Poll::Ready(Some(Err(e)));
+        }
+        if this.done {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_max_buffered_chunks() {
+        assert_eq!(max_buffered_chunks(None), DEFAULT_MAX_BUFFERED_CHUNKS);
+        assert_eq!(max_buffered_chunks(Some("8")), 8);
+        assert_eq!(max_buffered_chunks(Some(" 1 ")), 1);
+        assert_eq!(max_buffered_chunks(Some("0")), DEFAULT_MAX_BUFFERED_CHUNKS);
+        assert_eq!(max_buffered_chunks(Some("-2")), DEFAULT_MAX_BUFFERED_CHUNKS);
+        assert_eq!(
+            max_buffered_chunks(Some("lots")),
+            DEFAULT_MAX_BUFFERED_CHUNKS
+        );
+        assert_eq!(max_buffered_chunks(Some("100000")), MAX_BUFFERED_CHUNKS);
+    }
+
+    #[test]
+    fn test_chunk_queue_is_bounded() {
+        let mut queue = ChunkQueue::new(2);
+        assert!(!queue.is_full());
+        assert_eq!(queue.push(b"a".to_vec()), Ok(()));
+        assert_eq!(queue.push(b"b".to_vec()), Ok(()));
+        assert!(queue.is_full());
+        assert_eq!(queue.push(b"c".to_vec()), Err(b"c".to_vec()));
+
+        assert_eq!(queue.pop(), Some(b"a".to_vec()));
+        assert_eq!(queue.push(b"c".to_vec()), Ok(()));
+        assert_eq!(queue.pop(), Some(b"b".to_vec()));
+        assert_eq!(queue.pop(), Some(b"c".to_vec()));
+        assert_eq!(queue.pop(), None);
+
+        assert!(ChunkQueue::new(0).push(Vec::new()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_read_ahead_stops_at_the_limit() {
+        let pulled = Cell::new(0);
+        let source = stream::iter((0..10u8).map(|i| Ok(vec![i]))).inspect(|_| {
+            pulled.set(pulled.get() + 1);
+        });
+        let mut chunks = read_ahead(Box::pin(source), 3);
+
+        assert_eq!(chunks.next().await.unwrap().unwrap(), vec![0]);
+        assert_eq!(pulled.get(), 3);
+        assert_eq!(chunks.next().await.unwrap().unwrap(), vec![1]);
+        assert_eq!(pulled.get(), 4);
+
+        let rest: Vec<Vec<u8>> = chunks.map(|c| c.unwrap()).collect().await;
+        assert_eq!(rest, (2..10u8).map(|i| vec![i]).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_read_ahead_sends_chunks_before_an_error() {
+        let source = stream::iter(vec![
+            Ok(b"one".to_vec()),
+            Ok(b"two".to_vec()),
+            Err(worker::Error::RustError("stream broke".to_string())),
+            Ok(b"never".to_vec()),
+        ]);
+        let items: Vec<Result<Vec<u8>>> = read_ahead(source, 8).collect().await;
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].as_ref().unwrap(), b"one");
+        assert_eq!(items[1].as_ref().unwrap(), b"two");
+        assert!(items[2].is_err());
+    }
+}
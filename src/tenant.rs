@@ -0,0 +1,184 @@
+//! Tenant isolation for file storage
+//!
+//! Off unless `TENANTS` lists the allowed tenant IDs. When on, every `/files/`
+//! and `/blobs/` request must name a tenant, either with an `X-Tenant-Id`
+//! header or as the first label of the host name (`acme.files.example.com`).
+//! On a tenant's own host name the header can only repeat that tenant.
+//! Each tenant's file mappings live in their own `FileMappingObject` instance,
+//! so listings and deletes never see another tenant's files.
+//!
+//! Blobs are shared, and so deduplicated, across tenants by default. Set
+//! `TENANT_BLOB_ISOLATION=true` to store each tenant's blobs under
+//! `tenants/{id}/` instead.
+
+/// Request header naming the tenant
+pub const TENANT_HEADER: &str = "X-Tenant-Id";
+
+/// Which tenant a request is for
+#[derive(Debug, PartialEq)]
+pub enum TenantResolution {
+    /// Tenancy is off; use the shared namespace
+    Disabled,
+    /// An allowed tenant
+    Tenant(String),
+    /// Tenancy is on but the request didn't name a tenant
+    Missing,
+    /// The request named a tenant that isn't allowed
+    Unknown,
+    /// The header named a different tenant than the host name
+    Conflict,
+}
+
+/// Allowed tenant IDs from `TENANTS`, a comma-separated list
+///
+/// IDs may only contain ASCII letters, digits, `-` and `_`, so they're safe in
+/// storage keys; anything else is skipped.
+pub fn parse_tenant_allowlist(value: Option<&str>) -> Vec<String> {
+    value
+        .unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .filter(|id| is_valid_tenant_id(id))
+        .map(str::to_string)
+        .collect()
+}
+
+fn is_valid_tenant_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Work out the tenant from the `X-Tenant-Id` header or the host's subdomain
+///
+/// A subdomain only counts if it's allowed, since the Worker's own host name
+/// has labels too. When it does, the host decides: a header naming another
+/// tenant is a `Conflict`, so one tenant's host can't reach another's files.
+pub fn resolve_tenant(
+    header: Option<&str>,
+    host: Option<&str>,
+    allowlist: &[String],
+) -> TenantResolution {
+    if allowlist.is_empty() {
+        return TenantResolution::Disabled;
+    }
+
+    let subdomain = host
+        .map(|h| h.split(':').next().unwrap_or(h))
+        .filter(|h| h.split('.').count() >= 3)
+        .and_then(|h| h.split('.').next());
+    let host_tenant = subdomain.and_then(|label| {
+        allowlist
+            .iter()
+            .find(|allowed| allowed.eq_ignore_ascii_case(label))
+    });
+
+    if let Some(id) = header.map(str::trim).filter(|id| !id.is_empty()) {
+        return match (allowlist.iter().find(|allowed| *allowed == id), host_tenant) {
+            (None, _) => TenantResolution::Unknown,
+            (Some(named), Some(host)) if named != host => TenantResolution::Conflict,
+            (Some(named), _) => TenantResolution::Tenant(named.clone()),
+        };
+    }
+
+    match host_tenant {
+        Some(allowed) => TenantResolution::Tenant(allowed.clone()),
+        None => TenantResolution::Missing,
+    }
+}
+
+/// Prefix for a tenant's blob keys when blobs are isolated per tenant
+pub fn tenant_blob_prefix(tenant: &str) -> String {
+    format!("tenants/{}/", tenant)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist() -> Vec<String> {
+        parse_tenant_allowlist(Some("acme, globex"))
+    }
+
+    #[test]
+    fn test_parse_tenant_allowlist() {
+        assert!(parse_tenant_allowlist(None).is_empty());
+        assert_eq!(allowlist(), vec!["acme", "globex"]);
+        assert_eq!(
+            parse_tenant_allowlist(Some("ok_1,bad/id,,has space,../x")),
+            vec!["ok_1"]
+        );
+    }
+
+    #[test]
+    fn test_resolve_tenant_disabled() {
+        assert_eq!(
+            resolve_tenant(Some("acme"), None, &[]),
+            TenantResolution::Disabled
+        );
+    }
+
+    #[test]
+    fn test_resolve_tenant_from_header() {
+        let allowed = allowlist();
+        assert_eq!(
+            resolve_tenant(Some("acme"), Some("work-rs.me.workers.dev"), &allowed),
+            TenantResolution::Tenant("acme".to_string())
+        );
+        assert_eq!(
+            resolve_tenant(Some("acme"), None, &allowed),
+            TenantResolution::Tenant("acme".to_string())
+        );
+        assert_eq!(
+            resolve_tenant(Some("initech"), None, &allowed),
+            TenantResolution::Unknown
+        );
+    }
+
+    #[test]
+    fn test_resolve_tenant_header_must_match_host() {
+        let allowed = allowlist();
+        // acme's host can't be pointed at globex's files
+        assert_eq!(
+            resolve_tenant(Some("globex"), Some("acme.files.example.com"), &allowed),
+            TenantResolution::Conflict
+        );
+        // Repeating the host's tenant is fine
+        assert_eq!(
+            resolve_tenant(Some("acme"), Some("ACME.files.example.com"), &allowed),
+            TenantResolution::Tenant("acme".to_string())
+        );
+        assert_eq!(
+            resolve_tenant(Some("initech"), Some("acme.files.example.com"), &allowed),
+            TenantResolution::Unknown
+        );
+    }
+
+    #[test]
+    fn test_resolve_tenant_from_subdomain() {
+        let allowed = allowlist();
+        assert_eq!(
+            resolve_tenant(None, Some("Globex.files.example.com:443"), &allowed),
+            TenantResolution::Tenant("globex".to_string())
+        );
+        // Unlisted subdomains, and hosts without one, don't name a tenant
+        assert_eq!(
+            resolve_tenant(None, Some("work-rs.me.workers.dev"), &allowed),
+            TenantResolution::Missing
+        );
+        assert_eq!(
+            resolve_tenant(None, Some("acme.com"), &allowed),
+            TenantResolution::Missing
+        );
+        assert_eq!(
+            resolve_tenant(None, None, &allowed),
+            TenantResolution::Missing
+        );
+    }
+
+    #[test]
+    fn test_tenant_blob_prefix() {
+        assert_eq!(tenant_blob_prefix("acme"), "tenants/acme/");
+    }
+}
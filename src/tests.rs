@@ -1,6 +1,5 @@
 #[cfg(test)]
 mod tests {
-    use serde_json;
     use std::collections::HashMap;
 
     // Simple KV store mock for testing Durable Objects concepts
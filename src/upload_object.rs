@@ -0,0 +1,301 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use worker::*;
+
+/// How long an upload may go without a new chunk before it's discarded
+pub const UPLOAD_EXPIRY_SECS: u64 = 24 * 60 * 60;
+
+/// A chunk received for a resumable upload, staged in R2 until completion
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UploadChunk {
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Progress of a resumable upload
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UploadState {
+    pub key: String,
+    pub created_at: u64,
+    /// Received chunks, sorted by offset
+    pub chunks: Vec<UploadChunk>,
+}
+
+impl UploadState {
+    pub fn new(key: &str, created_at: u64) -> Self {
+        Self {
+            key: key.to_string(),
+            created_at,
+            chunks: Vec::new(),
+        }
+    }
+
+    /// Record a chunk, replacing any earlier chunk at the same offset
+    ///
+    /// Chunks may arrive in any order, but can't overlap other chunks.
+    pub fn add_chunk(&mut self, offset: u64, size: u64) -> std::result::Result<(), String> {
+        if size == 0 {
+            return Err("Chunk is empty".to_string());
+        }
+        let end = offset
+            .checked_add(size)
+            .ok_or_else(|| "Chunk offset out of range".to_string())?;
+
+        // A retried chunk replaces the earlier attempt
+        let others = self.chunks.iter().filter(|c| c.offset != offset);
+        if let Some(other) = others
+            .clone()
+            .find(|c| offset < c.offset + c.size && c.offset < end)
+        {
+            return Err(format!(
+                "Chunk at offset {} overlaps chunk at offset {}",
+                offset, other.offset
+            ));
+        }
+
+        let mut chunks: Vec<UploadChunk> = others.cloned().collect();
+        chunks.push(UploadChunk { offset, size });
+        chunks.sort_by_key(|c| c.offset);
+        self.chunks = chunks;
+        Ok(())
+    }
+
+    /// Total bytes received so far
+    pub fn received_bytes(&self) -> u64 {
+        self.chunks.iter().map(|c| c.size).sum()
+    }
+
+    /// Bytes received outside the chunk at `offset`, which a retry would replace
+    pub fn received_bytes_excluding(&self, offset: u64) -> u64 {
+        self.chunks
+            .iter()
+            .filter(|c| c.offset != offset)
+            .map(|c| c.size)
+            .sum()
+    }
+
+    /// Check the chunks cover the file from offset 0 with no gaps
+    pub fn check_complete(&self) -> std::result::Result<(), String> {
+        if self.chunks.is_empty() {
+            return Err("No chunks uploaded".to_string());
+        }
+
+        let mut expected = 0;
+        for chunk in &self.chunks {
+            if chunk.offset != expected {
+                return Err(format!("Missing bytes at offset {}", expected));
+            }
+            expected = chunk.offset + chunk.size;
+        }
+        Ok(())
+    }
+}
+
+/// A resumable upload endpoint, parsed from a file key
+#[derive(Debug, PartialEq)]
+pub enum UploadRoute<'a> {
+    /// `{key}/uploads`: start an upload
+    Start { key: &'a str },
+    /// `{key}/uploads/{id}`: send a chunk or abort
+    Chunk { key: &'a str, upload_id: &'a str },
+    /// `{key}/uploads/{id}/complete`: assemble the chunks
+    Complete { key: &'a str, upload_id: &'a str },
+}
+
+impl UploadRoute<'_> {
+    /// Methods the route accepts, for the `Allow` header of a 405
+    pub fn allowed_methods(&self) -> &'static [Method] {
+        match self {
+            UploadRoute::Start { .. } | UploadRoute::Complete { .. } => &[Method::Post],
+            UploadRoute::Chunk { .. } => &[Method::Put, Method::Delete],
+        }
+    }
+}
+
+/// Recognize resumable upload paths
+///
+/// Only well-formed upload IDs match, so a file that happens to live under an
+/// `uploads/` directory is still reachable as a plain file.
+pub fn parse_upload_route(path: &str) -> Option<UploadRoute<'_>> {
+    if let Some(key) = path.strip_suffix("/uploads") {
+        return (!key.is_empty()).then_some(UploadRoute::Start { key });
+    }
+
+    let (rest, complete) = match path.strip_suffix("/complete") {
+        Some(rest) => (rest, true),
+        None => (path, false),
+    };
+    let (prefix, upload_id) = rest.rsplit_once('/')?;
+    let key = prefix.strip_suffix("/uploads")?;
+    if key.is_empty() || !is_valid_upload_id(upload_id) {
+        return None;
+    }
+
+    Some(if complete {
+        UploadRoute::Complete { key, upload_id }
+    } else {
+        UploadRoute::Chunk { key, upload_id }
+    })
+}
+
+/// R2 prefix under which an upload's chunks are staged
+pub fn staging_prefix(upload_id: &str) -> String {
+    format!("staging/{}/", upload_id)
+}
+
+/// R2 key where a chunk is staged until the upload completes
+pub fn staging_key(upload_id: &str, offset: u64) -> String {
+    format!("{}{}", staging_prefix(upload_id), offset)
+}
+
+/// Where an upload's chunks are staged, so an expired upload can clean them up
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StagingArea {
+    pub upload_id: String,
+    /// Binding of the R2 bucket the chunks are written to
+    pub binding: String,
+}
+
+/// Check that an upload ID looks like one we generated (32 hex characters)
+pub fn is_valid_upload_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Generate a random upload ID
+pub fn new_upload_id() -> String {
+    let mut id = String::with_capacity(32);
+    for _ in 0..4 {
+        let word = (js_sys::Math::random() * u32::MAX as f64) as u32;
+        id.push_str(&format!("{:08x}", word));
+    }
+    id
+}
+
+/// Durable Object tracking the chunks of one resumable upload
+///
+/// One instance exists per upload ID. Chunk bytes live in R2 under
+/// `staging/{upload_id}/`; this object only records which offsets arrived.
+/// An upload that gets no chunks for `UPLOAD_EXPIRY_SECS` is discarded by an
+/// alarm, staged chunks included.
+#[durable_object]
+pub struct UploadObject {
+    state: State,
+    env: Env,
+}
+
+impl UploadObject {
+    /// Push the upload's expiry back to `UPLOAD_EXPIRY_SECS` from now
+    async fn extend_expiry(&self) -> Result<()> {
+        self.state
+            .storage()
+            .set_alarm(Duration::from_secs(UPLOAD_EXPIRY_SECS))
+            .await
+    }
+
+    /// Delete everything staged under the upload's prefix in R2
+    async fn delete_staged_chunks(&self, staging: &StagingArea) -> Result<()> {
+        let bucket = self.env.bucket(&staging.binding)?;
+        let mut cursor: Option<String> = None;
+        loop {
+            let mut list = bucket.list().prefix(staging_prefix(&staging.upload_id));
+            if let Some(c) = cursor.take() {
+                list = list.cursor(c);
+            }
+            let page = list.execute().await?;
+
+            for object in page.objects() {
+                bucket.delete(object.key()).await?;
+            }
+
+            match page.cursor() {
+                Some(c) if page.truncated() => cursor = Some(c),
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DurableObject for UploadObject {
+    fn new(state: State, env: Env) -> Self {
+        Self { state, env }
+    }
+
+    async fn fetch(&self, mut req: Request) -> Result<Response> {
+        let storage = self.state.storage();
+        let path = req.path();
+
+        match (req.method(), path.as_str()) {
+            (Method::Get, "/") => match storage.get::<UploadState>("upload").await {
+                Ok(upload) => Response::from_json(&upload),
+                Err(_) => Response::error("Upload not found", 404),
+            },
+            (Method::Put, "/") => {
+                // Start the upload
+                let body: serde_json::Value = req.json().await?;
+                let key = match body.get("key").and_then(|k| k.as_str()) {
+                    Some(k) => k,
+                    None => return Response::error("Key required", 400),
+                };
+
+                // Older callers don't say where chunks are staged; those uploads
+                // still expire, but their chunks are left for an abort to delete
+                let staging = match (
+                    body.get("upload_id").and_then(|i| i.as_str()),
+                    body.get("binding").and_then(|b| b.as_str()),
+                ) {
+                    (Some(upload_id), Some(binding)) => Some(StagingArea {
+                        upload_id: upload_id.to_string(),
+                        binding: binding.to_string(),
+                    }),
+                    _ => None,
+                };
+
+                let upload = UploadState::new(key, js_sys::Date::now() as u64);
+                storage.put("upload", &upload).await?;
+                if let Some(staging) = staging {
+                    storage.put("staging", &staging).await?;
+                }
+                self.extend_expiry().await?;
+                Ok(Response::from_json(&upload)?.with_status(201))
+            }
+            (Method::Put, "/chunks") => {
+                // Record a staged chunk
+                let chunk: UploadChunk = req.json().await?;
+
+                let mut upload = match storage.get::<UploadState>("upload").await {
+                    Ok(u) => u,
+                    Err(_) => return Response::error("Upload not found", 404),
+                };
+                if let Err(e) = upload.add_chunk(chunk.offset, chunk.size) {
+                    return Response::error(e, 409);
+                }
+
+                storage.put("upload", &upload).await?;
+                self.extend_expiry().await?;
+                Response::from_json(&upload)
+            }
+            (Method::Delete, "/") => {
+                storage.delete_all().await?;
+                storage.delete_alarm().await?;
+                Response::ok("Upload removed")
+            }
+            _ => Response::error("Not found", 404),
+        }
+    }
+
+    async fn alarm(&self) -> Result<Response> {
+        let storage = self.state.storage();
+        if let Ok(staging) = storage.get::<StagingArea>("staging").await {
+            if let Err(e) = self.delete_staged_chunks(&staging).await {
+                console_log!("Failed to delete staged chunks: {:?}", e);
+                // Keep the record so the next attempt can find the chunks
+                self.extend_expiry().await?;
+                return Response::ok("Expiry retried");
+            }
+        }
+
+        storage.delete_all().await?;
+        Response::ok("Upload expired")
+    }
+}
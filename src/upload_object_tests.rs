@@ -0,0 +1,165 @@
+#[cfg(test)]
+mod upload_object_tests {
+    use crate::upload_object::*;
+    use worker::Method;
+
+    const UPLOAD_ID: &str = "0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn test_add_chunk_out_of_order() {
+        let mut upload = UploadState::new("big.bin", 1000);
+        upload.add_chunk(200, 100).unwrap();
+        upload.add_chunk(0, 100).unwrap();
+        upload.add_chunk(100, 100).unwrap();
+
+        let offsets: Vec<u64> = upload.chunks.iter().map(|c| c.offset).collect();
+        assert_eq!(offsets, vec![0, 100, 200]);
+        assert_eq!(upload.received_bytes(), 300);
+        assert!(upload.check_complete().is_ok());
+    }
+
+    #[test]
+    fn test_add_chunk_retry_replaces() {
+        let mut upload = UploadState::new("big.bin", 1000);
+        upload.add_chunk(0, 100).unwrap();
+        upload.add_chunk(0, 50).unwrap();
+
+        assert_eq!(
+            upload.chunks,
+            vec![UploadChunk {
+                offset: 0,
+                size: 50
+            }]
+        );
+    }
+
+    #[test]
+    fn test_received_bytes_excluding() {
+        let mut upload = UploadState::new("big.bin", 1000);
+        upload.add_chunk(0, 100).unwrap();
+        upload.add_chunk(100, 50).unwrap();
+
+        assert_eq!(upload.received_bytes_excluding(100), 100);
+        assert_eq!(upload.received_bytes_excluding(0), 50);
+        // A new offset counts everything received so far
+        assert_eq!(upload.received_bytes_excluding(150), 150);
+    }
+
+    #[test]
+    fn test_add_chunk_rejects_overlap_and_empty() {
+        let mut upload = UploadState::new("big.bin", 1000);
+        upload.add_chunk(100, 100).unwrap();
+
+        assert!(upload.add_chunk(150, 100).is_err());
+        assert!(upload.add_chunk(50, 100).is_err());
+        assert!(upload.add_chunk(0, 0).is_err());
+        assert!(upload.add_chunk(u64::MAX, 2).is_err());
+
+        // Adjacent chunks are fine
+        assert!(upload.add_chunk(0, 100).is_ok());
+        assert!(upload.add_chunk(200, 1).is_ok());
+    }
+
+    #[test]
+    fn test_check_complete_gaps() {
+        let mut upload = UploadState::new("big.bin", 1000);
+        assert_eq!(
+            upload.check_complete(),
+            Err("No chunks uploaded".to_string())
+        );
+
+        upload.add_chunk(100, 100).unwrap();
+        assert_eq!(
+            upload.check_complete(),
+            Err("Missing bytes at offset 0".to_string())
+        );
+
+        upload.add_chunk(0, 50).unwrap();
+        assert_eq!(
+            upload.check_complete(),
+            Err("Missing bytes at offset 50".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_upload_route() {
+        assert_eq!(
+            parse_upload_route("videos/big.mp4/uploads"),
+            Some(UploadRoute::Start {
+                key: "videos/big.mp4"
+            })
+        );
+
+        let path = format!("big.mp4/uploads/{}", UPLOAD_ID);
+        assert_eq!(
+            parse_upload_route(&path),
+            Some(UploadRoute::Chunk {
+                key: "big.mp4",
+                upload_id: UPLOAD_ID
+            })
+        );
+
+        let path = format!("big.mp4/uploads/{}/complete", UPLOAD_ID);
+        assert_eq!(
+            parse_upload_route(&path),
+            Some(UploadRoute::Complete {
+                key: "big.mp4",
+                upload_id: UPLOAD_ID
+            })
+        );
+    }
+
+    #[test]
+    fn test_upload_route_allowed_methods() {
+        let route = parse_upload_route("big.mp4/uploads").unwrap();
+        assert_eq!(route.allowed_methods(), &[Method::Post]);
+
+        let path = format!("big.mp4/uploads/{}", UPLOAD_ID);
+        let route = parse_upload_route(&path).unwrap();
+        assert_eq!(route.allowed_methods(), &[Method::Put, Method::Delete]);
+    }
+
+    #[test]
+    fn test_parse_upload_route_plain_files() {
+        // Ordinary files, including ones under an uploads/ directory
+        assert_eq!(parse_upload_route("big.mp4"), None);
+        assert_eq!(parse_upload_route("uploads"), None);
+        assert_eq!(parse_upload_route("docs/uploads/report.pdf"), None);
+        assert_eq!(parse_upload_route("docs/uploads/report.pdf/complete"), None);
+        assert_eq!(parse_upload_route(&format!("uploads/{}", UPLOAD_ID)), None);
+    }
+
+    #[test]
+    fn test_upload_ids() {
+        assert!(is_valid_upload_id(UPLOAD_ID));
+        assert!(!is_valid_upload_id("short"));
+        assert!(!is_valid_upload_id(&"z".repeat(32)));
+
+        assert_eq!(
+            staging_key(UPLOAD_ID, 1024),
+            format!("staging/{}/1024", UPLOAD_ID)
+        );
+    }
+
+    #[test]
+    fn test_staging_prefix_covers_chunks() {
+        let prefix = staging_prefix(UPLOAD_ID);
+        assert_eq!(prefix, format!("staging/{}/", UPLOAD_ID));
+        assert!(staging_key(UPLOAD_ID, 0).starts_with(&prefix));
+        assert!(staging_key(UPLOAD_ID, 1 << 40).starts_with(&prefix));
+
+        // Another upload's chunks are never under this prefix
+        let other = "f".repeat(32);
+        assert!(!staging_key(&other, 0).starts_with(&prefix));
+    }
+
+    #[test]
+    fn test_upload_state_serialization() {
+        let mut upload = UploadState::new("big.bin", 1000);
+        upload.add_chunk(0, 10).unwrap();
+
+        let json = serde_json::to_string(&upload).unwrap();
+        let parsed: UploadState = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, upload);
+    }
+}
@@ -0,0 +1,367 @@
+//! ZIP archives of files, written on the fly
+//!
+//! `GET /files/{prefix}/?archive=zip` streams every file under a prefix as a
+//! ZIP. Entries are stored uncompressed, since most large files (images,
+//! video, archives) are compressed already, and each entry's CRC-32 is worked
+//! out as its bytes stream past and sent in a data descriptor after them, so
+//! nothing is buffered beyond the chunk in flight. ZIP64 isn't written, so an
+//! archive must stay under 4 GiB and 65,535 entries; `fits_zip32` checks that
+//! up front.
+
+use crate::http_date::civil_from_days;
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x0807_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0605_4b50;
+
+const LOCAL_HEADER_LEN: u64 = 30;
+const DATA_DESCRIPTOR_LEN: u64 = 16;
+const CENTRAL_HEADER_LEN: u64 = 46;
+const END_OF_CENTRAL_DIRECTORY_LEN: u64 = 22;
+
+/// Version 2.0, the first with data descriptors
+const VERSION: u16 = 20;
+/// Sizes and CRC follow the data (bit 3), and names are UTF-8 (bit 11)
+const FLAGS: u16 = 0x0808;
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Incremental CRC-32 (IEEE), the checksum ZIP entries carry
+pub struct Crc32(u32);
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32(!0)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 = CRC32_TABLE[((self.0 ^ byte as u32) & 0xFF) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+/// MS-DOS `(time, date)` of a timestamp in milliseconds
+///
+/// DOS dates start in 1980, so anything earlier is clamped to its start.
+pub fn dos_date_time(timestamp_ms: u64) -> (u16, u16) {
+    let secs = timestamp_ms / 1000;
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    if year < 1980 {
+        return (0, 1 << 5 | 1);
+    }
+    let secs_of_day = secs % 86_400;
+    let time = (secs_of_day / 3600) << 11 | (secs_of_day % 3600 / 60) << 5 | (secs_of_day % 60 / 2);
+    let date = ((year - 1980).min(127) as u64) << 9 | (month as u64) << 5 | day as u64;
+    (time as u16, date as u16)
+}
+
+/// Whether files with these `(name length, size)` fit in a ZIP without ZIP64
+pub fn fits_zip32(entries: impl IntoIterator<Item = (usize, u64)>) -> bool {
+    let mut count: u64 = 0;
+    let mut total = END_OF_CENTRAL_DIRECTORY_LEN;
+    for (name_len, size) in entries {
+        if name_len > u16::MAX as usize || size > u32::MAX as u64 {
+            return false;
+        }
+        count += 1;
+        total += LOCAL_HEADER_LEN + DATA_DESCRIPTOR_LEN + CENTRAL_HEADER_LEN;
+        total += 2 * name_len as u64 + size;
+    }
+    count <= u16::MAX as u64 && total <= u32::MAX as u64
+}
+
+/// The `Content-Disposition` filename for an archive of `prefix`
+///
+/// Named after the prefix's last segment, e.g. `reports.zip` for
+/// `docs/reports/`, with anything but letters, digits, `-`, `_` and `.`
+/// replaced by `_`. The bucket root is `files.zip`.
+pub fn archive_filename(prefix: &str) -> String {
+    let name: String = prefix
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or("")
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let name = name.trim_matches('.');
+    format!("{}.zip", if name.is_empty() { "files" } else { name })
+}
+
+struct CentralEntry {
+    name: String,
+    time: u16,
+    date: u16,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+/// Writes the framing of a ZIP around entry data the caller streams itself
+///
+/// For each entry, send `start_entry`'s header, then the entry's bytes, then
+/// `finish_entry`'s descriptor; `finish` ends the archive. Offsets wrap
+/// rather than fail past 4 GiB, so check `fits_zip32` first.
+#[derive(Default)]
+pub struct ZipWriter {
+    offset: u64,
+    entries: Vec<CentralEntry>,
+}
+
+impl ZipWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The local header opening an entry named `name`
+    pub fn start_entry(&mut self, name: &str, modified_ms: u64) -> Vec<u8> {
+        let (time, date) = dos_date_time(modified_ms);
+        let mut header = Vec::with_capacity(LOCAL_HEADER_LEN as usize + name.len());
+        put_u32(&mut header, LOCAL_HEADER_SIGNATURE);
+        put_u16(&mut header, VERSION);
+        put_u16(&mut header, FLAGS);
+        put_u16(&mut header, 0); // Stored
+        put_u16(&mut header, time);
+        put_u16(&mut header, date);
+        // CRC and sizes are zero here and sent in the data descriptor
+        put_u32(&mut header, 0);
+        put_u32(&mut header, 0);
+        put_u32(&mut header, 0);
+        put_u16(&mut header, name.len() as u16);
+        put_u16(&mut header, 0);
+        header.extend_from_slice(name.as_bytes());
+
+        self.entries.push(CentralEntry {
+            name: name.to_string(),
+            time,
+            date,
+            crc: 0,
+            size: 0,
+            offset: self.offset as u32,
+        });
+        self.offset += header.len() as u64;
+        header
+    }
+
+    /// The data descriptor closing the current entry, once its data is sent
+    pub fn finish_entry(&mut self, crc: u32, size: u64) -> Vec<u8> {
+        if let Some(entry) = self.entries.last_mut() {
+            entry.crc = crc;
+            entry.size = size as u32;
+        }
+        let mut descriptor = Vec::with_capacity(DATA_DESCRIPTOR_LEN as usize);
+        put_u32(&mut descriptor, DATA_DESCRIPTOR_SIGNATURE);
+        put_u32(&mut descriptor, crc);
+        put_u32(&mut descriptor, size as u32);
+        put_u32(&mut descriptor, size as u32);
+        self.offset += size + descriptor.len() as u64;
+        descriptor
+    }
+
+    /// The central directory and end record that finish the archive
+    pub fn finish(&mut self) -> Vec<u8> {
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            put_u32(&mut directory, CENTRAL_HEADER_SIGNATURE);
+            put_u16(&mut directory, VERSION);
+            put_u16(&mut directory, VERSION);
+            put_u16(&mut directory, FLAGS);
+            put_u16(&mut directory, 0);
+            put_u16(&mut directory, entry.time);
+            put_u16(&mut directory, entry.date);
+            put_u32(&mut directory, entry.crc);
+            put_u32(&mut directory, entry.size);
+            put_u32(&mut directory, entry.size);
+            put_u16(&mut directory, entry.name.len() as u16);
+            put_u16(&mut directory, 0); // Extra field length
+            put_u16(&mut directory, 0); // Comment length
+            put_u16(&mut directory, 0); // Disk number
+            put_u16(&mut directory, 0); // Internal attributes
+            put_u32(&mut directory, 0); // External attributes
+            put_u32(&mut directory, entry.offset);
+            directory.extend_from_slice(entry.name.as_bytes());
+        }
+
+        let directory_len = directory.len() as u32;
+        put_u32(&mut directory, END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        put_u16(&mut directory, 0);
+        put_u16(&mut directory, 0);
+        put_u16(&mut directory, self.entries.len() as u16);
+        put_u16(&mut directory, self.entries.len() as u16);
+        put_u32(&mut directory, directory_len);
+        put_u32(&mut directory, self.offset as u32);
+        put_u16(&mut directory, 0);
+        self.offset += directory.len() as u64;
+        directory
+    }
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([bytes[at], bytes[at + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    fn crc32(data: &[u8]) -> u32 {
+        let mut crc = Crc32::new();
+        crc.update(data);
+        crc.finish()
+    }
+
+    /// An archive of `files`, written the way the download streams it
+    fn archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new();
+        let mut out = Vec::new();
+        for (name, data) in files {
+            out.extend(writer.start_entry(name, 1_700_000_000_000));
+            let mut crc = Crc32::new();
+            for chunk in data.chunks(3) {
+                crc.update(chunk);
+                out.extend_from_slice(chunk);
+            }
+            out.extend(writer.finish_entry(crc.finish(), data.len() as u64));
+        }
+        out.extend(writer.finish());
+        out
+    }
+
+    #[test]
+    fn test_crc32() {
+        // The standard check value
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414F_A339
+        );
+    }
+
+    #[test]
+    fn test_dos_date_time() {
+        // 2023-11-14T22:13:20Z; DOS seconds are halved
+        assert_eq!(
+            dos_date_time(1_700_000_000_000),
+            (22 << 11 | 13 << 5 | 10, 43 << 9 | 11 << 5 | 14)
+        );
+        assert_eq!(dos_date_time(0), (0, 1 << 5 | 1));
+    }
+
+    #[test]
+    fn test_archive_layout() {
+        let zip = archive(&[("a.txt", b"hello"), ("docs/b.txt", b"")]);
+
+        // First entry: header, data, then the descriptor with its CRC and size
+        assert_eq!(u32_at(&zip, 0), LOCAL_HEADER_SIGNATURE);
+        assert_eq!(u16_at(&zip, 6), FLAGS);
+        assert_eq!(u16_at(&zip, 8), 0);
+        assert_eq!(u16_at(&zip, 26), 5);
+        assert_eq!(&zip[30..35], b"a.txt");
+        assert_eq!(&zip[35..40], b"hello");
+        assert_eq!(u32_at(&zip, 40), DATA_DESCRIPTOR_SIGNATURE);
+        assert_eq!(u32_at(&zip, 44), crc32(b"hello"));
+        assert_eq!(u32_at(&zip, 48), 5);
+
+        // Second entry starts straight after
+        let second = 56;
+        assert_eq!(u32_at(&zip, second), LOCAL_HEADER_SIGNATURE);
+        assert_eq!(&zip[second + 30..second + 40], b"docs/b.txt");
+
+        // The end record counts both entries and points at the central directory
+        let end = zip.len() - END_OF_CENTRAL_DIRECTORY_LEN as usize;
+        assert_eq!(u32_at(&zip, end), END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        assert_eq!(u16_at(&zip, end + 10), 2);
+        let directory = u32_at(&zip, end + 16) as usize;
+        assert_eq!(directory, second + 30 + 10 + 16);
+        assert_eq!(u32_at(&zip, end + 12) as usize, end - directory);
+
+        // Central headers carry the CRC, size and where each entry starts
+        assert_eq!(u32_at(&zip, directory), CENTRAL_HEADER_SIGNATURE);
+        assert_eq!(u32_at(&zip, directory + 16), crc32(b"hello"));
+        assert_eq!(u32_at(&zip, directory + 24), 5);
+        assert_eq!(u32_at(&zip, directory + 42), 0);
+        let next = directory + 46 + 5;
+        assert_eq!(u32_at(&zip, next), CENTRAL_HEADER_SIGNATURE);
+        assert_eq!(u32_at(&zip, next + 42) as usize, second);
+        assert_eq!(&zip[next + 46..next + 56], b"docs/b.txt");
+    }
+
+    #[test]
+    fn test_empty_archive() {
+        let zip = archive(&[]);
+        assert_eq!(zip.len(), END_OF_CENTRAL_DIRECTORY_LEN as usize);
+        assert_eq!(u32_at(&zip, 0), END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        assert_eq!(u16_at(&zip, 10), 0);
+    }
+
+    #[test]
+    fn test_fits_zip32() {
+        assert!(fits_zip32([]));
+        assert!(fits_zip32([(10, 1 << 20), (10, 1 << 20)]));
+        assert!(!fits_zip32([(10, u32::MAX as u64 + 1)]));
+        assert!(!fits_zip32([(10, 3 << 30), (10, 2 << 30)]));
+        assert!(!fits_zip32(std::iter::repeat((1, 0)).take(65_536)));
+        assert!(!fits_zip32([(70_000, 0)]));
+    }
+
+    #[test]
+    fn test_archive_filename() {
+        assert_eq!(archive_filename("docs/reports/"), "reports.zip");
+        assert_eq!(archive_filename("photos/"), "photos.zip");
+        assert_eq!(archive_filename(""), "files.zip");
+        assert_eq!(archive_filename("my%20docs/"), "my_20docs.zip");
+        assert_eq!(archive_filename("a/\"x\";y/"), "_x__y.zip");
+        assert_eq!(archive_filename("../"), "files.zip");
+    }
+}